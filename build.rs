@@ -0,0 +1,16 @@
+fn main() {
+    std::env::set_var(
+        "PROTOC",
+        protoc_bin_vendored::protoc_bin_path().expect("Failed to find vendored protoc"),
+    );
+
+    prost_build::compile_protos(&["proto/realtime.proto"], &["proto/"])
+        .expect("Failed to compile protobuf schema");
+
+    // realtime types are the ones already generated above
+    tonic_build::configure()
+        .build_client(false)
+        .extern_path(".realtime", "crate::connection::proto")
+        .compile(&["proto/gateway.proto"], &["proto/"])
+        .expect("Failed to compile grpc schema");
+}
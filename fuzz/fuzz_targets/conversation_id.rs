@@ -0,0 +1,16 @@
+#![no_main]
+
+// conversation ids come from clients, so any string can show up. one that parses has to print back the same way
+
+use libfuzzer_sys::fuzz_target;
+use realtime::fuzzing::ConversationId;
+
+fuzz_target!(|data: &str| {
+    if let Ok(conversation_id) = ConversationId::try_from(data.to_owned()) {
+        let _ = conversation_id.get_chooser_hash();
+        let _ = conversation_id.get_choosee_hash();
+        let _ = conversation_id.is_current();
+
+        assert_eq!(conversation_id.to_string(), data);
+    }
+});
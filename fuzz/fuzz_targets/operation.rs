@@ -0,0 +1,14 @@
+#![no_main]
+
+// whatever a client sends ends up here, text frames for json and binary ones for protobuf and grpc
+
+use libfuzzer_sys::fuzz_target;
+use realtime::fuzzing::Operation;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = Operation::from_str(text);
+    }
+
+    let _ = Operation::from_protobuf(data);
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+// events come off the message bus, where anything on the cluster can publish. whatever parses is also encoded again
+// the way it would be sent to a client
+
+use libfuzzer_sys::fuzz_target;
+use realtime::fuzzing::{Encoding, UserEvent};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(user_event) = UserEvent::from_slice(data) {
+        for encoding in [Encoding::Json, Encoding::Protobuf, Encoding::Grpc] {
+            let _ = user_event.to_message(encoding);
+        }
+    }
+});
@@ -0,0 +1,276 @@
+-- applied on startup by the postgres storage backend, so every statement has to be safe to run again
+
+-- written by the api when an account is made, the gateway only reads it
+CREATE TABLE IF NOT EXISTS "user" (
+    username TEXT PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+-- for searching by username prefix
+CREATE INDEX IF NOT EXISTS user_username_prefix ON "user" (username text_pattern_ops);
+
+CREATE TABLE IF NOT EXISTS conversation (
+    id TEXT PRIMARY KEY,
+    chooser_username TEXT NOT NULL,
+    choosee_username TEXT NOT NULL,
+    chooser_name TEXT NOT NULL,
+    choosee_name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS message (
+    conversation_id TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL,
+    content TEXT NOT NULL,
+    from_chooser BOOLEAN NOT NULL,
+    PRIMARY KEY (conversation_id, sent_at)
+);
+
+CREATE TABLE IF NOT EXISTS choosee_presence (
+    conversation_id TEXT NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL,
+    leaving BOOLEAN NOT NULL,
+    chooser_hash TEXT NOT NULL,
+    PRIMARY KEY (conversation_id, occurred_at)
+);
+
+-- the column only ever held the chooser's hash
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = 'choosee_presence' AND column_name = 'chooser_username') THEN
+        ALTER TABLE choosee_presence RENAME COLUMN chooser_username TO chooser_hash;
+    END IF;
+END $$;
+
+CREATE TABLE IF NOT EXISTS friend_request (
+    sender_username TEXT NOT NULL,
+    sender_name TEXT NOT NULL,
+    receiver_username TEXT NOT NULL,
+    receiver_name TEXT NOT NULL,
+    PRIMARY KEY (sender_username, receiver_username)
+);
+
+CREATE INDEX IF NOT EXISTS friend_request_receiver ON friend_request (receiver_username);
+
+CREATE TABLE IF NOT EXISTS friend (
+    username TEXT NOT NULL,
+    friend_username TEXT NOT NULL,
+    friend_name TEXT NOT NULL,
+    friendship_started_on TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (username, friend_username)
+);
+
+CREATE TABLE IF NOT EXISTS friend_of_friend (
+    username TEXT NOT NULL,
+    friend_of_friend_username TEXT NOT NULL,
+    friend_of_friend_name TEXT NOT NULL,
+    PRIMARY KEY (username, friend_of_friend_username)
+);
+
+-- split into buckets like the scylla table. whatever is left in the old outbox table gets moved here and the table
+-- dropped on startup
+CREATE TABLE IF NOT EXISTS outbox_entry (
+    bucket INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    entry_id TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    data BYTEA NOT NULL,
+    PRIMARY KEY (bucket, created_at, entry_id)
+);
+
+CREATE TABLE IF NOT EXISTS token_revocation (
+    username TEXT PRIMARY KEY,
+    revoked_before TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS bot_key (
+    key_id TEXT PRIMARY KEY,
+    username TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS failed_event (
+    subject TEXT NOT NULL,
+    failed_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    data BYTEA NOT NULL,
+    reason TEXT NOT NULL,
+    PRIMARY KEY (subject, failed_at)
+);
+
+-- disappearing messages. expired messages are filtered out of reads, nothing deletes them yet
+ALTER TABLE conversation ADD COLUMN IF NOT EXISTS disappearing_ttl_seconds INTEGER;
+
+ALTER TABLE message ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ;
+
+-- object store key of the avatar, null until one is uploaded
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS avatar_object_key TEXT;
+
+-- json, like the scylla column
+ALTER TABLE message ADD COLUMN IF NOT EXISTS attachment TEXT;
+
+CREATE TABLE IF NOT EXISTS pinned_message (
+    conversation_id TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL,
+    pinned_at TIMESTAMPTZ NOT NULL,
+    pinned_by_chooser BOOLEAN NOT NULL,
+    PRIMARY KEY (conversation_id, sent_at)
+);
+
+-- what each user has set on a conversation for themselves
+CREATE TABLE IF NOT EXISTS user_conversation (
+    username TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    pinned BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (username, conversation_id)
+);
+
+ALTER TABLE user_conversation ADD COLUMN IF NOT EXISTS archived BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- messages sent before this are hidden from this user only
+ALTER TABLE user_conversation ADD COLUMN IF NOT EXISTS cleared_before TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS push_token (
+    username_hash TEXT NOT NULL,
+    token TEXT NOT NULL,
+    platform TEXT NOT NULL,
+    username TEXT NOT NULL,
+    registered_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (username_hash, token)
+);
+
+ALTER TABLE user_conversation ADD COLUMN IF NOT EXISTS muted BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE user_conversation ADD COLUMN IF NOT EXISTS mentions_only BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- the dnd columns are all set or all null
+CREATE TABLE IF NOT EXISTS notification_prefs (
+    username TEXT PRIMARY KEY,
+    muted BOOLEAN NOT NULL,
+    mentions_only BOOLEAN NOT NULL,
+    dnd_start_minute SMALLINT,
+    dnd_end_minute SMALLINT,
+    dnd_utc_offset_minutes SMALLINT
+);
+
+-- a device counts as logged out while logged_out_at is after last_seen_at
+CREATE TABLE IF NOT EXISTS device (
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    last_seen_at TIMESTAMPTZ NOT NULL,
+    logged_out_at TIMESTAMPTZ,
+    PRIMARY KEY (username, device_id)
+);
+
+-- set once either user deletes their account, after which the conversation has no messages left
+ALTER TABLE conversation ADD COLUMN IF NOT EXISTS tombstoned_at TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS report (
+    report_id TEXT PRIMARY KEY,
+    reporter_username TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    message_sent_at TIMESTAMPTZ,
+    reason TEXT NOT NULL,
+    reported_at TIMESTAMPTZ NOT NULL
+);
+
+-- append only, nothing updates or deletes from it
+CREATE TABLE IF NOT EXISTS audit_log (
+    entry_id TEXT PRIMARY KEY,
+    action TEXT NOT NULL,
+    actor TEXT,
+    target TEXT,
+    connection_id TEXT,
+    detail TEXT NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_occurred_at ON audit_log (occurred_at);
+
+-- for the retention sweep
+CREATE INDEX IF NOT EXISTS conversation_created_at ON conversation (created_at);
+
+-- one row per background job, overwritten as it moves along
+CREATE TABLE IF NOT EXISTS job_status (
+    job_id TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    state TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT,
+    enqueued_at TIMESTAMPTZ NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+-- key material passed between the participants of a conversation, opaque to the server. expired rows are only
+-- filtered out, like expired messages
+CREATE TABLE IF NOT EXISTS key_distribution (
+    conversation_id TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL,
+    from_chooser BOOLEAN NOT NULL,
+    key_material TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (conversation_id, sent_at, from_chooser)
+);
+
+-- how many messages have been sent, counted as they're saved
+CREATE TABLE IF NOT EXISTS conversation_stats (
+    conversation_id TEXT NOT NULL,
+    from_chooser BOOLEAN NOT NULL,
+    messages BIGINT NOT NULL,
+    PRIMARY KEY (conversation_id, from_chooser)
+);
+
+CREATE TABLE IF NOT EXISTS user_daily_messages (
+    username TEXT NOT NULL,
+    day DATE NOT NULL,
+    messages BIGINT NOT NULL,
+    PRIMARY KEY (username, day)
+);
+
+-- the days both users sent something in a row, see streaks. each user's last day lets the other's first message of
+-- the day tell whether it extends the streak
+ALTER TABLE conversation ADD COLUMN IF NOT EXISTS streak INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE conversation ADD COLUMN IF NOT EXISTS streak_day DATE;
+
+ALTER TABLE conversation ADD COLUMN IF NOT EXISTS chooser_messaged_on DATE;
+
+ALTER TABLE conversation ADD COLUMN IF NOT EXISTS choosee_messaged_on DATE;
+
+-- from any device, for the daily digest
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS last_connected_at TIMESTAMPTZ;
+
+-- messages that mentioned the user, by the hash their events are published under. expired rows are only filtered out
+CREATE TABLE IF NOT EXISTS mention (
+    username_hash TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL,
+    conversation_id TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (username_hash, sent_at, conversation_id)
+);
+
+-- json, like the attachment
+ALTER TABLE message ADD COLUMN IF NOT EXISTS kind TEXT;
+
+-- votes are indexes into options, one column per participant like the streak
+CREATE TABLE IF NOT EXISTS poll (
+    conversation_id TEXT NOT NULL,
+    poll_id TEXT NOT NULL,
+    question TEXT NOT NULL,
+    options TEXT[] NOT NULL,
+    created_by_chooser BOOLEAN NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    chooser_vote INTEGER,
+    choosee_vote INTEGER,
+    PRIMARY KEY (conversation_id, poll_id)
+);
+
+-- the conversation each chooser most recently started with each choosee, for announcing the next one as its successor
+CREATE TABLE IF NOT EXISTS conversation_link (
+    chooser_hash TEXT NOT NULL,
+    choosee_hash TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    PRIMARY KEY (chooser_hash, choosee_hash)
+);
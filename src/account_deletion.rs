@@ -0,0 +1,105 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::audit::AuditLog;
+use crate::connection::{nats_message::NatsMessage, user_event::UserEvent};
+use crate::db::{self, Storage};
+use crate::hash::Hasher;
+use crate::jobs::{Job, JobError, JobHandler};
+use crate::message_bus::MessageBus;
+use crate::models::audit_entry::{AuditAction, AuditEntry};
+
+// runs as a job rather than on any connection, because the first thing it does is close all of the user's
+// connections. reached from the user deleting their own account, or an admin deleting it for them. audit_entry says
+// which, and is recorded once the purge is done along with one friend removal for each of the user's friends
+//
+// a retry after the purge went through finds no friends left, so only the first attempt to get that far records them
+
+pub const JOB_KIND: &str = "delete_account";
+
+// the purpose claim of the token the api issues once the user has confirmed, which deleteMyAccount has to be sent with
+pub const CONFIRMATION_PURPOSE: &str = "delete_account";
+
+pub const CONFIRMATION_MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize)]
+pub struct AccountDeletionJob {
+    pub username: String,
+    pub audit_entry: AuditEntry,
+}
+
+impl AccountDeletionJob {
+    pub fn job(self) -> Job {
+        Job::new(JOB_KIND, self)
+    }
+}
+
+pub struct AccountDeletion {
+    pub db: Arc<dyn Storage>,
+    pub message_bus: Arc<dyn MessageBus>,
+    pub hasher: Arc<Hasher>,
+    pub audit_log: Arc<AuditLog>,
+}
+
+#[async_trait]
+impl JobHandler for AccountDeletion {
+    async fn handle(&self, job: &Job) -> Result<(), JobError> {
+        let AccountDeletionJob {
+            username,
+            audit_entry,
+        } = job.payload()?;
+
+        publish(
+            self.message_bus.as_ref(),
+            NatsMessage {
+                to_username_hash: self.hasher.hash(&username),
+                user_event: UserEvent::AccountDeleted,
+            },
+        )
+        .await;
+
+        let friend_profiles = self.db.get_friends(&username).await?; // read before the purge takes them away
+
+        db::purge_user(self.db.as_ref(), &username, &self.hasher.hashes(&username)).await?;
+
+        self.audit_log.record(audit_entry);
+
+        for friend_profile in friend_profiles {
+            self.audit_log.record(AuditEntry {
+                entry_id: Uuid::new_v4().to_string(),
+                action: AuditAction::FriendRemoved,
+                actor: Some(username.clone()),
+                target: Some(friend_profile.username.clone()),
+                connection_id: None,
+                detail: "Account deleted".to_owned(),
+                occurred_at: Utc::now(),
+            });
+
+            publish(
+                self.message_bus.as_ref(),
+                NatsMessage {
+                    to_username_hash: self.hasher.hash(&friend_profile.username),
+                    user_event: UserEvent::FriendRemoved {
+                        username: username.clone(),
+                    },
+                },
+            )
+            .await;
+        }
+
+        Ok(())
+    }
+}
+
+async fn publish(message_bus: &dyn MessageBus, nats_message: NatsMessage) {
+    if let Err(err) = message_bus
+        .publish(&nats_message.subject(), &nats_message.data())
+        .await
+    {
+        warn!("Failed to publish account deletion event: {}", err); // deletion itself still goes ahead, clients will catch up on reconnect
+    }
+}
@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::message_bus::MessageBusError;
+use crate::metrics;
+
+#[cfg(feature = "kafka")]
+mod kafka;
+
+#[cfg(feature = "kafka")]
+pub use self::kafka::KafkaSink;
+
+// a copy of every message and choose that gets stored, for engagement dashboards that shouldn't have to query scylla.
+// recording never holds up an operation and nothing is retried, so dashboards can be missing a few events but users
+// never notice the pipeline being down
+//
+// registerPresenceChoosee doesn't store anything yet, so there are no presence events to mirror until it does
+//
+// content is left out unless asked for, events still say how long it was
+
+#[async_trait]
+pub trait AnalyticsSink: Send + Sync {
+    // key keeps every event of a conversation in order, for sinks that partition
+    async fn send(&self, key: &str, data: Vec<u8>) -> Result<(), MessageBusError>;
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AnalyticsEvent {
+    #[serde(rename_all = "camelCase")]
+    Chose {
+        conversation_id: String,
+        sent_at: DateTime<Utc>,
+        content_length: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Sent {
+        conversation_id: String,
+        sent_at: DateTime<Utc>,
+        from_chooser: bool,
+        has_attachment: bool,
+        content_length: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+}
+
+impl AnalyticsEvent {
+    fn conversation_id(&self) -> &str {
+        match self {
+            Self::Chose {
+                conversation_id, ..
+            }
+            | Self::Sent {
+                conversation_id, ..
+            } => conversation_id,
+        }
+    }
+}
+
+pub struct Analytics {
+    sink: Arc<dyn AnalyticsSink>,
+    include_content: bool,
+}
+
+impl Analytics {
+    pub fn new(sink: Arc<dyn AnalyticsSink>, include_content: bool) -> Self {
+        Self {
+            sink,
+            include_content,
+        }
+    }
+
+    // what goes in an event's content, None unless content is included
+    pub fn content(&self, content: &str) -> Option<String> {
+        self.include_content.then(|| content.to_owned())
+    }
+
+    pub fn record(&self, event: AnalyticsEvent) {
+        let sink = self.sink.clone();
+
+        tokio::task::spawn(async move {
+            let data = serde_json::to_vec(&event).expect("Analytics event should serialize");
+
+            if let Err(err) = sink.send(event.conversation_id(), data).await {
+                metrics::ANALYTICS_DROPPED.increment();
+
+                warn!("Failed to record analytics event: {}", err);
+            }
+        });
+    }
+}
@@ -0,0 +1,51 @@
+use async_trait::async_trait;
+use rdkafka::{
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    ClientConfig,
+};
+use std::time::Duration;
+
+use super::AnalyticsSink;
+use crate::message_bus::MessageBusError;
+
+// works the same against redpanda. events that can't be delivered within the timeout are dropped instead of piling up
+// in memory while the brokers are down
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct KafkaSink {
+    topic: String,
+    producer: FutureProducer,
+}
+
+impl KafkaSink {
+    pub fn connect(brokers: &str, topic: &str) -> Result<Self, MessageBusError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set(
+                "message.timeout.ms",
+                DELIVERY_TIMEOUT.as_millis().to_string(),
+            )
+            .create::<FutureProducer>()?;
+
+        Ok(Self {
+            topic: topic.to_owned(),
+            producer,
+        })
+    }
+}
+
+#[async_trait]
+impl AnalyticsSink for KafkaSink {
+    async fn send(&self, key: &str, data: Vec<u8>) -> Result<(), MessageBusError> {
+        self.producer
+            .send(
+                FutureRecord::to(&self.topic).key(key).payload(&data),
+                Timeout::After(DELIVERY_TIMEOUT),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|(err, _)| MessageBusError::from(err))
+    }
+}
@@ -0,0 +1,92 @@
+use chrono::prelude::*;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::connection::nats_message;
+use crate::db::Storage;
+use crate::message_bus::MessageBus;
+use crate::models::{
+    audit_entry::{AuditAction, AuditEntry},
+    ip_ban::IpBan,
+};
+
+// sensitive actions are written to the audit_log table, and also published to audit.log when AUDIT_LOG_PUBLISH is set
+// for anything that wants them as they happen. recording never holds up or fails the action being recorded, a write
+// that fails is only logged
+//
+// admins read it back a day at a time with the auditLog admin operation
+
+pub struct AuditLog {
+    db: Arc<dyn Storage>,
+    message_bus: Arc<dyn MessageBus>,
+    publish: bool,
+}
+
+impl AuditLog {
+    pub fn new(db: Arc<dyn Storage>, message_bus: Arc<dyn MessageBus>, publish: bool) -> Self {
+        Self {
+            db,
+            message_bus,
+            publish,
+        }
+    }
+
+    pub fn record(self: &Arc<Self>, audit_entry: AuditEntry) {
+        let audit_log = self.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = audit_log.db.add_audit_entry(&audit_entry).await {
+                error!(
+                    "Failed to add {} audit entry: {}",
+                    audit_entry.action.as_str(),
+                    err
+                );
+            }
+
+            if audit_log.publish {
+                if let Err(err) = audit_log
+                    .message_bus
+                    .publish(
+                        &nats_message::audit_log_subject(),
+                        &serde_json::to_vec(&audit_entry).expect("Audit entry should serialize"),
+                    )
+                    .await
+                {
+                    warn!("Failed to publish audit entry: {}", err);
+                }
+            }
+        });
+    }
+    // the same for every way in, the websocket handshake, grpc and the rest api. username is unset when the token
+    // couldn't be read
+    pub fn auth_failed(
+        self: &Arc<Self>,
+        username: Option<String>,
+        connection_id: &str,
+        detail: String,
+    ) {
+        self.record(AuditEntry {
+            entry_id: Uuid::new_v4().to_string(),
+            action: AuditAction::AuthFailed,
+            actor: username,
+            target: None,
+            connection_id: Some(connection_id.to_owned()),
+            detail,
+            occurred_at: Utc::now(),
+        });
+    }
+    // for the bans made automatically, admins' are recorded along with who made them
+    pub fn banned(self: &Arc<Self>, ban: &IpBan) {
+        warn!("Banned {} until {}: {}", ban.ip, ban.until, ban.reason);
+
+        self.record(AuditEntry {
+            entry_id: Uuid::new_v4().to_string(),
+            action: AuditAction::Banned,
+            actor: None,
+            target: Some(ban.ip.to_string()),
+            connection_id: None,
+            detail: ban.reason.clone(),
+            occurred_at: Utc::now(),
+        });
+    }
+}
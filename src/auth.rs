@@ -1,10 +1,28 @@
-use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use chrono::prelude::*;
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
 use tungstenite::handshake::server::Request;
 
+use crate::db::{DatabaseError, Storage};
+
+pub mod bot;
+pub mod identify;
+pub mod permissions;
+
 pub struct JWTAuth {
     decoding_key: DecodingKey,
     validation: Validation,
+    max_token_age: Option<Duration>,
+}
+
+// issuer and audience are only checked when configured, but once they are tokens without the claim are rejected
+pub struct JWTValidationConfig {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub leeway: Duration,
+    pub max_token_age: Option<Duration>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -12,31 +30,249 @@ pub struct JWTAuth {
 pub struct AccessTokenPayload {
     pub phone_number: i64,
     pub username: String,
+    pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+    // tokens issued for a particular device. takes precedence over whatever device id the client sends itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    // set on tokens the api issues to confirm one action, like deleting an account. those aren't access tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+}
+
+impl AccessTokenPayload {
+    pub fn expires_in(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time before unix epoch")
+            .as_secs();
+
+        Duration::from_secs(self.exp.saturating_sub(now))
+    }
+}
+
+// display strings are sent as the body of the 401 handshake response, so they shouldn't leak anything about the secret
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("Malformed authorization header")]
+    MalformedHeader,
+    #[error("Malformed access token")]
+    Malformed,
+    #[error("Invalid access token signature")]
+    InvalidSignature,
+    #[error("Access token expired")]
+    Expired,
+    #[error("Access token not yet valid")]
+    NotYetValid,
+    #[error("Access token has wrong issuer")]
+    InvalidIssuer,
+    #[error("Access token has wrong audience")]
+    InvalidAudience,
+    #[error("Access token missing claim: {0}")]
+    MissingClaim(String),
+    #[error("Access token too old")]
+    TooOld,
+    #[error("Access token issued for another purpose")]
+    WrongPurpose,
+    #[error("Invalid access token")]
+    Invalid,
+}
+
+impl From<jsonwebtoken::errors::Error> for AuthError {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        match err.into_kind() {
+            ErrorKind::InvalidToken
+            | ErrorKind::Base64(_)
+            | ErrorKind::Json(_)
+            | ErrorKind::Utf8(_) => Self::Malformed,
+            ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => Self::InvalidSignature,
+            ErrorKind::ExpiredSignature => Self::Expired,
+            ErrorKind::ImmatureSignature => Self::NotYetValid,
+            ErrorKind::InvalidIssuer => Self::InvalidIssuer,
+            ErrorKind::InvalidAudience => Self::InvalidAudience,
+            ErrorKind::MissingRequiredClaim(claim) => Self::MissingClaim(claim),
+            _ => Self::Invalid,
+        }
+    }
 }
 
 impl JWTAuth {
-    pub fn new(access_token_secret: &str) -> Self {
+    pub fn new(access_token_secret: &str, config: JWTValidationConfig) -> Self {
         let access_token_secret = access_token_secret.as_bytes();
 
+        let mut validation = Validation::new(Algorithm::HS256);
+
+        let mut required_spec_claims = vec!["exp"];
+
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+            required_spec_claims.push("iss");
+        }
+
+        if let Some(audience) = &config.audience {
+            validation.set_audience(&[audience]);
+            required_spec_claims.push("aud");
+        }
+
+        validation.set_required_spec_claims(&required_spec_claims);
+        validation.leeway = config.leeway.as_secs();
+
         Self {
             decoding_key: DecodingKey::from_secret(access_token_secret),
-            validation: Validation::new(Algorithm::HS256),
+            validation,
+            max_token_age: config.max_token_age,
+        }
+    }
+
+    // browsers can't set headers on websocket upgrades, so the token may also come as a ?token= query param. Ok(None)
+    // means no token was passed at all and the client is expected to identify with its first frame instead
+    pub fn veryify_req(&self, req: &Request) -> Result<Option<AccessTokenPayload>, AuthError> {
+        let token = match req.headers().get("Authorization") {
+            Some(header) => Some(
+                header
+                    .to_str()
+                    .map_err(|_| AuthError::MalformedHeader)?
+                    .strip_prefix("Bearer ")
+                    .ok_or(AuthError::MalformedHeader)?,
+            ),
+            None => req.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("token="))
+            }),
+        };
+
+        token.map(|token| self.verify_token(token)).transpose()
+    }
+
+    // clients can say which device they're on with an X-Device-Id header or a ?deviceId= query param. ignored when
+    // it isn't a plausible id
+    pub fn requested_device_id(req: &Request) -> Option<String> {
+        match req.headers().get("X-Device-Id") {
+            Some(header) => header.to_str().ok().map(|device_id| device_id.to_owned()),
+            None => req.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("deviceId="))
+                    .map(|device_id| device_id.to_owned())
+            }),
         }
+        .filter(|device_id| is_valid_device_id(device_id))
     }
 
-    pub fn veryify_req(&self, req: &Request) -> Result<AccessTokenPayload, ()> {
-        jsonwebtoken::decode::<AccessTokenPayload>(
-            req.headers()
-                .get("Authorization")
-                .ok_or(())?
-                .to_str()
-                .map_err(|_| ())?
-                .strip_prefix("Bearer ")
-                .ok_or(())?,
+    pub fn verify_token(&self, token: &str) -> Result<AccessTokenPayload, AuthError> {
+        let payload = self.decode(token)?;
+
+        if payload.purpose.is_some() {
+            return Err(AuthError::WrongPurpose);
+        }
+
+        Ok(payload)
+    }
+
+    // for tokens confirming a single action. they have to have been issued for it, and recently, so one lying around
+    // in a client's storage or logs is soon useless
+    pub fn verify_purpose_token(
+        &self,
+        token: &str,
+        purpose: &str,
+        max_age: Duration,
+    ) -> Result<AccessTokenPayload, AuthError> {
+        let payload = self.decode(token)?;
+
+        if payload.purpose.as_deref() != Some(purpose) {
+            return Err(AuthError::WrongPurpose);
+        }
+
+        let issued_at = payload
+            .iat
+            .ok_or_else(|| AuthError::MissingClaim("iat".to_owned()))?;
+
+        if Self::age(issued_at) > max_age.as_secs() + self.validation.leeway {
+            return Err(AuthError::TooOld);
+        }
+
+        Ok(payload)
+    }
+
+    fn decode(&self, token: &str) -> Result<AccessTokenPayload, AuthError> {
+        let payload = jsonwebtoken::decode::<AccessTokenPayload>(
+            token,
             &self.decoding_key,
             &self.validation,
-        )
-        .map_err(|_| ())
-        .map(|token_data| token_data.claims)
+        )?
+        .claims;
+
+        if let Some(max_token_age) = self.max_token_age {
+            let issued_at = payload
+                .iat
+                .ok_or_else(|| AuthError::MissingClaim("iat".to_owned()))?;
+
+            if Self::age(issued_at) > max_token_age.as_secs() + self.validation.leeway {
+                return Err(AuthError::TooOld);
+            }
+        }
+
+        Ok(payload)
     }
+
+    fn age(issued_at: u64) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time before unix epoch")
+            .as_secs()
+            .saturating_sub(issued_at)
+    }
+}
+
+const MAX_DEVICE_ID_LENGTH: usize = 128;
+
+pub fn is_valid_device_id(device_id: &str) -> bool {
+    !device_id.is_empty()
+        && device_id.len() <= MAX_DEVICE_ID_LENGTH
+        && device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+// the revokeTokens admin op writes the time to token_revocation and publishes to <prefix>control.revoke.<username_hash>
+// so open connections close too. tokens issued before the last revocation are rejected, and so are tokens without an
+// iat once there has been one
+//
+// logging out a device does the same for just the tokens used on that device
+//
+// checked before the websocket upgrade when the token comes with the request. a token sent in the identify frame only
+// arrives after the upgrade, so it's checked then and the socket closed with a policy violation
+pub async fn is_revoked(
+    db: &dyn Storage,
+    payload: &AccessTokenPayload,
+    device_id: Option<&str>,
+) -> Result<bool, DatabaseError> {
+    let issued_before = |revoked_before: DateTime<Utc>| {
+        payload
+            .iat
+            .is_none_or(|issued_at| issued_at as i64 <= revoked_before.timestamp())
+    };
+
+    if db
+        .get_revoked_before(&payload.username)
+        .await?
+        .is_some_and(issued_before)
+    {
+        return Ok(true);
+    }
+
+    Ok(match device_id {
+        Some(device_id) => db
+            .get_device_logged_out_at(&payload.username, device_id)
+            .await?
+            .is_some_and(issued_before),
+        None => false,
+    })
 }
@@ -0,0 +1,111 @@
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use thiserror::Error;
+use tungstenite::http::HeaderMap;
+
+use super::AccessTokenPayload;
+use crate::db::{DatabaseError, Storage};
+
+// bot and integration accounts authenticate with an api key instead of an access token, in three headers:
+//
+//   X-Api-Key: <key id>
+//   X-Api-Timestamp: 1700000000
+//   X-Api-Signature: <hex hmac-sha256 of "1700000000.<key id>" with the key's secret>
+//
+// the timestamp has to be recent, so a captured handshake can't be replayed for long. the connection then runs as if
+// it had an access token for the bot's account that lasts SESSION_LENGTH, after which it's closed like an expired
+// one and the bot signs a new handshake
+
+// either way, for clocks that are a little off
+const MAX_CLOCK_SKEW: u64 = 300;
+
+const SESSION_LENGTH: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct BotCredentials {
+    key_id: String,
+    timestamp: i64,
+    signature: Vec<u8>,
+}
+
+// display strings are sent back like AuthError's, and don't say whether a key exists without a valid signature for it
+
+#[derive(Error, Debug)]
+pub enum BotAuthError {
+    #[error("Malformed api key headers")]
+    Malformed,
+    #[error("Api key signature timestamp too far from now")]
+    Stale,
+    #[error("Invalid api key signature")]
+    InvalidSignature,
+    #[error("Api key revoked")]
+    Revoked,
+    #[error("Error getting api key: {0}")]
+    DatabaseError(#[from] DatabaseError),
+}
+
+impl BotCredentials {
+    // Ok(None) means there's no api key, and whoever it is should authenticate like a user
+    pub fn from_headers(headers: &HeaderMap) -> Result<Option<Self>, BotAuthError> {
+        let header = |name| {
+            headers
+                .get(name)
+                .map(|value| value.to_str().map_err(|_| BotAuthError::Malformed))
+                .transpose()
+        };
+
+        let Some(key_id) = header("X-Api-Key")? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            key_id: key_id.to_owned(),
+            timestamp: header("X-Api-Timestamp")?
+                .and_then(|timestamp| timestamp.parse().ok())
+                .ok_or(BotAuthError::Malformed)?,
+            signature: header("X-Api-Signature")?
+                .and_then(|signature| hex::decode(signature).ok())
+                .ok_or(BotAuthError::Malformed)?,
+        }))
+    }
+
+    pub async fn verify(&self, db: &dyn Storage) -> Result<AccessTokenPayload, BotAuthError> {
+        let now = Utc::now().timestamp();
+
+        if now.abs_diff(self.timestamp) > MAX_CLOCK_SKEW {
+            return Err(BotAuthError::Stale);
+        }
+
+        let bot_key = db
+            .get_bot_key(&self.key_id)
+            .await?
+            .ok_or(BotAuthError::InvalidSignature)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(bot_key.secret.as_bytes())
+            .expect("HMAC should accept keys of any length");
+
+        mac.update(format!("{}.{}", self.timestamp, self.key_id).as_bytes());
+
+        mac.verify_slice(&self.signature)
+            .map_err(|_| BotAuthError::InvalidSignature)?;
+
+        if bot_key
+            .revoked_at
+            .is_some_and(|revoked_at| revoked_at <= Utc::now())
+        {
+            return Err(BotAuthError::Revoked);
+        }
+
+        Ok(AccessTokenPayload {
+            phone_number: 0, // bots don't have one
+            username: bot_key.username,
+            exp: now as u64 + SESSION_LENGTH.as_secs(),
+            iat: Some(now as u64),
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            device_id: None,
+            purpose: None,
+        })
+    }
+}
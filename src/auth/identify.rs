@@ -0,0 +1,75 @@
+use futures_util::StreamExt;
+use prost::Message as _;
+use serde::Deserialize;
+use std::time::Duration;
+use thiserror::Error;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::Message;
+
+use super::{AccessTokenPayload, AuthError, JWTAuth};
+use crate::connection::proto;
+use crate::transport::Transport;
+
+// the only frame accepted before a connection is authenticated
+
+#[derive(Deserialize)]
+#[serde(tag = "op", content = "d", rename_all = "camelCase")]
+enum PreAuthFrame {
+    Identify {
+        token: String,
+        #[serde(default, rename = "deviceId")]
+        device_id: Option<String>,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum IdentifyError {
+    #[error("Client did not identify before the deadline")]
+    DeadlineExceeded,
+    #[error("Connection closed before client identified")]
+    Closed,
+    #[error("Websocket error: {0}")]
+    WebSocketError(#[from] tungstenite::Error),
+    #[error("Received unexpected message format: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Received unexpected message format: {0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("Received unexpected frame before identifying")]
+    UnexpectedFrame,
+    #[error("{0}")]
+    InvalidToken(#[from] AuthError),
+}
+
+// also returns the device id the client sent with its token, if any
+pub async fn identify(
+    websocket: &mut WebSocketStream<Transport>,
+    jwt_auth: &JWTAuth,
+    deadline: Duration,
+) -> Result<(AccessTokenPayload, Option<String>), IdentifyError> {
+    let (token, device_id) = tokio::time::timeout(deadline, async {
+        loop {
+            match websocket.next().await.ok_or(IdentifyError::Closed)?? {
+                Message::Text(text) => {
+                    let PreAuthFrame::Identify { token, device_id } = serde_json::from_str(&text)?;
+
+                    return Ok((token, device_id));
+                }
+                Message::Binary(binary) => {
+                    let identify = proto::Identify::decode(binary.as_slice())?;
+
+                    return Ok((identify.token, identify.device_id));
+                }
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Close(_) => return Err(IdentifyError::Closed),
+                Message::Frame(_) => return Err(IdentifyError::UnexpectedFrame),
+            }
+        }
+    })
+    .await
+    .map_err(|_| IdentifyError::DeadlineExceeded)??;
+
+    Ok((
+        jwt_auth.verify_token(&token)?,
+        device_id.filter(|device_id| super::is_valid_device_id(device_id)),
+    ))
+}
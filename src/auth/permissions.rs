@@ -0,0 +1,43 @@
+use super::AccessTokenPayload;
+
+const ADMIN_ROLE: &str = "admin";
+const READ_SCOPE: &str = "read";
+const WRITE_SCOPE: &str = "write";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+// tokens issued before scopes existed don't have any, and keep full user access
+
+#[derive(Clone, Debug)]
+pub struct Permissions {
+    read: bool,
+    write: bool,
+    admin: bool,
+}
+
+impl Permissions {
+    pub fn from_payload(payload: &AccessTokenPayload) -> Self {
+        let has_scope = |scope: &str| {
+            payload.scopes.is_empty() || payload.scopes.iter().any(|granted| granted == scope)
+        };
+
+        Self {
+            read: has_scope(READ_SCOPE),
+            write: has_scope(WRITE_SCOPE),
+            admin: payload.roles.iter().any(|role| role == ADMIN_ROLE),
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        match permission {
+            Permission::Read => self.read || self.admin,
+            Permission::Write => self.write || self.admin,
+            Permission::Admin => self.admin,
+        }
+    }
+}
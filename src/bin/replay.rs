@@ -0,0 +1,210 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+use tokio::sync::oneshot;
+use tokio::time::{sleep_until, Instant};
+use tungstenite::{client::IntoClientRequest, Message};
+
+use realtime::{
+    auth::{AccessTokenPayload, JWTAuth, JWTValidationConfig},
+    hash::{HashAlgorithm, HashEncoding, Hasher},
+    Server,
+};
+
+// replays the inbound frames of a recording made with RECORD_DIR, then prints what came back next to what was
+// originally recorded. by default against a server started in process on the in memory backends, so nothing has to
+// be running and nothing the replay does outlives it. pass a url and ACCESS_TOKEN to replay against a running
+// gateway instead
+//
+// usage: cargo run --bin replay -- <recording.jsonl> [ws://127.0.0.1:<port>]
+//
+// the in process server signs a token for REPLAY_USERNAME, or "replay" when that isn't set
+
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+const DEFAULT_USERNAME: &str = "replay";
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordedFrame {
+    direction: Direction,
+    at_ms: u64,
+    frame: String,
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = env::args().skip(1);
+
+    let recording_path = args
+        .next()
+        .expect("Must pass the path of a recording to replay");
+
+    // kept alive until the replay is done, the in process server shuts down once it's dropped
+    let (url, access_token, _shutdown_tx) = match args.next() {
+        Some(url) => (
+            url,
+            env::var("ACCESS_TOKEN").expect("Must set ACCESS_TOKEN environment variable"),
+            None,
+        ),
+        None => {
+            let username =
+                env::var("REPLAY_USERNAME").unwrap_or_else(|_| DEFAULT_USERNAME.to_owned());
+
+            let (addr, access_token, shutdown_tx) = start_in_process(&username).await;
+
+            (format!("ws://{}", addr), access_token, Some(shutdown_tx))
+        }
+    };
+
+    let recorded_frames = fs::read_to_string(&recording_path)
+        .expect("Failed to read recording")
+        .lines()
+        .map(|line| serde_json::from_str::<RecordedFrame>(line).expect("Invalid recorded frame"))
+        .collect::<Vec<_>>();
+
+    let mut request = url.into_client_request().expect("Invalid url");
+    request.headers_mut().insert(
+        "Authorization",
+        format!("Bearer {}", access_token)
+            .parse()
+            .expect("Invalid access token"),
+    );
+
+    let (websocket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("Failed to connect to gateway");
+
+    let (mut tx, mut rx) = websocket.split();
+
+    let receiver = tokio::task::spawn(async move {
+        let mut received = Vec::new();
+
+        while let Some(Ok(message)) = rx.next().await {
+            if let Message::Text(text) = message {
+                println!("<- {}", text);
+
+                received.push(text);
+            }
+        }
+
+        received
+    });
+
+    let started_at = Instant::now();
+
+    for recorded_frame in recorded_frames
+        .iter()
+        .filter(|recorded_frame| recorded_frame.direction == Direction::Inbound)
+    {
+        sleep_until(started_at + Duration::from_millis(recorded_frame.at_ms)).await;
+
+        println!("-> {}", recorded_frame.frame);
+
+        tx.send(Message::Text(recorded_frame.frame.clone()))
+            .await
+            .expect("Failed to send frame");
+    }
+
+    tokio::time::sleep(GRACE_PERIOD).await;
+
+    let _ = tx.close().await;
+
+    let received = receiver.await.unwrap_or_default();
+
+    println!();
+    println!("Originally recorded outbound frames:");
+
+    let mut recorded_outbound_count = 0;
+
+    for recorded_frame in recorded_frames
+        .iter()
+        .filter(|recorded_frame| recorded_frame.direction == Direction::Outbound)
+    {
+        println!("<- {}", recorded_frame.frame);
+
+        recorded_outbound_count += 1;
+    }
+
+    println!();
+    println!(
+        "Received {} frames, recording had {}",
+        received.len(),
+        recorded_outbound_count
+    );
+}
+
+// the secret only has to be shared with the token signed here
+async fn start_in_process(username: &str) -> (SocketAddr, String, oneshot::Sender<()>) {
+    let secret = format!("{:032x}", rand::random::<u128>());
+
+    let (bound_tx, bound_rx) = oneshot::channel();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+    let server = Server::builder()
+        .with_auth(JWTAuth::new(
+            &secret,
+            JWTValidationConfig {
+                issuer: None,
+                audience: None,
+                leeway: Duration::from_secs(60),
+                max_token_age: None,
+            },
+        ))
+        .with_hasher(Arc::new(Hasher::new(
+            secret.clone(),
+            HashAlgorithm::HmacSha256,
+            HashEncoding::Base64Url,
+            false,
+        )))
+        .bind("127.0.0.1:0".parse().unwrap())
+        .on_bound(|addrs| {
+            let _ = bound_tx.send(addrs[0]);
+        })
+        .with_shutdown(async {
+            let _ = shutdown_rx.await;
+        });
+
+    tokio::spawn(async {
+        if let Err(err) = server.run().await {
+            panic!("In process server failed: {}", err);
+        }
+    });
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time before unix epoch")
+        .as_secs();
+
+    let access_token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &AccessTokenPayload {
+            phone_number: 0,
+            username: username.to_owned(),
+            exp: now + 24 * 60 * 60,
+            iat: Some(now),
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            device_id: None,
+            purpose: None,
+        },
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .expect("Failed to sign access token");
+
+    (
+        bound_rx.await.expect("In process server failed to start"),
+        access_token,
+        shutdown_tx,
+    )
+}
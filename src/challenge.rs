@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::net::IpAddr;
+use thiserror::Error;
+
+mod site_verify;
+
+pub use self::site_verify::{SiteVerifyChallenges, SiteVerifyOptions};
+
+// a way to slow down abuse, like waves of fresh accounts made by scripts, without turning anyone away. a verifier
+// decides which connections have to prove they're a person on a real device first, and those are sent a
+// challengeRequired response right after hello and in answer to every mutation until they send a solution to
+// solveChallenge. queries, token refreshes and everything bots do are never held back
+//
+// the gateway only passes challenges and solutions along, what they are is up to the verifier. a captcha's solution
+// is the token its widget hands the client, an attestation's is whatever the platform's attestation api returned for
+// the nonce
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ChallengeKind {
+    Captcha,
+    Attestation,
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct Challenge {
+    pub kind: ChallengeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site_key: Option<String>, // for rendering a captcha widget
+    pub nonce: String, // for binding an attestation to this connection
+}
+
+// what's known about a client when deciding whether to challenge it
+pub struct ChallengeContext {
+    pub username: String,
+    pub ip: Option<IpAddr>, // unknown for unix sockets without a proxy protocol header
+    pub device_id: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ChallengeError {
+    #[error("Error verifying challenge: {0}")]
+    Unavailable(String),
+}
+
+#[async_trait]
+pub trait ChallengeVerifier: Send + Sync {
+    // asked once per connection, right after hello. None lets the connection do everything straight away
+    async fn challenge(&self, context: &ChallengeContext) -> Option<Challenge>;
+
+    // whether the solution solves the challenge this connection was given
+    async fn verify(
+        &self,
+        context: &ChallengeContext,
+        challenge: &Challenge,
+        solution: &str,
+    ) -> Result<bool, ChallengeError>;
+}
@@ -0,0 +1,235 @@
+use chrono::prelude::*;
+use futures_util::future::try_join_all;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::connection::{nats_message, ConnectionRegistry};
+use crate::handshake_limit::HandshakeLimiter;
+use crate::message_bus::{BusMessage, MessageBus, Subscription};
+use crate::models::node_summary::NodeSummary;
+
+// running more than one node. nodes don't know about each other, any number of them can run against the same storage
+// and message bus with a load balancer spreading connections over them
+//
+// per user subjects stay plain subscriptions since every node with a connection for the user needs the event. work
+// that has to happen once cluster wide, like sending a push notification, is consumed through a queue group instead
+// so the bus hands each message to only one of the nodes subscribed. work that's enqueued or runs on an interval, like
+// account deletion or the retention sweep, goes through the job queue instead. message retention doesn't need either,
+// scylla expires messages with a ttl on its own. the outbox can't go through the bus since it's for when the bus is
+// down, so it's split into buckets that each node mostly drains its own of, see outbox.rs
+//
+// nats and kafka have queue groups but redis doesn't, so with redis only one node should run each kind of worker
+//
+// every node also announces itself on an interval, so any of them can show what the whole fleet looks like
+//
+// which node a user is connected to is tracked by presence, for skipping push notifications to users connected
+// anywhere and for sending control messages like kicks to just the node that needs them
+
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+// how many announcements in a row a node can miss before it's assumed gone
+const MISSED_ANNOUNCEMENTS: u32 = 3;
+
+// one queue group per kind of work
+pub const PUSH_QUEUE: &str = "push";
+
+// hands each message published to any of the subjects to handle on only one node in the queue group. resubscribes
+// whenever the bus ends the subscription, so it never returns
+pub async fn consume(
+    message_bus: &dyn MessageBus,
+    subjects: &[String],
+    queue: &str,
+    mut handle: impl FnMut(BusMessage),
+) {
+    loop {
+        let mut subscription = match try_join_all(
+            subjects
+                .iter()
+                .map(|subject| message_bus.queue_subscribe(subject, queue)),
+        )
+        .await
+        {
+            Ok(subscriptions) => Subscription::merge(subscriptions),
+            Err(err) => {
+                warn!("Failed to subscribe to the {} queue: {}", queue, err);
+
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+
+                continue;
+            }
+        };
+
+        while let Some(bus_message) = subscription.next().await {
+            handle(bus_message);
+        }
+
+        warn!("Subscription to the {} queue ended, resubscribing", queue);
+    }
+}
+
+// kicks for users the presence registry says are on this node, from admins connected to other nodes
+pub async fn handle_kicks(
+    message_bus: Arc<dyn MessageBus>,
+    node_id: String,
+    registry: Arc<ConnectionRegistry>,
+) {
+    let subject = nats_message::node_kick_subject(&node_id);
+
+    loop {
+        let mut subscription = match message_bus.subscribe(&subject).await {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                warn!("Failed to subscribe to kicks for this node: {}", err);
+
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+
+                continue;
+            }
+        };
+
+        while let Some(bus_message) = subscription.next().await {
+            if let Ok(username) = std::str::from_utf8(&bus_message.data) {
+                let kicked = registry.kick(username);
+
+                info!(
+                    "Kicked {} connections of user {} for another node",
+                    kicked, username
+                );
+            }
+        }
+
+        warn!("Subscription to kicks for this node ended, resubscribing");
+    }
+}
+
+// admin bans from any node, this one included
+pub async fn handle_bans(
+    message_bus: Arc<dyn MessageBus>,
+    handshake_limiter: Arc<HandshakeLimiter>,
+) {
+    let subject = nats_message::bans_subject();
+
+    loop {
+        let mut subscription = match message_bus.subscribe(&subject).await {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                warn!("Failed to subscribe to bans: {}", err);
+
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+
+                continue;
+            }
+        };
+
+        while let Some(bus_message) = subscription.next().await {
+            match serde_json::from_slice(&bus_message.data) {
+                Ok(ban_update) => handshake_limiter.apply(ban_update),
+                Err(err) => warn!("Invalid ban received from the bus: {}", err),
+            }
+        }
+
+        warn!("Subscription to bans ended, resubscribing");
+    }
+}
+
+// the latest announcement from every node that's still announcing, this one included
+pub struct Membership {
+    announce_interval: Duration,
+    nodes: Mutex<HashMap<String, (NodeSummary, Instant)>>, // and when it was received, since clocks can disagree
+}
+
+impl Membership {
+    pub fn new(announce_interval: Duration) -> Self {
+        Self {
+            announce_interval,
+            nodes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn nodes(&self) -> Vec<NodeSummary> {
+        let mut nodes = self.nodes.lock().unwrap();
+
+        nodes.retain(|_, (_, received_at)| {
+            received_at.elapsed() < self.announce_interval * MISSED_ANNOUNCEMENTS
+        });
+
+        let mut nodes = nodes
+            .values()
+            .map(|(node, _)| node.clone())
+            .collect::<Vec<_>>();
+
+        nodes.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+
+        nodes
+    }
+
+    fn received(&self, node: NodeSummary) {
+        self.nodes
+            .lock()
+            .unwrap()
+            .insert(node.node_id.clone(), (node, Instant::now()));
+    }
+}
+
+pub async fn announce(
+    message_bus: Arc<dyn MessageBus>,
+    node_id: String,
+    registry: Arc<ConnectionRegistry>,
+    membership: Arc<Membership>,
+) {
+    let started_at = Utc::now();
+
+    let mut interval = tokio::time::interval(membership.announce_interval);
+
+    loop {
+        interval.tick().await;
+
+        let node = NodeSummary {
+            node_id: node_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            connections: registry.count(),
+            started_at,
+            announced_at: Utc::now(),
+        };
+
+        // this node is in its own view even while the bus is down
+        membership.received(node.clone());
+
+        if let Err(err) = message_bus
+            .publish(
+                &nats_message::node_announcements_subject(),
+                &serde_json::to_vec(&node).expect("Node summary should serialize"),
+            )
+            .await
+        {
+            warn!("Failed to announce node: {}", err);
+        }
+    }
+}
+
+pub async fn track_membership(message_bus: Arc<dyn MessageBus>, membership: Arc<Membership>) {
+    let subject = nats_message::node_announcements_subject();
+
+    loop {
+        let mut subscription = match message_bus.subscribe(&subject).await {
+            Ok(subscription) => subscription,
+            Err(err) => {
+                warn!("Failed to subscribe to node announcements: {}", err);
+
+                tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+
+                continue;
+            }
+        };
+
+        while let Some(bus_message) = subscription.next().await {
+            match serde_json::from_slice(&bus_message.data) {
+                Ok(node) => membership.received(node),
+                Err(err) => warn!("Received undecodable node announcement: {}", err),
+            }
+        }
+
+        warn!("Subscription to node announcements ended, resubscribing");
+    }
+}
@@ -0,0 +1,217 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::{env, fmt, fs, str::FromStr};
+
+// every setting init reads, layered from lowest to highest priority: the defaults in init, a config file, environment
+// variables (including .env), then command line flags. keys are the environment variable names everywhere, though
+// the file can also write them lowercase, like max_content_length = 4096
+//
+// reading a setting never fails on its own. problems are collected so that everything wrong with the config can be
+// reported at once instead of one restart at a time
+
+#[derive(Parser)]
+#[command(about = "Realtime websocket gateway")]
+pub struct Cli {
+    /// toml or yaml, by extension
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    /// runs without scylla, nats or any config, for trying a client against locally
+    #[arg(long)]
+    pub dev: bool,
+    /// only handles background jobs, without accepting connections
+    #[arg(long)]
+    pub worker: bool,
+    /// prints the json protocol as a json schema and exits
+    #[arg(long)]
+    pub dump_schema: bool,
+    /// like 0.0.0.0 or ::
+    #[arg(long)]
+    pub host: Option<String>,
+    /// 0 binds any free port
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// any other setting, like --set MAX_CONTENT_LENGTH=8192
+    #[arg(long = "set", value_name = "KEY=VALUE", value_parser = parse_override)]
+    pub overrides: Vec<(String, String)>,
+}
+
+// lists become comma separated, the way the environment variables take them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<FileValue>),
+}
+
+impl fmt::Display for FileValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Integer(value) => write!(f, "{}", value),
+            Self::Float(value) => write!(f, "{}", value),
+            Self::String(value) => write!(f, "{}", value),
+            Self::List(values) => write!(
+                f,
+                "{}",
+                values
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+
+        for problem in &self.problems {
+            writeln!(f, "  {}", problem)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub struct Config {
+    values: HashMap<String, String>,
+    problems: RefCell<Vec<String>>,
+}
+
+impl Config {
+    pub fn load(cli: &Cli) -> Self {
+        let config = Self {
+            values: HashMap::new(),
+            problems: RefCell::new(Vec::new()),
+        };
+
+        let mut values = HashMap::new();
+
+        if let Some(path) = &cli.config {
+            match Self::read_file(path) {
+                Ok(file) => values.extend(
+                    file.into_iter()
+                        .map(|(key, value)| (key.to_uppercase(), value.to_string())),
+                ),
+                Err(err) => config.problem(format!("{}: {}", path.display(), err)),
+            }
+        }
+
+        values.extend(env::vars());
+
+        if let Some(host) = &cli.host {
+            values.insert("HOST".to_owned(), host.clone());
+        }
+
+        if let Some(port) = cli.port {
+            values.insert("PORT".to_owned(), port.to_string());
+        }
+
+        values.extend(cli.overrides.iter().cloned());
+
+        Self { values, ..config }
+    }
+
+    fn read_file(path: &Path) -> Result<HashMap<String, FileValue>, String> {
+        let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|err| err.to_string()),
+            Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+            _ => Err("config file has to end in .toml, .yaml or .yml".to_owned()),
+        }
+    }
+
+    // for --dev, which only fills in what wasn't set some other way
+    pub fn set_default(&mut self, key: &str, value: &str) {
+        self.values
+            .entry(key.to_owned())
+            .or_insert_with(|| value.to_owned());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(key)
+            .map(String::as_str)
+            .filter(|value| !value.is_empty())
+    }
+
+    pub fn is_set(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn optional<T: FromStr>(&self, key: &str) -> Option<T> {
+        let value = self.get(key)?;
+
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.invalid(key, value);
+
+                None
+            }
+        }
+    }
+
+    // for tunables that have a sensible default, unlike the connection details
+    pub fn or<T: FromStr>(&self, key: &str, default: T) -> T {
+        self.optional(key).unwrap_or(default)
+    }
+
+    // the default is only a placeholder until finish reports it missing
+    pub fn required<T: FromStr + Default>(&self, key: &str) -> T {
+        if !self.is_set(key) {
+            self.problem(format!("{} must be set", key));
+        }
+
+        self.optional(key).unwrap_or_default()
+    }
+
+    // same as required, for settings that are only needed because another one is set
+    pub fn required_with<T: FromStr + Default>(&self, key: &str, with: &str) -> T {
+        if !self.is_set(key) {
+            self.problem(format!("{} must be set with {}", key, with));
+        }
+
+        self.optional(key).unwrap_or_default()
+    }
+
+    pub fn invalid(&self, key: &str, value: &str) {
+        self.problem(format!("{} could not be parsed from {:?}", key, value));
+    }
+
+    pub fn problem(&self, problem: String) {
+        self.problems.borrow_mut().push(problem);
+    }
+
+    pub fn finish(self) -> Result<(), ConfigError> {
+        let problems = self.problems.into_inner();
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+}
+
+fn parse_override(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.trim().to_uppercase(), value.to_owned()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {}", arg))
+}
@@ -1,75 +1,368 @@
+use arc_swap::ArcSwap;
 use futures_util::StreamExt;
-use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
+use std::net::IpAddr;
+use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
 use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
 
-use crate::db::Database;
-use crate::hash;
+use crate::analytics::Analytics;
+use crate::audit::AuditLog;
+use crate::auth::{permissions::Permissions, JWTAuth};
+use crate::challenge::{ChallengeContext, ChallengeVerifier};
+use crate::cluster::Membership;
+use crate::db::Storage;
+use crate::handshake_limit::HandshakeLimiter;
+use crate::hash::Hasher;
+use crate::jobs::JobQueue;
+use crate::message_bus::MessageBus;
+use crate::presence::Presence;
+use crate::rate_limit::RateLimiter;
+use crate::runtime_config::SharedRuntimeConfig;
+use crate::spam::SpamDetector;
+use crate::storage::object_store::ObjectStore;
+use crate::transport::Transport;
+use crate::webhook::Webhooks;
 
+use active_conversations::ActiveConversations;
+pub use close_reason::CloseReason;
+pub use encoding::{proto, Encoding};
 use error::FatalConnectionError;
-use notification_loop::NotificationLoop;
-use operation_loop::OperationLoop;
+use notification_loop::{NotificationLoop, PrefsCache};
+#[cfg(fuzzing)]
+pub use operation_loop::operation::Operation;
+use operation_loop::{response::Response, OperationLoop, RetryPolicy, Scheduler, Timeouts};
+use recorder::Recorder;
+pub use registry::ConnectionRegistry;
+use subscriptions::Subscriptions;
+use user_event::UserEvent;
+use user_tx::UserTx;
 
 // handles connection and closing it but caller handles printing error
 
 // only unwrap when stringifying struct
 
+const PROTOCOL_VERSION: u32 = 1;
+
+// announced in the hello event so clients don't have to guess what this server supports
+const FEATURES: [&str; 30] = [
+    "rateLimiting",
+    "conversationRollover",
+    "connectionStatus",
+    "protobuf",
+    "tokenRefresh",
+    "retentionPolicy",
+    "disappearingMessages",
+    "pins",
+    "archive",
+    "clearHistory",
+    "userSearch",
+    "pushNotifications",
+    "notificationPrefs",
+    "devices",
+    "selfSync",
+    "disconnectOtherSessions",
+    "reports",
+    "subscriptionFilters",
+    "conversationSubscriptions",
+    "time",
+    "ping",
+    "connectionInfo",
+    "onlineFriends",
+    "closeReasons",
+    "keyDistribution",
+    "conversationStats",
+    "streaks",
+    "mentions",
+    "messageKinds",
+    "polls",
+];
+
+mod active_conversations;
+mod close_reason;
+mod encoding;
 mod error;
-mod nats_message;
+mod heartbeat;
+pub mod nats_message;
 mod notification_loop;
 mod operation_loop;
-mod user_event;
+mod recorder;
+mod registry;
+pub mod schema;
+mod subscriptions;
+pub mod user_event;
+mod user_tx;
 
 pub struct Connection {
-    pub websocket: WebSocketStream<TcpStream>,
-    pub db: Arc<Database>,
-    pub nc: Arc<nats::asynk::Connection>,
+    pub connection_id: String,
+    pub websocket: WebSocketStream<Transport>,
+    pub encoding: Encoding,
+    pub db: Arc<dyn Storage>,
+    pub message_bus: Arc<dyn MessageBus>,
+    pub hasher: Arc<Hasher>,
+    pub object_store: Option<Arc<ObjectStore>>,
+    pub analytics: Option<Arc<Analytics>>,
+    pub webhooks: Option<Arc<Webhooks>>,
+    pub presence: Arc<dyn Presence>,
+    pub membership: Arc<Membership>,
+    pub audit_log: Arc<AuditLog>,
+    pub job_queue: Arc<dyn JobQueue>,
+    pub node_id: String,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub spam_detector: Arc<SpamDetector>,
+    pub handshake_limiter: Arc<HandshakeLimiter>,
+    pub challenges: Option<Arc<dyn ChallengeVerifier>>, // nobody is challenged without one
+    pub jwt_auth: Arc<JWTAuth>,
+    pub registry: Arc<ConnectionRegistry>,
+    pub runtime: SharedRuntimeConfig,
+    pub max_attachment_size: u64,
+    pub key_distribution_ttl: Duration,
+    pub mention_ttl: Duration,
+    pub max_frame_size: usize,
+    pub operation_concurrency: usize,
+    pub operation_queue_limit: usize,
+    pub database_timeout: Duration,
+    pub nats_timeout: Duration,
+    pub nats_outage_limit: Duration,
+    pub nats_publish_max_attempts: u32,
+    pub send_timeout: Duration,
+    pub token_expires_in: Duration,
+    pub permissions: Permissions,
+    #[allow(dead_code)] // in the token, nothing on the connection needs it yet
     pub phone_number: i64,
     pub username: String,
+    pub device_id: Option<String>, // None when the client didn't say which device it's on
+    pub ip: Option<IpAddr>, // None for bridged connections, and unix sockets without a proxy protocol header
+    pub is_bot: bool,       // authenticated with an api key, see auth::bot
 }
 
 impl Connection {
     pub async fn handle(self) -> Result<(), FatalConnectionError> {
         let (user_tx, user_rx) = self.websocket.split();
-        let user_tx = Arc::new(Mutex::new(user_tx));
+
+        let recorder = Recorder::from_env().map(Arc::new);
+
+        let user_tx = Arc::new(UserTx::new(
+            user_tx,
+            recorder.clone(),
+            self.encoding,
+            self.connection_id.clone(),
+            self.send_timeout,
+        ));
+
+        let runtime = self.runtime.load_full();
+
+        let hello = UserEvent::Hello {
+            connection_id: self.connection_id.clone(),
+            protocol_version: PROTOCOL_VERSION,
+            heartbeat_interval_ms: runtime.heartbeat_interval.as_millis() as u64,
+            max_content_length: runtime.max_content_length,
+            max_frame_size: self.max_frame_size,
+            features: FEATURES
+                .iter()
+                .chain(
+                    self.object_store
+                        .iter()
+                        .flat_map(|_| ["avatars", "attachments", "voiceMessages"].iter()),
+                ) // only when uploads are configured
+                .chain(self.challenges.iter().map(|_| &"challenges"))
+                .map(|feature| feature.to_string())
+                .collect(),
+        };
+
+        user_tx.send_user_event(&hello).await?;
+
+        // bots are trusted with their api keys
+        let challenge = match &self.challenges {
+            Some(challenges) if !self.is_bot => {
+                challenges
+                    .challenge(&ChallengeContext {
+                        username: self.username.clone(),
+                        ip: self.ip,
+                        device_id: self.device_id.clone(),
+                    })
+                    .await
+            }
+            _ => None,
+        };
+
+        if let Some(challenge) = &challenge {
+            user_tx
+                .send_response(&Response::ChallengeRequired(challenge.clone()))
+                .await?;
+        }
 
         let (result_tx, mut result_rx) = mpsc::channel::<Result<(), FatalConnectionError>>(1);
         let result_tx_clone = result_tx.clone();
 
+        let (registration, control_rx) = self.registry.register(
+            self.connection_id.clone(),
+            self.username.clone(),
+            self.device_id.clone(),
+        );
+
+        // for the daily digest, which is about what happened since
+        let db = self.db.clone();
+        let username = self.username.clone();
+        let device_id = self.device_id.clone();
+
+        tokio::task::spawn(
+            async move {
+                let connected_at = chrono::Utc::now();
+
+                if let Err(err) = db.touch_user(&username, connected_at).await {
+                    warn!("Failed to record connection: {}", err);
+                }
+
+                if let Some(device_id) = device_id {
+                    if let Err(err) = db.touch_device(&username, &device_id, connected_at).await {
+                        warn!("Failed to record device: {}", err);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+
+        // what the user's other devices are told this one is, so self sync events can skip where they came from
+        let sync_origin = self
+            .device_id
+            .clone()
+            .unwrap_or_else(|| self.connection_id.clone());
+
+        let active_conversations = Arc::new(ActiveConversations::new());
+
+        let (token_deadline_tx, token_deadline_rx) =
+            watch::channel(Instant::now() + self.token_expires_in);
+
+        let (subscriptions_tx, subscriptions_rx) = watch::channel(Subscriptions::default());
+
         let (notification_loop_cancel_tx, notification_loop_cancel_rx) = mpsc::channel::<()>(1);
         let (operation_loop_cancel_tx, operation_loop_cancel_rx) = mpsc::channel::<()>(1);
 
         let notification_loop = NotificationLoop {
             user_tx: user_tx.clone(),
-            nc: self.nc.clone(),
-            username_hash: hash::base64_encoded_md5_hash_with_secret(self.username.clone()),
+            message_bus: self.message_bus.clone(),
+            username_hashes: self.hasher.hashes(&self.username),
+            active_conversations: active_conversations.clone(),
+            runtime: self.runtime.clone(),
+            token_deadline: token_deadline_rx,
+            nats_outage_limit: self.nats_outage_limit,
+            db: self.db.clone(),
+            username: self.username.clone(),
+            prefs_cache: PrefsCache::new(),
+            device_id: self.device_id.clone(),
+            sync_origin: sync_origin.clone(),
+            subscriptions: subscriptions_rx,
+            presence: self.presence.clone(),
+            node_id: self.node_id.clone(),
         };
 
+        let registry = self.registry.clone();
+        let username = self.username.clone();
+        let closing_user_tx = user_tx.clone();
+
         let operation_loop = OperationLoop {
             user_rx,
-            user_tx,
+            user_tx: user_tx.clone(),
+            recorder,
             db: self.db,
-            nc: self.nc,
+            message_bus: self.message_bus,
+            hasher: self.hasher,
+            object_store: self.object_store,
+            analytics: self.analytics,
+            webhooks: self.webhooks,
+            rate_limiter: self.rate_limiter,
+            handshake_limiter: self.handshake_limiter,
+            challenges: self.challenges,
+            pending_challenge: Arc::new(Mutex::new(challenge)),
+            spam_detector: self.spam_detector,
+            jwt_auth: self.jwt_auth,
+            registry: self.registry,
+            degraded: Arc::new(AtomicBool::new(false)),
+            active_conversations,
+            scheduler: Scheduler::spawn(
+                self.operation_concurrency,
+                self.operation_queue_limit,
+                user_tx,
+            ),
+            timeouts: Timeouts {
+                database: self.database_timeout,
+                nats: self.nats_timeout,
+            },
+            retry_policy: RetryPolicy {
+                max_attempts: self.nats_publish_max_attempts.max(1),
+                base_delay: Duration::from_millis(50),
+                max_delay: Duration::from_secs(1),
+            },
+            token_deadline: Arc::new(token_deadline_tx),
+            subscriptions: subscriptions_tx,
+            permissions: Arc::new(ArcSwap::from_pointee(self.permissions)),
+            runtime: self.runtime,
+            presence: self.presence.clone(),
+            membership: self.membership,
+            audit_log: self.audit_log,
+            job_queue: self.job_queue,
+            node_id: self.node_id.clone(),
+            max_attachment_size: self.max_attachment_size,
+            key_distribution_ttl: self.key_distribution_ttl,
+            mention_ttl: self.mention_ttl,
+            is_bot: self.is_bot,
             username: self.username,
+            device_id: self.device_id,
+            ip: self.ip,
+            sync_origin,
         };
 
-        tokio::task::spawn(async move {
-            let result = notification_loop.handle(notification_loop_cancel_rx).await;
+        tokio::task::spawn(
+            async move {
+                let result = notification_loop
+                    .handle(notification_loop_cancel_rx, control_rx)
+                    .await;
+
+                let _ = operation_loop_cancel_tx.send(()).await; // will return error if other task completed first because sender will have been dropped, so we'll ignore this error
+
+                let _ = result_tx.send(result).await; // same as above ^^^
+            }
+            .in_current_span(),
+        );
+
+        tokio::task::spawn(
+            async move {
+                let result = operation_loop.handle(operation_loop_cancel_rx).await;
+
+                let _ = notification_loop_cancel_tx.send(()).await;
+
+                let _ = result_tx_clone.send(result).await;
+            }
+            .in_current_span(),
+        );
 
-            let _ = operation_loop_cancel_tx.send(()).await; // will return error if other task completed first because sender will have been dropped, so we'll ignore this error
+        let result = result_rx.recv().await.unwrap(); // senders won't drop until after sending to this channel
 
-            let _ = result_tx.send(result).await; // same as above ^^^
-        });
+        // the loops close the connection themselves when they end it on purpose, this tells the client about the rest
+        if let Err(err) = &result {
+            let close_reason = if closing_user_tx.is_slow() {
+                Some(CloseReason::SlowConsumer)
+            } else {
+                err.close_reason()
+            };
 
-        tokio::task::spawn(async move {
-            let result = operation_loop.handle(operation_loop_cancel_rx).await;
+            if let Some(close_reason) = close_reason {
+                let _ = closing_user_tx.close(close_reason).await;
+            }
+        }
 
-            let _ = notification_loop_cancel_tx.send(()).await;
+        drop(registration);
 
-            let _ = result_tx_clone.send(result).await;
-        });
+        // still online through this node if they have another connection here
+        if !registry.is_connected(&username) {
+            if let Err(err) = self.presence.remove(&username, &self.node_id).await {
+                warn!("Failed to remove presence: {}", err);
+            }
+        }
 
-        result_rx.recv().await.unwrap() // senders won't drop until after sending to this channel
+        result
     }
 }
@@ -0,0 +1,36 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::conversation_id::ConversationId;
+
+// conversations this connection has touched, so the user can be told when their hour window ends. which conversation
+// succeeds which is stored instead, see announce_rollover
+
+pub struct ActiveConversations(Mutex<HashSet<ConversationId>>);
+
+impl ActiveConversations {
+    pub fn new() -> Self {
+        Self(Mutex::new(HashSet::new()))
+    }
+
+    pub fn insert(&self, conversation_id: &str) {
+        if let Ok(conversation_id) = ConversationId::try_from(conversation_id.to_owned()) {
+            self.0.lock().unwrap().insert(conversation_id);
+        }
+    }
+
+    pub fn take_rolled_over(&self) -> Vec<String> {
+        let mut active = self.0.lock().unwrap();
+
+        let (current, rolled_over) = active
+            .drain()
+            .partition::<HashSet<_>, _>(|conversation_id| conversation_id.is_current());
+
+        *active = current;
+
+        rolled_over
+            .iter()
+            .map(|conversation_id| conversation_id.to_string())
+            .collect()
+    }
+}
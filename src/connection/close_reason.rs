@@ -0,0 +1,66 @@
+use tungstenite::protocol::frame::coding::CloseCode;
+
+// every way the server ends a connection once it's set up, so clients can pick how to reconnect from the code alone.
+// standard codes where one fits, ours in the 4000s for the rest
+//
+//   1000 account deleted   don't reconnect
+//   1008 policy            revoked, logged out, kicked or forbidden. only reconnect with a new token
+//   1013 try again later   the server couldn't keep the connection going, reconnect with backoff
+//   4000 protocol error    the client sent something it never should, reconnecting won't help
+//   4001 token expired     refresh the token, then reconnect
+//   4002 session replaced  logged out from another session, don't reconnect
+//   4003 rate limited      reconnect with backoff, sooner gets the same treatment
+//   4004 slow consumer     frames weren't read fast enough, reconnect and resync since events were dropped
+//   4005 server shutdown   reconnect right away, it'll land on another node
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    AccountDeleted,
+    SessionRevoked,
+    DeviceLoggedOut,
+    Kicked,
+    Forbidden,
+    Unavailable,
+    ProtocolError,
+    TokenExpired,
+    SessionReplaced,
+    RateLimited,
+    SlowConsumer,
+    ServerShutdown,
+}
+
+impl CloseReason {
+    pub fn code(self) -> CloseCode {
+        match self {
+            Self::AccountDeleted => CloseCode::Normal,
+            Self::SessionRevoked | Self::DeviceLoggedOut | Self::Kicked | Self::Forbidden => {
+                CloseCode::Policy
+            }
+            Self::Unavailable => CloseCode::Again,
+            Self::ProtocolError => CloseCode::Library(4000),
+            Self::TokenExpired => CloseCode::Library(4001),
+            Self::SessionReplaced => CloseCode::Library(4002),
+            Self::RateLimited => CloseCode::Library(4003),
+            Self::SlowConsumer => CloseCode::Library(4004),
+            Self::ServerShutdown => CloseCode::Library(4005),
+        }
+    }
+
+    // for humans reading logs, clients should only go by the code
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::AccountDeleted => "Account deleted",
+            Self::SessionRevoked => "Session revoked",
+            Self::DeviceLoggedOut => "Device logged out",
+            Self::Kicked => "Disconnected by an admin",
+            Self::Forbidden => "Forbidden",
+            Self::Unavailable => "Temporarily unavailable",
+            Self::ProtocolError => "Protocol error",
+            Self::TokenExpired => "Access token expired",
+            Self::SessionReplaced => "Session replaced",
+            Self::RateLimited => "Rate limited",
+            Self::SlowConsumer => "Not reading fast enough",
+            Self::ServerShutdown => "Server shutting down",
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use chrono::prelude::*;
+use tungstenite::handshake::server::Request;
+
+use super::error::UnsupportedFormatError;
+use crate::models::{
+    attachment::{Attachment, AttachmentKind},
+    message_kind::{GifProvider, MessageKind},
+    notification_prefs::{DndWindow, NotificationPrefs},
+    poll::Poll,
+};
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/realtime.rs"));
+}
+
+const SUBPROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+// chosen per connection through the websocket subprotocol. json stays the default so existing clients don't need to change
+//
+// grpc is protobuf with every frame wrapped in a ServerFrame, for connections bridged from the grpc service. websocket
+// clients can't ask for it
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Json,
+    Protobuf,
+    Grpc,
+}
+
+impl Encoding {
+    // none means the client didn't ask for a subprotocol, in which case none should be echoed back either
+    pub fn negotiate(req: &Request) -> Option<Self> {
+        req.headers()
+            .get_all(SUBPROTOCOL_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .find_map(|subprotocol| match subprotocol.trim() {
+                "protobuf" => Some(Self::Protobuf),
+                "json" => Some(Self::Json),
+                _ => None,
+            })
+    }
+
+    pub fn subprotocol_header() -> &'static str {
+        SUBPROTOCOL_HEADER
+    }
+
+    pub fn subprotocol(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Protobuf => "protobuf",
+            Self::Grpc => "grpc",
+        }
+    }
+}
+
+pub fn timestamp_from_datetime(datetime: DateTime<Utc>) -> i64 {
+    datetime.timestamp_millis()
+}
+
+pub fn datetime_from_timestamp(timestamp: i64) -> Result<DateTime<Utc>, UnsupportedFormatError> {
+    Utc.timestamp_millis_opt(timestamp)
+        .single()
+        .ok_or(UnsupportedFormatError::InvalidTimestamp(timestamp))
+}
+
+impl From<Attachment> for proto::Attachment {
+    fn from(attachment: Attachment) -> Self {
+        Self {
+            content_type: attachment.content_type,
+            object_key: attachment.object_key,
+            size: attachment.size,
+            width: attachment.width,
+            height: attachment.height,
+            thumbhash: attachment.thumbhash,
+            kind: match attachment.kind {
+                AttachmentKind::File => proto::AttachmentKind::File,
+                AttachmentKind::Voice => proto::AttachmentKind::Voice,
+            } as i32,
+            duration_ms: attachment.duration_ms,
+            waveform: attachment.waveform,
+        }
+    }
+}
+
+impl TryFrom<proto::Attachment> for Attachment {
+    type Error = UnsupportedFormatError;
+
+    fn try_from(attachment: proto::Attachment) -> Result<Self, Self::Error> {
+        Ok(Self {
+            kind: match proto::AttachmentKind::from_i32(attachment.kind) {
+                Some(proto::AttachmentKind::File) => AttachmentKind::File,
+                Some(proto::AttachmentKind::Voice) => AttachmentKind::Voice,
+                None => return Err(UnsupportedFormatError::OutOfRange("kind")),
+            },
+            content_type: attachment.content_type,
+            object_key: attachment.object_key,
+            size: attachment.size,
+            width: attachment.width,
+            height: attachment.height,
+            thumbhash: attachment.thumbhash,
+            duration_ms: attachment.duration_ms,
+            waveform: attachment.waveform,
+        })
+    }
+}
+
+impl From<MessageKind> for proto::MessageKind {
+    fn from(message_kind: MessageKind) -> Self {
+        use proto::message_kind::Kind;
+
+        Self {
+            kind: Some(match message_kind {
+                MessageKind::Sticker {
+                    pack_id,
+                    sticker_id,
+                } => Kind::Sticker(proto::StickerKind {
+                    pack_id,
+                    sticker_id,
+                }),
+                MessageKind::Gif { provider, id, url } => Kind::Gif(proto::GifKind {
+                    provider: match provider {
+                        GifProvider::Giphy => proto::GifProvider::Giphy,
+                        GifProvider::Tenor => proto::GifProvider::Tenor,
+                    } as i32,
+                    id,
+                    url,
+                }),
+            }),
+        }
+    }
+}
+
+impl From<Poll> for proto::Poll {
+    fn from(poll: Poll) -> Self {
+        Self {
+            poll_id: poll.poll_id,
+            question: poll.question,
+            options: poll
+                .options
+                .into_iter()
+                .map(|option| proto::PollOption {
+                    text: option.text,
+                    votes: option.votes,
+                })
+                .collect(),
+            created_by_chooser: poll.created_by_chooser,
+            created_at: timestamp_from_datetime(poll.created_at),
+            chooser_vote: poll.chooser_vote,
+            choosee_vote: poll.choosee_vote,
+        }
+    }
+}
+
+impl TryFrom<proto::MessageKind> for MessageKind {
+    type Error = UnsupportedFormatError;
+
+    fn try_from(message_kind: proto::MessageKind) -> Result<Self, Self::Error> {
+        use proto::message_kind::Kind;
+
+        Ok(
+            match message_kind
+                .kind
+                .ok_or(UnsupportedFormatError::MissingField("kind"))?
+            {
+                Kind::Sticker(sticker) => Self::Sticker {
+                    pack_id: sticker.pack_id,
+                    sticker_id: sticker.sticker_id,
+                },
+                Kind::Gif(gif) => Self::Gif {
+                    provider: match proto::GifProvider::from_i32(gif.provider) {
+                        Some(proto::GifProvider::Giphy) => GifProvider::Giphy,
+                        Some(proto::GifProvider::Tenor) => GifProvider::Tenor,
+                        None => return Err(UnsupportedFormatError::OutOfRange("provider")),
+                    },
+                    id: gif.id,
+                    url: gif.url,
+                },
+            },
+        )
+    }
+}
+
+impl From<NotificationPrefs> for proto::NotificationPrefs {
+    fn from(notification_prefs: NotificationPrefs) -> Self {
+        Self {
+            muted: notification_prefs.muted,
+            mentions_only: notification_prefs.mentions_only,
+            dnd: notification_prefs.dnd.map(|dnd| proto::DndWindow {
+                start_minute: dnd.start_minute as u32,
+                end_minute: dnd.end_minute as u32,
+                utc_offset_minutes: dnd.utc_offset_minutes as i32,
+            }),
+        }
+    }
+}
+
+impl TryFrom<proto::NotificationPrefs> for NotificationPrefs {
+    type Error = UnsupportedFormatError;
+
+    fn try_from(notification_prefs: proto::NotificationPrefs) -> Result<Self, Self::Error> {
+        Ok(Self {
+            muted: notification_prefs.muted,
+            mentions_only: notification_prefs.mentions_only,
+            dnd: notification_prefs
+                .dnd
+                .map(|dnd| {
+                    Ok::<_, UnsupportedFormatError>(DndWindow {
+                        start_minute: dnd
+                            .start_minute
+                            .try_into()
+                            .map_err(|_| UnsupportedFormatError::OutOfRange("start_minute"))?,
+                        end_minute: dnd
+                            .end_minute
+                            .try_into()
+                            .map_err(|_| UnsupportedFormatError::OutOfRange("end_minute"))?,
+                        utc_offset_minutes: dnd.utc_offset_minutes.try_into().map_err(|_| {
+                            UnsupportedFormatError::OutOfRange("utc_offset_minutes")
+                        })?,
+                    })
+                })
+                .transpose()?,
+        })
+    }
+}
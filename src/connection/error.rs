@@ -1,7 +1,11 @@
 use thiserror::Error;
 use tungstenite::Message;
 
+use super::close_reason::CloseReason;
+use crate::challenge::ChallengeError;
 use crate::db::DatabaseError;
+use crate::error::ErrorCategory;
+use crate::message_bus::MessageBusError;
 
 #[derive(Error, Debug)]
 pub enum ConnectionError {
@@ -11,25 +15,78 @@ pub enum ConnectionError {
     NonFatal(NonFatalConnectionError),
 }
 
+#[allow(dead_code)] // for whoever ends up handling these by category, logging doesn't need it
+impl ConnectionError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Fatal(err) => err.category(),
+            Self::NonFatal(err) => err.category(),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum FatalConnectionError {
     #[error("Websocket error: {0}")]
     WebSocketError(#[from] tungstenite::Error),
     #[error("Unexpected close frame: {close_frame}")]
     UnexpectedClose { close_frame: String },
-    #[error("Nats error while attempting to subscribe: {0}")]
-    NatsSubscribeError(#[from] std::io::Error),
-    #[error("Nats subscription terminated unexpectedly")]
-    UnexpectedNatsSubscriptionTerminate,
+    #[error("Message bus error while attempting to subscribe: {0}")]
+    SubscribeError(#[from] MessageBusError),
+    #[error("Subscription terminated unexpectedly")]
+    UnexpectedSubscriptionTerminate,
     #[error("Received unsupported protocol: {0}")]
     UnsupportedProtocol(Message),
     #[error("Forbidden error: {0}")]
     Forbidden(&'static str),
+    #[error("Closed for spamming")]
+    Spam,
+}
+
+#[allow(dead_code)]
+impl FatalConnectionError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::WebSocketError(_)
+            | Self::SubscribeError(_)
+            | Self::UnexpectedSubscriptionTerminate => ErrorCategory::Transient,
+            Self::UnexpectedClose { .. } => ErrorCategory::Permanent,
+            Self::UnsupportedProtocol(_) => ErrorCategory::Validation,
+            Self::Forbidden(_) | Self::Spam => ErrorCategory::Auth,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
+
+    // None when the websocket is already gone, so there's no one to tell
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        match self {
+            Self::WebSocketError(_) | Self::UnexpectedClose { .. } => None,
+            Self::SubscribeError(_) | Self::UnexpectedSubscriptionTerminate => {
+                Some(CloseReason::Unavailable)
+            }
+            Self::UnsupportedProtocol(_) => Some(CloseReason::ProtocolError),
+            Self::Forbidden(_) => Some(CloseReason::Forbidden),
+            Self::Spam => Some(CloseReason::RateLimited),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
-#[error("{0}")]
-pub struct UnsupportedFormatError(#[from] serde_json::Error);
+pub enum UnsupportedFormatError {
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    Protobuf(#[from] prost::DecodeError),
+    #[error("Missing field: {0}")]
+    MissingField(&'static str),
+    #[error("Field out of range: {0}")]
+    OutOfRange(&'static str),
+    #[error("Invalid timestamp: {0}")]
+    InvalidTimestamp(i64),
+}
 
 #[derive(Error, Debug)]
 pub enum NonFatalConnectionError {
@@ -37,6 +94,26 @@ pub enum NonFatalConnectionError {
     DatabaseError(DatabaseError),
     #[error("Received unexpected message format: {0}")]
     UnsupportedFormat(#[from] UnsupportedFormatError), // non fatal error because this mainly serves as an indicator that the websocket client may have been implemented incorrectly
-    #[error("Nats error while attempting to publish: {0}")]
-    NatsPublishError(#[from] std::io::Error),
+    #[error("Message bus error while attempting to publish: {0}")]
+    PublishError(#[from] MessageBusError),
+    #[error("Timed out while {0}")]
+    Timeout(&'static str),
+    #[error("{0}")]
+    ChallengeError(#[from] ChallengeError),
+}
+
+impl NonFatalConnectionError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::DatabaseError(err) => err.category(),
+            Self::UnsupportedFormat(_) => ErrorCategory::Validation,
+            Self::PublishError(_) | Self::Timeout(_) | Self::ChallengeError(_) => {
+                ErrorCategory::Transient
+            }
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
+    }
 }
@@ -0,0 +1,19 @@
+use chrono::prelude::*;
+use std::time::Duration;
+use tungstenite::Message;
+
+// heartbeat pings carry when they were sent, which clients echo back in their pong, so every pong is a round trip
+// time measurement without keeping track of which pings are outstanding
+
+pub fn ping() -> Message {
+    Message::Ping(Utc::now().timestamp_micros().to_be_bytes().to_vec())
+}
+
+// None for pongs that weren't answering one of our pings, which clients are allowed to send unprompted
+pub fn round_trip_time(pong: &[u8]) -> Option<Duration> {
+    let sent_at = i64::from_be_bytes(pong.try_into().ok()?);
+
+    u64::try_from(Utc::now().timestamp_micros() - sent_at)
+        .ok()
+        .map(Duration::from_micros)
+}
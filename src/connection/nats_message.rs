@@ -1,16 +1,97 @@
+use std::env;
+
 use super::user_event::UserEvent;
 
+// events for a user are published to user.<username_hash>.<event_type> so other consumers can subscribe to just the
+// types they care about. connections subscribe to all of them with user_subject_wildcard
+
+// every subject gets NATS_SUBJECT_PREFIX (e.g. "prod.") in front so deployments sharing a nats cluster don't see each
+// other's events
+
 pub struct NatsMessage {
     pub to_username_hash: String,
     pub user_event: UserEvent,
 }
 
 impl NatsMessage {
-    pub fn subject(&self) -> &str {
-        &self.to_username_hash
+    pub fn subject(&self) -> String {
+        prefixed(format!(
+            "user.{}.{}",
+            self.to_username_hash,
+            self.user_event.event_type()
+        ))
     }
 
     pub fn data(&self) -> Vec<u8> {
         self.user_event.to_vec()
     }
 }
+
+pub fn user_subject_wildcard(username_hash: &str) -> String {
+    prefixed(format!("user.{}.*", username_hash))
+}
+
+// every user's events of one type, for consumers that act on events rather than deliver them to connections
+pub fn event_type_wildcard(event_type: &str) -> String {
+    prefixed(format!("user.*.{}", event_type))
+}
+
+// None when the subject isn't a user subject
+pub fn username_hash_of(subject: &str) -> Option<&str> {
+    let prefix = env::var("NATS_SUBJECT_PREFIX").unwrap_or_default();
+
+    let mut tokens = subject.strip_prefix(prefix.as_str())?.split('.');
+
+    match (tokens.next(), tokens.next()) {
+        (Some("user"), Some(username_hash)) => Some(username_hash),
+        _ => None,
+    }
+}
+
+pub fn revoke_subject(username_hash: &str) -> String {
+    prefixed(format!("control.revoke.{}", username_hash))
+}
+
+// published with the device id as the data, closing the connections from that device
+pub fn device_logout_subject(username_hash: &str) -> String {
+    prefixed(format!("control.logout.{}", username_hash))
+}
+
+// published with the sync origin of the connection that asked, closing all of the user's connections but its device's
+pub fn disconnect_others_subject(username_hash: &str) -> String {
+    prefixed(format!("control.disconnect.{}", username_hash))
+}
+
+// published with the username to the node the presence registry says they're on, for kicks from admins on other nodes
+pub fn node_kick_subject(node_id: &str) -> String {
+    prefixed(format!("control.kick.{}", node_id))
+}
+
+// admin bans and unbans, which every node applies
+pub fn bans_subject() -> String {
+    prefixed("control.bans".to_owned())
+}
+
+// each node's summary of itself, published on an interval by every node
+pub fn node_announcements_subject() -> String {
+    prefixed("cluster.nodes".to_owned())
+}
+
+// announcements to every connected user, which every connection subscribes to along with its own user's events
+pub fn broadcast_subject() -> String {
+    prefixed("broadcast.all".to_owned())
+}
+
+// every report users make, for trust and safety to act on
+pub fn moderation_reports_subject() -> String {
+    prefixed("moderation.reports".to_owned())
+}
+
+// every audit log entry, when AUDIT_LOG_PUBLISH is set
+pub fn audit_log_subject() -> String {
+    prefixed("audit.log".to_owned())
+}
+
+fn prefixed(subject: String) -> String {
+    env::var("NATS_SUBJECT_PREFIX").unwrap_or_default() + &subject
+}
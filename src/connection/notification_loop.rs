@@ -1,56 +1,304 @@
-use futures_util::{stream::SplitSink, SinkExt};
+use chrono::{prelude::*, Duration};
 use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::WebSocketStream;
-use tungstenite::Message;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tracing::Instrument;
 
+use super::active_conversations::ActiveConversations;
+use super::close_reason::CloseReason;
 use super::error::FatalConnectionError;
-use super::nats_message::NatsMessage;
+use super::heartbeat;
+use super::nats_message;
+use super::registry::Control;
+use super::subscriptions::Subscriptions;
 use super::user_event::UserEvent;
+use super::user_tx::UserTx;
+use crate::db::Storage;
+use crate::message_bus::{MessageBus, MessageBusError};
+use crate::presence::Presence;
+use crate::runtime_config::SharedRuntimeConfig;
+use live_subscription::LiveSubscription;
 use notification::Notification;
+pub use prefs_cache::PrefsCache;
 
+mod live_subscription;
 mod notification;
+mod prefs_cache;
 
 pub struct NotificationLoop {
-    pub user_tx: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-    pub nc: Arc<nats::asynk::Connection>,
-    pub username_hash: String,
+    pub user_tx: Arc<UserTx>,
+    pub message_bus: Arc<dyn MessageBus>,
+    pub username_hashes: Vec<String>, // more than one while legacy hashes are accepted
+    pub active_conversations: Arc<ActiveConversations>,
+    pub runtime: SharedRuntimeConfig,
+    pub token_deadline: watch::Receiver<Instant>,
+    pub nats_outage_limit: std::time::Duration,
+    pub db: Arc<dyn Storage>,
+    pub username: String,
+    pub prefs_cache: PrefsCache,
+    pub device_id: Option<String>,
+    pub sync_origin: String,
+    pub subscriptions: watch::Receiver<Subscriptions>,
+    pub presence: Arc<dyn Presence>,
+    pub node_id: String,
 }
 
 impl NotificationLoop {
     pub async fn handle(
         mut self,
         mut cancel_rx: mpsc::Receiver<()>,
+        mut control_rx: mpsc::UnboundedReceiver<Control>,
     ) -> Result<(), FatalConnectionError> {
-        let message_sub = self.nc.subscribe(&self.username_hash).await?;
+        let message_subjects = self
+            .username_hashes
+            .iter()
+            .map(|username_hash| nats_message::user_subject_wildcard(username_hash))
+            .chain([nats_message::broadcast_subject()])
+            .collect::<Vec<_>>();
+
+        let mut message_sub = self.subscribe(message_subjects).await?;
+
+        let revoke_subjects = self
+            .username_hashes
+            .iter()
+            .map(|username_hash| nats_message::revoke_subject(username_hash))
+            .collect::<Vec<_>>();
+
+        let mut revoke_sub = self.subscribe(revoke_subjects).await?;
+
+        let logout_subjects = self
+            .username_hashes
+            .iter()
+            .map(|username_hash| nats_message::device_logout_subject(username_hash))
+            .collect::<Vec<_>>();
+
+        let mut logout_sub = self.subscribe(logout_subjects).await?;
+
+        let disconnect_subjects = self
+            .username_hashes
+            .iter()
+            .map(|username_hash| nats_message::disconnect_others_subject(username_hash))
+            .collect::<Vec<_>>();
+
+        let mut disconnect_sub = self.subscribe(disconnect_subjects).await?;
+
+        let mut heartbeat_interval = self.runtime.load().heartbeat_interval;
+        let mut heartbeat = tokio::time::interval(heartbeat_interval);
+
+        let mut token_refreshes = self.token_deadline.clone(); // separate receiver so the deadline can be read while waiting for a refresh
+
+        'notification_loop: loop {
+            let nats_message = tokio::select! {
+                next = message_sub.next() => next?.data,
+                _ = cancel_rx.recv() => return Ok(()),
+                next = revoke_sub.next() => {
+                    next?;
+
+                    self.user_tx.close(CloseReason::SessionRevoked).await?;
+
+                    return Ok(());
+                }
+                next = logout_sub.next() => {
+                    if self.device_id.as_deref().map(str::as_bytes) == Some(next?.data.as_slice()) {
+                        self.user_tx.close(CloseReason::DeviceLoggedOut).await?;
+
+                        return Ok(());
+                    }
+
+                    continue 'notification_loop;
+                }
+                next = disconnect_sub.next() => {
+                    if next?.data != self.sync_origin.as_bytes() {
+                        self.user_tx.close(CloseReason::SessionReplaced).await?;
+
+                        return Ok(());
+                    }
+
+                    continue 'notification_loop;
+                }
+                Some(control) = control_rx.recv() => {
+                    match control {
+                        Control::Kick => {
+                            self.user_tx.close(CloseReason::Kicked).await?;
+
+                            return Ok(());
+                        }
+                        Control::Shutdown => {
+                            self.user_tx.close(CloseReason::ServerShutdown).await?;
+
+                            return Ok(());
+                        }
+                        Control::UserEvent(user_event) => self.handle_user_event(*user_event).await?,
+                    }
+
+                    continue 'notification_loop;
+                }
+                _ = heartbeat.tick() => {
+                    self.user_tx.send(heartbeat::ping()).await?;
+
+                    self.refresh_presence();
+
+                    // picks up a reloaded interval at the next beat
+                    let reloaded_interval = self.runtime.load().heartbeat_interval;
+
+                    if reloaded_interval != heartbeat_interval {
+                        heartbeat_interval = reloaded_interval;
+                        heartbeat = tokio::time::interval_at(Instant::now() + heartbeat_interval, heartbeat_interval);
+                    }
+
+                    continue 'notification_loop;
+                }
+                Ok(()) = token_refreshes.changed() => continue 'notification_loop, // refreshed, so sleep until the new deadline
+                _ = tokio::time::sleep_until(*self.token_deadline.borrow()) => {
+                    self.user_tx.close(CloseReason::TokenExpired).await?;
+
+                    return Ok(());
+                }
+                _ = tokio::time::sleep(Self::until_next_window()) => {
+                    self.handle_rollover().await?;
+
+                    continue 'notification_loop;
+                }
+            };
 
-        while let Some(nats_message) = tokio::select! {
-            next = message_sub.next() => next,
-            _ = cancel_rx.recv() => return Ok(()),
-        } {
             match Notification::from(nats_message) {
+                Ok(Notification(UserEvent::AccountDeleted)) => {
+                    self.handle_user_event(UserEvent::AccountDeleted).await?;
+
+                    self.user_tx.close(CloseReason::AccountDeleted).await?;
+
+                    return Ok(());
+                }
                 Ok(Notification(user_event)) => {
                     self.handle_user_event(user_event).await?;
                 }
                 Err(err) => {
-                    warn!("Invalid nats message received: {}", err);
+                    warn!("Invalid message received from the bus: {}", err);
 
                     continue;
                 }
             }
         }
+    }
 
-        Err(FatalConnectionError::UnexpectedNatsSubscriptionTerminate) // will only get to this when message_sub returns none. this line won't run if nc_loop is canceled
+    // on every heartbeat, including the first right after connecting. spawned so a slow presence store can't hold up
+    // the loop
+    fn refresh_presence(&self) {
+        let presence = self.presence.clone();
+        let username = self.username.clone();
+        let node_id = self.node_id.clone();
+
+        tokio::task::spawn(
+            async move {
+                if let Err(err) = presence.refresh(&username, &node_id).await {
+                    warn!("Failed to refresh presence: {}", err);
+                }
+            }
+            .in_current_span(),
+        );
     }
 
-    pub async fn handle_user_event(&mut self, data: UserEvent) -> Result<(), FatalConnectionError> {
-        self.user_tx
-            .lock()
+    async fn subscribe(&self, subjects: Vec<String>) -> Result<LiveSubscription, MessageBusError> {
+        LiveSubscription::subscribe(self.message_bus.clone(), subjects, self.nats_outage_limit)
             .await
-            .send(Message::Text(data.to_string()))
+    }
+
+    pub async fn handle_user_event(
+        &mut self,
+        mut data: UserEvent,
+    ) -> Result<(), FatalConnectionError> {
+        if !self.subscriptions.borrow().allows(&data) {
+            return Ok(());
+        }
+
+        if let UserEvent::SelfSync { origin, .. } = &data {
+            if *origin == self.sync_origin {
+                return Ok(()); // this device already knows, it's the one that did it
+            }
+        }
+
+        match &mut data {
+            UserEvent::Chosen {
+                conversation_id,
+                sent_at,
+                silent,
+                ..
+            }
+            | UserEvent::Message {
+                conversation_id,
+                sent_at,
+                silent,
+                ..
+            } => {
+                match self
+                    .prefs_cache
+                    .silences(self.db.as_ref(), &self.username, conversation_id, *sent_at)
+                    .await
+                {
+                    Ok(silences) => *silent = silences,
+                    Err(err) => warn!("Failed to get notification prefs: {}", err), // better to make a sound than drop the event
+                }
+            }
+            UserEvent::Mention {
+                sent_at, silent, ..
+            } => {
+                match self
+                    .prefs_cache
+                    .silences_mention(self.db.as_ref(), &self.username, *sent_at)
+                    .await
+                {
+                    Ok(silences) => *silent = silences,
+                    Err(err) => warn!("Failed to get notification prefs: {}", err),
+                }
+            }
+            UserEvent::NotificationPrefsChanged {
+                conversation_id,
+                prefs,
+            } => self.prefs_cache.update(conversation_id.as_deref(), prefs),
+            _ => {}
+        }
+
+        match &data {
+            UserEvent::Chosen {
+                conversation_id, ..
+            }
+            | UserEvent::Message {
+                conversation_id, ..
+            } => self.active_conversations.insert(conversation_id),
+            UserEvent::ConversationRollover {
+                successor_conversation_id: Some(successor_conversation_id),
+                ..
+            } => self.active_conversations.insert(successor_conversation_id),
+            _ => {}
+        }
+
+        self.user_tx.send_user_event(&data).await?;
+
+        Ok(())
+    }
+
+    // successors aren't known yet at this point. if the conversation gets chosen again, the chooser announces it
+    async fn handle_rollover(&mut self) -> Result<(), FatalConnectionError> {
+        for conversation_id in self.active_conversations.take_rolled_over() {
+            self.handle_user_event(UserEvent::ConversationRollover {
+                conversation_id,
+                successor_conversation_id: None,
+            })
             .await?;
+        }
 
         Ok(())
     }
+
+    fn until_next_window() -> std::time::Duration {
+        let now = Utc::now();
+
+        let next_window = (now + Duration::hours(1))
+            .with_minute(0)
+            .and_then(|next_window| next_window.with_second(0))
+            .and_then(|next_window| next_window.with_nanosecond(0))
+            .expect("Top of the hour should always be a valid time");
+
+        (next_window - now).to_std().unwrap_or_default()
+    }
 }
@@ -0,0 +1,108 @@
+use futures_util::future::BoxFuture;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::Instrument;
+
+use crate::connection::error::FatalConnectionError;
+use crate::message_bus::{BusMessage, MessageBus, MessageBusError, Subscription};
+
+// bus clients reconnect on their own and keep subscriptions alive through that, so a subscription only ends if the
+// client gave up. this keeps trying for a while before taking the user's connection down with it
+//
+// the resubscription lives here rather than in whoever awaits next, so next can be one branch of a select without
+// holding up the others, and without starting over each time another branch wins
+pub struct LiveSubscription {
+    message_bus: Arc<dyn MessageBus>,
+    subjects: Arc<[String]>,
+    outage_limit: Duration,
+    state: State,
+}
+
+enum State {
+    Subscribed(Subscription),
+    Resubscribing(BoxFuture<'static, Result<Subscription, FatalConnectionError>>),
+}
+
+impl LiveSubscription {
+    pub async fn subscribe(
+        message_bus: Arc<dyn MessageBus>,
+        subjects: Vec<String>,
+        outage_limit: Duration,
+    ) -> Result<Self, MessageBusError> {
+        let subscription = subscribe(message_bus.as_ref(), &subjects).await?;
+
+        Ok(Self {
+            message_bus,
+            subjects: subjects.into(),
+            outage_limit,
+            state: State::Subscribed(subscription),
+        })
+    }
+
+    // cancel safe
+    pub async fn next(&mut self) -> Result<BusMessage, FatalConnectionError> {
+        loop {
+            match &mut self.state {
+                State::Subscribed(subscription) => match subscription.next().await {
+                    Some(bus_message) => return Ok(bus_message),
+                    None => {
+                        self.state = State::Resubscribing(Box::pin(
+                            resubscribe(
+                                self.message_bus.clone(),
+                                self.subjects.clone(),
+                                self.outage_limit,
+                            )
+                            .in_current_span(),
+                        ));
+                    }
+                },
+                State::Resubscribing(resubscription) => {
+                    self.state = State::Subscribed(resubscription.await?);
+                }
+            }
+        }
+    }
+}
+
+async fn subscribe(
+    message_bus: &dyn MessageBus,
+    subjects: &[String],
+) -> Result<Subscription, MessageBusError> {
+    let mut subscriptions = Vec::with_capacity(subjects.len());
+
+    for subject in subjects {
+        subscriptions.push(message_bus.subscribe(subject).await?);
+    }
+
+    Ok(Subscription::merge(subscriptions))
+}
+
+async fn resubscribe(
+    message_bus: Arc<dyn MessageBus>,
+    subjects: Arc<[String]>,
+    outage_limit: Duration,
+) -> Result<Subscription, FatalConnectionError> {
+    let subject = subjects.join(", ");
+
+    warn!("Subscription to {} ended, resubscribing", subject);
+
+    let started_at = Instant::now();
+
+    let mut backoff = Duration::from_millis(100);
+
+    loop {
+        match subscribe(message_bus.as_ref(), &subjects).await {
+            Ok(subscription) => return Ok(subscription),
+            Err(err) => warn!("Error resubscribing to {}: {}", subject, err),
+        }
+
+        if started_at.elapsed() + backoff > outage_limit {
+            return Err(FatalConnectionError::UnexpectedSubscriptionTerminate);
+        }
+
+        tokio::time::sleep(backoff).await;
+
+        backoff = (backoff * 2).min(Duration::from_secs(5));
+    }
+}
@@ -7,11 +7,7 @@ use crate::connection::error::UnsupportedFormatError;
 pub struct Notification(pub UserEvent);
 
 impl Notification {
-    pub fn from(raw_nats_message: nats::asynk::Message) -> Result<Self, UnsupportedFormatError> {
-        Ok(Self(UserEvent::from_slice(&raw_nats_message.data)?))
-    }
-
-    pub fn to_message(&self) -> tungstenite::Message {
-        tungstenite::Message::Text(serde_json::to_string(self).unwrap())
+    pub fn from(data: Vec<u8>) -> Result<Self, UnsupportedFormatError> {
+        Ok(Self(UserEvent::from_slice(&data)?))
     }
 }
@@ -0,0 +1,77 @@
+use chrono::prelude::*;
+use std::collections::HashMap;
+
+use crate::db::{DatabaseError, Storage};
+use crate::models::{notification_prefs::NotificationPrefs, user_conversation::UserConversation};
+
+// the connection's copy of its user's notification prefs, so a database read isn't needed for every message. loaded
+// on first use and kept up to date by the NotificationPrefsChanged events every change publishes to the user
+pub struct PrefsCache {
+    prefs: Option<NotificationPrefs>,
+    conversations: HashMap<String, UserConversation>,
+}
+
+impl PrefsCache {
+    pub fn new() -> Self {
+        Self {
+            prefs: None,
+            conversations: HashMap::new(),
+        }
+    }
+
+    pub async fn silences(
+        &mut self,
+        db: &dyn Storage,
+        username: &str,
+        conversation_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.load_prefs(db, username).await?;
+
+        if !self.conversations.contains_key(conversation_id) {
+            let user_conversation = db.get_user_conversation(username, conversation_id).await?;
+
+            self.conversations
+                .insert(conversation_id.to_owned(), user_conversation);
+        }
+
+        Ok(self
+            .prefs
+            .as_ref()
+            .is_some_and(|prefs| prefs.silences(&self.conversations[conversation_id], at)))
+    }
+
+    pub async fn silences_mention(
+        &mut self,
+        db: &dyn Storage,
+        username: &str,
+        at: DateTime<Utc>,
+    ) -> Result<bool, DatabaseError> {
+        self.load_prefs(db, username).await?;
+
+        Ok(self
+            .prefs
+            .as_ref()
+            .is_some_and(|prefs| prefs.silences_mention(at)))
+    }
+
+    async fn load_prefs(&mut self, db: &dyn Storage, username: &str) -> Result<(), DatabaseError> {
+        if self.prefs.is_none() {
+            self.prefs = Some(db.get_notification_prefs(username).await?);
+        }
+
+        Ok(())
+    }
+
+    pub fn update(&mut self, conversation_id: Option<&str>, prefs: &NotificationPrefs) {
+        match conversation_id {
+            Some(conversation_id) => {
+                if let Some(user_conversation) = self.conversations.get_mut(conversation_id) {
+                    user_conversation.muted = prefs.muted;
+                    user_conversation.mentions_only = prefs.mentions_only;
+                }
+            }
+            None => self.prefs = Some(prefs.clone()),
+        }
+    }
+}
@@ -1,43 +1,193 @@
+use arc_swap::ArcSwap;
+use base64::{engine::general_purpose, Engine as _};
 use chrono::prelude::*;
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
-use serde_json::json;
-use std::sync::Arc;
-use tokio::net::TcpStream;
-use tokio::sync::{
-    mpsc::{self, UnboundedSender},
-    Mutex,
+use futures_util::{future::try_join_all, stream::SplitStream, StreamExt};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
 };
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::sync::watch;
+use tokio::time::Instant;
 use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
 use tungstenite::{protocol::frame::coding::CloseCode, Message};
+use uuid::Uuid;
 
 use super::{
+    active_conversations::ActiveConversations,
     error::{ConnectionError, FatalConnectionError, NonFatalConnectionError},
-    nats_message::NatsMessage,
-    user_event::UserEvent,
+    heartbeat,
+    nats_message::{self, NatsMessage},
+    recorder::{Direction, Recorder},
+    registry::ConnectionRegistry,
+    subscriptions::Subscriptions,
+    user_event::{SyncAction, UserEvent},
+    user_tx::UserTx,
+    PROTOCOL_VERSION,
 };
+pub use crate::retry_policy::RetryPolicy;
 use crate::{
+    account_deletion::{self, AccountDeletionJob},
+    analytics::{Analytics, AnalyticsEvent},
+    audit::AuditLog,
+    auth::{self, permissions::Permissions, JWTAuth},
+    challenge::{Challenge, ChallengeContext, ChallengeVerifier},
+    cluster::Membership,
     conversation_id::{ConversationId, ConversationRole},
-    db::Database,
+    db::{DatabaseError, Storage},
+    dead_letter,
+    handshake_limit::{BanUpdate, HandshakeLimiter},
+    hash::Hasher,
+    jobs::{self, JobQueue},
+    message_bus::MessageBus,
+    metrics,
+    models::{
+        attachment::{Attachment, AttachmentKind},
+        audit_entry::{AuditAction, AuditEntry},
+        device::Device,
+        key_distribution::KeyDistribution,
+        mention::Mention,
+        message::Message as StoredMessage,
+        message_kind::MessageKind,
+        pinned_message::PinnedMessage,
+        poll::Poll,
+        push_token::{PushPlatform, PushToken},
+        report::Report,
+    },
+    outbox,
+    presence::Presence,
+    rate_limit::RateLimiter,
+    runtime_config::SharedRuntimeConfig,
+    spam::{SpamDetector, SpamVerdict},
+    storage::object_store::ObjectStore,
+    streaks,
+    transport::Transport,
+    webhook::{WebhookEvent, Webhooks},
 };
+use admin::Admin;
 use mutation::Mutation;
 use operation::Operation;
+use publisher::Publisher;
 use query::Query;
-use response::Response;
+use response::{ErrorCode, Response};
+pub use scheduler::Scheduler;
+pub use timeouts::Timeouts;
 
+mod admin;
 mod mutation;
-mod operation;
+pub mod operation;
+mod panic_guard;
+mod publisher;
 mod query;
-mod response;
+pub mod response;
+mod scheduler;
+mod timeouts;
+
+// the longest ttl scylla accepts, 20 years
+const MAX_DISAPPEARING_TTL_SECONDS: u32 = 630_720_000;
+
+// what throttled spammers are told to wait, the detector doesn't know when they'd stop tripping it
+const SPAM_THROTTLE_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// shared by every connection so time answers from one server can be ordered
+static TIME_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+const AVATAR_CONTENT_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+
+const ATTACHMENT_CONTENT_TYPES: [&str; 10] = [
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "image/gif",
+    "video/mp4",
+    "audio/mpeg",
+    "audio/mp4",
+    "audio/ogg",
+    "audio/webm",
+    "application/pdf",
+];
+
+const MAX_ATTACHMENT_DIMENSION: u32 = 16_384;
+
+const MAX_THUMBHASH_LENGTH: usize = 64; // thumbhashes are around 25 bytes
+
+const MAX_VOICE_DURATION_MS: u32 = 15 * 60 * 1000;
+
+const MAX_WAVEFORM_LENGTH: usize = 256; // one byte per bar of the player
+
+// scylla partitions users by their first 2 characters, so anything shorter can't be searched in one read
+const MIN_SEARCH_PREFIX_LENGTH: usize = 2;
+
+// ids are what the pack or the gif provider calls them, which is nowhere near this long
+const MAX_MESSAGE_KIND_ID_LENGTH: usize = 128;
+
+const MAX_GIF_URL_LENGTH: usize = 2048;
+
+const MAX_SEARCH_TAKE: i8 = 25;
+
+const MAX_MENTIONS_TAKE: i8 = 100;
+
+const MAX_POLL_QUESTION_LENGTH: usize = 300;
+
+// short answers are all a poll's layout has room for
+const MAX_POLL_OPTION_LENGTH: usize = 100;
+
+const MAX_POLL_OPTIONS: usize = 10;
+
+const MAX_AUDIT_LOG_TAKE: i32 = 1000;
+
+// apns tokens are 32 bytes hex encoded and fcm ones around 160 characters today, both say to expect them to grow
+const MAX_PUSH_TOKEN_LENGTH: usize = 512;
+
+const MAX_REPORT_REASON_LENGTH: usize = 1000;
+
+// room for a sender key and signature per device of a participant with a lot of them
+const MAX_KEY_MATERIAL_LENGTH: usize = 16 << 10;
 
 pub struct OperationLoop {
-    pub user_rx: SplitStream<WebSocketStream<TcpStream>>,
-    pub user_tx: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>,
-    pub db: Arc<Database>,
-    pub nc: Arc<nats::asynk::Connection>,
+    pub user_rx: SplitStream<WebSocketStream<Transport>>,
+    pub user_tx: Arc<UserTx>,
+    pub recorder: Option<Arc<Recorder>>,
+    pub db: Arc<dyn Storage>,
+    pub message_bus: Arc<dyn MessageBus>,
+    pub hasher: Arc<Hasher>,
+    pub object_store: Option<Arc<ObjectStore>>, // None when uploads aren't configured
+    pub analytics: Option<Arc<Analytics>>,
+    pub webhooks: Option<Arc<Webhooks>>, // None when no endpoints are configured
+    pub rate_limiter: Arc<RateLimiter>,
+    pub spam_detector: Arc<SpamDetector>,
+    pub handshake_limiter: Arc<HandshakeLimiter>,
+    pub challenges: Option<Arc<dyn ChallengeVerifier>>,
+    pub pending_challenge: Arc<Mutex<Option<Challenge>>>, // None once solved, or if there never was one
+    pub jwt_auth: Arc<JWTAuth>,
+    pub registry: Arc<ConnectionRegistry>,
+    pub degraded: Arc<AtomicBool>,
+    pub active_conversations: Arc<ActiveConversations>,
+    pub scheduler: Scheduler,
+    pub timeouts: Timeouts,
+    pub retry_policy: RetryPolicy,
+    pub token_deadline: Arc<watch::Sender<Instant>>,
+    pub subscriptions: watch::Sender<Subscriptions>,
+    pub permissions: Arc<ArcSwap<Permissions>>, // swapped when a refreshed token grants something else
+    pub runtime: SharedRuntimeConfig,
+    pub presence: Arc<dyn Presence>,
+    pub membership: Arc<Membership>,
+    pub audit_log: Arc<AuditLog>,
+    pub job_queue: Arc<dyn JobQueue>,
+    pub node_id: String,
+    pub max_attachment_size: u64,
+    pub key_distribution_ttl: Duration,
+    pub mention_ttl: Duration,
+    pub is_bot: bool,
     pub username: String,
+    pub device_id: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub sync_origin: String,
 }
 
 impl OperationLoop {
@@ -60,7 +210,7 @@ impl OperationLoop {
                         return Err(err);
                     }
                     ConnectionError::NonFatal(err) => {
-                        warn!("Non fatal error: {}", err);
+                        warn!("Non fatal {:?} error: {}", err.category(), err);
                     }
                 };
 
@@ -69,21 +219,13 @@ impl OperationLoop {
         } {
             let message = message?;
 
-            match message {
-                Message::Text(message) => match Operation::from_str(&message) {
-                    Ok(user_operation) => {
-                        let err_tx = err_tx.clone();
-
-                        self.handle_operation(user_operation, err_tx);
-                    }
-                    Err(err) => {
-                        let _ = err_tx.send(ConnectionError::NonFatal(
-                            NonFatalConnectionError::UnsupportedFormat(err),
-                        )); // no way for err_rx to be dropped if this is running
+            if let Some(recorder) = &self.recorder {
+                recorder.record(Direction::Inbound, &message);
+            }
 
-                        continue;
-                    }
-                },
+            let user_operation = match message {
+                Message::Text(message) => Operation::from_str(&message),
+                Message::Binary(message) => Operation::from_protobuf(&message), // binary frames are only used by protobuf clients and grpc
                 Message::Close(close_frame) => {
                     if let Some(close_frame) = close_frame {
                         match close_frame.code {
@@ -100,9 +242,38 @@ impl OperationLoop {
 
                     return Ok(());
                 }
+                Message::Ping(_) => {
+                    continue; // tungstenite answers pings itself
+                }
+                Message::Pong(pong) => {
+                    if let Some(round_trip_time) = heartbeat::round_trip_time(&pong) {
+                        metrics::CONNECTION_RTT.observe(round_trip_time);
+                    }
+
+                    continue;
+                }
                 _ => {
                     return Err(FatalConnectionError::UnsupportedProtocol(message));
                 }
+            };
+
+            match user_operation {
+                Ok(user_operation) => {
+                    let err_tx = err_tx.clone();
+
+                    if let Err(panic) = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        self.handle_operation(user_operation, err_tx)
+                    })) {
+                        panic_guard::report(&self.user_tx, "handling operation", panic).await;
+                    }
+                }
+                Err(err) => {
+                    let _ = err_tx.send(ConnectionError::NonFatal(
+                        NonFatalConnectionError::UnsupportedFormat(err),
+                    )); // no way for err_rx to be dropped if this is running
+
+                    continue;
+                }
             }
         }
 
@@ -114,6 +285,137 @@ impl OperationLoop {
         user_operation: Operation,
         err_tx: UnboundedSender<ConnectionError>,
     ) {
+        if !self
+            .permissions
+            .load()
+            .allows(user_operation.required_permission())
+        {
+            self.send_response(
+                Response::error(
+                    ErrorCode::Forbidden,
+                    "Access token does not permit this operation",
+                ),
+                err_tx,
+            );
+
+            return;
+        }
+
+        if let Err(retry_after) = self.rate_limiter.check(
+            &self.username,
+            user_operation.rate_limit_class(),
+            self.is_bot,
+        ) {
+            self.send_response(
+                Response::Error {
+                    code: ErrorCode::RateLimited,
+                    message: "Rate limit exceeded".to_owned(),
+                    retryable: true,
+                    retry_after_ms: Some(retry_after.as_millis() as u64),
+                },
+                err_tx,
+            );
+
+            return;
+        }
+
+        // until the challenge is solved only the solution and what keeps the connection open get through
+        if let Operation::Mutation(mutation) = &user_operation {
+            if !matches!(
+                mutation,
+                Mutation::SolveChallenge { .. } | Mutation::RefreshToken { .. }
+            ) {
+                let pending_challenge = self.pending_challenge.lock().unwrap().clone();
+
+                if let Some(challenge) = pending_challenge {
+                    self.send_response(Response::ChallengeRequired(challenge), err_tx);
+
+                    return;
+                }
+            }
+        }
+
+        if self.scheduler.is_full() {
+            metrics::OPERATIONS_REJECTED.increment();
+
+            self.send_response(
+                Response::error(ErrorCode::RateLimited, "Too many operations in flight"),
+                err_tx,
+            );
+
+            return;
+        }
+
+        if let Operation::Mutation(
+            Mutation::Choose { content, .. } | Mutation::Send { content, .. },
+        ) = &user_operation
+        {
+            let max_content_length = self.runtime.load().max_content_length;
+
+            if content.len() > max_content_length {
+                self.send_response(
+                    Response::error(
+                        ErrorCode::ContentTooLong,
+                        &format!("Content must be at most {} bytes", max_content_length),
+                    ),
+                    err_tx,
+                );
+
+                return;
+            }
+
+            let choosee_username = match &user_operation {
+                Operation::Mutation(Mutation::Choose {
+                    choosee_username, ..
+                }) => Some(choosee_username.as_str()),
+                _ => None,
+            };
+
+            // automated responders send the same few messages to everyone who chooses them, so only their rate
+            // limits hold them back
+            let spam_verdict = if self.is_bot {
+                SpamVerdict::Allow
+            } else {
+                self.spam_detector
+                    .check(&self.username, content, choosee_username)
+            };
+
+            match spam_verdict {
+                SpamVerdict::Allow => {}
+                SpamVerdict::Throttle(_) => {
+                    self.send_response(
+                        Response::Error {
+                            code: ErrorCode::RateLimited,
+                            message: "Slow down".to_owned(),
+                            retryable: true,
+                            retry_after_ms: Some(SPAM_THROTTLE_RETRY_AFTER.as_millis() as u64),
+                        },
+                        err_tx,
+                    );
+
+                    return;
+                }
+                SpamVerdict::Mute(remaining) => {
+                    self.send_response(
+                        Response::Error {
+                            code: ErrorCode::RateLimited,
+                            message: "Temporarily muted for spamming".to_owned(),
+                            retryable: true,
+                            retry_after_ms: Some(remaining.as_millis() as u64),
+                        },
+                        err_tx,
+                    );
+
+                    return;
+                }
+                SpamVerdict::Close(_) => {
+                    let _ = err_tx.send(ConnectionError::Fatal(FatalConnectionError::Spam)); // closed as rate limited once the loop ends
+
+                    return;
+                }
+            }
+        }
+
         match user_operation {
             Operation::Query(query) => match query {
                 Query::Messages {
@@ -121,9 +423,13 @@ impl OperationLoop {
                     take,
                     after_sent_at,
                 } => {
-                    let conversation_id = ConversationId::from(conversation_id);
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
 
-                    if conversation_id.get_role_of_username(&self.username)
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
                         == ConversationRole::NotInConversation
                     {
                         let _ =
@@ -133,200 +439,3437 @@ impl OperationLoop {
                         return;
                     }
 
+                    self.active_conversations
+                        .insert(&conversation_id.to_string());
+
                     let db = self.db.clone();
                     let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
 
-                    tokio::task::spawn(async move {
-                        match db
-                            .get_messages(&conversation_id.to_string(), take, after_sent_at)
-                            .await
-                        {
-                            Ok(messages) => {
-                                let response = Response::Messages {
-                                    conversation_id: conversation_id.to_string(),
-                                    messages,
-                                };
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            match timeouts
+                                .database(
+                                    "getting messages",
+                                    db.get_messages(
+                                        &conversation_id.to_string(),
+                                        &username,
+                                        take,
+                                        after_sent_at,
+                                    ),
+                                )
+                                .await
+                            {
+                                Ok(messages) => {
+                                    let response = Response::Messages {
+                                        conversation_id: conversation_id.to_string(),
+                                        messages,
+                                    };
 
-                                if let Err(err) =
-                                    user_tx.lock().await.send(response.to_message()).await
-                                {
-                                    let _ = err_tx.send(ConnectionError::Fatal(
-                                        FatalConnectionError::WebSocketError(err),
-                                    )); // ignoring error because loop could've already closed
+                                    if let Err(err) = user_tx.send_response(&response).await {
+                                        let _ = err_tx.send(ConnectionError::Fatal(
+                                            FatalConnectionError::WebSocketError(err),
+                                        )); // ignoring error because loop could've already closed
+                                    }
                                 }
-                            }
-                            Err(err) => {
-                                let _ = err_tx.send(ConnectionError::NonFatal(
-                                    NonFatalConnectionError::DatabaseError(err),
-                                ));
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
 
-                                if let Err(err) = user_tx
-                                    .lock()
-                                    .await
-                                    .send(
-                                        Response::Error(
-                                            "Failed to get messages for this conversation"
-                                                .to_owned(),
-                                        )
-                                        .to_message(),
-                                    )
-                                    .await
-                                {
-                                    let _ = err_tx.send(ConnectionError::Fatal(
-                                        FatalConnectionError::WebSocketError(err),
-                                    ));
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    if let Err(err) = user_tx
+                                        .send_response(&Response::error(
+                                            code,
+                                            "Failed to get messages for this conversation",
+                                        ))
+                                        .await
+                                    {
+                                        let _ = err_tx.send(ConnectionError::Fatal(
+                                            FatalConnectionError::WebSocketError(err),
+                                        ));
+                                    }
                                 }
                             }
-                        }
-                    });
+                        });
                 }
-            },
-            Operation::Mutation(mutation) => match mutation {
-                Mutation::Choose {
-                    content,
-                    choosee_username,
-                } => {
-                    let conversation_id =
-                        ConversationId::new(self.username.clone(), choosee_username.clone());
-
-                    let user_event = UserEvent::Chosen {
-                        conversation_id: conversation_id.to_string(),
-                        content: content.clone(),
-                        sent_at: DateTime::<Utc>::default(),
-                    };
-
-                    let nats_message = NatsMessage {
-                        to_username_hash: conversation_id.get_choosee_hash().to_owned(),
-                        user_event,
-                    };
-
-                    let nc = self.nc.clone();
-                    let err_tx_clone = err_tx.clone();
-
-                    tokio::task::spawn(async move {
-                        if let Err(err) = nc
-                            .publish(nats_message.subject(), nats_message.data())
-                            .await
-                        {
-                            let _ = err_tx_clone.send(ConnectionError::NonFatal(
-                                // err_rx could potentially be dropped because this is running in task and after an await, so unfortunately error will not get logged, but not really worth doing anything about because of how unlikely it is
-                                NonFatalConnectionError::NatsPublishError(err),
-                            ));
-                        }
-                    });
+                Query::Ping { nonce } => {
+                    self.send_response(
+                        Response::Pong {
+                            nonce,
+                            server_time: Utc::now(),
+                        },
+                        err_tx,
+                    );
+                }
+                Query::ConnectionInfo => {
+                    let token_expires_in = self
+                        .token_deadline
+                        .borrow()
+                        .saturating_duration_since(Instant::now());
 
+                    self.send_response(
+                        Response::ConnectionInfo {
+                            connection_id: self.user_tx.connection_id().to_owned(),
+                            protocol_version: PROTOCOL_VERSION,
+                            encoding: self.user_tx.encoding().subprotocol(),
+                            token_expires_at: Utc::now()
+                                + chrono::Duration::from_std(token_expires_in)
+                                    .unwrap_or_else(|_| chrono::Duration::zero()),
+                            rate_limit_tokens: self
+                                .rate_limiter
+                                .remaining(&self.username, self.is_bot),
+                            is_bot: self.is_bot,
+                            subscriptions: self.subscriptions.borrow().clone(),
+                        },
+                        err_tx,
+                    );
+                }
+                Query::OnlineFriends => {
                     let db = self.db.clone();
+                    let presence = self.presence.clone();
+                    let user_tx = self.user_tx.clone();
                     let username = self.username.clone();
-                    let conversation_id_string = conversation_id.to_string();
-                    let err_tx_clone = err_tx.clone();
+                    let timeouts = self.timeouts;
 
-                    tokio::task::spawn(async move {
-                        if let Err(err) = db
-                            .new_conversation(&username, &choosee_username, &conversation_id_string)
-                            .await
+                    self.scheduler.schedule(&self.username, async move {
+                        let response = match Self::online_friends(
+                            timeouts,
+                            db.as_ref(),
+                            presence.as_ref(),
+                            &username,
+                        )
+                        .await
                         {
-                            let _ = err_tx_clone.send(ConnectionError::NonFatal(
-                                NonFatalConnectionError::DatabaseError(err),
-                            ));
-                        }
-                    });
+                            Ok(usernames) => Response::OnlineFriends { usernames },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
 
-                    let db = self.db.clone();
-                    let conversation_id_string = conversation_id.to_string();
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
 
-                    tokio::task::spawn(async move {
-                        if let Err(err) = db
-                            .new_message(&conversation_id_string, &content, true)
-                            .await
-                        {
-                            let _ = err_tx.send(ConnectionError::NonFatal(
-                                NonFatalConnectionError::DatabaseError(err),
+                                Response::error(code, "Failed to get online friends")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
                             ));
                         }
                     });
                 }
-                Mutation::Send {
-                    content,
+                Query::Time => {
+                    self.send_response(
+                        Response::Time {
+                            server_time: Utc::now(),
+                            sequence: TIME_SEQUENCE.fetch_add(1, Ordering::Relaxed),
+                        },
+                        err_tx,
+                    );
+                }
+                Query::RetentionPolicy => {
+                    self.send_response(
+                        Response::RetentionPolicy {
+                            message_retention_seconds: self
+                                .db
+                                .message_retention()
+                                .map(|retention| retention.as_secs()),
+                        },
+                        err_tx,
+                    );
+                }
+                Query::ChooseePresence {
                     conversation_id,
+                    take,
                 } => {
-                    let conversation_id = ConversationId::from(conversation_id);
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
 
-                    let (to_username_hash, from_chooser) =
-                        match conversation_id.get_role_of_username(&self.username) {
-                            ConversationRole::Chooser => {
-                                (conversation_id.get_choosee_hash().to_owned(), true)
-                            }
-                            ConversationRole::Choosee => {
-                                (conversation_id.get_chooser_hash().to_owned(), false)
-                            }
-                            ConversationRole::NotInConversation => {
-                                let _ = err_tx
-                                .send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
-                                "User attempted to send message to conversation not belonging to",
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to get choosee presence in conversation not belonging to",
                             )));
+                        return;
+                    }
 
-                                return;
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let response = match timeouts
+                                .database(
+                                    "getting choosee presence",
+                                    db.get_choosee_presence(&conversation_id.to_string(), take),
+                                )
+                                .await
+                            {
+                                Ok(events) => Response::ChooseePresence {
+                                    conversation_id: conversation_id.to_string(),
+                                    events,
+                                },
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(
+                                        code,
+                                        "Failed to get choosee presence for this conversation",
+                                    )
+                                }
+                            };
+
+                            if let Err(err) = user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
                             }
-                        };
+                        });
+                }
+                Query::KeyDistributions { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
 
-                    let user_event = UserEvent::Message {
-                        conversation_id: conversation_id.to_string(),
-                        content: content.clone(),
-                        sent_at: DateTime::<Utc>::default(),
+                    // what the other participant sent
+                    let from_chooser = match conversation_id
+                        .get_role_of_username(&self.hasher, &self.username)
+                    {
+                        ConversationRole::Chooser => false,
+                        ConversationRole::Choosee => true,
+                        ConversationRole::NotInConversation => {
+                            let _ = err_tx
+                                .send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to get key distributions in conversation not belonging to",
+                            )));
+
+                            return;
+                        }
                     };
 
-                    let nats_message = NatsMessage {
-                        to_username_hash,
-                        user_event,
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let response = match timeouts
+                                .database(
+                                    "getting key distributions",
+                                    db.get_key_distributions(
+                                        &conversation_id.to_string(),
+                                        from_chooser,
+                                    ),
+                                )
+                                .await
+                            {
+                                Ok(key_distributions) => Response::KeyDistributions {
+                                    conversation_id: conversation_id.to_string(),
+                                    key_distributions,
+                                },
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Failed to get key distributions")
+                                }
+                            };
+
+                            if let Err(err) = user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        });
+                }
+                Query::ConversationStats { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
                     };
 
-                    let nc = self.nc.clone();
-                    let err_tx_clone = err_tx.clone();
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to get stats of conversation not belonging to",
+                            )));
 
-                    tokio::task::spawn(async move {
-                        if let Err(err) = nc
-                            .publish(nats_message.subject(), nats_message.data())
-                            .await
-                        {
-                            let _ = err_tx_clone.send(ConnectionError::NonFatal(
-                                NonFatalConnectionError::NatsPublishError(err),
-                            ));
-                        }
-                    });
+                        return;
+                    }
 
                     let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
 
-                    tokio::task::spawn(async move {
-                        if let Err(err) = db
-                            .new_message(&conversation_id.to_string(), &content, from_chooser)
-                            .await
-                        {
-                            let _ = err_tx.send(ConnectionError::NonFatal(
-                                NonFatalConnectionError::DatabaseError(err),
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let response = match timeouts
+                                .database(
+                                    "getting conversation stats",
+                                    db.get_conversation_stats(&conversation_id.to_string()),
+                                )
+                                .await
+                            {
+                                Ok(stats) => Response::ConversationStats {
+                                    conversation_id: conversation_id.to_string(),
+                                    stats,
+                                },
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Failed to get conversation stats")
+                                }
+                            };
+
+                            if let Err(err) = user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        });
+                }
+                Query::Mentions { take } => {
+                    if !(1..=MAX_MENTIONS_TAKE).contains(&take) {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!("Take must be between 1 and {}", MAX_MENTIONS_TAKE),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+
+                    // kept under the hash the sender had for the user, which is an older one during a hash rollout
+                    let username_hashes = self.hasher.hashes(&self.username);
+
+                    self.scheduler.schedule(&self.username, async move {
+                        let result = timeouts
+                            .database("getting mentions", async {
+                                let mut mentions = Vec::new();
+
+                                for username_hash in &username_hashes {
+                                    mentions
+                                        .extend(db.get_mentions(username_hash, take as i32).await?);
+                                }
+
+                                Ok::<_, DatabaseError>(mentions)
+                            })
+                            .await;
+
+                        let response = match result {
+                            Ok(mut mentions) => {
+                                mentions.sort_by_key(|mention| std::cmp::Reverse(mention.sent_at));
+                                mentions.truncate(take as usize);
+
+                                Response::Mentions { mentions }
+                            }
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to get mentions")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
                             ));
                         }
                     });
                 }
-                Mutation::RegisterPresenceChoosee {
-                    conversation_id,
-                    leaving,
-                } => {
-                    let conversation_id = ConversationId::from(conversation_id);
-
-                    let role_in_conversation = conversation_id.get_role_of_username(&self.username);
+                Query::Polls { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
 
-                    if role_in_conversation == ConversationRole::NotInConversation
-                        || role_in_conversation == ConversationRole::Chooser
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
                     {
-                        let _ = err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden("User attempted to register choosee presence in conversation not not a choosee of")));
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to get polls of conversation not belonging to",
+                            )));
 
                         return;
                     }
 
-                    todo!();
-                    // db.update_choosee_last_presence_at(choosee_username, created_at);
-                }
-            },
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let response = match timeouts
+                                .database(
+                                    "getting polls",
+                                    db.get_polls(&conversation_id.to_string()),
+                                )
+                                .await
+                            {
+                                Ok(polls) => Response::Polls {
+                                    conversation_id: conversation_id.to_string(),
+                                    polls,
+                                },
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Failed to get polls")
+                                }
+                            };
+
+                            if let Err(err) = user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        });
+                }
+                Query::SearchUsers { prefix, take } => {
+                    if prefix.chars().count() < MIN_SEARCH_PREFIX_LENGTH {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!(
+                                    "Search prefix must be at least {} characters",
+                                    MIN_SEARCH_PREFIX_LENGTH
+                                ),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    if !(1..=MAX_SEARCH_TAKE).contains(&take) {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!("Take must be between 1 and {}", MAX_SEARCH_TAKE),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        // one extra in case the user finds themselves
+                        let response = match timeouts
+                            .database("searching users", db.search_users(&prefix, take + 1))
+                            .await
+                        {
+                            Ok(users) => Response::Users {
+                                prefix,
+                                users: users
+                                    .into_iter()
+                                    .filter(|user| user.username != username)
+                                    .take(take as usize)
+                                    .collect(),
+                            },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to search users")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Query::Conversations { archived } => {
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    // same key as pinning a conversation, so the list reflects a pin sent just before it
+                    self.scheduler.schedule(&self.username, async move {
+                        let response = match timeouts
+                            .database("getting conversations", db.get_conversations(&username))
+                            .await
+                        {
+                            Ok(conversations) => Response::Conversations {
+                                conversations: conversations
+                                    .into_iter()
+                                    .filter(|conversation| conversation.archived == archived)
+                                    .collect(),
+                            },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to get conversations")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Query::Conversation { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to get conversation not belonging to",
+                            )));
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let conversation_id = conversation_id.to_string();
+
+                            let result = timeouts
+                                .database("getting conversation", async {
+                                    Ok::<_, DatabaseError>((
+                                        db.get_user_conversation(&username, &conversation_id)
+                                            .await?,
+                                        db.get_pinned_messages(&conversation_id).await?,
+                                    ))
+                                })
+                                .await;
+
+                            let response = match result {
+                                Ok((user_conversation, pinned_messages)) => {
+                                    Response::Conversation {
+                                        conversation_id,
+                                        pinned: user_conversation.pinned,
+                                        archived: user_conversation.archived,
+                                        cleared_before: user_conversation.cleared_before,
+                                        pinned_messages: pinned_messages
+                                            .into_iter()
+                                            .filter(|pinned_message| {
+                                                user_conversation.cleared_before.is_none_or(
+                                                    |cleared_before| {
+                                                        pinned_message.sent_at > cleared_before
+                                                    },
+                                                )
+                                            })
+                                            .collect(),
+                                        muted: user_conversation.muted,
+                                        mentions_only: user_conversation.mentions_only,
+                                    }
+                                }
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Failed to get this conversation")
+                                }
+                            };
+
+                            if let Err(err) = user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        });
+                }
+                Query::Devices => {
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let device_id = self.device_id.clone();
+                    let connected_device_ids = self.registry.connected_device_ids(&self.username);
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        let response = match timeouts
+                            .database("getting devices", db.get_devices(&username))
+                            .await
+                        {
+                            Ok(devices) => Response::Devices {
+                                devices: devices
+                                    .into_iter()
+                                    .map(|device| Device {
+                                        connected: connected_device_ids.contains(&device.device_id),
+                                        current: device_id.as_ref() == Some(&device.device_id),
+                                        ..device
+                                    })
+                                    .collect(),
+                            },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to get devices")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Query::NotificationPrefs => {
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        let response = match timeouts
+                            .database(
+                                "getting notification prefs",
+                                db.get_notification_prefs(&username),
+                            )
+                            .await
+                        {
+                            Ok(prefs) => Response::NotificationPrefs { prefs },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to get notification prefs")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+            },
+            Operation::Mutation(mutation) => match mutation {
+                Mutation::Choose {
+                    content,
+                    choosee_username,
+                } => {
+                    let conversation_id =
+                        ConversationId::new(&self.hasher, &self.username, &choosee_username);
+
+                    let sent_at = Utc::now(); // the one timestamp for the event, the stored rows and the ack
+
+                    self.announce_rollover(&conversation_id, err_tx.clone());
+
+                    self.active_conversations
+                        .insert(&conversation_id.to_string());
+
+                    let user_event = UserEvent::Chosen {
+                        conversation_id: conversation_id.to_string(),
+                        content: content.clone(),
+                        sent_at,
+                        silent: false,
+                    };
+
+                    let nats_message = NatsMessage {
+                        to_username_hash: conversation_id.get_choosee_hash().to_owned(),
+                        user_event,
+                    };
+
+                    let self_sync = self.self_sync(SyncAction::Chose {
+                        conversation_id: conversation_id.to_string(),
+                        choosee_username: choosee_username.clone(),
+                        content: content.clone(),
+                        sent_at,
+                    });
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let conversation_id_string = conversation_id.to_string();
+                    let timeouts = self.timeouts;
+                    let analytics = self.analytics.clone();
+                    let webhooks = self.webhooks.clone();
+
+                    // one task, since nothing should be published or stored for a choosee that doesn't exist
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let profiles = timeouts
+                                .database("getting profiles", async {
+                                    Ok((
+                                        db.get_profile(&username).await?,
+                                        db.get_profile(&choosee_username).await?,
+                                    ))
+                                })
+                                .await;
+
+                            let (chooser, choosee) = match profiles {
+                                Ok((Some(chooser), Some(choosee))) => (chooser, choosee),
+                                Ok(_) => {
+                                    if let Err(err) = user_tx
+                                        .send_response(&Response::error(
+                                            ErrorCode::InvalidRequest,
+                                            "No user with that username",
+                                        ))
+                                        .await
+                                    {
+                                        let _ = err_tx.send(ConnectionError::Fatal(
+                                            FatalConnectionError::WebSocketError(err),
+                                        ));
+                                    }
+
+                                    return;
+                                }
+                                Err(err) => {
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    return;
+                                }
+                            };
+
+                            publisher.publish(nats_message, err_tx.clone()).await;
+
+                            if let Err(err) = timeouts
+                                .database(
+                                    "creating conversation",
+                                    db.new_conversation(
+                                        &chooser.username,
+                                        &choosee.username,
+                                        &chooser.name,
+                                        &choosee.name,
+                                        &conversation_id_string,
+                                        sent_at,
+                                    ),
+                                )
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                return;
+                            }
+
+                            if let Some(webhooks) = &webhooks {
+                                webhooks.dispatch(WebhookEvent::ConversationCreated {
+                                    conversation_id: conversation_id_string.clone(),
+                                    chooser_username: chooser.username.clone(),
+                                    choosee_username: choosee.username.clone(),
+                                    created_at: sent_at,
+                                });
+                            }
+
+                            if let Err(err) = timeouts
+                                .database(
+                                    "saving message",
+                                    db.new_message(
+                                        &conversation_id_string,
+                                        &chooser.username,
+                                        &StoredMessage {
+                                            content: content.clone(),
+                                            sent_at,
+                                            from_chooser: true,
+                                            attachment: None,
+                                            kind: None,
+                                        },
+                                    ),
+                                )
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                return;
+                            }
+
+                            if let Some(analytics) = analytics {
+                                analytics.record(AnalyticsEvent::Chose {
+                                    conversation_id: conversation_id_string.clone(),
+                                    sent_at,
+                                    content_length: content.len(),
+                                    content: analytics.content(&content),
+                                });
+                            }
+
+                            publisher.publish(self_sync, err_tx.clone()).await;
+
+                            Self::acknowledge_sent(
+                                &user_tx,
+                                conversation_id_string,
+                                sent_at,
+                                &err_tx,
+                            )
+                            .await;
+                        });
+                }
+                Mutation::Send {
+                    content,
+                    conversation_id,
+                    attachment,
+                    mention,
+                    kind,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if let Some(kind) = &kind {
+                        let checked = match attachment {
+                            Some(_) => {
+                                Err("A message can't have both an attachment and a kind".to_owned())
+                            }
+                            None => Self::check_message_kind(kind),
+                        };
+
+                        if let Err(message) = checked {
+                            self.send_response(
+                                Response::error(ErrorCode::InvalidRequest, &message),
+                                err_tx,
+                            );
+
+                            return;
+                        }
+                    }
+
+                    if let Some(attachment) = &attachment {
+                        if self.uploads_configured(&err_tx).is_none() {
+                            return;
+                        }
+
+                        if let Err(message) = self.check_attachment(&conversation_id, attachment) {
+                            self.send_response(
+                                Response::error(ErrorCode::InvalidRequest, &message),
+                                err_tx,
+                            );
+
+                            return;
+                        }
+                    }
+
+                    let (to_username_hash, from_chooser) =
+                        match conversation_id.get_role_of_username(&self.hasher, &self.username) {
+                            ConversationRole::Chooser => {
+                                (conversation_id.get_choosee_hash().to_owned(), true)
+                            }
+                            ConversationRole::Choosee => {
+                                (conversation_id.get_chooser_hash().to_owned(), false)
+                            }
+                            ConversationRole::NotInConversation => {
+                                let _ = err_tx
+                                .send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to send message to conversation not belonging to",
+                            )));
+
+                                return;
+                            }
+                        };
+
+                    self.active_conversations
+                        .insert(&conversation_id.to_string());
+
+                    let sent_at = Utc::now();
+
+                    let user_event = UserEvent::Message {
+                        conversation_id: conversation_id.to_string(),
+                        content: content.clone(),
+                        sent_at,
+                        attachment: attachment.clone(),
+                        kind: kind.clone(),
+                        silent: false,
+                        mention,
+                    };
+
+                    let nats_message = NatsMessage {
+                        to_username_hash: to_username_hash.clone(),
+                        user_event,
+                    };
+
+                    self.publish(&conversation_id, nats_message, err_tx.clone());
+
+                    let self_sync = self.self_sync(SyncAction::Sent {
+                        conversation_id: conversation_id.to_string(),
+                        content: content.clone(),
+                        sent_at,
+                        attachment: attachment.clone(),
+                        kind: kind.clone(),
+                    });
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+                    let analytics = self.analytics.clone();
+                    let username = self.username.clone();
+                    let mention_ttl = self.mention_ttl;
+
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let streak = match timeouts
+                                .database(
+                                    "saving message",
+                                    db.new_message(
+                                        &conversation_id.to_string(),
+                                        &username,
+                                        &StoredMessage {
+                                            content: content.clone(),
+                                            sent_at,
+                                            from_chooser,
+                                            attachment: attachment.clone(),
+                                            kind,
+                                        },
+                                    ),
+                                )
+                                .await
+                            {
+                                Ok(streak) => streak,
+                                Err(err) => {
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    return;
+                                }
+                            };
+
+                            if let Some(analytics) = analytics {
+                                analytics.record(AnalyticsEvent::Sent {
+                                    conversation_id: conversation_id.to_string(),
+                                    sent_at,
+                                    from_chooser,
+                                    has_attachment: attachment.is_some(),
+                                    content_length: content.len(),
+                                    content: analytics.content(&content),
+                                });
+                            }
+
+                            publisher.publish(self_sync, err_tx.clone()).await;
+
+                            // still sent when it couldn't be kept for the mentions query, it's the notification
+                            // that matters most
+                            if mention {
+                                let mention = Mention {
+                                    conversation_id: conversation_id.to_string(),
+                                    sent_at,
+                                };
+
+                                if let Err(err) = timeouts
+                                    .database(
+                                        "adding mention",
+                                        db.add_mention(&to_username_hash, &mention, mention_ttl),
+                                    )
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+                                }
+
+                                let nats_message = NatsMessage {
+                                    to_username_hash,
+                                    user_event: UserEvent::Mention {
+                                        conversation_id: conversation_id.to_string(),
+                                        content: content.clone(),
+                                        sent_at,
+                                        silent: false,
+                                    },
+                                };
+
+                                publisher.publish(nats_message, err_tx.clone()).await;
+                            }
+
+                            // both of them hear about it, the sender on every device
+                            if let Some(streak) =
+                                streak.filter(|streak| streaks::is_milestone(*streak))
+                            {
+                                for to_username_hash in [
+                                    conversation_id.get_chooser_hash(),
+                                    conversation_id.get_choosee_hash(),
+                                ] {
+                                    let nats_message = NatsMessage {
+                                        to_username_hash: to_username_hash.to_owned(),
+                                        user_event: UserEvent::StreakMilestone {
+                                            conversation_id: conversation_id.to_string(),
+                                            streak,
+                                            reached_at: sent_at,
+                                        },
+                                    };
+
+                                    publisher.publish(nats_message, err_tx.clone()).await;
+                                }
+                            }
+
+                            Self::acknowledge_sent(
+                                &user_tx,
+                                conversation_id.to_string(),
+                                sent_at,
+                                &err_tx,
+                            )
+                            .await;
+                        });
+                }
+                Mutation::RegisterPresenceChoosee {
+                    conversation_id,
+                    leaving,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    let role_in_conversation =
+                        conversation_id.get_role_of_username(&self.hasher, &self.username);
+
+                    if role_in_conversation == ConversationRole::NotInConversation
+                        || role_in_conversation == ConversationRole::Chooser
+                    {
+                        let _ = err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden("User attempted to register choosee presence in conversation not not a choosee of")));
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let timeouts = self.timeouts;
+                    let occurred_at = Utc::now();
+
+                    // saved first so the chooser never hears about presence their query won't show
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let chooser_hash = conversation_id.get_chooser_hash().to_owned();
+
+                            if let Err(err) = timeouts
+                                .database(
+                                    "updating choosee presence",
+                                    db.update_choosee_last_presence_at(
+                                        &conversation_id.to_string(),
+                                        occurred_at,
+                                        leaving,
+                                        &chooser_hash,
+                                    ),
+                                )
+                                .await
+                            {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                if let Err(err) = publisher
+                                    .user_tx
+                                    .send_response(&Response::error(
+                                        code,
+                                        "Failed to register presence",
+                                    ))
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::Fatal(
+                                        FatalConnectionError::WebSocketError(err),
+                                    ));
+                                }
+
+                                return;
+                            }
+
+                            let nats_message = NatsMessage {
+                                to_username_hash: chooser_hash,
+                                user_event: UserEvent::ChooseePresence {
+                                    conversation_id: conversation_id.to_string(),
+                                    leaving,
+                                    occurred_at,
+                                },
+                            };
+
+                            publisher.publish(nats_message, err_tx).await;
+                        });
+                }
+                Mutation::DeleteMyAccount { confirmation_token } => {
+                    // a token of its own, so a stolen session can't delete the account with the access token it has
+                    match self.jwt_auth.verify_purpose_token(
+                        &confirmation_token,
+                        account_deletion::CONFIRMATION_PURPOSE,
+                        account_deletion::CONFIRMATION_MAX_AGE,
+                    ) {
+                        Ok(payload) if payload.username == self.username => {}
+                        _ => {
+                            self.audit_log.record(self.audit_entry(
+                                AuditAction::AuthFailed,
+                                None,
+                                "Invalid account deletion confirmation token".to_owned(),
+                            ));
+
+                            self.send_response(
+                                Response::error(
+                                    ErrorCode::Forbidden,
+                                    "Confirmation token is not valid for this account",
+                                ),
+                                err_tx,
+                            );
+
+                            return;
+                        }
+                    }
+
+                    let db = self.db.clone();
+                    let job_queue = self.job_queue.clone();
+                    let user_tx = self.user_tx.clone();
+                    let job = AccountDeletionJob {
+                        username: self.username.clone(),
+                        audit_entry: self.audit_entry(
+                            AuditAction::AccountDeleted,
+                            Some(self.username.clone()),
+                            "Deleted by the user".to_owned(),
+                        ),
+                    }
+                    .job();
+
+                    tokio::task::spawn(
+                        panic_guard::guard(self.user_tx.clone(), "deleting account", async move {
+                            // not scheduled on this connection because deleting the account closes it
+                            let response =
+                                match jobs::enqueue(job_queue.as_ref(), db.as_ref(), job).await {
+                                    Ok(()) => Response::AccountDeletionScheduled,
+                                    Err(err) => {
+                                        error!("Error enqueuing account deletion: {}", err);
+
+                                        Response::error(
+                                            ErrorCode::Internal,
+                                            "Failed to schedule account deletion",
+                                        )
+                                    }
+                                };
+
+                            // the job may already have closed the connection
+                            let _ = user_tx.send_response(&response).await;
+                        })
+                        .in_current_span(),
+                    );
+                }
+                Mutation::SolveChallenge { solution } => {
+                    let pending_challenge = self.pending_challenge.lock().unwrap().clone();
+
+                    let (Some(challenges), Some(challenge)) =
+                        (self.challenges.clone(), pending_challenge)
+                    else {
+                        // already solved, maybe by an earlier solution that crossed this one on the wire
+                        self.send_response(Response::ChallengeSolved, err_tx);
+
+                        return;
+                    };
+
+                    let context = ChallengeContext {
+                        username: self.username.clone(),
+                        ip: self.ip,
+                        device_id: self.device_id.clone(),
+                    };
+                    let pending_challenge = self.pending_challenge.clone();
+                    let user_tx = self.user_tx.clone();
+
+                    self.scheduler.schedule("solve_challenge", async move {
+                        let response =
+                            match challenges.verify(&context, &challenge, &solution).await {
+                                Ok(true) => {
+                                    *pending_challenge.lock().unwrap() = None;
+
+                                    Response::ChallengeSolved
+                                }
+                                Ok(false) => Response::error(
+                                    ErrorCode::InvalidRequest,
+                                    "Challenge solution rejected",
+                                ),
+                                Err(err) => {
+                                    let err = NonFatalConnectionError::from(err);
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Unable to verify challenge solution")
+                                }
+                            };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Mutation::RefreshToken { token } => {
+                    let access_token_payload = match self.jwt_auth.verify_token(&token) {
+                        Ok(payload) if payload.username == self.username => payload,
+                        Ok(payload) => {
+                            self.audit_log.record(self.audit_entry(
+                                AuditAction::AuthFailed,
+                                Some(payload.username),
+                                "Refreshed with another account's access token".to_owned(),
+                            ));
+
+                            self.send_response(
+                                Response::error(
+                                    ErrorCode::Forbidden,
+                                    "Access token belongs to a different account",
+                                ),
+                                err_tx,
+                            );
+
+                            return;
+                        }
+                        Err(err) => {
+                            self.audit_log.record(self.audit_entry(
+                                AuditAction::AuthFailed,
+                                None,
+                                format!("Invalid refresh token: {}", err),
+                            ));
+
+                            self.send_response(
+                                Response::error(ErrorCode::Forbidden, &err.to_string()),
+                                err_tx,
+                            );
+
+                            return;
+                        }
+                    };
+
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let token_deadline = self.token_deadline.clone();
+                    let permissions = self.permissions.clone();
+                    let device_id = self.device_id.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule("refresh_token", async move {
+                        let response = match timeouts
+                            .database(
+                                "checking token revocation",
+                                auth::is_revoked(
+                                    db.as_ref(),
+                                    &access_token_payload,
+                                    device_id.as_deref(),
+                                ),
+                            )
+                            .await
+                        {
+                            Ok(false) => {
+                                // roles and scopes can be taken away as well as granted on refresh
+                                permissions.store(Arc::new(Permissions::from_payload(
+                                    &access_token_payload,
+                                )));
+
+                                let expires_in = access_token_payload.expires_in();
+
+                                token_deadline.send_replace(Instant::now() + expires_in);
+
+                                Response::TokenRefreshed {
+                                    token_expires_at: Utc::now()
+                                        + chrono::Duration::from_std(expires_in)
+                                            .unwrap_or_else(|_| chrono::Duration::zero()),
+                                }
+                            }
+                            Ok(true) => {
+                                Response::error(ErrorCode::Forbidden, "Access token revoked")
+                            }
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to refresh access token")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Mutation::SetDisappearing {
+                    conversation_id,
+                    ttl_seconds,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    let (own_hash, other_hash) = match conversation_id
+                        .get_role_of_username(&self.hasher, &self.username)
+                    {
+                        ConversationRole::Chooser => (
+                            conversation_id.get_chooser_hash().to_owned(),
+                            conversation_id.get_choosee_hash().to_owned(),
+                        ),
+                        ConversationRole::Choosee => (
+                            conversation_id.get_choosee_hash().to_owned(),
+                            conversation_id.get_chooser_hash().to_owned(),
+                        ),
+                        ConversationRole::NotInConversation => {
+                            let _ = err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                    "User attempted to set disappearing messages in conversation not belonging to",
+                                )));
+
+                            return;
+                        }
+                    };
+
+                    if ttl_seconds > MAX_DISAPPEARING_TTL_SECONDS {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!(
+                                    "Disappearing messages can last at most {} seconds",
+                                    MAX_DISAPPEARING_TTL_SECONDS
+                                ),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let timeouts = self.timeouts;
+
+                    // saved first so neither client shows a mode that didn't stick
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            if let Err(err) = timeouts
+                                .database(
+                                    "setting disappearing messages",
+                                    db.set_disappearing(
+                                        &conversation_id.to_string(),
+                                        (ttl_seconds > 0)
+                                            .then(|| Duration::from_secs(ttl_seconds.into())),
+                                    ),
+                                )
+                                .await
+                            {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                if let Err(err) = publisher
+                                    .user_tx
+                                    .send_response(&Response::error(
+                                        code,
+                                        "Failed to set disappearing messages",
+                                    ))
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::Fatal(
+                                        FatalConnectionError::WebSocketError(err),
+                                    ));
+                                }
+
+                                return;
+                            }
+
+                            let user_event = UserEvent::DisappearingChanged {
+                                conversation_id: conversation_id.to_string(),
+                                ttl_seconds,
+                                changed_at: Utc::now(),
+                            };
+
+                            for to_username_hash in [other_hash, own_hash] {
+                                publisher
+                                    .publish(
+                                        NatsMessage {
+                                            to_username_hash,
+                                            user_event: user_event.clone(),
+                                        },
+                                        err_tx.clone(),
+                                    )
+                                    .await;
+                            }
+                        });
+                }
+                Mutation::RequestAvatarUpload { content_type } => {
+                    let Some(object_store) = self.uploads_configured(&err_tx) else {
+                        return;
+                    };
+
+                    if !AVATAR_CONTENT_TYPES.contains(&content_type.as_str()) {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!(
+                                    "Avatars must be one of {}",
+                                    AVATAR_CONTENT_TYPES.join(", ")
+                                ),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let object_key = format!("{}{}", self.avatar_prefix(), Uuid::new_v4());
+
+                    self.send_response(
+                        Response::Upload(object_store.presign_put(
+                            &object_key,
+                            &content_type,
+                            None,
+                        )),
+                        err_tx,
+                    );
+                }
+                Mutation::RequestAttachmentUpload {
+                    conversation_id,
+                    content_type,
+                    size,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ = err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                            "User attempted to upload an attachment to conversation not belonging to",
+                        )));
+
+                        return;
+                    }
+
+                    let Some(object_store) = self.uploads_configured(&err_tx) else {
+                        return;
+                    };
+
+                    if let Err(message) = self.check_upload(&content_type, size) {
+                        self.send_response(
+                            Response::error(ErrorCode::InvalidRequest, &message),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let object_key = format!(
+                        "{}{}",
+                        Self::attachment_prefix(&conversation_id),
+                        Uuid::new_v4()
+                    );
+
+                    // the size is signed into the url, so the upload can't be bigger than what was declared
+                    self.send_response(
+                        Response::Upload(object_store.presign_put(
+                            &object_key,
+                            &content_type,
+                            Some(size),
+                        )),
+                        err_tx,
+                    );
+                }
+                Mutation::SetAvatar { object_key } => {
+                    if self.uploads_configured(&err_tx).is_none() {
+                        return;
+                    }
+
+                    // keys are only ever handed out under the user's own prefix, so anything else is someone else's
+                    // upload or made up
+                    if !object_key.starts_with(&self.avatar_prefix()) || object_key.contains("..") {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                "Avatar must be uploaded with a url from requestAvatarUpload",
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let hasher = self.hasher.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        if let Err(err) = timeouts
+                            .database("setting avatar", db.set_avatar(&username, &object_key))
+                            .await
+                        {
+                            let code = ErrorCode::from(&err);
+
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            if let Err(err) = publisher
+                                .user_tx
+                                .send_response(&Response::error(code, "Failed to set avatar"))
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+
+                            return;
+                        }
+
+                        let friends = match timeouts
+                            .database("getting friends", db.get_friends(&username))
+                            .await
+                        {
+                            Ok(friends) => friends,
+                            Err(err) => {
+                                let _ = err_tx.send(ConnectionError::NonFatal(err)); // the avatar is saved, friends see it next time they load the profile
+
+                                return;
+                            }
+                        };
+
+                        let user_event = UserEvent::AvatarChanged {
+                            username: username.clone(),
+                            object_key,
+                        };
+
+                        // own connections too, so other devices update
+                        for to_username in friends
+                            .iter()
+                            .map(|friend| friend.username.as_str())
+                            .chain([username.as_str()])
+                        {
+                            publisher
+                                .publish(
+                                    NatsMessage {
+                                        to_username_hash: hasher.hash(to_username),
+                                        user_event: user_event.clone(),
+                                    },
+                                    err_tx.clone(),
+                                )
+                                .await;
+                        }
+                    });
+                }
+                Mutation::PinMessage {
+                    conversation_id,
+                    sent_at,
+                    pinned,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    let (own_hash, other_hash, pinned_by_chooser) =
+                        match conversation_id.get_role_of_username(&self.hasher, &self.username) {
+                            ConversationRole::Chooser => (
+                                conversation_id.get_chooser_hash().to_owned(),
+                                conversation_id.get_choosee_hash().to_owned(),
+                                true,
+                            ),
+                            ConversationRole::Choosee => (
+                                conversation_id.get_choosee_hash().to_owned(),
+                                conversation_id.get_chooser_hash().to_owned(),
+                                false,
+                            ),
+                            ConversationRole::NotInConversation => {
+                                let _ = err_tx
+                                .send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to pin message in conversation not belonging to",
+                            )));
+
+                                return;
+                            }
+                        };
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let timeouts = self.timeouts;
+
+                    // pins are shared by both users, so they're saved before either is told
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let pinned_at = Utc::now();
+
+                            let result = if pinned {
+                                timeouts
+                                    .database(
+                                        "pinning message",
+                                        db.pin_message(
+                                            &conversation_id.to_string(),
+                                            &PinnedMessage {
+                                                sent_at,
+                                                pinned_at,
+                                                pinned_by_chooser,
+                                            },
+                                        ),
+                                    )
+                                    .await
+                            } else {
+                                timeouts
+                                    .database(
+                                        "unpinning message",
+                                        db.unpin_message(&conversation_id.to_string(), sent_at),
+                                    )
+                                    .await
+                            };
+
+                            if let Err(err) = result {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                if let Err(err) = publisher
+                                    .user_tx
+                                    .send_response(&Response::error(code, "Failed to pin message"))
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::Fatal(
+                                        FatalConnectionError::WebSocketError(err),
+                                    ));
+                                }
+
+                                return;
+                            }
+
+                            let user_event = UserEvent::MessagePinned {
+                                conversation_id: conversation_id.to_string(),
+                                sent_at,
+                                pinned,
+                                pinned_at,
+                            };
+
+                            for to_username_hash in [other_hash, own_hash] {
+                                publisher
+                                    .publish(
+                                        NatsMessage {
+                                            to_username_hash,
+                                            user_event: user_event.clone(),
+                                        },
+                                        err_tx.clone(),
+                                    )
+                                    .await;
+                            }
+                        });
+                }
+                Mutation::PinConversation {
+                    conversation_id,
+                    pinned,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to pin conversation not belonging to",
+                            )));
+
+                        return;
+                    }
+
+                    let self_sync = self.self_sync(SyncAction::ConversationPinned {
+                        conversation_id: conversation_id.to_string(),
+                        pinned,
+                    });
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    // only for this user's devices, the other one never finds out
+                    self.scheduler.schedule(&self.username, async move {
+                        match timeouts
+                            .database(
+                                "pinning conversation",
+                                db.set_conversation_pinned(
+                                    &username,
+                                    &conversation_id.to_string(),
+                                    pinned,
+                                ),
+                            )
+                            .await
+                        {
+                            Ok(()) => publisher.publish(self_sync, err_tx).await,
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                if let Err(err) = user_tx
+                                    .send_response(&Response::error(
+                                        code,
+                                        "Failed to pin conversation",
+                                    ))
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::Fatal(
+                                        FatalConnectionError::WebSocketError(err),
+                                    ));
+                                }
+                            }
+                        }
+                    });
+                }
+                Mutation::ArchiveConversation { conversation_id } => {
+                    self.set_conversation_archived(conversation_id, true, err_tx);
+                }
+                Mutation::UnarchiveConversation { conversation_id } => {
+                    self.set_conversation_archived(conversation_id, false, err_tx);
+                }
+                Mutation::ClearConversation { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to clear conversation not belonging to",
+                            )));
+
+                        return;
+                    }
+
+                    let cleared_before = Utc::now();
+
+                    let self_sync = self.self_sync(SyncAction::ConversationCleared {
+                        conversation_id: conversation_id.to_string(),
+                        cleared_before,
+                    });
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    // keyed by the conversation so a messages query sent after this doesn't get what was cleared
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            match timeouts
+                                .database(
+                                    "clearing conversation",
+                                    db.clear_conversation(
+                                        &username,
+                                        &conversation_id.to_string(),
+                                        cleared_before,
+                                    ),
+                                )
+                                .await
+                            {
+                                Ok(()) => publisher.publish(self_sync, err_tx).await,
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    if let Err(err) = user_tx
+                                        .send_response(&Response::error(
+                                            code,
+                                            "Failed to clear conversation",
+                                        ))
+                                        .await
+                                    {
+                                        let _ = err_tx.send(ConnectionError::Fatal(
+                                            FatalConnectionError::WebSocketError(err),
+                                        ));
+                                    }
+                                }
+                            }
+                        });
+                }
+                Mutation::RegisterPushToken { platform, token } => {
+                    let valid = !token.is_empty()
+                        && token.len() <= MAX_PUSH_TOKEN_LENGTH
+                        && match platform {
+                            PushPlatform::Apns => token.chars().all(|c| c.is_ascii_hexdigit()),
+                            PushPlatform::Fcm => token
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || "-_:".contains(c)),
+                        };
+
+                    if !valid {
+                        self.send_response(
+                            Response::error(ErrorCode::InvalidRequest, "Invalid push token"),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+
+                    // under every hash the user's events could be published to during a hash rollout
+                    let username_hashes = self.hasher.hashes(&self.username);
+
+                    let push_token = PushToken {
+                        token,
+                        platform,
+                        username: self.username.clone(),
+                        registered_at: Utc::now(),
+                    };
+
+                    self.scheduler.schedule(&self.username, async move {
+                        let result = timeouts
+                            .database("registering push token", async {
+                                for username_hash in &username_hashes {
+                                    db.add_push_token(username_hash, &push_token).await?;
+                                }
+
+                                Ok::<_, DatabaseError>(())
+                            })
+                            .await;
+
+                        if let Err(err) = result {
+                            let code = ErrorCode::from(&err);
+
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            if let Err(err) = user_tx
+                                .send_response(&Response::error(
+                                    code,
+                                    "Failed to register push token",
+                                ))
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        }
+                    });
+                }
+                Mutation::UnregisterPushToken { token } => {
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+                    let username_hashes = self.hasher.hashes(&self.username);
+
+                    self.scheduler.schedule(&self.username, async move {
+                        let result = timeouts
+                            .database("unregistering push token", async {
+                                for username_hash in &username_hashes {
+                                    db.remove_push_token(username_hash, &token).await?;
+                                }
+
+                                Ok::<_, DatabaseError>(())
+                            })
+                            .await;
+
+                        if let Err(err) = result {
+                            let code = ErrorCode::from(&err);
+
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            if let Err(err) = user_tx
+                                .send_response(&Response::error(
+                                    code,
+                                    "Failed to unregister push token",
+                                ))
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        }
+                    });
+                }
+                Mutation::SetNotificationPrefs {
+                    conversation_id,
+                    prefs,
+                } => {
+                    if let Some(dnd) = prefs.dnd {
+                        if conversation_id.is_some() || !dnd.is_valid() {
+                            self.send_response(
+                                Response::error(
+                                    ErrorCode::InvalidRequest,
+                                    "Do not disturb has to be a valid window set for all conversations",
+                                ),
+                                err_tx,
+                            );
+
+                            return;
+                        }
+                    }
+
+                    let conversation_id = match conversation_id {
+                        Some(conversation_id) => {
+                            let Some(conversation_id) =
+                                self.parse_conversation_id(conversation_id, &err_tx)
+                            else {
+                                return;
+                            };
+
+                            if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                                == ConversationRole::NotInConversation
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::Forbidden(
+                                        "User attempted to set notification prefs of conversation not belonging to",
+                                    ),
+                                ));
+
+                                return;
+                            }
+
+                            Some(conversation_id.to_string())
+                        }
+                        None => None,
+                    };
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let username_hash = self.hasher.hash(&self.username);
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        let result = match &conversation_id {
+                            Some(conversation_id) => {
+                                timeouts
+                                    .database(
+                                        "setting conversation notification prefs",
+                                        db.set_conversation_notification_prefs(
+                                            &username,
+                                            conversation_id,
+                                            prefs.muted,
+                                            prefs.mentions_only,
+                                        ),
+                                    )
+                                    .await
+                            }
+                            None => {
+                                timeouts
+                                    .database(
+                                        "setting notification prefs",
+                                        db.set_notification_prefs(&username, &prefs),
+                                    )
+                                    .await
+                            }
+                        };
+
+                        match result {
+                            // every connection of the user caches their prefs, this one included
+                            Ok(()) => {
+                                publisher
+                                    .publish(
+                                        NatsMessage {
+                                            to_username_hash: username_hash,
+                                            user_event: UserEvent::NotificationPrefsChanged {
+                                                conversation_id,
+                                                prefs,
+                                            },
+                                        },
+                                        err_tx,
+                                    )
+                                    .await
+                            }
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                if let Err(err) = user_tx
+                                    .send_response(&Response::error(
+                                        code,
+                                        "Failed to set notification prefs",
+                                    ))
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::Fatal(
+                                        FatalConnectionError::WebSocketError(err),
+                                    ));
+                                }
+                            }
+                        }
+                    });
+                }
+                Mutation::LogoutDevice { device_id } => {
+                    if !auth::is_valid_device_id(&device_id) {
+                        self.send_response(
+                            Response::error(ErrorCode::InvalidRequest, "Invalid device id"),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let db = self.db.clone();
+                    let message_bus = self.message_bus.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let username_hash = self.hasher.hash(&self.username);
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        // stored first so the device can't just reconnect, then its open connections are closed
+                        let result = match timeouts
+                            .database(
+                                "logging out device",
+                                db.log_out_device(&username, &device_id, Utc::now()),
+                            )
+                            .await
+                        {
+                            Ok(()) => {
+                                timeouts
+                                    .nats(
+                                        "publishing device logout",
+                                        message_bus.publish(
+                                            &nats_message::device_logout_subject(&username_hash),
+                                            device_id.as_bytes(),
+                                        ),
+                                    )
+                                    .await
+                            }
+                            Err(err) => Err(err),
+                        };
+
+                        if let Err(err) = result {
+                            let code = ErrorCode::from(&err);
+
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            if let Err(err) = user_tx
+                                .send_response(&Response::error(code, "Failed to log out device"))
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        }
+                    });
+                }
+                Mutation::UpdateSubscriptions {
+                    presence,
+                    typing,
+                    receipts,
+                } => {
+                    self.subscriptions.send_modify(|subscriptions| {
+                        subscriptions.presence = presence;
+                        subscriptions.typing = typing;
+                        subscriptions.receipts = receipts;
+                    });
+                }
+                Mutation::Subscribe { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to subscribe to conversation not belonging to",
+                            )));
+                        return;
+                    }
+
+                    self.subscriptions.send_modify(|subscriptions| {
+                        subscriptions
+                            .conversations
+                            .get_or_insert_with(HashSet::new)
+                            .insert(conversation_id.to_string());
+                    });
+                }
+                Mutation::Unsubscribe { conversation_id } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    self.subscriptions.send_modify(|subscriptions| {
+                        if let Some(conversations) = &mut subscriptions.conversations {
+                            conversations.remove(&conversation_id.to_string());
+                        }
+                    });
+                }
+                Mutation::DistributeKey {
+                    conversation_id,
+                    key_material,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    let (other_hash, from_chooser) =
+                        match conversation_id.get_role_of_username(&self.hasher, &self.username) {
+                            ConversationRole::Chooser => {
+                                (conversation_id.get_choosee_hash().to_owned(), true)
+                            }
+                            ConversationRole::Choosee => {
+                                (conversation_id.get_chooser_hash().to_owned(), false)
+                            }
+                            ConversationRole::NotInConversation => {
+                                let _ = err_tx
+                                .send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to distribute key in conversation not belonging to",
+                            )));
+
+                                return;
+                            }
+                        };
+
+                    if key_material.is_empty() || key_material.len() > MAX_KEY_MATERIAL_LENGTH {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!(
+                                    "Key material must be between 1 and {} bytes",
+                                    MAX_KEY_MATERIAL_LENGTH
+                                ),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let key_distribution = KeyDistribution {
+                        key_material,
+                        sent_at: Utc::now(),
+                        from_chooser,
+                    };
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let timeouts = self.timeouts;
+                    let ttl = self.key_distribution_ttl;
+
+                    // stored before it's sent so it's there for the other participant to fetch if they're offline
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let response = match timeouts
+                                .database(
+                                    "adding key distribution",
+                                    db.add_key_distribution(
+                                        &conversation_id.to_string(),
+                                        &key_distribution,
+                                        ttl,
+                                    ),
+                                )
+                                .await
+                            {
+                                Ok(()) => {
+                                    publisher
+                                        .publish(
+                                            NatsMessage {
+                                                to_username_hash: other_hash,
+                                                user_event: UserEvent::KeyDistribution {
+                                                    conversation_id: conversation_id.to_string(),
+                                                    key_material: key_distribution.key_material,
+                                                    sent_at: key_distribution.sent_at,
+                                                },
+                                            },
+                                            err_tx.clone(),
+                                        )
+                                        .await;
+
+                                    Response::KeyDistributed {
+                                        conversation_id: conversation_id.to_string(),
+                                        sent_at: key_distribution.sent_at,
+                                    }
+                                }
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Failed to distribute key")
+                                }
+                            };
+
+                            if let Err(err) = publisher.user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        });
+                }
+                Mutation::CreatePoll {
+                    conversation_id,
+                    question,
+                    options,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    let (own_hash, other_hash, created_by_chooser) =
+                        match conversation_id.get_role_of_username(&self.hasher, &self.username) {
+                            ConversationRole::Chooser => (
+                                conversation_id.get_chooser_hash().to_owned(),
+                                conversation_id.get_choosee_hash().to_owned(),
+                                true,
+                            ),
+                            ConversationRole::Choosee => (
+                                conversation_id.get_choosee_hash().to_owned(),
+                                conversation_id.get_chooser_hash().to_owned(),
+                                false,
+                            ),
+                            ConversationRole::NotInConversation => {
+                                let _ = err_tx
+                                .send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to create poll in conversation not belonging to",
+                            )));
+
+                                return;
+                            }
+                        };
+
+                    if let Err(message) = Self::check_poll(&question, &options) {
+                        self.send_response(
+                            Response::error(ErrorCode::InvalidRequest, &message),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let poll = Poll::new(
+                        Uuid::new_v4().to_string(),
+                        question,
+                        options,
+                        created_by_chooser,
+                        Utc::now(),
+                    );
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let timeouts = self.timeouts;
+
+                    // the poll update is the only answer, to every device of both users
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            if let Err(err) = timeouts
+                                .database(
+                                    "creating poll",
+                                    db.new_poll(&conversation_id.to_string(), &poll),
+                                )
+                                .await
+                            {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                if let Err(err) = publisher
+                                    .user_tx
+                                    .send_response(&Response::error(code, "Failed to create poll"))
+                                    .await
+                                {
+                                    let _ = err_tx.send(ConnectionError::Fatal(
+                                        FatalConnectionError::WebSocketError(err),
+                                    ));
+                                }
+
+                                return;
+                            }
+
+                            let user_event = UserEvent::PollUpdate {
+                                conversation_id: conversation_id.to_string(),
+                                poll,
+                            };
+
+                            for to_username_hash in [other_hash, own_hash] {
+                                publisher
+                                    .publish(
+                                        NatsMessage {
+                                            to_username_hash,
+                                            user_event: user_event.clone(),
+                                        },
+                                        err_tx.clone(),
+                                    )
+                                    .await;
+                            }
+                        });
+                }
+                Mutation::Vote {
+                    conversation_id,
+                    poll_id,
+                    option,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    let (own_hash, other_hash, from_chooser) =
+                        match conversation_id.get_role_of_username(&self.hasher, &self.username) {
+                            ConversationRole::Chooser => (
+                                conversation_id.get_chooser_hash().to_owned(),
+                                conversation_id.get_choosee_hash().to_owned(),
+                                true,
+                            ),
+                            ConversationRole::Choosee => (
+                                conversation_id.get_choosee_hash().to_owned(),
+                                conversation_id.get_chooser_hash().to_owned(),
+                                false,
+                            ),
+                            ConversationRole::NotInConversation => {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::Forbidden(
+                                        "User attempted to vote in conversation not belonging to",
+                                    ),
+                                ));
+
+                                return;
+                            }
+                        };
+
+                    let db = self.db.clone();
+                    let publisher = self.publisher();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler
+                        .schedule(&conversation_id.to_string(), async move {
+                            let conversation_id = conversation_id.to_string();
+
+                            let result = timeouts
+                                .database("voting in poll", async {
+                                    let poll = db.get_poll(&conversation_id, &poll_id).await?;
+
+                                    if poll.is_none_or(|poll| option as usize >= poll.options.len())
+                                    {
+                                        return Ok(None);
+                                    }
+
+                                    db.vote_in_poll(
+                                        &conversation_id,
+                                        &poll_id,
+                                        from_chooser,
+                                        option,
+                                    )
+                                    .await?;
+
+                                    // read back rather than counted here, so the other user's vote is in it too
+                                    db.get_poll(&conversation_id, &poll_id).await
+                                })
+                                .await;
+
+                            let response = match result {
+                                Ok(Some(poll)) => {
+                                    let user_event = UserEvent::PollUpdate {
+                                        conversation_id,
+                                        poll,
+                                    };
+
+                                    for to_username_hash in [other_hash, own_hash] {
+                                        publisher
+                                            .publish(
+                                                NatsMessage {
+                                                    to_username_hash,
+                                                    user_event: user_event.clone(),
+                                                },
+                                                err_tx.clone(),
+                                            )
+                                            .await;
+                                    }
+
+                                    return;
+                                }
+                                Ok(None) => Response::error(
+                                    ErrorCode::InvalidRequest,
+                                    "No such poll or option in this conversation",
+                                ),
+                                Err(err) => {
+                                    let code = ErrorCode::from(&err);
+
+                                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                    Response::error(code, "Failed to vote")
+                                }
+                            };
+
+                            if let Err(err) = publisher.user_tx.send_response(&response).await {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        });
+                }
+                Mutation::DisconnectOtherSessions => {
+                    let message_bus = self.message_bus.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username_hash = self.hasher.hash(&self.username);
+                    let sync_origin = self.sync_origin.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule(&self.username, async move {
+                        if let Err(err) = timeouts
+                            .nats(
+                                "publishing disconnect",
+                                message_bus.publish(
+                                    &nats_message::disconnect_others_subject(&username_hash),
+                                    sync_origin.as_bytes(),
+                                ),
+                            )
+                            .await
+                        {
+                            let code = ErrorCode::from(&err);
+
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            if let Err(err) = user_tx
+                                .send_response(&Response::error(
+                                    code,
+                                    "Failed to disconnect other sessions",
+                                ))
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+                        }
+                    });
+                }
+                Mutation::Report {
+                    conversation_id,
+                    message_sent_at,
+                    reason,
+                } => {
+                    let Some(conversation_id) =
+                        self.parse_conversation_id(conversation_id, &err_tx)
+                    else {
+                        return;
+                    };
+
+                    if conversation_id.get_role_of_username(&self.hasher, &self.username)
+                        == ConversationRole::NotInConversation
+                    {
+                        let _ =
+                            err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                                "User attempted to report conversation not belonging to",
+                            )));
+
+                        return;
+                    }
+
+                    let reason = reason.trim().to_owned();
+
+                    if reason.is_empty() || reason.chars().count() > MAX_REPORT_REASON_LENGTH {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!(
+                                    "Report reason must be between 1 and {} characters",
+                                    MAX_REPORT_REASON_LENGTH
+                                ),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let report = Report {
+                        report_id: Uuid::new_v4().to_string(),
+                        reporter_username: self.username.clone(),
+                        conversation_id: conversation_id.to_string(),
+                        message_sent_at,
+                        reason,
+                        reported_at: Utc::now(),
+                    };
+
+                    let db = self.db.clone();
+                    let message_bus = self.message_bus.clone();
+                    let outbox_bucket = outbox::home_bucket(&self.node_id);
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+                    let webhooks = self.webhooks.clone();
+                    let audit_log = self.audit_log.clone();
+                    let audit_entry = self.audit_entry(
+                        AuditAction::Reported,
+                        Some(report.conversation_id.clone()),
+                        report.reason.clone(),
+                    );
+
+                    self.scheduler.schedule(&self.username, async move {
+                        if let Err(err) = timeouts
+                            .database("saving report", db.add_report(&report))
+                            .await
+                        {
+                            let code = ErrorCode::from(&err);
+
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            if let Err(err) = user_tx
+                                .send_response(&Response::error(code, "Failed to report"))
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::Fatal(
+                                    FatalConnectionError::WebSocketError(err),
+                                ));
+                            }
+
+                            return;
+                        }
+
+                        audit_log.record(audit_entry);
+
+                        if let Some(webhooks) = &webhooks {
+                            webhooks.dispatch(WebhookEvent::ReportFiled(report.clone()));
+                        }
+
+                        let subject = nats_message::moderation_reports_subject();
+
+                        let data = serde_json::to_vec(&report).unwrap();
+
+                        if let Err(err) = timeouts
+                            .nats("publishing report", message_bus.publish(&subject, &data))
+                            .await
+                        {
+                            let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                            // it's stored already, so the outbox gets it to trust and safety later rather than failing the report
+                            if let Err(err) = timeouts
+                                .database(
+                                    "adding to outbox",
+                                    db.add_to_outbox(outbox_bucket, &subject, data),
+                                )
+                                .await
+                            {
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+                            }
+                        }
+
+                        if let Err(err) = user_tx
+                            .send_response(&Response::Reported {
+                                report_id: report.report_id,
+                            })
+                            .await
+                        {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+            },
+            Operation::Admin(admin) => match admin {
+                Admin::ListConnections => {
+                    self.send_response(
+                        Response::Connections {
+                            connections: self.registry.list(),
+                        },
+                        err_tx,
+                    );
+                }
+                Admin::AuditLog { day, take } => {
+                    if !(1..=MAX_AUDIT_LOG_TAKE).contains(&take) {
+                        self.send_response(
+                            Response::error(
+                                ErrorCode::InvalidRequest,
+                                &format!("Take must be between 1 and {}", MAX_AUDIT_LOG_TAKE),
+                            ),
+                            err_tx,
+                        );
+
+                        return;
+                    }
+
+                    let day = day.unwrap_or_else(|| Utc::now().date_naive());
+
+                    let db = self.db.clone();
+                    let user_tx = self.user_tx.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule("audit_log", async move {
+                        let response = match timeouts
+                            .database("getting audit log", db.get_audit_log(day, take))
+                            .await
+                        {
+                            Ok(entries) => Response::AuditLog { day, entries },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to get audit log")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Admin::ListBans => {
+                    self.send_response(
+                        Response::Bans {
+                            bans: self.handshake_limiter.bans(),
+                        },
+                        err_tx,
+                    );
+                }
+                Admin::Ban {
+                    ip,
+                    seconds,
+                    reason,
+                } => {
+                    let ban = self
+                        .handshake_limiter
+                        .ban(ip, Duration::from_secs(seconds), reason);
+
+                    info!("Admin {} banned {} until {}", self.username, ip, ban.until);
+
+                    self.audit_log.record(self.audit_entry(
+                        AuditAction::Banned,
+                        Some(ip.to_string()),
+                        ban.reason.clone(),
+                    ));
+
+                    self.publish_ban_update(BanUpdate::Ban(ban), err_tx);
+                }
+                Admin::Unban { ip } => {
+                    self.handshake_limiter.apply(BanUpdate::Unban { ip });
+
+                    info!("Admin {} unbanned {}", self.username, ip);
+
+                    self.audit_log.record(self.audit_entry(
+                        AuditAction::Unbanned,
+                        Some(ip.to_string()),
+                        String::new(),
+                    ));
+
+                    self.publish_ban_update(BanUpdate::Unban { ip }, err_tx);
+                }
+                Admin::RevokeTokens { username } => {
+                    let db = self.db.clone();
+                    let message_bus = self.message_bus.clone();
+                    let audit_log = self.audit_log.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username_hash = self.hasher.hash(&username);
+                    let timeouts = self.timeouts;
+                    let revoked_before = Utc::now();
+                    let audit_entry = self.audit_entry(
+                        AuditAction::TokensRevoked,
+                        Some(username.clone()),
+                        format!("Issued up to {}", revoked_before.to_rfc3339()),
+                    );
+
+                    info!(
+                        "Admin {} is revoking the tokens of user {}",
+                        self.username, username
+                    );
+
+                    self.scheduler.schedule("revoke_tokens", async move {
+                        // stored first so they can't just reconnect, then their open connections are closed
+                        let result = match timeouts
+                            .database(
+                                "revoking tokens",
+                                db.revoke_tokens(&username, revoked_before),
+                            )
+                            .await
+                        {
+                            Ok(()) => {
+                                audit_log.record(audit_entry);
+
+                                timeouts
+                                    .nats(
+                                        "publishing token revocation",
+                                        message_bus.publish(
+                                            &nats_message::revoke_subject(&username_hash),
+                                            &[],
+                                        ),
+                                    )
+                                    .await
+                            }
+                            Err(err) => Err(err),
+                        };
+
+                        let response = match result {
+                            Ok(()) => Response::TokensRevoked {
+                                username,
+                                revoked_before,
+                            },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to revoke tokens")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Admin::ListNodes => {
+                    self.send_response(
+                        Response::Nodes {
+                            nodes: self.membership.nodes(),
+                        },
+                        err_tx,
+                    );
+                }
+                Admin::Kick { username } => {
+                    let kicked = self.registry.kick(&username);
+
+                    info!(
+                        "Admin {} kicked {} connections of user {}",
+                        self.username, kicked, username
+                    );
+
+                    self.audit_log.record(self.audit_entry(
+                        AuditAction::Kicked,
+                        Some(username.clone()),
+                        format!("{} connections on node {}", kicked, self.node_id),
+                    ));
+
+                    // and on whichever other node presence says they're on
+                    let message_bus = self.message_bus.clone();
+                    let presence = self.presence.clone();
+                    let user_tx = self.user_tx.clone();
+                    let node_id = self.node_id.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule("kick", async move {
+                        let other_node_id = match timeouts
+                            .nats("checking presence", presence.node_of(&username))
+                            .await
+                        {
+                            Ok(Some(kicked_node_id)) if kicked_node_id != node_id => timeouts
+                                .nats(
+                                    "publishing kick",
+                                    message_bus.publish(
+                                        &nats_message::node_kick_subject(&kicked_node_id),
+                                        username.as_bytes(),
+                                    ),
+                                )
+                                .await
+                                .map(|()| Some(kicked_node_id)),
+                            Ok(_) => Ok(None),
+                            Err(err) => Err(err),
+                        };
+
+                        let response = match other_node_id {
+                            Ok(other_node_id) => Response::Kicked {
+                                username,
+                                connections: kicked,
+                                other_node_id,
+                            },
+                            Err(err) => {
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(
+                                    code,
+                                    &format!(
+                                        "Kicked {} connections on this node, but couldn't reach the others",
+                                        kicked
+                                    ),
+                                )
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Admin::Announce { title, body, level } => {
+                    let user_event = UserEvent::Announcement {
+                        title,
+                        body,
+                        level,
+                        sent_at: Utc::now(),
+                    };
+
+                    let message_bus = self.message_bus.clone();
+                    let registry = self.registry.clone();
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+                    let timeouts = self.timeouts;
+
+                    self.scheduler.schedule("announce", async move {
+                        let response = match timeouts
+                            .nats(
+                                "publishing announcement",
+                                message_bus.publish(
+                                    &nats_message::broadcast_subject(),
+                                    &user_event.to_vec(),
+                                ),
+                            )
+                            .await
+                        {
+                            Ok(()) => {
+                                info!("Admin {} announced to every connection", username);
+
+                                Response::Announced {
+                                    everywhere: true,
+                                    connections: None,
+                                }
+                            }
+                            Err(err) => {
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                // for emergencies especially, reaching this instance's connections beats reaching none
+                                let announced_to = registry.broadcast(user_event);
+
+                                warn!(
+                                    "Admin {} announced to only the {} connections on this instance",
+                                    username, announced_to
+                                );
+
+                                Response::Announced {
+                                    everywhere: false,
+                                    connections: Some(announced_to),
+                                }
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Admin::ReplayFailedEvents => {
+                    let db = self.db.clone();
+                    let outbox_bucket = outbox::home_bucket(&self.node_id);
+                    let user_tx = self.user_tx.clone();
+                    let username = self.username.clone();
+
+                    self.scheduler.schedule("replay_failed_events", async move {
+                        let response = match dead_letter::replay(db, outbox_bucket).await {
+                            Ok(replayed) => {
+                                info!("Admin {} replayed {} failed events", username, replayed);
+
+                                Response::FailedEventsReplayed { replayed }
+                            }
+                            Err(err) => {
+                                let err = NonFatalConnectionError::DatabaseError(err);
+                                let code = ErrorCode::from(&err);
+
+                                let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                                Response::error(code, "Failed to replay failed events")
+                            }
+                        };
+
+                        if let Err(err) = user_tx.send_response(&response).await {
+                            let _ = err_tx.send(ConnectionError::Fatal(
+                                FatalConnectionError::WebSocketError(err),
+                            ));
+                        }
+                    });
+                }
+                Admin::DeleteAccount { username } => {
+                    info!(
+                        "Admin {} is deleting the account of user {}",
+                        self.username, username
+                    );
+
+                    let db = self.db.clone();
+                    let job_queue = self.job_queue.clone();
+                    let job = AccountDeletionJob {
+                        audit_entry: self.audit_entry(
+                            AuditAction::AccountDeleted,
+                            Some(username.clone()),
+                            "Deleted by an admin".to_owned(),
+                        ),
+                        username,
+                    }
+                    .job();
+
+                    tokio::task::spawn(
+                        panic_guard::guard(self.user_tx.clone(), "deleting account", async move {
+                            if let Err(err) =
+                                jobs::enqueue(job_queue.as_ref(), db.as_ref(), job).await
+                            {
+                                error!("Error enqueuing account deletion: {}", err);
+                            }
+                        })
+                        .in_current_span(),
+                    );
+                }
+            },
+        }
+    }
+
+    // friends connected to any node
+    async fn online_friends(
+        timeouts: Timeouts,
+        db: &dyn Storage,
+        presence: &dyn Presence,
+        username: &str,
+    ) -> Result<Vec<String>, NonFatalConnectionError> {
+        let friends = timeouts
+            .database("getting friends", db.get_friends(username))
+            .await?;
+
+        let node_ids = timeouts
+            .nats(
+                "checking presence",
+                try_join_all(
+                    friends
+                        .iter()
+                        .map(|friend| presence.node_of(&friend.username)),
+                ),
+            )
+            .await?;
+
+        Ok(friends
+            .into_iter()
+            .zip(node_ids)
+            .filter(|(_, node_id)| node_id.is_some())
+            .map(|(friend, _)| friend.username)
+            .collect())
+    }
+
+    // tells both users the previous conversation between them continues in the new one, if they'd talked in it.
+    // the link is stored, so it works whichever connection or device chose before
+    fn announce_rollover(
+        &self,
+        conversation_id: &ConversationId,
+        err_tx: UnboundedSender<ConnectionError>,
+    ) {
+        let publisher = self.publisher();
+        let db = self.db.clone();
+        let timeouts = self.timeouts;
+        let conversation_id = conversation_id.clone();
+
+        self.scheduler
+            .schedule(&conversation_id.to_string(), async move {
+                let chooser_hash = conversation_id.get_chooser_hash();
+                let choosee_hash = conversation_id.get_choosee_hash();
+
+                let predecessor_conversation_id = match timeouts
+                    .database(
+                        "getting latest conversation",
+                        db.get_latest_conversation_id(chooser_hash, choosee_hash),
+                    )
+                    .await
+                {
+                    Ok(predecessor_conversation_id) => predecessor_conversation_id,
+                    Err(err) => {
+                        let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                        None
+                    }
+                };
+
+                if let Err(err) = timeouts
+                    .database(
+                        "setting latest conversation",
+                        db.set_latest_conversation_id(
+                            chooser_hash,
+                            choosee_hash,
+                            &conversation_id.to_string(),
+                        ),
+                    )
+                    .await
+                {
+                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+                }
+
+                let Some(predecessor_conversation_id) = predecessor_conversation_id
+                    .filter(|predecessor| *predecessor != conversation_id.to_string())
+                else {
+                    return;
+                };
+
+                match timeouts
+                    .database(
+                        "checking for messages",
+                        db.has_messages(&predecessor_conversation_id),
+                    )
+                    .await
+                {
+                    Ok(true) => {
+                        let user_event = UserEvent::ConversationRollover {
+                            conversation_id: predecessor_conversation_id,
+                            successor_conversation_id: Some(conversation_id.to_string()),
+                        };
+
+                        let nats_message = NatsMessage {
+                            to_username_hash: choosee_hash.to_owned(),
+                            user_event: user_event.clone(),
+                        };
+
+                        publisher.publish(nats_message, err_tx.clone()).await;
+
+                        publisher.send_to_self(user_event, err_tx).await;
+                    }
+                    Ok(false) => {}
+                    Err(err) => {
+                        let _ = err_tx.send(ConnectionError::NonFatal(err));
+                    }
+                }
+            });
+    }
+
+    fn publisher(&self) -> Publisher {
+        Publisher {
+            message_bus: self.message_bus.clone(),
+            db: self.db.clone(),
+            outbox_bucket: outbox::home_bucket(&self.node_id),
+            user_tx: self.user_tx.clone(),
+            degraded: self.degraded.clone(),
+            timeouts: self.timeouts,
+            retry_policy: self.retry_policy,
+        }
+    }
+
+    fn publish(
+        &self,
+        conversation_id: &ConversationId,
+        nats_message: NatsMessage,
+        err_tx: UnboundedSender<ConnectionError>,
+    ) {
+        let publisher = self.publisher();
+
+        self.scheduler
+            .schedule(&conversation_id.to_string(), async move {
+                publisher.publish(nats_message, err_tx).await
+            });
+    }
+
+    // tells the user's other devices about something done on this one, to be published once it's stored
+    fn self_sync(&self, action: SyncAction) -> NatsMessage {
+        NatsMessage {
+            to_username_hash: self.hasher.hash(&self.username),
+            user_event: UserEvent::SelfSync {
+                origin: self.sync_origin.clone(),
+                action,
+            },
+        }
+    }
+
+    // something this connection's user did, for the audit log
+    // already applied here, so a bus that's down only keeps it from the other nodes
+    fn publish_ban_update(&self, ban_update: BanUpdate, err_tx: UnboundedSender<ConnectionError>) {
+        let message_bus = self.message_bus.clone();
+        let user_tx = self.user_tx.clone();
+        let timeouts = self.timeouts;
+
+        self.scheduler.schedule("ban", async move {
+            let everywhere = match timeouts
+                .nats(
+                    "publishing ban",
+                    message_bus.publish(
+                        &nats_message::bans_subject(),
+                        &serde_json::to_vec(&ban_update).expect("Bans should always serialize"),
+                    ),
+                )
+                .await
+            {
+                Ok(()) => true,
+                Err(err) => {
+                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                    false
+                }
+            };
+
+            let response = match ban_update {
+                BanUpdate::Ban(ban) => Response::Banned { ban, everywhere },
+                BanUpdate::Unban { ip } => Response::Unbanned { ip, everywhere },
+            };
+
+            if let Err(err) = user_tx.send_response(&response).await {
+                let _ = err_tx.send(ConnectionError::Fatal(
+                    FatalConnectionError::WebSocketError(err),
+                ));
+            }
+        });
+    }
+
+    fn audit_entry(
+        &self,
+        action: AuditAction,
+        target: Option<String>,
+        detail: String,
+    ) -> AuditEntry {
+        AuditEntry {
+            entry_id: Uuid::new_v4().to_string(),
+            action,
+            actor: Some(self.username.clone()),
+            target,
+            connection_id: Some(self.user_tx.connection_id().to_owned()),
+            detail,
+            occurred_at: Utc::now(),
+        }
+    }
+
+    fn uploads_configured(
+        &self,
+        err_tx: &UnboundedSender<ConnectionError>,
+    ) -> Option<Arc<ObjectStore>> {
+        if self.object_store.is_none() {
+            self.send_response(
+                Response::error(
+                    ErrorCode::InvalidRequest,
+                    "Uploads aren't enabled on this server",
+                ),
+                err_tx.clone(),
+            );
+        }
+
+        self.object_store.clone()
+    }
+
+    fn avatar_prefix(&self) -> String {
+        format!("avatars/{}/", self.hasher.hash(&self.username))
+    }
+
+    fn attachment_prefix(conversation_id: &ConversationId) -> String {
+        format!("attachments/{}/", conversation_id)
+    }
+
+    fn check_upload(&self, content_type: &str, size: u64) -> Result<(), String> {
+        if !ATTACHMENT_CONTENT_TYPES.contains(&content_type) {
+            return Err(format!(
+                "Attachments must be one of {}",
+                ATTACHMENT_CONTENT_TYPES.join(", ")
+            ));
+        }
+
+        if size == 0 || size > self.max_attachment_size {
+            return Err(format!(
+                "Attachments must be between 1 and {} bytes",
+                self.max_attachment_size
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_poll(question: &str, options: &[String]) -> Result<(), String> {
+        if question.trim().is_empty() || question.len() > MAX_POLL_QUESTION_LENGTH {
+            return Err(format!(
+                "Poll question must be between 1 and {} bytes",
+                MAX_POLL_QUESTION_LENGTH
+            ));
+        }
+
+        if !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+            return Err(format!(
+                "Poll must have between 2 and {} options",
+                MAX_POLL_OPTIONS
+            ));
+        }
+
+        if options
+            .iter()
+            .any(|option| option.trim().is_empty() || option.len() > MAX_POLL_OPTION_LENGTH)
+        {
+            return Err(format!(
+                "Poll options must be between 1 and {} bytes",
+                MAX_POLL_OPTION_LENGTH
+            ));
+        }
+
+        Ok(())
+    }
+
+    // the declared details are relayed as is, so they're checked against what the upload url allowed
+    fn check_message_kind(kind: &MessageKind) -> Result<(), String> {
+        let ids = match kind {
+            MessageKind::Sticker {
+                pack_id,
+                sticker_id,
+            } => vec![pack_id, sticker_id],
+            MessageKind::Gif { id, url, provider } => {
+                // anywhere else and a gif could be used to see who opened it
+                let on_provider = reqwest::Url::parse(url).is_ok_and(|url| {
+                    url.scheme() == "https"
+                        && url.host_str().is_some_and(|host| {
+                            host == provider.domain()
+                                || host.ends_with(&format!(".{}", provider.domain()))
+                        })
+                });
+
+                if url.len() > MAX_GIF_URL_LENGTH || !on_provider {
+                    return Err(format!(
+                        "Gif url must be an https url on {} of at most {} bytes",
+                        provider.domain(),
+                        MAX_GIF_URL_LENGTH
+                    ));
+                }
+
+                vec![id]
+            }
+        };
+
+        if ids.iter().any(|id| {
+            id.is_empty()
+                || id.len() > MAX_MESSAGE_KIND_ID_LENGTH
+                || !id.chars().all(|char| char.is_ascii_graphic())
+        }) {
+            return Err(format!(
+                "Sticker and gif ids must be between 1 and {} printable ascii characters",
+                MAX_MESSAGE_KIND_ID_LENGTH
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn check_attachment(
+        &self,
+        conversation_id: &ConversationId,
+        attachment: &Attachment,
+    ) -> Result<(), String> {
+        if !attachment
+            .object_key
+            .starts_with(&Self::attachment_prefix(conversation_id))
+            || attachment.object_key.contains("..")
+        {
+            return Err(
+                "Attachment must be uploaded with a url from requestAttachmentUpload for this conversation"
+                    .to_owned(),
+            );
+        }
+
+        self.check_upload(&attachment.content_type, attachment.size)?;
+
+        let has_dimensions = attachment.content_type.starts_with("image/")
+            || attachment.content_type.starts_with("video/");
+
+        for dimension in [attachment.width, attachment.height].into_iter().flatten() {
+            if !has_dimensions || dimension == 0 || dimension > MAX_ATTACHMENT_DIMENSION {
+                return Err(format!(
+                    "Attachment dimensions must be between 1 and {}, and only images and videos have them",
+                    MAX_ATTACHMENT_DIMENSION
+                ));
+            }
+        }
+
+        match attachment.kind {
+            AttachmentKind::Voice => {
+                if !attachment.content_type.starts_with("audio/") {
+                    return Err("Voice messages must be audio".to_owned());
+                }
+
+                if !matches!(attachment.duration_ms, Some(1..=MAX_VOICE_DURATION_MS)) {
+                    return Err(format!(
+                        "Voice messages need a duration between 1 and {} milliseconds",
+                        MAX_VOICE_DURATION_MS
+                    ));
+                }
+
+                if attachment
+                    .waveform
+                    .as_ref()
+                    .is_some_and(|waveform| waveform.len() > MAX_WAVEFORM_LENGTH)
+                {
+                    return Err(format!(
+                        "Waveform must be at most {} bytes",
+                        MAX_WAVEFORM_LENGTH
+                    ));
+                }
+            }
+            AttachmentKind::File => {
+                if attachment.duration_ms.is_some() || attachment.waveform.is_some() {
+                    return Err("Only voice messages have a duration and waveform".to_owned());
+                }
+            }
+        }
+
+        if let Some(thumbhash) = &attachment.thumbhash {
+            if thumbhash.len() > MAX_THUMBHASH_LENGTH
+                || general_purpose::STANDARD.decode(thumbhash).is_err()
+            {
+                return Err(format!(
+                    "Thumbhash must be base64 and at most {} characters",
+                    MAX_THUMBHASH_LENGTH
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    // malformed ids are answered with an error rather than dropping the connection, they're most likely from a client
+    // that held on to one from before the format changed
+    // per user like pinning, and messages are left alone so the history can still be queried
+    fn set_conversation_archived(
+        &self,
+        conversation_id: String,
+        archived: bool,
+        err_tx: UnboundedSender<ConnectionError>,
+    ) {
+        let Some(conversation_id) = self.parse_conversation_id(conversation_id, &err_tx) else {
+            return;
+        };
+
+        if conversation_id.get_role_of_username(&self.hasher, &self.username)
+            == ConversationRole::NotInConversation
+        {
+            let _ = err_tx.send(ConnectionError::Fatal(FatalConnectionError::Forbidden(
+                "User attempted to archive conversation not belonging to",
+            )));
+
+            return;
+        }
+
+        let self_sync = self.self_sync(SyncAction::ConversationArchived {
+            conversation_id: conversation_id.to_string(),
+            archived,
+        });
+
+        let db = self.db.clone();
+        let publisher = self.publisher();
+        let user_tx = self.user_tx.clone();
+        let username = self.username.clone();
+        let timeouts = self.timeouts;
+
+        self.scheduler.schedule(&self.username, async move {
+            match timeouts
+                .database(
+                    "archiving conversation",
+                    db.set_conversation_archived(&username, &conversation_id.to_string(), archived),
+                )
+                .await
+            {
+                Ok(()) => publisher.publish(self_sync, err_tx).await,
+                Err(err) => {
+                    let code = ErrorCode::from(&err);
+
+                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                    let message = if archived {
+                        "Failed to archive conversation"
+                    } else {
+                        "Failed to unarchive conversation"
+                    };
+
+                    if let Err(err) = user_tx.send_response(&Response::error(code, message)).await {
+                        let _ = err_tx.send(ConnectionError::Fatal(
+                            FatalConnectionError::WebSocketError(err),
+                        ));
+                    }
+                }
+            }
+        });
+    }
+
+    fn parse_conversation_id(
+        &self,
+        conversation_id: String,
+        err_tx: &UnboundedSender<ConnectionError>,
+    ) -> Option<ConversationId> {
+        match ConversationId::try_from(conversation_id) {
+            Ok(conversation_id) => Some(conversation_id),
+            Err(err) => {
+                self.send_response(
+                    Response::error(ErrorCode::InvalidRequest, &err.to_string()),
+                    err_tx.clone(),
+                );
+
+                None
+            }
+        }
+    }
+
+    fn send_response(&self, response: Response, err_tx: UnboundedSender<ConnectionError>) {
+        let user_tx = self.user_tx.clone();
+
+        tokio::task::spawn(
+            async move {
+                if let Err(err) = user_tx.send_response(&response).await {
+                    let _ = err_tx.send(ConnectionError::Fatal(
+                        FatalConnectionError::WebSocketError(err),
+                    ));
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    // only once the message is stored, so an ack means it'll show up in history
+    async fn acknowledge_sent(
+        user_tx: &UserTx,
+        conversation_id: String,
+        sent_at: DateTime<Utc>,
+        err_tx: &UnboundedSender<ConnectionError>,
+    ) {
+        if let Err(err) = user_tx
+            .send_response(&Response::Sent {
+                conversation_id,
+                sent_at,
+            })
+            .await
+        {
+            let _ = err_tx.send(ConnectionError::Fatal(
+                FatalConnectionError::WebSocketError(err),
+            ));
         }
     }
 }
@@ -0,0 +1,44 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::connection::user_event::AnnouncementLevel;
+
+// only accepted from connections whose access token has the admin role
+
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "op", content = "d", rename_all = "camelCase")]
+pub enum Admin {
+    ListConnections,
+    ListNodes, // every node in the cluster, from the announcements they make
+    Kick {
+        username: String,
+    },
+    Announce {
+        title: String,
+        body: String,
+        #[serde(default)]
+        level: AnnouncementLevel,
+    }, // to every connection on every instance
+    ReplayFailedEvents,
+    DeleteAccount {
+        username: String,
+    }, // closes their connections everywhere and purges their data
+    AuditLog {
+        day: Option<NaiveDate>, // utc, today unless set
+        take: i32,
+    },
+    ListBans, // on this node, since the ones made automatically stay on the node that made them
+    Ban {
+        ip: IpAddr,
+        seconds: u64,
+        reason: String,
+    }, // on every node. connections already open from the address stay open
+    Unban {
+        ip: IpAddr,
+    },
+    RevokeTokens {
+        username: String,
+    }, // closes their connections everywhere, and tokens issued until now can't connect again
+}
@@ -1,6 +1,13 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize)]
+use crate::models::{
+    attachment::Attachment, message_kind::MessageKind, notification_prefs::NotificationPrefs,
+    push_token::PushPlatform,
+};
+
+#[derive(Deserialize, Serialize, JsonSchema)]
 #[serde(tag = "op", content = "d", rename_all = "camelCase")]
 pub enum Mutation {
     Choose {
@@ -10,9 +17,105 @@ pub enum Mutation {
     Send {
         content: String,
         conversation_id: String,
+        #[serde(default)]
+        attachment: Option<Attachment>,
+        #[serde(default)]
+        mention: bool, // calls the other participant's attention to it past their conversation mutes
+
+        #[serde(default)]
+        kind: Option<MessageKind>, // unset for plain text
     },
     RegisterPresenceChoosee {
         conversation_id: String,
         leaving: bool,
     },
+    DeleteMyAccount {
+        confirmation_token: String,
+    },
+    RefreshToken {
+        token: String,
+    },
+    SolveChallenge {
+        solution: String, // whatever the challenge's captcha widget or attestation api handed the client
+    },
+    SetDisappearing {
+        conversation_id: String,
+        ttl_seconds: u32, // 0 turns disappearing messages off
+    },
+    RequestAvatarUpload {
+        content_type: String,
+    },
+    SetAvatar {
+        object_key: String,
+    },
+    RequestAttachmentUpload {
+        conversation_id: String,
+        content_type: String,
+        size: u64, // the upload has to be exactly this many bytes
+    },
+    PinMessage {
+        conversation_id: String,
+        sent_at: DateTime<Utc>,
+        pinned: bool,
+    },
+    PinConversation {
+        conversation_id: String,
+        pinned: bool,
+    },
+    ArchiveConversation {
+        conversation_id: String,
+    },
+    UnarchiveConversation {
+        conversation_id: String,
+    },
+    ClearConversation {
+        conversation_id: String,
+    },
+    RegisterPushToken {
+        platform: PushPlatform,
+        token: String,
+    },
+    UnregisterPushToken {
+        token: String,
+    },
+    SetNotificationPrefs {
+        #[serde(default)]
+        conversation_id: Option<String>, // the user's own prefs when unset
+        prefs: NotificationPrefs,
+    },
+    LogoutDevice {
+        device_id: String, // any of the user's devices, this one included
+    },
+    DisconnectOtherSessions,
+    Report {
+        conversation_id: String,
+        #[serde(default)]
+        message_sent_at: Option<DateTime<Utc>>, // the conversation as a whole when unset
+        reason: String,
+    },
+    UpdateSubscriptions {
+        presence: bool,
+        typing: bool,
+        receipts: bool,
+    },
+    Subscribe {
+        conversation_id: String, // the one the user is looking at
+    },
+    Unsubscribe {
+        conversation_id: String,
+    },
+    DistributeKey {
+        conversation_id: String,
+        key_material: String, // opaque, only ever passed along to the other participant
+    },
+    CreatePoll {
+        conversation_id: String,
+        question: String,
+        options: Vec<String>,
+    },
+    Vote {
+        conversation_id: String,
+        poll_id: String,
+        option: u32, // index into the poll's options. replaces the user's earlier vote
+    },
 }
@@ -1,17 +1,290 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use super::{mutation::Mutation, query::Query};
+use prost::Message as _;
+
+use super::{admin::Admin, mutation::Mutation, query::Query};
+use crate::auth::permissions::Permission;
+use crate::connection::encoding::{datetime_from_timestamp, proto};
 use crate::connection::error::UnsupportedFormatError;
+use crate::connection::user_event::AnnouncementLevel;
+use crate::models::{attachment::Attachment, message_kind::MessageKind, push_token::PushPlatform};
+use crate::rate_limit::OperationClass;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
 pub enum Operation {
     Query(Query),
     Mutation(Mutation),
+    Admin(Admin),
 }
 
 impl Operation {
     pub fn from_str(str: &str) -> Result<Self, UnsupportedFormatError> {
         Ok(serde_json::from_str(str)?)
     }
+
+    pub fn from_protobuf(slice: &[u8]) -> Result<Self, UnsupportedFormatError> {
+        use proto::operation::Op;
+
+        Ok(
+            match proto::Operation::decode(slice)?
+                .op
+                .ok_or(UnsupportedFormatError::MissingField("op"))?
+            {
+                Op::Messages(messages) => Self::Query(Query::Messages {
+                    conversation_id: messages.conversation_id,
+                    take: messages
+                        .take
+                        .try_into()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("take"))?,
+                    after_sent_at: datetime_from_timestamp(messages.after_sent_at)?,
+                }),
+                Op::RetentionPolicy(_) => Self::Query(Query::RetentionPolicy),
+                Op::ChooseePresence(choosee_presence) => Self::Query(Query::ChooseePresence {
+                    conversation_id: choosee_presence.conversation_id,
+                    take: choosee_presence
+                        .take
+                        .try_into()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("take"))?,
+                }),
+                Op::SearchUsers(search_users) => Self::Query(Query::SearchUsers {
+                    prefix: search_users.prefix,
+                    take: search_users
+                        .take
+                        .try_into()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("take"))?,
+                }),
+                Op::Conversations(conversations) => Self::Query(Query::Conversations {
+                    archived: conversations.archived,
+                }),
+                Op::NotificationPrefs(_) => Self::Query(Query::NotificationPrefs),
+                Op::Devices(_) => Self::Query(Query::Devices),
+                Op::Time(_) => Self::Query(Query::Time),
+                Op::Ping(ping) => Self::Query(Query::Ping { nonce: ping.nonce }),
+                Op::ConnectionInfo(_) => Self::Query(Query::ConnectionInfo),
+                Op::OnlineFriends(_) => Self::Query(Query::OnlineFriends),
+                Op::Conversation(conversation) => Self::Query(Query::Conversation {
+                    conversation_id: conversation.conversation_id,
+                }),
+                Op::Choose(choose) => Self::Mutation(Mutation::Choose {
+                    content: choose.content,
+                    choosee_username: choose.choosee_username,
+                }),
+                Op::Send(send) => Self::Mutation(Mutation::Send {
+                    content: send.content,
+                    conversation_id: send.conversation_id,
+                    attachment: send.attachment.map(Attachment::try_from).transpose()?,
+                    mention: send.mention,
+                    kind: send.kind.map(MessageKind::try_from).transpose()?,
+                }),
+                Op::RegisterPresenceChoosee(register_presence_choosee) => {
+                    Self::Mutation(Mutation::RegisterPresenceChoosee {
+                        conversation_id: register_presence_choosee.conversation_id,
+                        leaving: register_presence_choosee.leaving,
+                    })
+                }
+                Op::DeleteMyAccount(delete_my_account) => {
+                    Self::Mutation(Mutation::DeleteMyAccount {
+                        confirmation_token: delete_my_account.confirmation_token,
+                    })
+                }
+                Op::RefreshToken(refresh_token) => Self::Mutation(Mutation::RefreshToken {
+                    token: refresh_token.token,
+                }),
+                Op::SolveChallenge(solve_challenge) => Self::Mutation(Mutation::SolveChallenge {
+                    solution: solve_challenge.solution,
+                }),
+                Op::DistributeKey(distribute_key) => Self::Mutation(Mutation::DistributeKey {
+                    conversation_id: distribute_key.conversation_id,
+                    key_material: distribute_key.key_material,
+                }),
+                Op::KeyDistributions(key_distributions) => Self::Query(Query::KeyDistributions {
+                    conversation_id: key_distributions.conversation_id,
+                }),
+                Op::ConversationStats(conversation_stats) => {
+                    Self::Query(Query::ConversationStats {
+                        conversation_id: conversation_stats.conversation_id,
+                    })
+                }
+                Op::Mentions(mentions) => Self::Query(Query::Mentions {
+                    take: mentions
+                        .take
+                        .try_into()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("take"))?,
+                }),
+                Op::CreatePoll(create_poll) => Self::Mutation(Mutation::CreatePoll {
+                    conversation_id: create_poll.conversation_id,
+                    question: create_poll.question,
+                    options: create_poll.options,
+                }),
+                Op::Vote(vote) => Self::Mutation(Mutation::Vote {
+                    conversation_id: vote.conversation_id,
+                    poll_id: vote.poll_id,
+                    option: vote.option,
+                }),
+                Op::Polls(polls) => Self::Query(Query::Polls {
+                    conversation_id: polls.conversation_id,
+                }),
+                Op::SetDisappearing(set_disappearing) => {
+                    Self::Mutation(Mutation::SetDisappearing {
+                        conversation_id: set_disappearing.conversation_id,
+                        ttl_seconds: set_disappearing.ttl_seconds,
+                    })
+                }
+                Op::RequestAvatarUpload(request_avatar_upload) => {
+                    Self::Mutation(Mutation::RequestAvatarUpload {
+                        content_type: request_avatar_upload.content_type,
+                    })
+                }
+                Op::SetAvatar(set_avatar) => Self::Mutation(Mutation::SetAvatar {
+                    object_key: set_avatar.object_key,
+                }),
+                Op::RequestAttachmentUpload(request_attachment_upload) => {
+                    Self::Mutation(Mutation::RequestAttachmentUpload {
+                        conversation_id: request_attachment_upload.conversation_id,
+                        content_type: request_attachment_upload.content_type,
+                        size: request_attachment_upload.size,
+                    })
+                }
+                Op::PinMessage(pin_message) => Self::Mutation(Mutation::PinMessage {
+                    conversation_id: pin_message.conversation_id,
+                    sent_at: datetime_from_timestamp(pin_message.sent_at)?,
+                    pinned: pin_message.pinned,
+                }),
+                Op::PinConversation(pin_conversation) => {
+                    Self::Mutation(Mutation::PinConversation {
+                        conversation_id: pin_conversation.conversation_id,
+                        pinned: pin_conversation.pinned,
+                    })
+                }
+                Op::ArchiveConversation(archive_conversation) => {
+                    Self::Mutation(Mutation::ArchiveConversation {
+                        conversation_id: archive_conversation.conversation_id,
+                    })
+                }
+                Op::UnarchiveConversation(unarchive_conversation) => {
+                    Self::Mutation(Mutation::UnarchiveConversation {
+                        conversation_id: unarchive_conversation.conversation_id,
+                    })
+                }
+                Op::ClearConversation(clear_conversation) => {
+                    Self::Mutation(Mutation::ClearConversation {
+                        conversation_id: clear_conversation.conversation_id,
+                    })
+                }
+                Op::RegisterPushToken(register_push_token) => {
+                    Self::Mutation(Mutation::RegisterPushToken {
+                        platform: match proto::PushPlatform::from_i32(register_push_token.platform)
+                        {
+                            Some(proto::PushPlatform::Apns) => PushPlatform::Apns,
+                            Some(proto::PushPlatform::Fcm) => PushPlatform::Fcm,
+                            None => return Err(UnsupportedFormatError::OutOfRange("platform")),
+                        },
+                        token: register_push_token.token,
+                    })
+                }
+                Op::UnregisterPushToken(unregister_push_token) => {
+                    Self::Mutation(Mutation::UnregisterPushToken {
+                        token: unregister_push_token.token,
+                    })
+                }
+                Op::SetNotificationPrefs(set_notification_prefs) => {
+                    Self::Mutation(Mutation::SetNotificationPrefs {
+                        conversation_id: set_notification_prefs.conversation_id,
+                        prefs: set_notification_prefs
+                            .prefs
+                            .ok_or(UnsupportedFormatError::MissingField("prefs"))?
+                            .try_into()?,
+                    })
+                }
+                Op::LogoutDevice(logout_device) => Self::Mutation(Mutation::LogoutDevice {
+                    device_id: logout_device.device_id,
+                }),
+                Op::DisconnectOtherSessions(_) => Self::Mutation(Mutation::DisconnectOtherSessions),
+                Op::Report(report) => Self::Mutation(Mutation::Report {
+                    conversation_id: report.conversation_id,
+                    message_sent_at: report
+                        .message_sent_at
+                        .map(datetime_from_timestamp)
+                        .transpose()?,
+                    reason: report.reason,
+                }),
+                Op::UpdateSubscriptions(update_subscriptions) => {
+                    Self::Mutation(Mutation::UpdateSubscriptions {
+                        presence: update_subscriptions.presence,
+                        typing: update_subscriptions.typing,
+                        receipts: update_subscriptions.receipts,
+                    })
+                }
+                Op::Subscribe(subscribe) => Self::Mutation(Mutation::Subscribe {
+                    conversation_id: subscribe.conversation_id,
+                }),
+                Op::Unsubscribe(unsubscribe) => Self::Mutation(Mutation::Unsubscribe {
+                    conversation_id: unsubscribe.conversation_id,
+                }),
+                Op::ListConnections(_) => Self::Admin(Admin::ListConnections),
+                Op::ListNodes(_) => Self::Admin(Admin::ListNodes),
+                Op::Kick(kick) => Self::Admin(Admin::Kick {
+                    username: kick.username,
+                }),
+                Op::Announce(announce) => Self::Admin(Admin::Announce {
+                    title: announce.title,
+                    body: announce.body,
+                    level: match proto::AnnouncementLevel::from_i32(announce.level) {
+                        Some(proto::AnnouncementLevel::Info) => AnnouncementLevel::Info,
+                        Some(proto::AnnouncementLevel::Warning) => AnnouncementLevel::Warning,
+                        Some(proto::AnnouncementLevel::Critical) => AnnouncementLevel::Critical,
+                        None => return Err(UnsupportedFormatError::OutOfRange("level")),
+                    },
+                }),
+                Op::ReplayFailedEvents(_) => Self::Admin(Admin::ReplayFailedEvents),
+                Op::DeleteAccount(delete_account) => Self::Admin(Admin::DeleteAccount {
+                    username: delete_account.username,
+                }),
+                Op::AuditLog(audit_log) => Self::Admin(Admin::AuditLog {
+                    day: audit_log
+                        .day
+                        .map(|day| day.parse())
+                        .transpose()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("day"))?,
+                    take: audit_log.take,
+                }),
+                Op::ListBans(_) => Self::Admin(Admin::ListBans),
+                Op::Ban(ban) => Self::Admin(Admin::Ban {
+                    ip: ban
+                        .ip
+                        .parse()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("ip"))?,
+                    seconds: ban.seconds,
+                    reason: ban.reason,
+                }),
+                Op::Unban(unban) => Self::Admin(Admin::Unban {
+                    ip: unban
+                        .ip
+                        .parse()
+                        .map_err(|_| UnsupportedFormatError::OutOfRange("ip"))?,
+                }),
+                Op::RevokeTokens(revoke_tokens) => Self::Admin(Admin::RevokeTokens {
+                    username: revoke_tokens.username,
+                }),
+            },
+        )
+    }
+
+    pub fn rate_limit_class(&self) -> OperationClass {
+        match self {
+            Self::Query(_) | Self::Admin(_) => OperationClass::Query,
+            Self::Mutation(Mutation::Choose { .. }) => OperationClass::Choose,
+            Self::Mutation(_) => OperationClass::Send,
+        }
+    }
+
+    pub fn required_permission(&self) -> Permission {
+        match self {
+            Self::Query(_) | Self::Mutation(Mutation::RefreshToken { .. }) => Permission::Read,
+            Self::Mutation(_) => Permission::Write,
+            Self::Admin(_) => Permission::Admin,
+        }
+    }
 }
@@ -0,0 +1,39 @@
+use futures_util::FutureExt;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+
+use super::response::{ErrorCode, Response};
+use crate::connection::user_tx::UserTx;
+
+// a panicking operation shouldn't go unanswered or take the rest of the connection down with it
+
+pub async fn guard(
+    user_tx: Arc<UserTx>,
+    context: &'static str,
+    job: impl Future<Output = ()> + Send + 'static,
+) {
+    if let Err(panic) = AssertUnwindSafe(job).catch_unwind().await {
+        report(&user_tx, context, panic).await;
+    }
+}
+
+pub async fn report(user_tx: &UserTx, context: &str, panic: Box<dyn Any + Send>) {
+    error!("Panic while {}: {}", context, panic_message(&*panic));
+
+    let _ = user_tx
+        .send_response(&Response::error(
+            ErrorCode::Internal,
+            "Internal error while handling operation",
+        ))
+        .await; // if this fails too the connection is going down anyway
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
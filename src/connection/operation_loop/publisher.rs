@@ -0,0 +1,126 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::response::{ErrorCode, Response};
+use super::timeouts::Timeouts;
+use crate::connection::{
+    error::{ConnectionError, FatalConnectionError, NonFatalConnectionError},
+    nats_message::NatsMessage,
+    user_event::UserEvent,
+    user_tx::UserTx,
+};
+use crate::db::Storage;
+use crate::message_bus::MessageBus;
+use crate::retry_policy::RetryPolicy;
+
+// cheap to clone so it can be moved into tasks that need to publish after awaiting something else
+
+#[derive(Clone)]
+pub struct Publisher {
+    pub message_bus: Arc<dyn MessageBus>,
+    pub db: Arc<dyn Storage>,
+    pub outbox_bucket: i32, // this node's
+    pub user_tx: Arc<UserTx>,
+    pub degraded: Arc<AtomicBool>,
+    pub timeouts: Timeouts,
+    pub retry_policy: RetryPolicy,
+}
+
+impl Publisher {
+    // publishes are retried a few times first. when the bus stays unreachable the event is kept in the outbox to be published later, and the user is told their connection is degraded until a publish succeeds again. only if that fails too is the sender told the event was lost
+    pub async fn publish(
+        &self,
+        nats_message: NatsMessage,
+        err_tx: UnboundedSender<ConnectionError>,
+    ) {
+        let subject = nats_message.subject();
+
+        let data = nats_message.data();
+
+        let status_changed = match self.publish_with_retries(&subject, &data).await {
+            Ok(()) => self.degraded.swap(false, Ordering::Relaxed),
+            Err(err) => {
+                let _ = err_tx.send(ConnectionError::NonFatal(err)); // err_rx could potentially be dropped because this is running in task and after an await, so unfortunately error will not get logged, but not really worth doing anything about because of how unlikely it is
+
+                if let Err(err) = self
+                    .timeouts
+                    .database(
+                        "adding to outbox",
+                        self.db.add_to_outbox(self.outbox_bucket, &subject, data),
+                    )
+                    .await
+                {
+                    let _ = err_tx.send(ConnectionError::NonFatal(err));
+
+                    self.send_response(
+                        Response::error(ErrorCode::DeliveryFailed, "Failed to deliver event"),
+                        err_tx.clone(),
+                    )
+                    .await;
+                }
+
+                !self.degraded.swap(true, Ordering::Relaxed)
+            }
+        };
+
+        if status_changed {
+            let user_event = UserEvent::ConnectionStatus {
+                degraded: self.degraded.load(Ordering::Relaxed),
+            };
+
+            self.send_to_self(user_event, err_tx).await;
+        }
+    }
+
+    async fn publish_with_retries(
+        &self,
+        subject: &str,
+        data: &[u8],
+    ) -> Result<(), NonFatalConnectionError> {
+        let mut attempt = 0;
+
+        loop {
+            match self
+                .timeouts
+                .nats(
+                    "publishing to the message bus",
+                    self.message_bus.publish(subject, data),
+                )
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt + 1 >= self.retry_policy.max_attempts => return Err(err),
+                Err(err) => {
+                    debug!("Retrying publish to {}: {}", subject, err);
+
+                    tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn send_response(&self, response: Response, err_tx: UnboundedSender<ConnectionError>) {
+        if let Err(err) = self.user_tx.send_response(&response).await {
+            let _ = err_tx.send(ConnectionError::Fatal(
+                FatalConnectionError::WebSocketError(err),
+            ));
+        }
+    }
+
+    pub async fn send_to_self(
+        &self,
+        user_event: UserEvent,
+        err_tx: UnboundedSender<ConnectionError>,
+    ) {
+        if let Err(err) = self.user_tx.send_user_event(&user_event).await {
+            let _ = err_tx.send(ConnectionError::Fatal(
+                FatalConnectionError::WebSocketError(err),
+            ));
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use chrono::prelude::*;
-use serde::Deserialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 #[serde(tag = "op", content = "d", rename_all = "camelCase")]
 pub enum Query {
     Messages {
@@ -9,4 +10,40 @@ pub enum Query {
         take: i8,
         after_sent_at: DateTime<Utc>,
     },
+    RetentionPolicy,
+    ChooseePresence {
+        conversation_id: String,
+        take: i8,
+    },
+    SearchUsers {
+        prefix: String,
+        take: i8,
+    },
+    Conversations {
+        #[serde(default)]
+        archived: bool, // lists archived conversations instead of the rest
+    },
+    Conversation {
+        conversation_id: String,
+    },
+    NotificationPrefs,
+    Devices,
+    Time, // for clients to work out how far off their clock is
+    Ping {
+        nonce: String, // echoed back so the client can match the pong to its ping
+    },
+    ConnectionInfo, // for debugging panels and support
+    OnlineFriends,  // connected to any node
+    KeyDistributions {
+        conversation_id: String,
+    },
+    ConversationStats {
+        conversation_id: String,
+    },
+    Mentions {
+        take: i8,
+    },
+    Polls {
+        conversation_id: String,
+    },
 }
@@ -1,19 +1,578 @@
+use chrono::prelude::*;
+use prost::Message as _;
+use schemars::JsonSchema;
 use serde::Serialize;
+use std::net::IpAddr;
 
-use crate::models::message::Message;
+use crate::challenge::{Challenge, ChallengeKind};
+use crate::connection::encoding::{proto, timestamp_from_datetime, Encoding};
+use crate::connection::error::NonFatalConnectionError;
+use crate::connection::subscriptions::Subscriptions;
+use crate::error::ErrorCategory;
+use crate::models::{
+    audit_entry::AuditEntry, connection_summary::ConnectionSummary,
+    conversation_stats::ConversationStats, conversation_summary::ConversationSummary,
+    device::Device, ip_ban::IpBan, key_distribution::KeyDistribution, mention::Mention,
+    message::Message, node_summary::NodeSummary, notification_prefs::NotificationPrefs,
+    pinned_message::PinnedMessage, poll::Poll, presence_event::PresenceEvent, profile::Profile,
+};
+use crate::rate_limit::RemainingTokens;
+use crate::storage::object_store::PresignedUpload;
 
-#[derive(Serialize)]
+#[derive(Serialize, JsonSchema)]
 #[serde(tag = "op", content = "d", rename_all = "camelCase")]
 pub enum Response {
-    Error(String),
+    Error {
+        code: ErrorCode,
+        message: String,
+        retryable: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
+    },
     Messages {
         conversation_id: String,
         messages: Vec<Message>,
     },
+    Connections {
+        connections: Vec<ConnectionSummary>,
+    },
+    Nodes {
+        nodes: Vec<NodeSummary>, // ordered by node id
+    },
+    AuditLog {
+        day: NaiveDate,
+        entries: Vec<AuditEntry>, // most recent first
+    },
+    Bans {
+        bans: Vec<IpBan>, // ordered by address
+    },
+    Kicked {
+        username: String,
+        connections: usize, // on this node
+        #[serde(skip_serializing_if = "Option::is_none")]
+        other_node_id: Option<String>, // the node presence has them on, also told to kick them when it isn't this one
+    },
+    Announced {
+        everywhere: bool, // false when the bus was down, and only this node's connections were reached
+        #[serde(skip_serializing_if = "Option::is_none")]
+        connections: Option<usize>, // only counted when it wasn't everywhere
+    },
+    Banned {
+        ban: IpBan,
+        everywhere: bool, // false when only this node has it, because the bus was down
+    },
+    Unbanned {
+        ip: IpAddr,
+        everywhere: bool,
+    },
+    FailedEventsReplayed {
+        replayed: usize, // moved back into the outbox, to be delivered again
+    },
+    TokensRevoked {
+        username: String,
+        revoked_before: DateTime<Utc>,
+    },
+    RetentionPolicy {
+        message_retention_seconds: Option<u64>, // null when messages are kept forever
+    },
+    ChooseePresence {
+        conversation_id: String,
+        events: Vec<PresenceEvent>, // most recent first
+    },
+    Sent {
+        conversation_id: String, // how a client learns the id of a conversation it just chose
+        sent_at: DateTime<Utc>,
+    },
+    Upload(PresignedUpload),
+    ChallengeRequired(Challenge), // in answer to mutations until solveChallenge succeeds, and once right after hello
+    ChallengeSolved,
+    AccountDeletionScheduled, // the connection closes once the deletion goes through
+    Users {
+        prefix: String, // so results can be matched to what's in the search box now
+        users: Vec<Profile>,
+    },
+    Conversations {
+        conversations: Vec<ConversationSummary>, // pinned first, then newest first
+    },
+    Conversation {
+        conversation_id: String,
+        pinned: bool,
+        archived: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cleared_before: Option<DateTime<Utc>>,
+        pinned_messages: Vec<PinnedMessage>, // only the ones sent after cleared_before
+        muted: bool,
+        mentions_only: bool,
+    },
+    NotificationPrefs {
+        prefs: NotificationPrefs,
+    },
+    Devices {
+        devices: Vec<Device>, // most recently seen first
+    },
+    Reported {
+        report_id: String,
+    },
+    Time {
+        server_time: DateTime<Utc>,
+        sequence: u64, // increases with every answer from this server, for ordering answers that cross on the wire
+    },
+    Pong {
+        nonce: String,
+        server_time: DateTime<Utc>,
+    },
+    ConnectionInfo {
+        connection_id: String,
+        protocol_version: u32,
+        encoding: &'static str,          // the subprotocol name
+        token_expires_at: DateTime<Utc>, // unless it's refreshed
+        rate_limit_tokens: RemainingTokens,
+        subscriptions: Subscriptions,
+        is_bot: bool,
+    },
+    OnlineFriends {
+        usernames: Vec<String>,
+    },
+    KeyDistributed {
+        conversation_id: String,
+        sent_at: DateTime<Utc>,
+    },
+    KeyDistributions {
+        conversation_id: String,
+        key_distributions: Vec<KeyDistribution>, // from the other participant, oldest first
+    },
+    ConversationStats {
+        conversation_id: String,
+        stats: ConversationStats,
+    },
+    Mentions {
+        mentions: Vec<Mention>, // newest first
+    },
+    Polls {
+        conversation_id: String,
+        polls: Vec<Poll>, // oldest first
+    },
+    TokenRefreshed {
+        token_expires_at: DateTime<Utc>, // when the connection closes unless it's refreshed again
+    },
+}
+
+#[derive(Serialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    Internal,
+    Unavailable,
+    Forbidden,
+    InvalidRequest,
+    RateLimited,
+    ContentTooLong,
+    Timeout,
+    DeliveryFailed,
+}
+
+impl ErrorCode {
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::Unavailable | Self::RateLimited | Self::Timeout)
+    }
+}
+
+impl From<ErrorCode> for proto::ErrorCode {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Internal => Self::Internal,
+            ErrorCode::Unavailable => Self::Unavailable,
+            ErrorCode::Forbidden => Self::Forbidden,
+            ErrorCode::InvalidRequest => Self::InvalidRequest,
+            ErrorCode::RateLimited => Self::RateLimited,
+            ErrorCode::ContentTooLong => Self::ContentTooLong,
+            ErrorCode::Timeout => Self::Timeout,
+            ErrorCode::DeliveryFailed => Self::DeliveryFailed,
+        }
+    }
+}
+
+impl From<ChallengeKind> for proto::ChallengeKind {
+    fn from(kind: ChallengeKind) -> Self {
+        match kind {
+            ChallengeKind::Captcha => Self::Captcha,
+            ChallengeKind::Attestation => Self::Attestation,
+        }
+    }
+}
+
+impl From<&IpBan> for proto::IpBan {
+    fn from(ban: &IpBan) -> Self {
+        Self {
+            ip: ban.ip.to_string(),
+            reason: ban.reason.clone(),
+            banned_at: timestamp_from_datetime(ban.banned_at),
+            until: timestamp_from_datetime(ban.until),
+        }
+    }
+}
+
+impl From<&NonFatalConnectionError> for ErrorCode {
+    fn from(err: &NonFatalConnectionError) -> Self {
+        match err {
+            NonFatalConnectionError::Timeout(_) => Self::Timeout,
+            _ => Self::from(err.category()),
+        }
+    }
+}
+
+impl From<ErrorCategory> for ErrorCode {
+    fn from(category: ErrorCategory) -> Self {
+        match category {
+            ErrorCategory::Transient => Self::Unavailable,
+            ErrorCategory::Permanent => Self::Internal,
+            ErrorCategory::Auth => Self::Forbidden,
+            ErrorCategory::Validation => Self::InvalidRequest,
+        }
+    }
 }
 
 impl Response {
-    pub fn to_message(&self) -> tungstenite::Message {
-        tungstenite::Message::Text(serde_json::to_string(self).unwrap())
+    pub fn error(code: ErrorCode, message: &str) -> Self {
+        Self::Error {
+            code,
+            message: message.to_owned(),
+            retryable: code.is_retryable(),
+            retry_after_ms: None,
+        }
+    }
+
+    pub fn to_message(&self, encoding: Encoding) -> tungstenite::Message {
+        match encoding {
+            Encoding::Json => tungstenite::Message::Text(serde_json::to_string(self).unwrap()),
+            Encoding::Protobuf => tungstenite::Message::Binary(self.to_protobuf().encode_to_vec()),
+            Encoding::Grpc => tungstenite::Message::Binary(
+                proto::ServerFrame {
+                    frame: Some(proto::server_frame::Frame::Response(self.to_protobuf())),
+                }
+                .encode_to_vec(),
+            ),
+        }
+    }
+
+    fn to_protobuf(&self) -> proto::Response {
+        use proto::response::Op;
+
+        proto::Response {
+            op: Some(match self {
+                Self::Error {
+                    code,
+                    message,
+                    retryable,
+                    retry_after_ms,
+                } => Op::Error(proto::ErrorResponse {
+                    code: proto::ErrorCode::from(*code) as i32,
+                    message: message.clone(),
+                    retryable: *retryable,
+                    retry_after_ms: *retry_after_ms,
+                }),
+                Self::Messages {
+                    conversation_id,
+                    messages,
+                } => Op::Messages(proto::MessagesResponse {
+                    conversation_id: conversation_id.clone(),
+                    messages: messages
+                        .iter()
+                        .map(|message| proto::StoredMessage {
+                            content: message.content.clone(),
+                            sent_at: timestamp_from_datetime(message.sent_at),
+                            from_chooser: message.from_chooser,
+                            attachment: message.attachment.clone().map(proto::Attachment::from),
+                            kind: message.kind.clone().map(proto::MessageKind::from),
+                        })
+                        .collect(),
+                }),
+                Self::Connections { connections } => Op::Connections(proto::ConnectionsResponse {
+                    connections: connections
+                        .iter()
+                        .map(|connection| proto::ConnectionSummary {
+                            id: connection.id.clone(),
+                            username: connection.username.clone(),
+                            connected_at: timestamp_from_datetime(connection.connected_at),
+                            device_id: connection.device_id.clone(),
+                        })
+                        .collect(),
+                }),
+                Self::Nodes { nodes } => Op::Nodes(proto::NodesResponse {
+                    nodes: nodes
+                        .iter()
+                        .map(|node| proto::NodeSummary {
+                            node_id: node.node_id.clone(),
+                            version: node.version.clone(),
+                            connections: node.connections as u64,
+                            started_at: timestamp_from_datetime(node.started_at),
+                            announced_at: timestamp_from_datetime(node.announced_at),
+                        })
+                        .collect(),
+                }),
+                Self::AuditLog { day, entries } => Op::AuditLog(proto::AuditLogResponse {
+                    day: day.to_string(),
+                    entries: entries
+                        .iter()
+                        .map(|entry| proto::AuditEntry {
+                            entry_id: entry.entry_id.clone(),
+                            action: entry.action.as_str().to_owned(),
+                            actor: entry.actor.clone(),
+                            target: entry.target.clone(),
+                            connection_id: entry.connection_id.clone(),
+                            detail: entry.detail.clone(),
+                            occurred_at: timestamp_from_datetime(entry.occurred_at),
+                        })
+                        .collect(),
+                }),
+                Self::Bans { bans } => Op::Bans(proto::BansResponse {
+                    bans: bans.iter().map(proto::IpBan::from).collect(),
+                }),
+                Self::Banned { ban, everywhere } => Op::Banned(proto::BannedResponse {
+                    ban: Some(ban.into()),
+                    everywhere: *everywhere,
+                }),
+                Self::Unbanned { ip, everywhere } => Op::Unbanned(proto::UnbannedResponse {
+                    ip: ip.to_string(),
+                    everywhere: *everywhere,
+                }),
+                Self::FailedEventsReplayed { replayed } => {
+                    Op::FailedEventsReplayed(proto::FailedEventsReplayedResponse {
+                        replayed: *replayed as u64,
+                    })
+                }
+                Self::TokensRevoked {
+                    username,
+                    revoked_before,
+                } => Op::TokensRevoked(proto::TokensRevokedResponse {
+                    username: username.clone(),
+                    revoked_before: timestamp_from_datetime(*revoked_before),
+                }),
+                Self::Kicked {
+                    username,
+                    connections,
+                    other_node_id,
+                } => Op::Kicked(proto::KickedResponse {
+                    username: username.clone(),
+                    connections: *connections as u64,
+                    other_node_id: other_node_id.clone(),
+                }),
+                Self::Announced {
+                    everywhere,
+                    connections,
+                } => Op::Announced(proto::AnnouncedResponse {
+                    everywhere: *everywhere,
+                    connections: connections.map(|connections| connections as u64),
+                }),
+                Self::RetentionPolicy {
+                    message_retention_seconds,
+                } => Op::RetentionPolicy(proto::RetentionPolicyResponse {
+                    message_retention_seconds: *message_retention_seconds,
+                }),
+                Self::ChooseePresence {
+                    conversation_id,
+                    events,
+                } => Op::ChooseePresence(proto::ChooseePresenceResponse {
+                    conversation_id: conversation_id.clone(),
+                    events: events
+                        .iter()
+                        .map(|event| proto::PresenceEvent {
+                            leaving: event.leaving,
+                            occurred_at: timestamp_from_datetime(event.occurred_at),
+                        })
+                        .collect(),
+                }),
+                Self::Sent {
+                    conversation_id,
+                    sent_at,
+                } => Op::Sent(proto::SentResponse {
+                    conversation_id: conversation_id.clone(),
+                    sent_at: timestamp_from_datetime(*sent_at),
+                }),
+                Self::Upload(upload) => Op::Upload(proto::UploadResponse {
+                    object_key: upload.object_key.clone(),
+                    url: upload.url.clone(),
+                    expires_at: timestamp_from_datetime(upload.expires_at),
+                }),
+                Self::ChallengeRequired(challenge) => {
+                    Op::ChallengeRequired(proto::ChallengeRequiredResponse {
+                        kind: proto::ChallengeKind::from(challenge.kind) as i32,
+                        site_key: challenge.site_key.clone(),
+                        nonce: challenge.nonce.clone(),
+                    })
+                }
+                Self::ChallengeSolved => Op::ChallengeSolved(proto::ChallengeSolvedResponse {}),
+                Self::AccountDeletionScheduled => {
+                    Op::AccountDeletionScheduled(proto::AccountDeletionScheduledResponse {})
+                }
+                Self::Users { prefix, users } => Op::Users(proto::UsersResponse {
+                    prefix: prefix.clone(),
+                    users: users
+                        .iter()
+                        .map(|user| proto::Profile {
+                            username: user.username.clone(),
+                            name: user.name.clone(),
+                        })
+                        .collect(),
+                }),
+                Self::Conversations { conversations } => {
+                    Op::Conversations(proto::ConversationsResponse {
+                        conversations: conversations
+                            .iter()
+                            .map(|conversation| proto::ConversationSummary {
+                                conversation_id: conversation.conversation_id.clone(),
+                                created_at: timestamp_from_datetime(conversation.created_at),
+                                is_chooser: conversation.is_chooser,
+                                choosee_name: conversation.choosee_name.clone(),
+                                pinned: conversation.pinned,
+                                archived: conversation.archived,
+                                tombstoned: conversation.tombstoned,
+                                streak: conversation.streak,
+                            })
+                            .collect(),
+                    })
+                }
+                Self::Conversation {
+                    conversation_id,
+                    pinned,
+                    archived,
+                    cleared_before,
+                    pinned_messages,
+                    muted,
+                    mentions_only,
+                } => Op::Conversation(proto::ConversationResponse {
+                    conversation_id: conversation_id.clone(),
+                    pinned: *pinned,
+                    archived: *archived,
+                    muted: *muted,
+                    mentions_only: *mentions_only,
+                    cleared_before: cleared_before.map(timestamp_from_datetime),
+                    pinned_messages: pinned_messages
+                        .iter()
+                        .map(|pinned_message| proto::PinnedMessage {
+                            sent_at: timestamp_from_datetime(pinned_message.sent_at),
+                            pinned_at: timestamp_from_datetime(pinned_message.pinned_at),
+                            pinned_by_chooser: pinned_message.pinned_by_chooser,
+                        })
+                        .collect(),
+                }),
+                Self::Devices { devices } => Op::Devices(proto::DevicesResponse {
+                    devices: devices
+                        .iter()
+                        .map(|device| proto::Device {
+                            device_id: device.device_id.clone(),
+                            last_seen_at: timestamp_from_datetime(device.last_seen_at),
+                            connected: device.connected,
+                            current: device.current,
+                        })
+                        .collect(),
+                }),
+                Self::NotificationPrefs { prefs } => {
+                    Op::NotificationPrefs(proto::NotificationPrefsResponse {
+                        prefs: Some(prefs.clone().into()),
+                    })
+                }
+                Self::Reported { report_id } => Op::Reported(proto::ReportedResponse {
+                    report_id: report_id.clone(),
+                }),
+                Self::Time {
+                    server_time,
+                    sequence,
+                } => Op::Time(proto::TimeResponse {
+                    server_time: timestamp_from_datetime(*server_time),
+                    sequence: *sequence,
+                }),
+                Self::Pong { nonce, server_time } => Op::Pong(proto::PongResponse {
+                    nonce: nonce.clone(),
+                    server_time: timestamp_from_datetime(*server_time),
+                }),
+                Self::ConnectionInfo {
+                    connection_id,
+                    protocol_version,
+                    encoding,
+                    token_expires_at,
+                    rate_limit_tokens,
+                    subscriptions,
+                    is_bot,
+                } => Op::ConnectionInfo(proto::ConnectionInfoResponse {
+                    connection_id: connection_id.clone(),
+                    protocol_version: *protocol_version,
+                    encoding: encoding.to_string(),
+                    token_expires_at: timestamp_from_datetime(*token_expires_at),
+                    rate_limit_tokens: Some(proto::RemainingTokens {
+                        send: rate_limit_tokens.send,
+                        choose: rate_limit_tokens.choose,
+                        query: rate_limit_tokens.query,
+                    }),
+                    subscriptions: Some(proto::Subscriptions {
+                        presence: subscriptions.presence,
+                        typing: subscriptions.typing,
+                        receipts: subscriptions.receipts,
+                        all_conversations: subscriptions.conversations.is_none(),
+                        conversation_ids: subscriptions
+                            .conversations
+                            .iter()
+                            .flatten()
+                            .cloned()
+                            .collect(),
+                    }),
+                    is_bot: *is_bot,
+                }),
+                Self::OnlineFriends { usernames } => {
+                    Op::OnlineFriends(proto::OnlineFriendsResponse {
+                        usernames: usernames.clone(),
+                    })
+                }
+                Self::KeyDistributed {
+                    conversation_id,
+                    sent_at,
+                } => Op::KeyDistributed(proto::KeyDistributedResponse {
+                    conversation_id: conversation_id.clone(),
+                    sent_at: timestamp_from_datetime(*sent_at),
+                }),
+                Self::KeyDistributions {
+                    conversation_id,
+                    key_distributions,
+                } => Op::KeyDistributions(proto::KeyDistributionsResponse {
+                    conversation_id: conversation_id.clone(),
+                    key_distributions: key_distributions
+                        .iter()
+                        .map(|key_distribution| proto::KeyDistribution {
+                            key_material: key_distribution.key_material.clone(),
+                            sent_at: timestamp_from_datetime(key_distribution.sent_at),
+                            from_chooser: key_distribution.from_chooser,
+                        })
+                        .collect(),
+                }),
+                Self::ConversationStats {
+                    conversation_id,
+                    stats,
+                } => Op::ConversationStats(proto::ConversationStatsResponse {
+                    conversation_id: conversation_id.clone(),
+                    messages_from_chooser: stats.messages_from_chooser,
+                    messages_from_choosee: stats.messages_from_choosee,
+                }),
+                Self::Mentions { mentions } => Op::Mentions(proto::MentionsResponse {
+                    mentions: mentions
+                        .iter()
+                        .map(|mention| proto::Mention {
+                            conversation_id: mention.conversation_id.clone(),
+                            sent_at: timestamp_from_datetime(mention.sent_at),
+                        })
+                        .collect(),
+                }),
+                Self::Polls {
+                    conversation_id,
+                    polls,
+                } => Op::Polls(proto::PollsResponse {
+                    conversation_id: conversation_id.clone(),
+                    polls: polls.iter().cloned().map(proto::Poll::from).collect(),
+                }),
+                Self::TokenRefreshed { token_expires_at } => {
+                    Op::TokenRefreshed(proto::TokenRefreshedResponse {
+                        token_expires_at: timestamp_from_datetime(*token_expires_at),
+                    })
+                }
+            }),
+        }
     }
 }
@@ -0,0 +1,121 @@
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+use super::panic_guard;
+use crate::connection::user_tx::UserTx;
+
+// runs a connection's operations with bounded concurrency, taking turns between conversations instead of going
+// first come first served, so a burst of slow work in one conversation can't hold up a quick send in another.
+// the queue behind that is bounded too, so an aggressive client gets turned away instead of piling up work
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub struct Scheduler {
+    job_tx: mpsc::UnboundedSender<(String, Job)>,
+    user_tx: Arc<UserTx>,
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+}
+
+// counts a job as pending until it's finished or dropped
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Scheduler {
+    pub fn spawn(concurrency: usize, max_queued: usize, user_tx: Arc<UserTx>) -> Self {
+        let concurrency = concurrency.max(1);
+
+        let (job_tx, job_rx) = mpsc::unbounded_channel();
+
+        tokio::task::spawn(Self::run(job_rx, concurrency).in_current_span());
+
+        Self {
+            job_tx,
+            user_tx,
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending: concurrency + max_queued,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.pending.load(Ordering::Relaxed) >= self.max_pending
+    }
+
+    pub fn schedule(&self, key: &str, job: impl Future<Output = ()> + Send + 'static) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+
+        let pending = Pending(self.pending.clone());
+
+        let job = panic_guard::guard(
+            self.user_tx.clone(),
+            "running scheduled operation",
+            async move {
+                job.await;
+
+                drop(pending);
+            },
+        );
+
+        let _ = self.job_tx.send((key.to_owned(), Box::pin(job))); // only fails once the connection is over, at which point the job isn't wanted anyway
+    }
+
+    async fn run(mut job_rx: mpsc::UnboundedReceiver<(String, Job)>, concurrency: usize) {
+        let mut queues = HashMap::<String, VecDeque<Job>>::new();
+        let mut turns = VecDeque::<String>::new();
+        let mut in_flight = FuturesUnordered::<JoinHandle<()>>::new();
+
+        loop {
+            while in_flight.len() < concurrency {
+                let Some(key) = turns.pop_front() else {
+                    break;
+                };
+
+                let queue = queues
+                    .get_mut(&key)
+                    .expect("Every key taking turns should have a queue");
+
+                let job = queue
+                    .pop_front()
+                    .expect("Queues are removed once they're empty");
+
+                if queue.is_empty() {
+                    queues.remove(&key);
+                } else {
+                    turns.push_back(key);
+                }
+
+                in_flight.push(tokio::task::spawn(job.in_current_span()));
+            }
+
+            tokio::select! {
+                job = job_rx.recv() => match job {
+                    Some((key, job)) => {
+                        let queue = queues.entry(key.clone()).or_default();
+
+                        if queue.is_empty() {
+                            turns.push_back(key);
+                        }
+
+                        queue.push_back(job);
+                    }
+                    None => return, // connection is over, so whatever is still queued gets dropped
+                },
+                Some(_) = in_flight.next(), if !in_flight.is_empty() => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::connection::error::NonFatalConnectionError;
+use crate::db::DatabaseError;
+use crate::message_bus::MessageBusError;
+use crate::metrics;
+
+// a stuck scylla query or nats publish would otherwise hold a task, and the client's answer, forever
+
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    pub database: Duration,
+    pub nats: Duration,
+}
+
+impl Timeouts {
+    pub async fn database<T>(
+        &self,
+        context: &'static str,
+        future: impl Future<Output = Result<T, DatabaseError>>,
+    ) -> Result<T, NonFatalConnectionError> {
+        match tokio::time::timeout(self.database, future).await {
+            Ok(result) => result.map_err(NonFatalConnectionError::DatabaseError),
+            Err(_) => {
+                metrics::DATABASE_TIMEOUTS.increment();
+
+                Err(NonFatalConnectionError::Timeout(context))
+            }
+        }
+    }
+
+    pub async fn nats<T>(
+        &self,
+        context: &'static str,
+        future: impl Future<Output = Result<T, MessageBusError>>,
+    ) -> Result<T, NonFatalConnectionError> {
+        match tokio::time::timeout(self.nats, future).await {
+            Ok(result) => result.map_err(NonFatalConnectionError::PublishError),
+            Err(_) => {
+                metrics::NATS_TIMEOUTS.increment();
+
+                Err(NonFatalConnectionError::Timeout(context))
+            }
+        }
+    }
+}
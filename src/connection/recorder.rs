@@ -0,0 +1,167 @@
+use chrono::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+use tungstenite::Message;
+
+// keys whose string values are kept as they were. every other string is masked rather than removed so replays still
+// hit length limits, which covers content and anything secret, like tokens, key material and challenge solutions,
+// including fields added after this list was last looked at
+const RECORDED_KEYS: [&str; 46] = [
+    "op",
+    "code",
+    "message", // error messages are written by the server
+    "conversation_id",
+    "successor_conversation_id",
+    "connection_id",
+    "node_id",
+    "device_id",
+    "poll_id",
+    "report_id",
+    "entry_id",
+    "pack_id",
+    "sticker_id",
+    "username",
+    "usernames",
+    "choosee_username",
+    "reporter_username",
+    "content_type",
+    "platform",
+    "level",
+    "encoding",
+    "kind",
+    "type",
+    "action",
+    "version",
+    "features",
+    "day",
+    "sent_at",
+    "after_sent_at",
+    "message_sent_at",
+    "occurred_at",
+    "created_at",
+    "changed_at",
+    "cleared_before",
+    "pinned_at",
+    "reached_at",
+    "reported_at",
+    "server_time",
+    "token_expires_at",
+    "expires_at",
+    "connected_at",
+    "last_seen_at",
+    "started_at",
+    "announced_at",
+    "banned_at",
+    "until",
+];
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecordedFrame<'a> {
+    direction: Direction,
+    at_ms: u128,
+    frame: &'a str,
+}
+
+// only ever active in debug builds, and only when RECORD_DIR is set
+pub struct Recorder {
+    started_at: Instant,
+    file: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn from_env() -> Option<Self> {
+        if !cfg!(debug_assertions) {
+            return None;
+        }
+
+        let record_dir = env::var("RECORD_DIR").ok()?;
+
+        let path = Path::new(&record_dir).join(format!(
+            "{}-{:08x}.jsonl",
+            Utc::now().format("%Y%m%dT%H%M%S"),
+            rand::random::<u32>()
+        ));
+
+        match File::create(&path) {
+            Ok(file) => {
+                info!("Recording connection frames to {}", path.display());
+
+                Some(Self {
+                    started_at: Instant::now(),
+                    file: Mutex::new(BufWriter::new(file)),
+                })
+            }
+            Err(err) => {
+                warn!("Failed to create recording at {}: {}", path.display(), err);
+
+                None
+            }
+        }
+    }
+
+    pub fn record(&self, direction: Direction, message: &Message) {
+        let Message::Text(text) = message else {
+            return;
+        };
+
+        let redacted = Self::redact(text);
+
+        let line = serde_json::to_string(&RecordedFrame {
+            direction,
+            at_ms: self.started_at.elapsed().as_millis(),
+            frame: &redacted,
+        })
+        .unwrap();
+
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(err) = writeln!(file, "{}", line).and_then(|_| file.flush()) {
+            warn!("Failed to write recorded frame: {}", err);
+        }
+    }
+
+    fn redact(text: &str) -> String {
+        match serde_json::from_str::<Value>(text) {
+            Ok(mut value) => {
+                Self::redact_value(None, &mut value);
+
+                value.to_string()
+            }
+            Err(_) => "*".repeat(text.chars().count()), // can't tell what's content in a malformed frame, so mask all of it
+        }
+    }
+
+    // key is the one the value is under, or the array's key for its elements
+    fn redact_value(key: Option<&str>, value: &mut Value) {
+        match value {
+            Value::String(string) if !key.is_some_and(|key| RECORDED_KEYS.contains(&key)) => {
+                *string = "*".repeat(string.chars().count());
+            }
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    Self::redact_value(Some(key), value);
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    Self::redact_value(key, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
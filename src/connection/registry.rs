@@ -0,0 +1,142 @@
+use chrono::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::user_event::UserEvent;
+use crate::models::connection_summary::ConnectionSummary;
+
+// every connection open on this node, so admins can see and act on them without going through nats
+
+pub enum Control {
+    Kick,
+    Shutdown,
+    UserEvent(Box<UserEvent>), // boxed since it's so much bigger than the rest
+}
+
+struct RegisteredConnection {
+    summary: ConnectionSummary,
+    control_tx: UnboundedSender<Control>,
+}
+
+pub struct ConnectionRegistry {
+    connections: Mutex<HashMap<String, RegisteredConnection>>,
+}
+
+// removes the connection from the registry when dropped, however the connection ends
+pub struct Registration {
+    registry: Arc<ConnectionRegistry>,
+    id: String,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn register(
+        self: &Arc<Self>,
+        id: String,
+        username: String,
+        device_id: Option<String>,
+    ) -> (Registration, UnboundedReceiver<Control>) {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+
+        self.connections.lock().unwrap().insert(
+            id.clone(),
+            RegisteredConnection {
+                summary: ConnectionSummary {
+                    id: id.clone(),
+                    username,
+                    device_id,
+                    connected_at: Utc::now(),
+                },
+                control_tx,
+            },
+        );
+
+        (
+            Registration {
+                registry: self.clone(),
+                id,
+            },
+            control_rx,
+        )
+    }
+
+    pub fn list(&self) -> Vec<ConnectionSummary> {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|connection| connection.summary.clone())
+            .collect()
+    }
+
+    pub fn count(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    // only knows about this node, a user connected elsewhere in the cluster looks offline
+    pub fn is_connected(&self, username: &str) -> bool {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .any(|connection| connection.summary.username == username)
+    }
+
+    // same caveat as is_connected
+    pub fn connected_device_ids(&self, username: &str) -> HashSet<String> {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.summary.username == username)
+            .filter_map(|connection| connection.summary.device_id.clone())
+            .collect()
+    }
+
+    // returns how many connections were kicked
+    pub fn kick(&self, username: &str) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.summary.username == username)
+            .filter(|connection| connection.control_tx.send(Control::Kick).is_ok())
+            .count()
+    }
+
+    // returns how many connections were told to close
+    pub fn shutdown(&self) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| connection.control_tx.send(Control::Shutdown).is_ok())
+            .count()
+    }
+
+    pub fn broadcast(&self, user_event: UserEvent) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|connection| {
+                connection
+                    .control_tx
+                    .send(Control::UserEvent(Box::new(user_event.clone())))
+                    .is_ok()
+            })
+            .count()
+    }
+}
@@ -0,0 +1,31 @@
+use schemars::gen::SchemaSettings;
+use serde_json::json;
+
+use super::operation_loop::{operation::Operation, response::Response};
+use super::user_event::UserEvent;
+use super::PROTOCOL_VERSION;
+
+// the json protocol as a json schema document, for clients to generate their types from instead of reading the serde
+// attributes. printed by --dump-schema
+//
+// operation is what clients send, response and userEvent are what they're sent. each is {"op": ..., "d": ...} and the
+// op tells them apart. the protobuf encoding has the same messages in proto/realtime.proto
+
+pub fn dump() -> String {
+    let mut generator = SchemaSettings::draft07().into_generator();
+
+    let operation = generator.subschema_for::<Operation>();
+    let response = generator.subschema_for::<Response>();
+    let user_event = generator.subschema_for::<UserEvent>();
+
+    serde_json::to_string_pretty(&json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "Realtime gateway protocol",
+        "protocolVersion": PROTOCOL_VERSION,
+        "operation": operation,
+        "response": response,
+        "userEvent": user_event,
+        "definitions": generator.definitions(),
+    }))
+    .expect("Schema should serialize")
+}
@@ -0,0 +1,47 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashSet;
+
+use super::user_event::UserEvent;
+
+// which kinds of events a connection wants, so clients that don't render some of them (or are saving battery) aren't
+// sent them. set by the operation loop and read by the notification loop right before writing to the socket
+
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct Subscriptions {
+    pub presence: bool,
+    // nothing sends typing events or read receipts yet, clients can opt out ahead of them
+    pub typing: bool,
+    pub receipts: bool,
+    // the conversations high frequency events are sent for. every conversation's until the client subscribes to one,
+    // after which the rest only get message events
+    pub conversations: Option<HashSet<String>>,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self {
+            presence: true,
+            typing: true,
+            receipts: true,
+            conversations: None,
+        }
+    }
+}
+
+impl Subscriptions {
+    pub fn allows(&self, user_event: &UserEvent) -> bool {
+        match user_event {
+            UserEvent::ChooseePresence {
+                conversation_id, ..
+            } => self.presence && self.subscribed_to(conversation_id),
+            _ => true,
+        }
+    }
+
+    fn subscribed_to(&self, conversation_id: &str) -> bool {
+        self.conversations
+            .as_ref()
+            .is_none_or(|conversations| conversations.contains(conversation_id))
+    }
+}
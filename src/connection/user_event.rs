@@ -1,38 +1,455 @@
 use chrono::prelude::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
+use prost::Message as _;
+
+use crate::connection::encoding::{proto, timestamp_from_datetime, Encoding};
 use crate::connection::error::UnsupportedFormatError;
+use crate::models::{
+    attachment::Attachment, message_kind::MessageKind, notification_prefs::NotificationPrefs,
+    poll::Poll,
+};
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(tag = "op", content = "d", rename_all = "camelCase")]
 pub enum UserEvent {
+    Hello {
+        connection_id: String,
+        protocol_version: u32,
+        heartbeat_interval_ms: u64,
+        max_content_length: usize,
+        max_frame_size: usize,
+        features: Vec<String>,
+    },
+    // silent is set by the recipient's connection from their notification prefs, it's always false on the bus
     Chosen {
         conversation_id: String,
         content: String,
         sent_at: DateTime<Utc>,
+        #[serde(default)]
+        silent: bool,
     },
     Message {
         conversation_id: String,
         content: String,
         sent_at: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attachment: Option<Attachment>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        kind: Option<MessageKind>,
+        #[serde(default)]
+        silent: bool,
+        #[serde(default)]
+        mention: bool, // a mention event for it follows, which is the one that gets pushed
+    },
+    // for a message sent with the mention flag, on top of the message itself. the recipient's conversation mutes don't
+    // silence it, only muting everything or do not disturb does
+    Mention {
+        conversation_id: String,
+        content: String,
+        sent_at: DateTime<Utc>,
+        #[serde(default)]
+        silent: bool,
     },
     ChooseePresence {
         conversation_id: String,
         leaving: bool,
         occurred_at: DateTime<Utc>,
     },
+    ConversationRollover {
+        conversation_id: String,
+        successor_conversation_id: Option<String>,
+    },
+    ConnectionStatus {
+        degraded: bool,
+    },
+    FriendRemoved {
+        username: String,
+    },
+    AccountDeleted,
+    Announcement {
+        title: String,
+        body: String,
+        level: AnnouncementLevel,
+        sent_at: DateTime<Utc>,
+    },
+    DisappearingChanged {
+        conversation_id: String,
+        ttl_seconds: u32,
+        changed_at: DateTime<Utc>,
+    },
+    AvatarChanged {
+        username: String,
+        object_key: String,
+    },
+    MessagePinned {
+        conversation_id: String,
+        sent_at: DateTime<Utc>,
+        pinned: bool,
+        pinned_at: DateTime<Utc>,
+    },
+    NotificationPrefsChanged {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conversation_id: Option<String>, // set when it was a conversation's prefs that changed
+        prefs: NotificationPrefs,
+    },
+    // something the user did on another of their devices. origin is that device's id, or its connection's id when it
+    // didn't say which device it is, so the connections it came from can skip it
+    SelfSync {
+        origin: String,
+        action: SyncAction,
+    },
+    // only relayed, the server can't read key_material. the groundwork for end to end encrypted conversations, where
+    // each participant hands the others the key it encrypts with
+    KeyDistribution {
+        conversation_id: String,
+        key_material: String,
+        sent_at: DateTime<Utc>,
+    },
+    // sent to both users when their streak reaches one of the lengths in streaks
+    StreakMilestone {
+        conversation_id: String,
+        streak: u32,
+        reached_at: DateTime<Utc>, // when the message that extended it was sent
+    },
+    // for the push workers to turn into the daily digest. only published for users who haven't connected in a while,
+    // a connection that gets one anyway can ignore it
+    Digest {
+        unread_conversations: u32,
+        pending_friend_requests: u32, // all of them, not only the ones sent since
+        since: DateTime<Utc>,         // when the user last connected
+    },
+    // sent to both users when a poll is created and whenever either of them votes, with the counts as they are now
+    PollUpdate {
+        conversation_id: String,
+        poll: Poll,
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(tag = "action", rename_all = "camelCase")]
+pub enum SyncAction {
+    Chose {
+        conversation_id: String,
+        choosee_username: String,
+        content: String,
+        sent_at: DateTime<Utc>,
+    },
+    Sent {
+        conversation_id: String,
+        content: String,
+        sent_at: DateTime<Utc>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attachment: Option<Attachment>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        kind: Option<MessageKind>,
+    },
+    ConversationPinned {
+        conversation_id: String,
+        pinned: bool,
+    },
+    ConversationArchived {
+        conversation_id: String,
+        archived: bool,
+    },
+    ConversationCleared {
+        conversation_id: String,
+        cleared_before: DateTime<Utc>,
+    },
+}
+
+// how prominently clients show an announcement, critical ones are for emergencies
+#[derive(Deserialize, Serialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AnnouncementLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
 }
 
 impl UserEvent {
-    pub fn to_vec(&self) -> Vec<u8> {
-        serde_json::to_vec(self).unwrap()
+    // last token of the nats subject the event is published to. hashes are base64 so they can't contain dots themselves
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            UserEvent::Hello { .. } | UserEvent::ConnectionStatus { .. } => "connection",
+            UserEvent::Chosen { .. }
+            | UserEvent::ConversationRollover { .. }
+            | UserEvent::DisappearingChanged { .. }
+            | UserEvent::MessagePinned { .. }
+            | UserEvent::StreakMilestone { .. }
+            | UserEvent::PollUpdate { .. } => "conversation",
+            UserEvent::Message { .. } | UserEvent::Mention { .. } => "message",
+            UserEvent::ChooseePresence { .. } => "presence",
+            UserEvent::FriendRemoved { .. } | UserEvent::AvatarChanged { .. } => "friend",
+            UserEvent::AccountDeleted => "account",
+            UserEvent::Announcement { .. } => "announcement",
+            UserEvent::NotificationPrefsChanged { .. } => "settings",
+            UserEvent::SelfSync { .. } => "sync",
+            UserEvent::KeyDistribution { .. } => "keys",
+            UserEvent::Digest { .. } => "digest",
+        }
     }
 
-    pub fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
     }
 
     pub fn from_slice(slice: &[u8]) -> Result<Self, UnsupportedFormatError> {
         Ok(serde_json::from_slice::<Self>(slice)?)
     }
+
+    pub fn to_message(&self, encoding: Encoding) -> tungstenite::Message {
+        match encoding {
+            Encoding::Json => tungstenite::Message::Text(self.to_string()),
+            Encoding::Protobuf => tungstenite::Message::Binary(self.to_protobuf().encode_to_vec()),
+            Encoding::Grpc => tungstenite::Message::Binary(
+                proto::ServerFrame {
+                    frame: Some(proto::server_frame::Frame::Event(self.to_protobuf())),
+                }
+                .encode_to_vec(),
+            ),
+        }
+    }
+
+    fn to_protobuf(&self) -> proto::UserEvent {
+        use proto::user_event::Op;
+
+        proto::UserEvent {
+            op: Some(match self.clone() {
+                Self::Hello {
+                    connection_id,
+                    protocol_version,
+                    heartbeat_interval_ms,
+                    max_content_length,
+                    max_frame_size,
+                    features,
+                } => Op::Hello(proto::HelloEvent {
+                    protocol_version,
+                    heartbeat_interval_ms,
+                    max_content_length: max_content_length as u64,
+                    max_frame_size: max_frame_size as u64,
+                    features,
+                    connection_id,
+                }),
+                Self::Chosen {
+                    conversation_id,
+                    content,
+                    sent_at,
+                    silent,
+                } => Op::Chosen(proto::ChosenEvent {
+                    conversation_id,
+                    content,
+                    sent_at: timestamp_from_datetime(sent_at),
+                    silent,
+                }),
+                Self::Message {
+                    conversation_id,
+                    content,
+                    sent_at,
+                    attachment,
+                    kind,
+                    silent,
+                    mention,
+                } => Op::Message(proto::MessageEvent {
+                    conversation_id,
+                    content,
+                    sent_at: timestamp_from_datetime(sent_at),
+                    attachment: attachment.map(proto::Attachment::from),
+                    silent,
+                    mention,
+                    kind: kind.map(proto::MessageKind::from),
+                }),
+                Self::Mention {
+                    conversation_id,
+                    content,
+                    sent_at,
+                    silent,
+                } => Op::Mention(proto::MentionEvent {
+                    conversation_id,
+                    content,
+                    sent_at: timestamp_from_datetime(sent_at),
+                    silent,
+                }),
+                Self::ChooseePresence {
+                    conversation_id,
+                    leaving,
+                    occurred_at,
+                } => Op::ChooseePresence(proto::ChooseePresenceEvent {
+                    conversation_id,
+                    leaving,
+                    occurred_at: timestamp_from_datetime(occurred_at),
+                }),
+                Self::ConversationRollover {
+                    conversation_id,
+                    successor_conversation_id,
+                } => Op::ConversationRollover(proto::ConversationRolloverEvent {
+                    conversation_id,
+                    successor_conversation_id,
+                }),
+                Self::ConnectionStatus { degraded } => {
+                    Op::ConnectionStatus(proto::ConnectionStatusEvent { degraded })
+                }
+                Self::FriendRemoved { username } => {
+                    Op::FriendRemoved(proto::FriendRemovedEvent { username })
+                }
+                Self::AccountDeleted => Op::AccountDeleted(proto::AccountDeletedEvent {}),
+                Self::Announcement {
+                    title,
+                    body,
+                    level,
+                    sent_at,
+                } => Op::Announcement(proto::AnnouncementEvent {
+                    body,
+                    sent_at: timestamp_from_datetime(sent_at),
+                    title,
+                    level: proto::AnnouncementLevel::from(level) as i32,
+                }),
+                Self::DisappearingChanged {
+                    conversation_id,
+                    ttl_seconds,
+                    changed_at,
+                } => Op::DisappearingChanged(proto::DisappearingChangedEvent {
+                    conversation_id,
+                    ttl_seconds,
+                    changed_at: timestamp_from_datetime(changed_at),
+                }),
+                Self::AvatarChanged {
+                    username,
+                    object_key,
+                } => Op::AvatarChanged(proto::AvatarChangedEvent {
+                    username,
+                    object_key,
+                }),
+                Self::MessagePinned {
+                    conversation_id,
+                    sent_at,
+                    pinned,
+                    pinned_at,
+                } => Op::MessagePinned(proto::MessagePinnedEvent {
+                    conversation_id,
+                    sent_at: timestamp_from_datetime(sent_at),
+                    pinned,
+                    pinned_at: timestamp_from_datetime(pinned_at),
+                }),
+                Self::NotificationPrefsChanged {
+                    conversation_id,
+                    prefs,
+                } => Op::NotificationPrefsChanged(proto::NotificationPrefsChangedEvent {
+                    conversation_id,
+                    prefs: Some(prefs.into()),
+                }),
+                Self::SelfSync { origin, action } => Op::SelfSync(proto::SelfSyncEvent {
+                    origin,
+                    action: Some(action.into()),
+                }),
+                Self::KeyDistribution {
+                    conversation_id,
+                    key_material,
+                    sent_at,
+                } => Op::KeyDistribution(proto::KeyDistributionEvent {
+                    conversation_id,
+                    key_material,
+                    sent_at: timestamp_from_datetime(sent_at),
+                }),
+                Self::StreakMilestone {
+                    conversation_id,
+                    streak,
+                    reached_at,
+                } => Op::StreakMilestone(proto::StreakMilestoneEvent {
+                    conversation_id,
+                    streak,
+                    reached_at: timestamp_from_datetime(reached_at),
+                }),
+                Self::Digest {
+                    unread_conversations,
+                    pending_friend_requests,
+                    since,
+                } => Op::Digest(proto::DigestEvent {
+                    unread_conversations,
+                    pending_friend_requests,
+                    since: timestamp_from_datetime(since),
+                }),
+                Self::PollUpdate {
+                    conversation_id,
+                    poll,
+                } => Op::PollUpdate(proto::PollUpdateEvent {
+                    conversation_id,
+                    poll: Some(poll.into()),
+                }),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for UserEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self).unwrap())
+    }
+}
+
+impl From<SyncAction> for proto::self_sync_event::Action {
+    fn from(action: SyncAction) -> Self {
+        use proto::self_sync_event::Action;
+
+        match action {
+            SyncAction::Chose {
+                conversation_id,
+                choosee_username,
+                content,
+                sent_at,
+            } => Action::Chose(proto::ChoseSync {
+                conversation_id,
+                choosee_username,
+                content,
+                sent_at: timestamp_from_datetime(sent_at),
+            }),
+            SyncAction::Sent {
+                conversation_id,
+                content,
+                sent_at,
+                attachment,
+                kind,
+            } => Action::Sent(proto::SentSync {
+                conversation_id,
+                content,
+                sent_at: timestamp_from_datetime(sent_at),
+                attachment: attachment.map(proto::Attachment::from),
+                kind: kind.map(proto::MessageKind::from),
+            }),
+            SyncAction::ConversationPinned {
+                conversation_id,
+                pinned,
+            } => Action::ConversationPinned(proto::ConversationPinnedSync {
+                conversation_id,
+                pinned,
+            }),
+            SyncAction::ConversationArchived {
+                conversation_id,
+                archived,
+            } => Action::ConversationArchived(proto::ConversationArchivedSync {
+                conversation_id,
+                archived,
+            }),
+            SyncAction::ConversationCleared {
+                conversation_id,
+                cleared_before,
+            } => Action::ConversationCleared(proto::ConversationClearedSync {
+                conversation_id,
+                cleared_before: timestamp_from_datetime(cleared_before),
+            }),
+        }
+    }
+}
+
+impl From<AnnouncementLevel> for proto::AnnouncementLevel {
+    fn from(level: AnnouncementLevel) -> Self {
+        match level {
+            AnnouncementLevel::Info => Self::Info,
+            AnnouncementLevel::Warning => Self::Warning,
+            AnnouncementLevel::Critical => Self::Critical,
+        }
+    }
 }
@@ -0,0 +1,96 @@
+use futures_util::{stream::SplitSink, SinkExt};
+use std::io;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::{protocol::CloseFrame, Message};
+
+use super::close_reason::CloseReason;
+use super::encoding::Encoding;
+use super::operation_loop::response::Response;
+use super::recorder::{Direction, Recorder};
+use super::user_event::UserEvent;
+use crate::transport::Transport;
+
+// every frame written to the user goes through here so there's one place to hook outbound traffic
+
+pub struct UserTx {
+    sink: Mutex<SplitSink<WebSocketStream<Transport>, Message>>,
+    recorder: Option<Arc<Recorder>>,
+    encoding: Encoding,
+    connection_id: String,
+    send_timeout: Duration, // a client that takes longer than this to accept a frame is a slow consumer
+    slow: AtomicBool,
+}
+
+impl UserTx {
+    pub fn new(
+        sink: SplitSink<WebSocketStream<Transport>, Message>,
+        recorder: Option<Arc<Recorder>>,
+        encoding: Encoding,
+        connection_id: String,
+        send_timeout: Duration,
+    ) -> Self {
+        Self {
+            sink: Mutex::new(sink),
+            recorder,
+            encoding,
+            connection_id,
+            send_timeout,
+            slow: AtomicBool::new(false),
+        }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn connection_id(&self) -> &str {
+        &self.connection_id
+    }
+
+    pub async fn send_response(&self, response: &Response) -> Result<(), tungstenite::Error> {
+        self.send(response.to_message(self.encoding)).await
+    }
+
+    pub async fn send_user_event(&self, user_event: &UserEvent) -> Result<(), tungstenite::Error> {
+        self.send(user_event.to_message(self.encoding)).await
+    }
+
+    // the connection id goes in the reason so a user's report can be matched up with the server logs
+    pub async fn close(&self, reason: CloseReason) -> Result<(), tungstenite::Error> {
+        self.send(Message::Close(Some(CloseFrame {
+            code: reason.code(),
+            reason: format!("{} [{}]", reason.reason(), self.connection_id).into(),
+        })))
+        .await
+    }
+
+    // whether a send timed out, in which case the connection ends with whatever error that caused
+    pub fn is_slow(&self) -> bool {
+        self.slow.load(Ordering::Relaxed)
+    }
+
+    pub async fn send(&self, message: Message) -> Result<(), tungstenite::Error> {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(Direction::Outbound, &message);
+        }
+
+        // otherwise events pile up behind a client that stopped reading, and every task sending to it waits forever
+        match tokio::time::timeout(self.send_timeout, self.sink.lock().await.send(message)).await {
+            Ok(result) => result,
+            Err(_) => {
+                self.slow.store(true, Ordering::Relaxed);
+
+                Err(tungstenite::Error::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out sending to a slow consumer",
+                )))
+            }
+        }
+    }
+}
@@ -1,8 +1,32 @@
-use chrono::prelude::*;
+use chrono::{prelude::*, Duration, DurationRound};
+use rand::{distributions::Alphanumeric, Rng};
+use std::fmt;
+use thiserror::Error;
 
-use crate::hash;
+use crate::hash::Hasher;
+
+// chooser hash, choosee hash, the hour window the conversation was created in and a random part, separated by dots.
+// hashes are base64 or base64url so they never contain dots themselves. the random part means two chooses never share an id, even
+// by the same pair in the same hour
+//
+// ids from before that are the two hashes and a time segment run together, which are still in the database and held
+// by clients, so they're parsed too and written back out exactly as they came. their time segment is the year's last
+// two digits then the month, day and hour without zero padding, so the window is only a best guess, see
+// legacy_window
+
+const WINDOW_FORMAT: &str = "%Y%m%d%H";
+
+const NONCE_LENGTH: usize = 12;
+
+const LEGACY_HASH_LENGTH: usize = 22;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct ConversationId {
-    inner: String,
+    chooser_hash: String,
+    choosee_hash: String,
+    window: DateTime<Utc>,               // top of the hour
+    nonce: String,                       // empty for legacy ids
+    legacy_time_segment: Option<String>, // only set for legacy ids
 }
 
 #[derive(PartialEq)]
@@ -12,55 +36,206 @@ pub enum ConversationRole {
     NotInConversation,
 }
 
-// for added security could append secret string to username before hashing
+#[derive(Debug, Error)]
+pub enum ConversationIdError {
+    #[error("Conversation id must have 4 parts separated by dots, or be a legacy id")]
+    Malformed,
+    #[error("Conversation id has an invalid {0}")]
+    InvalidPart(&'static str),
+}
 
 impl ConversationId {
-    pub fn new(chooser_username: String, choosee_username: String) -> Self {
-        let chooser_hash = hash::base64_encoded_md5_hash_with_secret(chooser_username);
-
-        let choosee_hash = hash::base64_encoded_md5_hash_with_secret(choosee_username);
-
-        let now: DateTime<Utc> = DateTime::default();
-
-        let time_segment = (now.year() % 100).to_string() // basically an hour id
-            + &now.month().to_string()
-            + &now.day().to_string()
-            + &now.hour().to_string();
-
-        ConversationId {
-            inner: chooser_hash + &choosee_hash + &time_segment,
+    pub fn new(hasher: &Hasher, chooser_username: &str, choosee_username: &str) -> Self {
+        Self {
+            chooser_hash: hasher.hash(chooser_username),
+            choosee_hash: hasher.hash(choosee_username),
+            window: Self::window_at(Utc::now()),
+            nonce: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(NONCE_LENGTH)
+                .map(char::from)
+                .collect(),
+            legacy_time_segment: None,
         }
     }
 
-    pub fn from(string: String) -> Self {
-        Self { inner: string }
+    pub fn window_at(at: DateTime<Utc>) -> DateTime<Utc> {
+        at.duration_trunc(Duration::hours(1))
+            .expect("An hour should always fit in a timestamp")
     }
 
-    pub fn to_string(&self) -> String {
-        self.inner.clone()
+    pub fn is_current(&self) -> bool {
+        self.window == Self::window_at(Utc::now())
     }
 
-    pub fn get_role_of_username(&self, username: &str) -> ConversationRole {
-        let chooser_hash = self.get_chooser_hash();
-
-        let choosee_hash = self.get_choosee_hash();
-
-        let username_hash = hash::base64_encoded_md5_hash_with_secret(username.to_owned());
+    pub fn get_role_of_username(&self, hasher: &Hasher, username: &str) -> ConversationRole {
+        let username_hashes = hasher.hashes(username);
 
-        if chooser_hash == username_hash {
+        if username_hashes.contains(&self.chooser_hash) {
             ConversationRole::Chooser
-        } else if choosee_hash == username_hash {
+        } else if username_hashes.contains(&self.choosee_hash) {
             ConversationRole::Choosee
         } else {
             ConversationRole::NotInConversation
         }
     }
 
+    pub fn get_window(&self) -> DateTime<Utc> {
+        self.window
+    }
+
     pub fn get_chooser_hash(&self) -> &str {
-        &self.inner[0..22]
+        &self.chooser_hash
     }
 
     pub fn get_choosee_hash(&self) -> &str {
-        &self.inner[22..44]
+        &self.choosee_hash
+    }
+}
+
+impl fmt::Display for ConversationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(legacy_time_segment) = &self.legacy_time_segment {
+            return write!(
+                f,
+                "{}{}{}",
+                self.chooser_hash, self.choosee_hash, legacy_time_segment
+            );
+        }
+
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.chooser_hash,
+            self.choosee_hash,
+            self.window.format(WINDOW_FORMAT),
+            self.nonce
+        )
     }
 }
+
+impl TryFrom<String> for ConversationId {
+    type Error = ConversationIdError;
+
+    fn try_from(string: String) -> Result<Self, Self::Error> {
+        if !string.contains('.') {
+            return Self::parse_legacy(&string);
+        }
+
+        let [chooser_hash, choosee_hash, window, nonce] = string
+            .split('.')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| ConversationIdError::Malformed)?;
+
+        if !is_hash(chooser_hash) {
+            return Err(ConversationIdError::InvalidPart("chooser hash"));
+        }
+
+        if !is_hash(choosee_hash) {
+            return Err(ConversationIdError::InvalidPart("choosee hash"));
+        }
+
+        // chrono is lenient about whitespace and field widths, which would let the same conversation go by several ids
+        if window.len() != 10 || !window.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ConversationIdError::InvalidPart("window"));
+        }
+
+        // chrono won't parse a time without minutes
+        let window = NaiveDateTime::parse_from_str(
+            &format!("{}00", window),
+            &format!("{}%M", WINDOW_FORMAT),
+        )
+        .map(|window| DateTime::<Utc>::from_utc(window, Utc))
+        .map_err(|_| ConversationIdError::InvalidPart("window"))?;
+
+        if nonce.len() != NONCE_LENGTH || !nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(ConversationIdError::InvalidPart("random part"));
+        }
+
+        Ok(Self {
+            chooser_hash: chooser_hash.to_owned(),
+            choosee_hash: choosee_hash.to_owned(),
+            window,
+            nonce: nonce.to_owned(),
+            legacy_time_segment: None,
+        })
+    }
+}
+
+impl ConversationId {
+    fn parse_legacy(string: &str) -> Result<Self, ConversationIdError> {
+        // hashes were always cut to the same length, and are ascii when valid
+        if string.len() <= 2 * LEGACY_HASH_LENGTH || !string.is_ascii() {
+            return Err(ConversationIdError::Malformed);
+        }
+
+        let (chooser_hash, rest) = string.split_at(LEGACY_HASH_LENGTH);
+        let (choosee_hash, time_segment) = rest.split_at(LEGACY_HASH_LENGTH);
+
+        if !is_hash(chooser_hash) {
+            return Err(ConversationIdError::InvalidPart("chooser hash"));
+        }
+
+        if !is_hash(choosee_hash) {
+            return Err(ConversationIdError::InvalidPart("choosee hash"));
+        }
+
+        let window = Self::legacy_window(time_segment)
+            .ok_or(ConversationIdError::InvalidPart("time segment"))?;
+
+        Ok(Self {
+            chooser_hash: chooser_hash.to_owned(),
+            choosee_hash: choosee_hash.to_owned(),
+            window,
+            nonce: String::new(),
+            legacy_time_segment: Some(time_segment.to_owned()),
+        })
+    }
+
+    // "2411213" could be the 2nd of november or the 12th of january, so of the readings that are a real hour and
+    // not in the future this takes the latest. only rollovers and predecessors look at the window, and legacy ids
+    // are all long past either way
+    fn legacy_window(time_segment: &str) -> Option<DateTime<Utc>> {
+        if !(5..=8).contains(&time_segment.len())
+            || !time_segment.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let year = 2000 + time_segment[..2].parse::<i32>().ok()?;
+        let rest = &time_segment[2..];
+
+        // never zero padded, so only a lone 0 may start with one
+        let number = |digits: &str| {
+            (digits.len() == 1 || !digits.starts_with('0')).then(|| digits.parse::<u32>().ok())?
+        };
+
+        let now = Utc::now();
+
+        (1..=2)
+            .flat_map(|month_length| (1..=2).map(move |day_length| (month_length, day_length)))
+            .filter_map(|(month_length, day_length)| {
+                let hour_length = rest.len().checked_sub(month_length + day_length)?;
+
+                if !(1..=2).contains(&hour_length) {
+                    return None;
+                }
+
+                let month = number(&rest[..month_length])?;
+                let day = number(&rest[month_length..month_length + day_length])?;
+                let hour = number(&rest[month_length + day_length..])?;
+
+                Utc.with_ymd_and_hms(year, month, day, hour, 0, 0).single()
+            })
+            .filter(|window| *window <= now)
+            .max()
+    }
+}
+
+fn is_hash(hash: &str) -> bool {
+    !hash.is_empty()
+        && hash
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '-' | '_'))
+}
@@ -1,695 +1,580 @@
-use chrono::{prelude::*, Duration};
-use futures_util::FutureExt;
-use scylla::prepared_statement::PreparedStatement;
-use std::sync::Arc;
+use ::scylla::{
+    cql_to_rust::FromRowError,
+    transport::errors::{DbError, QueryError},
+};
+use async_trait::async_trait;
+use chrono::prelude::*;
+use std::time::Duration;
 use thiserror::Error;
 
-use crate::models::{friend_profile::FriendProfile, message::Message, profile::Profile};
-
-const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
-
-pub struct Database {
-    db: Arc<scylla::Session>,
-    new_conversation_query: PreparedStatement,
-    new_message_query: PreparedStatement,
-    update_choosee_last_presence_at_query: PreparedStatement,
-    get_messages_query: PreparedStatement,
-    add_friend_request_on_sender_query: PreparedStatement,
-    add_friend_request_on_receiver_query: PreparedStatement,
-    get_friends_of_user_query: PreparedStatement,
-    remove_friend_request_on_sender_query: PreparedStatement,
-    remove_friend_request_on_receiver_query: PreparedStatement,
-    add_friend_query: PreparedStatement,
-    add_friends_of_friends_query: PreparedStatement,
-    remove_friend_query: PreparedStatement,
-    remove_friends_of_friends_query: PreparedStatement,
-}
-
-#[derive(Debug, Error)]
-#[error("{0}")]
-pub struct DatabaseError(String);
-
-impl Database {
-    pub async fn build(
-        known_node_hostname: &str,
-        username: &str,
-        password: &str,
-        keyspace: &str,
-    ) -> Result<Self, scylla::transport::errors::NewSessionError> {
-        let db = Arc::new(
-            scylla::SessionBuilder::new()
-                .known_node(known_node_hostname)
-                .user(username, password)
-                .use_keyspace(keyspace, true)
-                .build()
-                .await?,
-        );
-
-        let new_conversation_query = Self::prepare_new_conversation_query(&db).await;
-
-        let new_message_query = Self::prepare_new_message_query(&db).await;
-
-        let update_choosee_last_presence_at_query =
-            Self::prepare_update_choosee_last_presence_at_query(&db).await;
-
-        let get_messages_query = Self::prepare_get_messages_query(&db).await;
-
-        let add_friend_request_on_sender_query =
-            Self::prepare_add_friend_request_on_sender_query(&db).await;
-
-        let get_friends_of_user_query = Self::prepare_get_friends_of_user_query(&db).await;
-
-        let add_friend_request_on_receiver_query =
-            Self::prepare_add_friend_request_on_receiver_query(&db).await;
-
-        let remove_friend_request_on_sender_query =
-            Self::prepare_remove_friend_request_on_sender_query(&db).await;
-
-        let remove_friend_request_on_receiver_query =
-            Self::prepare_remove_friend_request_on_receiver_query(&db).await;
-
-        let add_friend_query = Self::prepare_add_friend_query(&db).await;
-
-        let add_friends_of_friends_query = Self::prepare_add_friends_of_friends_query(&db).await;
-
-        let remove_friend_query = Self::prepare_remove_friend_query(&db).await;
-
-        let remove_friends_of_friends_query =
-            Self::prepare_remove_friends_of_friends_query(&db).await;
-
-        Ok(Database {
-            db,
-            new_conversation_query,
-            new_message_query,
-            update_choosee_last_presence_at_query,
-            get_messages_query,
-            add_friend_request_on_sender_query,
-            add_friend_request_on_receiver_query,
-            get_friends_of_user_query,
-            remove_friend_request_on_sender_query,
-            remove_friend_request_on_receiver_query,
-            add_friend_query,
-            add_friends_of_friends_query,
-            remove_friend_query,
-            remove_friends_of_friends_query,
-        })
-    }
-
-    async fn prepare_new_conversation_query(db: &scylla::Session) -> PreparedStatement {
-        let mut new_conversation_query = db.prepare("INSERT INTO conversation (chooser_username, choosee_username, chooser_name, choosee_name, id, created_at) values (?, ?, ?, ?, ?, ?)").await.expect("New conversation prepared query failed");
-        new_conversation_query.set_is_idempotent(true);
-        new_conversation_query
-    }
-
-    pub async fn new_conversation(
+use crate::error::ErrorCategory;
+use crate::models::{
+    audit_entry::AuditEntry, bot_key::BotKey, conversation_stats::ConversationStats,
+    conversation_summary::ConversationSummary, device::Device, failed_event::FailedEvent,
+    friend_profile::FriendProfile, job_status::JobStatus, key_distribution::KeyDistribution,
+    mention::Mention, message::Message, notification_prefs::NotificationPrefs,
+    outbox_entry::OutboxEntry, pinned_message::PinnedMessage, poll::Poll,
+    presence_event::PresenceEvent, profile::Profile, push_token::PushToken, report::Report,
+    user_conversation::UserConversation,
+};
+
+mod content_keys;
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod scylla;
+
+pub use self::content_keys::{ContentKeyError, ContentKeys};
+pub use self::memory::MemoryStorage;
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStorage;
+#[cfg(feature = "scylla-tls")]
+pub use self::scylla::TlsOptions;
+pub use self::scylla::{
+    CoalescerOptions, Consistencies, KeyspaceOptions, Replication, ScyllaStorage, SessionOptions,
+};
+
+// everything the gateway persists goes through this, so small deployments can run on postgres instead of a scylla
+// cluster. STORAGE picks the backend in Init
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn new_conversation(
         &self,
         chooser_username: &str,
         choosee_username: &str,
         chooser_name: &str,
         choosee_name: &str,
         conversation_id: &str,
-    ) -> Result<(), DatabaseError> {
-        self.db
-            .execute(
-                &self.new_conversation_query,
-                (
-                    chooser_username,
-                    choosee_username,
-                    chooser_name,
-                    choosee_name,
-                    conversation_id.to_string(),
-                    Self::current_timestamp(),
-                ),
-            )
-            .await
-            .map(|_| ())
-            .map_err(|err| DatabaseError(format!("Error creating new conversation: {}", err)))
-    }
-
-    async fn prepare_new_message_query(db: &scylla::Session) -> PreparedStatement {
-        let mut get_messages_query = db
-            .prepare(
-                "INSERT INTO conversation (conversation_id, content, sent_at, from_chooser) VALUES (?, ?, ?, ?)",
-            )
-            .await
-            .expect("Get messages prepared query failed");
-        get_messages_query.set_is_idempotent(true);
-        get_messages_query
-    }
+        created_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
 
-    pub async fn new_message(
+    // sent_at comes from the gateway so the stored message matches the event and the ack the client got. also counts
+    // the message towards the conversation's stats and the sender's for the day, and returns the conversation's streak
+    // when the message extended it
+    async fn new_message(
         &self,
         conversation_id: &str,
-        content: &str,
-        from_chooser: bool,
-    ) -> Result<(), DatabaseError> {
-        self.db
-            .execute(
-                &self.new_message_query,
-                (
-                    conversation_id,
-                    content,
-                    Self::current_timestamp(),
-                    from_chooser,
-                ),
-            )
-            .await
-            .map(|_| ())
-            .map_err(|err| DatabaseError(format!("Error creating new message: {}", err)))
-    }
+        sender_username: &str,
+        message: &Message,
+    ) -> Result<Option<u32>, DatabaseError>;
 
-    async fn prepare_update_choosee_last_presence_at_query(
-        db: &scylla::Session,
-    ) -> PreparedStatement {
-        let mut update_choosee_last_presence_at_query = db
-            .prepare("INSERT INTO choosee_presence (conversation_id, occurred_at, leaving, chooser_username) VALUES (?, ?, ?, ?)")
-            .await
-            .expect("Update choosee last presence prepared query failed");
-        update_choosee_last_presence_at_query.set_is_idempotent(true);
-        update_choosee_last_presence_at_query
-    }
+    // messages sent afterwards expire this long after being sent, or not at all when None. earlier ones keep whatever
+    // they were sent with
+    async fn set_disappearing(
+        &self,
+        conversation_id: &str,
+        ttl: Option<Duration>,
+    ) -> Result<(), DatabaseError>;
 
-    pub async fn update_choosee_last_presence_at(
+    // the choosee only knows the chooser by the hash in the conversation id
+    async fn update_choosee_last_presence_at(
         &self,
         conversation_id: &str,
         occurred_at: DateTime<Utc>,
         leaving: bool,
-        chooser_username: &str,
-    ) -> Result<(), DatabaseError> {
-        self.db
-            .execute(
-                &self.update_choosee_last_presence_at_query,
-                (
-                    conversation_id,
-                    Self::timestamp_from_datetime(occurred_at),
-                    leaving,
-                    chooser_username,
-                ),
-            )
-            .await
-            .map(|_| ())
-            .map_err(|err| {
-                DatabaseError(format!("Error updating choosee_last_presence_at: {}", err))
-            })
-    }
-
-    async fn prepare_get_messages_query(db: &scylla::Session) -> PreparedStatement {
-        let mut get_messages_query = db
-            .prepare(
-                "SELECT content, sent_at, from_chooser FROM message WHERE conversation_id = ? AND sent_at > ? LIMIT ?",
-            )
-            .await
-            .expect("Get messages prepared query failed");
-        get_messages_query.set_is_idempotent(true);
-        get_messages_query
-    }
+        chooser_hash: &str,
+    ) -> Result<(), DatabaseError>;
 
-    pub async fn get_messages(
+    // leaves out whatever the user cleared from the conversation
+    async fn get_messages(
         &self,
         conversation_id: &str,
+        username: &str,
         take: i8,
         after_sent_at: DateTime<Utc>,
-    ) -> Result<Vec<Message>, DatabaseError> {
-        let mut message_vec = Vec::<Message>::new();
-
-        for row in self
-            .db
-            .execute(
-                &self.get_messages_query,
-                (
-                    conversation_id,
-                    Self::timestamp_from_datetime(after_sent_at),
-                    take,
-                ),
-            )
-            .await
-            .map_err(|err| DatabaseError(format!("Error getting messages: {}", err)))?
-            .rows_typed_or_empty::<(String, Duration, bool)>()
-        {
-            let row =
-                row.map_err(|err| DatabaseError(format!("Error getting messages: {}", err)))?;
-
-            message_vec.push(Message {
-                content: row.0,
-                sent_at: Self::datetime_from_timestamp(row.1),
-                from_chooser: row.2,
-            });
-        }
+    ) -> Result<Vec<Message>, DatabaseError>;
 
-        Ok(message_vec)
-    }
-
-    async fn prepare_add_friend_request_on_sender_query(db: &scylla::Session) -> PreparedStatement {
-        let mut add_friend_request_on_sender_query = db.prepare("UPDATE user SET friend_requests_sent = friend_requests_sent + { ? } WHERE username = ?").await.expect("Add friend request on sender prepared query failed");
-        add_friend_request_on_sender_query.set_is_idempotent(true);
-        add_friend_request_on_sender_query
-    }
+    // most recent first
+    async fn get_choosee_presence(
+        &self,
+        conversation_id: &str,
+        take: i8,
+    ) -> Result<Vec<PresenceEvent>, DatabaseError>;
 
-    async fn prepare_add_friend_request_on_receiver_query(
-        db: &scylla::Session,
-    ) -> PreparedStatement {
-        let mut add_friend_request_on_receiver_query = db.prepare("UPDATE user SET friend_requests_received = friend_requests_received + { ? } WHERE username = ?").await.expect("Add friend request on sender prepared query failed");
-        add_friend_request_on_receiver_query.set_is_idempotent(true);
-        add_friend_request_on_receiver_query
-    }
+    // friend requests are made through the api for now, these are for when the gateway takes them over
+    #[allow(dead_code)]
+    async fn create_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError>;
 
-    pub async fn create_friend_request(
+    #[allow(dead_code)]
+    async fn delete_friend_request(
         &self,
         sender: Profile,
         receiver: Profile,
-    ) -> Result<(), DatabaseError> {
-        let sender_username_clone = sender.username.clone();
-        let receiver_username_clone = receiver.username.clone();
-
-        let (sender_result, receiver_result) = tokio::join!(
-            self.db.execute(
-                &self.add_friend_request_on_sender_query,
-                (receiver, sender_username_clone),
-            ),
-            self.db.execute(
-                &self.add_friend_request_on_receiver_query,
-                (sender, receiver_username_clone),
-            )
-        );
-
-        sender_result.map_err(|err| {
-            DatabaseError(format!(
-                "Error adding friend requestee username to requester: {}",
-                err
-            ))
-        })?;
-
-        receiver_result.map_err(|err| {
-            DatabaseError(format!(
-                "Error adding friend requester username to requestee: {}",
-                err
-            ))
-        })?;
-
-        Ok(())
-    }
+    ) -> Result<(), DatabaseError>;
 
-    async fn prepare_remove_friend_request_on_sender_query(
-        db: &scylla::Session,
-    ) -> PreparedStatement {
-        let mut remove_friend_request_on_sender_query = db.prepare("UPDATE user SET friend_requests_sent = friend_requests_sent - { ? } WHERE username = ?").await.expect("Remove friend request on sender prepared query failed");
-        remove_friend_request_on_sender_query.set_is_idempotent(true);
-        remove_friend_request_on_sender_query
-    }
+    // the ones still waiting on an answer. when they were sent isn't kept
+    async fn get_friend_requests_received(
+        &self,
+        username: &str,
+    ) -> Result<Vec<Profile>, DatabaseError>;
 
-    async fn prepare_remove_friend_request_on_receiver_query(
-        db: &scylla::Session,
-    ) -> PreparedStatement {
-        let mut remove_friend_request_on_receiver_query = db.prepare("UPDATE user SET friend_requests_received = friend_requests_received - { ? } WHERE username = ?").await.expect("Remove friend request on sender prepared query failed");
-        remove_friend_request_on_receiver_query.set_is_idempotent(true);
-        remove_friend_request_on_receiver_query
-    }
+    async fn get_friends(&self, username: &str) -> Result<Vec<FriendProfile>, DatabaseError>;
+
+    // None when there's no user with the username
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, DatabaseError>;
 
-    pub async fn delete_friend_request(
+    // in username order
+    async fn search_users(&self, prefix: &str, take: i8) -> Result<Vec<Profile>, DatabaseError>;
+
+    async fn set_avatar(&self, username: &str, object_key: &str) -> Result<(), DatabaseError>;
+
+    // pinned first, then newest first
+    async fn get_conversations(
         &self,
-        sender: Profile,
-        receiver: Profile,
-    ) -> Result<(), DatabaseError> {
-        let sender_username_clone = sender.username.clone();
-        let receiver_username_clone = receiver.username.clone();
-
-        let (sender_result, receiver_result) = tokio::join!(
-            self.db.execute(
-                &self.remove_friend_request_on_sender_query,
-                (receiver, sender_username_clone),
-            ),
-            self.db.execute(
-                &self.remove_friend_request_on_receiver_query,
-                (sender, receiver_username_clone),
-            )
-        );
-
-        sender_result.map_err(|err| {
-            DatabaseError(format!(
-                "Error removing friend requestee username from requester: {}",
-                err
-            ))
-        })?;
-
-        receiver_result.map_err(|err| {
-            DatabaseError(format!(
-                "Error removing friend requester username from requestee: {}",
-                err
-            ))
-        })?;
-
-        Ok(())
-    }
+        username: &str,
+    ) -> Result<Vec<ConversationSummary>, DatabaseError>;
 
-    async fn prepare_get_friends_of_user_query(db: &scylla::Session) -> PreparedStatement {
-        let mut get_friends_of_user_query = db
-            .prepare("SELECT friends FROM user WHERE username = ?")
-            .await
-            .expect("Get friends of user prepared query failed");
-        get_friends_of_user_query.set_is_idempotent(true);
-        get_friends_of_user_query
-    }
+    // the default when the user hasn't set anything on the conversation
+    async fn get_user_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+    ) -> Result<UserConversation, DatabaseError>;
 
-    pub async fn get_friends(&self, username: &str) -> Result<Vec<FriendProfile>, DatabaseError> {
-        let mut friend_vec = Vec::<FriendProfile>::new();
+    async fn set_conversation_pinned(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        pinned: bool,
+    ) -> Result<(), DatabaseError>;
 
-        for row in self
-            .db
-            .execute(&self.get_friends_of_user_query, (username,))
-            .await
-            .map_err(|err| DatabaseError(format!("Error get friends of user: {}", err)))?
-            .rows_typed_or_empty::<(FriendProfile,)>()
-        {
-            let row =
-                row.map_err(|err| DatabaseError(format!("Error get friends of user: {}", err)))?;
+    // archived conversations are left out of the conversation list, nothing about their messages changes
+    async fn set_conversation_archived(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        archived: bool,
+    ) -> Result<(), DatabaseError>;
 
-            friend_vec.push(row.0);
-        }
+    // hides messages sent before cleared_before from this user only. the other user still has them
+    async fn clear_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        cleared_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
 
-        Ok(friend_vec)
-    }
+    async fn set_conversation_notification_prefs(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        muted: bool,
+        mentions_only: bool,
+    ) -> Result<(), DatabaseError>;
 
-    async fn prepare_add_friend_query(db: &scylla::Session) -> PreparedStatement {
-        let mut add_friend_query = db
-            .prepare("UPDATE user SET friends = friends + ? WHERE username = ?")
-            .await
-            .expect("Add friend prepared query failed");
-        add_friend_query.set_is_idempotent(true);
-        add_friend_query
-    }
+    // the default when the user hasn't set any
+    async fn get_notification_prefs(
+        &self,
+        username: &str,
+    ) -> Result<NotificationPrefs, DatabaseError>;
 
-    async fn prepare_add_friends_of_friends_query(db: &scylla::Session) -> PreparedStatement {
-        let mut add_friends_of_friends_query = db
-            .prepare(
-                "UPDATE user SET friends_of_friends = friends_of_friends + ? WHERE username = ?",
-            )
-            .await
-            .expect("Add friends of friends prepared query failed");
-        add_friends_of_friends_query.set_is_idempotent(true);
-        add_friends_of_friends_query
-    }
+    async fn set_notification_prefs(
+        &self,
+        username: &str,
+        notification_prefs: &NotificationPrefs,
+    ) -> Result<(), DatabaseError>;
+
+    async fn pin_message(
+        &self,
+        conversation_id: &str,
+        pinned_message: &PinnedMessage,
+    ) -> Result<(), DatabaseError>;
+
+    async fn unpin_message(
+        &self,
+        conversation_id: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    // in the order the messages were sent
+    async fn get_pinned_messages(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<PinnedMessage>, DatabaseError>;
+
+    // deleted along with the conversation's messages
+    async fn new_poll(&self, conversation_id: &str, poll: &Poll) -> Result<(), DatabaseError>;
+
+    // replaces the user's earlier vote. does nothing when the poll doesn't exist, so a vote racing the conversation
+    // being deleted can't bring the poll back
+    async fn vote_in_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+        from_chooser: bool,
+        option: u32,
+    ) -> Result<(), DatabaseError>;
+
+    async fn get_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+    ) -> Result<Option<Poll>, DatabaseError>;
+
+    // oldest first
+    async fn get_polls(&self, conversation_id: &str) -> Result<Vec<Poll>, DatabaseError>;
+
+    async fn get_conversation_stats(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationStats, DatabaseError>;
+
+    // kept until the ttl is up, so a participant who was offline can still pick it up. nothing else ever deletes them
+    async fn add_key_distribution(
+        &self,
+        conversation_id: &str,
+        key_distribution: &KeyDistribution,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError>;
 
-    pub async fn create_friendship(
+    // the ones from the chooser or the choosee that haven't expired, oldest first
+    async fn get_key_distributions(
+        &self,
+        conversation_id: &str,
+        from_chooser: bool,
+    ) -> Result<Vec<KeyDistribution>, DatabaseError>;
+
+    // under the hash of the user who was mentioned, since it's all the sender knows about them. kept until the ttl is
+    // up or the user deletes their account
+    async fn add_mention(
+        &self,
+        username_hash: &str,
+        mention: &Mention,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError>;
+
+    // the ones that haven't expired, newest first
+    async fn get_mentions(
+        &self,
+        username_hash: &str,
+        take: i32,
+    ) -> Result<Vec<Mention>, DatabaseError>;
+
+    async fn remove_mentions(&self, username_hash: &str) -> Result<(), DatabaseError>;
+
+    // the username hash is what push tokens are looked up by, since it's all a subject has
+    async fn add_push_token(
+        &self,
+        username_hash: &str,
+        push_token: &PushToken,
+    ) -> Result<(), DatabaseError>;
+
+    async fn get_push_tokens(&self, username_hash: &str) -> Result<Vec<PushToken>, DatabaseError>;
+
+    async fn remove_push_token(
+        &self,
+        username_hash: &str,
+        token: &str,
+    ) -> Result<(), DatabaseError>;
+
+    async fn remove_push_tokens(&self, username_hash: &str) -> Result<(), DatabaseError>;
+
+    // when the device last connected. brings back a logged out device that connected again with a newer token
+    async fn touch_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        seen_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    // when the user last connected, from any device or none in particular
+    async fn touch_user(
+        &self,
+        username: &str,
+        connected_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    // usernames with when they last connected, for users whose last connection was between the two. in pages of take,
+    // after being the last username of the previous page. a page that comes back short is the last one
+    async fn get_away_users(
+        &self,
+        connected_after: DateTime<Utc>,
+        connected_before: DateTime<Utc>,
+        after: Option<&str>,
+        take: i32,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, DatabaseError>;
+
+    // leaves out logged out devices, most recently seen first
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, DatabaseError>;
+
+    // tokens for the device issued before logged_out_at are rejected from then on
+    async fn log_out_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        logged_out_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    async fn get_device_logged_out_at(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError>;
+
+    async fn add_report(&self, report: &Report) -> Result<(), DatabaseError>;
+
+    #[allow(dead_code)]
+    async fn create_friendship(
         &self,
         sender: Profile,
         receiver: Profile,
         receiver_friends: Vec<Profile>,
-    ) -> Result<(), DatabaseError> {
-        let db = self.db.clone();
-        let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
-        let receiver_friends_clone = receiver_friends.clone();
-        let sender_username_clone = sender.username.clone();
-
-        tokio::spawn(async move {
-            db.execute(
-                &add_friends_of_friends_query,
-                (receiver_friends_clone, sender_username_clone),
-            )
-            .await
-        });
-
-        for receiver_friend in receiver_friends.iter() {
-            let db = self.db.clone();
-            let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
-            let sender_clone = sender.clone();
-            let receiver_friend_username = receiver_friend.username.to_owned();
-
-            tokio::spawn(async move {
-                db.execute(
-                    &add_friends_of_friends_query,
-                    (vec![sender_clone], receiver_friend_username),
-                )
-                .await
-            });
-        }
+    ) -> Result<(), DatabaseError>;
 
-        let db = self.db.clone();
-
-        let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
-        let get_friends_of_user_query = self.get_friends_of_user_query.clone();
-
-        let sender_clone = sender.clone();
-        let receiver_clone = receiver.clone();
-
-        tokio::spawn(async move {
-            match db
-                .execute(&get_friends_of_user_query, (&sender_clone.username,))
-                .await
-            {
-                Ok(sender_friends) => {
-                    let sender_friends = sender_friends
-                        .rows_typed_or_empty::<(FriendProfile,)>()
-                        .filter_map(|row| {
-                            row.ok().map(|row| Profile {
-                                username: row.0.username,
-                                name: row.0.name,
-                            })
-                        })
-                        .collect::<Vec<_>>();
-
-                    let db_clone = db.clone();
-                    let add_friends_of_friends_query_clone = add_friends_of_friends_query.clone();
-
-                    let sender_friends_clone = sender_friends.clone();
-                    let receiver_username = receiver_clone.username.clone();
-
-                    tokio::spawn(async move {
-                        db_clone
-                            .execute(
-                                &add_friends_of_friends_query_clone,
-                                (sender_friends_clone, receiver_username),
-                            )
-                            .await
-                    });
-
-                    for sender_friend in sender_friends.iter() {
-                        let db = db.clone();
-                        let add_friends_of_friends_query = add_friends_of_friends_query.clone();
-
-                        let reciever = receiver_clone.clone();
-                        let sender_friend = sender_friend.clone();
-
-                        tokio::spawn(async move {
-                            let _ = db
-                                .execute(
-                                    &add_friends_of_friends_query,
-                                    (vec![reciever], sender_friend),
-                                )
-                                .await;
-                        });
-                    }
-                }
-                Err(_) => return,
+    async fn add_to_outbox(
+        &self,
+        bucket: i32,
+        subject: &str,
+        data: Vec<u8>,
+    ) -> Result<(), DatabaseError>;
+
+    // oldest first
+    async fn get_outbox(&self, bucket: i32) -> Result<Vec<OutboxEntry>, DatabaseError>;
+
+    async fn remove_from_outbox(
+        &self,
+        bucket: i32,
+        created_at: DateTime<Utc>,
+        entry_id: &str,
+    ) -> Result<(), DatabaseError>;
+
+    // moves whatever is left in the outbox table from before it was split into buckets into this bucket, then drops
+    // it. returns how many entries were moved, and does nothing once the table is gone
+    async fn adopt_legacy_outbox(&self, bucket: i32) -> Result<u64, DatabaseError>;
+
+    async fn has_messages(&self, conversation_id: &str) -> Result<bool, DatabaseError>;
+
+    // the conversation the chooser most recently started with the choosee, so the next one can be announced as its
+    // successor. ids have a random part, so it can't be worked out from the two hashes
+    async fn get_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+    ) -> Result<Option<String>, DatabaseError>;
+
+    async fn set_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+        conversation_id: &str,
+    ) -> Result<(), DatabaseError>;
+
+    async fn remove_friend(
+        &self,
+        username: &str,
+        friend_username: &str,
+    ) -> Result<(), DatabaseError>;
+
+    // set elements have to match exactly to be removed, so implementations read back what's stored first
+    async fn remove_friend_of_friend(
+        &self,
+        username: &str,
+        friend_of_friend_username: &str,
+    ) -> Result<(), DatabaseError>;
+
+    // for when one of the users is gone. the conversation stays listed for the other one, its messages and pins don't
+    async fn tombstone_conversation(
+        &self,
+        conversation_id: &str,
+        tombstoned_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    async fn delete_user(&self, username: &str) -> Result<(), DatabaseError>;
+
+    // deletes up to take conversations created before the cutoff along with everything kept for them, other than
+    // reports. the conversation itself goes last, so one that fails partway is found again by the next call. returns
+    // how many were deleted
+    async fn delete_conversations_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        take: i32,
+    ) -> Result<usize, DatabaseError>;
+
+    // sets up to take streaks last extended before the day back to nothing, returns how many
+    async fn end_streaks(&self, before: NaiveDate, take: i32) -> Result<usize, DatabaseError>;
+
+    async fn get_revoked_before(
+        &self,
+        username: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError>;
+
+    async fn revoke_tokens(
+        &self,
+        username: &str,
+        revoked_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    // written by the api, revoked keys are still returned
+    async fn get_bot_key(&self, key_id: &str) -> Result<Option<BotKey>, DatabaseError>;
+
+    // how long messages are kept before they disappear, None when they're kept forever
+    fn message_retention(&self) -> Option<Duration>;
+
+    async fn health_check(&self) -> Result<(), DatabaseError>;
+
+    async fn add_failed_event(&self, failed_event: &FailedEvent) -> Result<(), DatabaseError>;
+
+    async fn get_failed_events(&self) -> Result<Vec<FailedEvent>, DatabaseError>;
+
+    async fn remove_failed_event(
+        &self,
+        subject: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError>;
+
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), DatabaseError>;
+
+    // the entries of one utc day, most recent first
+    async fn get_audit_log(
+        &self,
+        day: NaiveDate,
+        take: i32,
+    ) -> Result<Vec<AuditEntry>, DatabaseError>;
+
+    // overwrites whatever was stored for the job before
+    async fn set_job_status(&self, job_status: &JobStatus) -> Result<(), DatabaseError>;
+}
+
+// removes the user from everything other users can still see of them, then the user themself. each step can run again,
+// so a purge that fails partway can just be retried. username_hashes are every hash their push tokens could be under
+//
+// whoever was only a friend of a friend through the user keeps the other side as one until their friends change again,
+// working that out needs every friendship of everyone involved
+pub async fn purge_user(
+    db: &dyn Storage,
+    username: &str,
+    username_hashes: &[String],
+) -> Result<(), DatabaseError> {
+    let tombstoned_at = Utc::now();
+
+    for friend_profile in db.get_friends(username).await? {
+        db.remove_friend(&friend_profile.username, username).await?;
+
+        db.remove_friend_of_friend(&friend_profile.username, username)
+            .await?;
+
+        // the user was a friend of a friend to everyone their friends are friends with
+        for friend_of_friend in db.get_friends(&friend_profile.username).await? {
+            if friend_of_friend.username != username {
+                db.remove_friend_of_friend(&friend_of_friend.username, username)
+                    .await?;
             }
-        });
-
-        let sender_clone = sender.clone();
-        let receiver_clone = receiver.clone();
-
-        let results = tokio::join!(
-            self.delete_friend_request(sender, receiver),
-            self.db.execute(
-                &self.add_friend_query,
-                (&sender_clone, &receiver_clone.username)
-            ),
-            self.db.execute(
-                &self.add_friend_query,
-                (&receiver_clone, &sender_clone.username)
-            ),
-        );
-
-        results.0?;
-
-        results.1.map_err(|err| {
-            DatabaseError(format!(
-                "Error adding sender username to receiver's friends: {}",
-                err
-            ))
-        })?;
-
-        results.2.map_err(|err| {
-            DatabaseError(format!(
-                "Error adding receiver username to sender's friends: {}",
-                err
-            ))
-        })?;
-
-        Ok(())
+        }
     }
 
-    async fn prepare_remove_friend_query(db: &scylla::Session) -> PreparedStatement {
-        let mut remove_friend_query = db
-            .prepare("UPDATE user SET friends = friends - ? WHERE username = ?")
-            .await
-            .expect("Remove friend prepared query failed");
-        remove_friend_query.set_is_idempotent(true);
-        remove_friend_query
+    for conversation in db.get_conversations(username).await? {
+        db.tombstone_conversation(&conversation.conversation_id, tombstoned_at)
+            .await?;
     }
 
-    async fn prepare_remove_friends_of_friends_query(db: &scylla::Session) -> PreparedStatement {
-        let mut remove_friends_of_friends_query = db
-            .prepare(
-                "UPDATE user SET friends_of_friends = friends_of_friends - ? WHERE username IN ?",
-            )
-            .await
-            .expect("Add friends of friends prepared query failed");
-        remove_friends_of_friends_query.set_is_idempotent(true);
-        remove_friends_of_friends_query
+    for username_hash in username_hashes {
+        db.remove_push_tokens(username_hash).await?;
+
+        db.remove_mentions(username_hash).await?;
     }
 
-    async fn delete_friendship(
-        &self,
-        deleter: Profile,
-        other: Profile,
-        deleter_friends: Vec<Profile>,
-    ) -> Result<(), DatabaseError> {
-        let db = self.db.clone();
-        let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
-        let deleter_friends_clone = deleter_friends.clone();
-        let other_username_clone = other.username.clone();
-
-        tokio::spawn(async move {
-            db.execute(
-                &add_friends_of_friends_query,
-                (deleter_friends_clone, other_username_clone),
-            )
-            .await
-        });
-
-        for deleter_friend in deleter_friends.iter() {
-            let db = self.db.clone();
-            let remove_friends_of_friends_query = self.remove_friends_of_friends_query.clone();
-            let other_clone = other.clone();
-            let deleter_friend_username = deleter_friend.username.to_owned();
-
-            tokio::spawn(async move {
-                db.execute(
-                    &remove_friends_of_friends_query,
-                    (vec![other_clone], deleter_friend_username),
-                )
-                .await
-            });
-        }
+    db.delete_user(username).await
+}
 
-        let db = self.db.clone();
-
-        let remove_friends_of_friends_query = self.remove_friends_of_friends_query.clone();
-        let get_friends_of_user_query = self.get_friends_of_user_query.clone();
-
-        let deleter_clone = deleter.clone();
-        let other_clone = other.clone();
-
-        tokio::spawn(async move {
-            match db
-                .execute(&get_friends_of_user_query, (&other_clone.username,))
-                .await
-            {
-                Ok(other_friends) => {
-                    let other_friends = other_friends
-                        .rows_typed_or_empty::<(FriendProfile,)>()
-                        .filter_map(|row| {
-                            row.ok().map(|row| Profile {
-                                username: row.0.username,
-                                name: row.0.name,
-                            })
-                        })
-                        .collect::<Vec<_>>();
-
-                    let db_clone = db.clone();
-                    let remove_friends_of_friends_query_clone =
-                        remove_friends_of_friends_query.clone();
-
-                    let other_friends_clone = other_friends.clone();
-                    let deleter_username = deleter_clone.username.clone();
-
-                    tokio::spawn(async move {
-                        db_clone
-                            .execute(
-                                &remove_friends_of_friends_query_clone,
-                                (other_friends_clone, deleter_username),
-                            )
-                            .await
-                    });
-
-                    for other_friend in other_friends.iter() {
-                        let db = db.clone();
-                        let add_friends_of_friends_query = add_friends_of_friends_query.clone();
-
-                        let deleter = deleter_clone.clone();
-                        let other_friend = other_friend.clone();
-
-                        tokio::spawn(async move {
-                            let _ = db
-                                .execute(
-                                    &add_friends_of_friends_query,
-                                    (vec![reciever], sender_friend),
-                                )
-                                .await;
-                        });
-                    }
-                }
-                Err(_) => return,
-            }
-        });
-
-        let sender_clone = sender.clone();
-        let receiver_clone = receiver.clone();
-
-        let results = tokio::join!(
-            self.delete_friend_request(sender, receiver),
-            self.db.execute(
-                &self.add_friend_query,
-                (&sender_clone, &receiver_clone.username)
-            ),
-            self.db.execute(
-                &self.add_friend_query,
-                (&receiver_clone, &sender_clone.username)
-            ),
-        );
-
-        results.0?;
-
-        results.1.map_err(|err| {
-            DatabaseError(format!(
-                "Error adding sender username to receiver's friends: {}",
-                err
-            ))
-        })?;
-
-        results.2.map_err(|err| {
-            DatabaseError(format!(
-                "Error adding receiver username to sender's friends: {}",
-                err
-            ))
-        })?;
-
-        Ok(())
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    #[error("{context}: {source}")]
+    Query {
+        context: &'static str,
+        source: QueryError,
+    },
+    #[error("{context}: {source}")]
+    Row {
+        context: &'static str,
+        source: FromRowError,
+    },
+    #[cfg(feature = "postgres")]
+    #[error("{context}: {source}")]
+    Postgres {
+        context: &'static str,
+        source: sqlx::Error,
+    },
+    #[error("{context}: {source}")]
+    Decode {
+        context: &'static str,
+        source: serde_json::Error,
+    },
+    #[error("{context}: {source}")]
+    Encryption {
+        context: &'static str,
+        source: ContentKeyError,
+    },
+}
+
+impl DatabaseError {
+    fn query(context: &'static str, source: QueryError) -> Self {
+        Self::Query { context, source }
+    }
+
+    fn row(context: &'static str, source: FromRowError) -> Self {
+        Self::Row { context, source }
     }
 
-    async fn prepare_get_friends_of_friends_query(db: &scylla::Session) -> PreparedStatement {
-        let mut get_friends_of_friends_query = db
-            .prepare("SELECT friends_of_friends FROM user WHERE username = ?")
-            .await
-            .expect("Get friends of friends prepared query failed");
-        get_friends_of_friends_query.set_is_idempotent(true);
-        get_friends_of_friends_query
+    #[cfg(feature = "postgres")]
+    fn postgres(context: &'static str, source: sqlx::Error) -> Self {
+        Self::Postgres { context, source }
     }
 
-    fn current_timestamp() -> scylla::frame::value::Timestamp {
-        scylla::frame::value::Timestamp(Duration::milliseconds(
-            DateTime::<Utc>::default().timestamp_millis(),
-        ))
+    fn decode(context: &'static str, source: serde_json::Error) -> Self {
+        Self::Decode { context, source }
     }
 
-    fn timestamp_from_datetime(datetime: DateTime<Utc>) -> scylla::frame::value::Timestamp {
-        scylla::frame::value::Timestamp(Duration::milliseconds(datetime.timestamp_millis()))
+    fn encryption(context: &'static str, source: ContentKeyError) -> Self {
+        Self::Encryption { context, source }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Query { source, .. } => match source {
+                QueryError::DbError(db_error, _) => match db_error {
+                    DbError::Unavailable { .. }
+                    | DbError::Overloaded
+                    | DbError::IsBootstrapping
+                    | DbError::ReadTimeout { .. }
+                    | DbError::WriteTimeout { .. }
+                    | DbError::TruncateError => ErrorCategory::Transient,
+                    DbError::AuthenticationError | DbError::Unauthorized => ErrorCategory::Auth,
+                    _ => ErrorCategory::Permanent,
+                },
+                QueryError::BadQuery(_) => ErrorCategory::Validation,
+                QueryError::IoError(_)
+                | QueryError::TimeoutError
+                | QueryError::RequestTimeout(_)
+                | QueryError::TooManyOrphanedStreamIds(_)
+                | QueryError::UnableToAllocStreamId => ErrorCategory::Transient,
+                QueryError::ProtocolError(_) | QueryError::InvalidMessage(_) => {
+                    ErrorCategory::Permanent
+                }
+            },
+            Self::Row { .. } => ErrorCategory::Permanent, // schema doesn't match what we expect, retrying won't change that
+            #[cfg(feature = "postgres")]
+            Self::Postgres { source, .. } => match source {
+                sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => {
+                    ErrorCategory::Transient
+                }
+                sqlx::Error::Database(db_error) => match db_error.code().as_deref() {
+                    Some("40001" | "40P01" | "53300" | "57P03") => ErrorCategory::Transient, // serialization failure, deadlock, too many connections, starting up
+                    Some("28000" | "28P01" | "42501") => ErrorCategory::Auth,
+                    _ => ErrorCategory::Permanent,
+                },
+                _ => ErrorCategory::Permanent,
+            },
+            Self::Decode { .. } => ErrorCategory::Permanent,
+            Self::Encryption { .. } => ErrorCategory::Permanent, // a missing key or a tampered row stays that way
+        }
     }
 
-    fn datetime_from_timestamp(timestamp: Duration) -> DateTime<Utc> {
-        DateTime::<Utc>::from_utc(
-            NaiveDateTime::from_timestamp_millis(timestamp.num_milliseconds())
-                .expect("Timestamp out of range"),
-            Utc,
-        )
+    pub fn is_retryable(&self) -> bool {
+        self.category().is_retryable()
     }
 }
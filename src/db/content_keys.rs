@@ -0,0 +1,135 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine as _};
+use std::collections::HashMap;
+use thiserror::Error;
+
+// message content is encrypted with aes-256-gcm before it's written, so a leaked snapshot or backup of the message
+// table is only ciphertext. each row keeps the id of the key it was encrypted with, which is how keys get rotated: add
+// the new one, point CONTENT_ENCRYPTION_KEY_ID at it, and keep the old ones around until everything they encrypted
+// has expired. rows from before encryption was turned on have no key id and are read as they are
+//
+// the conversation id is authenticated along with the content, so a row copied into another conversation fails to
+// decrypt instead of showing up there
+
+const NONCE_LEN: usize = 12;
+
+pub struct ContentKeys {
+    current: String, // the id new messages are encrypted with
+    keys: HashMap<String, Aes256Gcm>,
+}
+
+#[derive(Debug, Error)]
+pub enum ContentKeyError {
+    #[error("No content encryption key with id {0}")]
+    UnknownKey(String),
+    #[error("Content encryption key {0} has to be 32 bytes of base64")]
+    InvalidKey(String),
+    #[error("Content encryption keys have to be given as id:key")]
+    MissingKeyId, // the key itself isn't in the message, it ends up in logs
+    #[error("Malformed encrypted content")]
+    Malformed,
+    #[error("Encrypted content failed authentication")]
+    Decrypt,
+}
+
+impl ContentKeys {
+    // keys are by id and base64 encoded
+    pub fn new(current: String, keys: HashMap<String, String>) -> Result<Self, ContentKeyError> {
+        let keys = keys
+            .into_iter()
+            .map(|(key_id, key)| {
+                let key = general_purpose::STANDARD
+                    .decode(key.trim())
+                    .ok()
+                    .filter(|key| key.len() == 32)
+                    .ok_or_else(|| ContentKeyError::InvalidKey(key_id.clone()))?;
+
+                Ok((
+                    key_id,
+                    Aes256Gcm::new_from_slice(&key).expect("Key is 32 bytes"),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        if !keys.contains_key(&current) {
+            return Err(ContentKeyError::UnknownKey(current));
+        }
+
+        Ok(Self { current, keys })
+    }
+
+    // "id:key,id:key", the format CONTENT_ENCRYPTION_KEYS is in
+    pub fn parse(current: String, keys: &str) -> Result<Self, ContentKeyError> {
+        Self::new(
+            current,
+            keys.split(',')
+                .filter(|key| !key.trim().is_empty())
+                .map(|key| match key.split_once(':') {
+                    Some((key_id, key)) => Ok((key_id.trim().to_owned(), key.trim().to_owned())),
+                    None => Err(ContentKeyError::MissingKeyId),
+                })
+                .collect::<Result<_, _>>()?,
+        )
+    }
+
+    // the key id to store with the row, and the nonce and ciphertext as base64
+    pub fn encrypt(&self, conversation_id: &str, content: &str) -> (String, String) {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+        let ciphertext = self.keys[&self.current]
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: content.as_bytes(),
+                    aad: conversation_id.as_bytes(),
+                },
+            )
+            .expect("Encrypting into a vec can't fail");
+
+        let mut sealed = nonce.to_vec();
+
+        sealed.extend_from_slice(&ciphertext);
+
+        (
+            self.current.clone(),
+            general_purpose::STANDARD.encode(sealed),
+        )
+    }
+
+    pub fn decrypt(
+        &self,
+        key_id: &str,
+        conversation_id: &str,
+        sealed: &str,
+    ) -> Result<String, ContentKeyError> {
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| ContentKeyError::UnknownKey(key_id.to_owned()))?;
+
+        let sealed = general_purpose::STANDARD
+            .decode(sealed)
+            .map_err(|_| ContentKeyError::Malformed)?;
+
+        if sealed.len() < NONCE_LEN {
+            return Err(ContentKeyError::Malformed);
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        let content = key
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: conversation_id.as_bytes(),
+                },
+            )
+            .map_err(|_| ContentKeyError::Decrypt)?;
+
+        String::from_utf8(content).map_err(|_| ContentKeyError::Malformed)
+    }
+}
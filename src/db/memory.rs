@@ -0,0 +1,1243 @@
+use async_trait::async_trait;
+use chrono::{prelude::*, Duration};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use super::{DatabaseError, Storage};
+use crate::models::{
+    audit_entry::AuditEntry, bot_key::BotKey, conversation_stats::ConversationStats,
+    conversation_summary::ConversationSummary, device::Device, failed_event::FailedEvent,
+    friend_profile::FriendProfile, job_status::JobStatus, key_distribution::KeyDistribution,
+    mention::Mention, message::Message, notification_prefs::NotificationPrefs,
+    outbox_entry::OutboxEntry, pinned_message::PinnedMessage, poll::Poll,
+    presence_event::PresenceEvent, profile::Profile, push_token::PushToken, report::Report,
+    user_conversation::UserConversation,
+};
+use crate::streaks;
+
+// for --dev. everything is lost on restart, and nothing is shared with other processes
+
+#[derive(Default)]
+pub struct MemoryStorage(Mutex<Data>);
+
+// keyed by when each message was sent, with when it expires
+type ConversationMessages = BTreeMap<DateTime<Utc>, (Message, Option<DateTime<Utc>>)>;
+
+// keyed by device id, with when it was last seen and logged out at
+type UserDevices = BTreeMap<String, (Option<DateTime<Utc>>, Option<DateTime<Utc>>)>;
+
+#[derive(Default)]
+struct Data {
+    conversations: HashMap<String, Conversation>,
+    messages: HashMap<String, ConversationMessages>,
+    disappearing: HashMap<String, Duration>,
+    choosee_presence: HashMap<String, BTreeMap<DateTime<Utc>, (bool, String)>>, // leaving and the chooser hash
+    latest_conversation_ids: HashMap<(String, String), String>, // by chooser hash and choosee hash
+    friend_requests: HashMap<(String, String), (Profile, Profile)>,
+    friends: HashMap<String, BTreeMap<String, FriendProfile>>,
+    friends_of_friends: HashMap<String, BTreeMap<String, Profile>>,
+    outbox: BTreeMap<(i32, DateTime<Utc>, String), OutboxEntry>, // by bucket, created_at and entry_id
+    revoked_before: HashMap<String, DateTime<Utc>>,
+    bot_keys: HashMap<String, BotKey>,
+    avatars: HashMap<String, String>,
+    user_conversations: HashMap<(String, String), UserConversation>,
+    notification_prefs: HashMap<String, NotificationPrefs>,
+    pinned_messages: HashMap<String, BTreeMap<DateTime<Utc>, PinnedMessage>>,
+    polls: HashMap<String, Vec<Poll>>, // oldest first
+    key_distributions: HashMap<String, Vec<(KeyDistribution, DateTime<Utc>)>>, // with when each expires
+    mentions: HashMap<String, Vec<(Mention, DateTime<Utc>)>>, // by username hash, with when each expires
+    conversation_stats: HashMap<String, ConversationStats>,
+    user_daily_messages: HashMap<(String, NaiveDate), u64>,
+    push_tokens: HashMap<String, BTreeMap<String, PushToken>>,
+    devices: HashMap<String, UserDevices>,
+    last_connected_at: BTreeMap<String, DateTime<Utc>>,
+    failed_events: BTreeMap<(String, DateTime<Utc>), FailedEvent>,
+    reports: HashMap<String, Report>,
+    audit_log: Vec<AuditEntry>,
+    job_statuses: HashMap<String, JobStatus>,
+}
+
+#[allow(dead_code)] // kept so the data matches what the other backends store
+struct Conversation {
+    chooser_username: String,
+    choosee_username: String,
+    chooser_name: String,
+    choosee_name: String,
+    created_at: DateTime<Utc>,
+    tombstoned_at: Option<DateTime<Utc>>,
+    streak: u32,
+    streak_day: Option<NaiveDate>, // the last day the streak was extended on
+    chooser_messaged_on: Option<NaiveDate>,
+    choosee_messaged_on: Option<NaiveDate>,
+}
+
+impl MemoryStorage {
+    fn data(&self) -> std::sync::MutexGuard<'_, Data> {
+        self.0.lock().unwrap()
+    }
+
+    // there's no api writing them here, so whoever embeds the server adds them
+    pub fn add_bot_key(&self, bot_key: BotKey) {
+        self.data().bot_keys.insert(bot_key.key_id.clone(), bot_key);
+    }
+
+    // job_status is only written through the Storage trait, this reads it back the way cqlsh would
+    pub fn job_status(&self, job_id: &str) -> Option<JobStatus> {
+        self.data().job_statuses.get(job_id).cloned()
+    }
+}
+
+// expired messages are only hidden, they stay in memory until the process exits
+fn is_expired(expires_at: Option<DateTime<Utc>>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+}
+
+impl Data {
+    // both people become friends of friends of each other's friends
+    fn add_friends_of_friends(&mut self, profile: &Profile, new_friend: &Profile) {
+        let new_friend_friends = self
+            .friends
+            .get(&new_friend.username)
+            .map(|friends| {
+                friends
+                    .values()
+                    .filter(|friend| friend.username != profile.username)
+                    .map(|friend| Profile {
+                        username: friend.username.clone(),
+                        name: friend.name.clone(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        for friend in new_friend_friends {
+            self.friends_of_friends
+                .entry(friend.username.clone())
+                .or_default()
+                .insert(profile.username.clone(), profile.clone());
+
+            self.friends_of_friends
+                .entry(profile.username.clone())
+                .or_default()
+                .insert(friend.username.clone(), friend);
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn new_conversation(
+        &self,
+        chooser_username: &str,
+        choosee_username: &str,
+        chooser_name: &str,
+        choosee_name: &str,
+        conversation_id: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data().conversations.insert(
+            conversation_id.to_owned(),
+            Conversation {
+                chooser_username: chooser_username.to_owned(),
+                choosee_username: choosee_username.to_owned(),
+                chooser_name: chooser_name.to_owned(),
+                choosee_name: choosee_name.to_owned(),
+                created_at,
+                tombstoned_at: None,
+                streak: 0,
+                streak_day: None,
+                chooser_messaged_on: None,
+                choosee_messaged_on: None,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn new_message(
+        &self,
+        conversation_id: &str,
+        sender_username: &str,
+        message: &Message,
+    ) -> Result<Option<u32>, DatabaseError> {
+        let mut data = self.data();
+
+        let expires_at = data
+            .disappearing
+            .get(conversation_id)
+            .map(|ttl| message.sent_at + *ttl);
+
+        data.messages
+            .entry(conversation_id.to_owned())
+            .or_default()
+            .insert(message.sent_at, (message.clone(), expires_at));
+
+        let conversation_stats = data
+            .conversation_stats
+            .entry(conversation_id.to_owned())
+            .or_default();
+
+        if message.from_chooser {
+            conversation_stats.messages_from_chooser += 1;
+        } else {
+            conversation_stats.messages_from_choosee += 1;
+        }
+
+        let day = message.sent_at.date_naive();
+
+        *data
+            .user_daily_messages
+            .entry((sender_username.to_owned(), day))
+            .or_default() += 1;
+
+        let Some(conversation) = data.conversations.get_mut(conversation_id) else {
+            return Ok(None);
+        };
+
+        let other_messaged_on = if message.from_chooser {
+            conversation.chooser_messaged_on = Some(day);
+            conversation.choosee_messaged_on
+        } else {
+            conversation.choosee_messaged_on = Some(day);
+            conversation.chooser_messaged_on
+        };
+
+        let streak = streaks::extend(
+            conversation.streak,
+            conversation.streak_day,
+            other_messaged_on,
+            day,
+        );
+
+        if let Some(streak) = streak {
+            conversation.streak = streak;
+            conversation.streak_day = Some(day);
+        }
+
+        Ok(streak)
+    }
+
+    async fn set_disappearing(
+        &self,
+        conversation_id: &str,
+        ttl: Option<std::time::Duration>,
+    ) -> Result<(), DatabaseError> {
+        let mut data = self.data();
+
+        match ttl.and_then(|ttl| Duration::from_std(ttl).ok()) {
+            Some(ttl) => data.disappearing.insert(conversation_id.to_owned(), ttl),
+            None => data.disappearing.remove(conversation_id),
+        };
+
+        Ok(())
+    }
+
+    async fn update_choosee_last_presence_at(
+        &self,
+        conversation_id: &str,
+        occurred_at: DateTime<Utc>,
+        leaving: bool,
+        chooser_hash: &str,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .choosee_presence
+            .entry(conversation_id.to_owned())
+            .or_default()
+            .insert(occurred_at, (leaving, chooser_hash.to_owned()));
+
+        Ok(())
+    }
+
+    async fn get_messages(
+        &self,
+        conversation_id: &str,
+        username: &str,
+        take: i8,
+        after_sent_at: DateTime<Utc>,
+    ) -> Result<Vec<Message>, DatabaseError> {
+        let data = self.data();
+
+        let after_sent_at = match data
+            .user_conversations
+            .get(&(username.to_owned(), conversation_id.to_owned()))
+            .and_then(|user_conversation| user_conversation.cleared_before)
+        {
+            Some(cleared_before) => after_sent_at.max(cleared_before),
+            None => after_sent_at,
+        };
+
+        Ok(data
+            .messages
+            .get(conversation_id)
+            .map(|messages| {
+                messages
+                    .range(after_sent_at + Duration::nanoseconds(1)..)
+                    .filter(|(_, (_, expires_at))| !is_expired(*expires_at))
+                    .take(take.max(0) as usize)
+                    .map(|(_, (message, _))| message.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_choosee_presence(
+        &self,
+        conversation_id: &str,
+        take: i8,
+    ) -> Result<Vec<PresenceEvent>, DatabaseError> {
+        Ok(self
+            .data()
+            .choosee_presence
+            .get(conversation_id)
+            .map(|choosee_presence| {
+                choosee_presence
+                    .iter()
+                    .rev()
+                    .take(take.max(0) as usize)
+                    .map(|(occurred_at, (leaving, _))| PresenceEvent {
+                        leaving: *leaving,
+                        occurred_at: *occurred_at,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn create_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError> {
+        self.data().friend_requests.insert(
+            (sender.username.clone(), receiver.username.clone()),
+            (sender, receiver),
+        );
+
+        Ok(())
+    }
+
+    async fn delete_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .friend_requests
+            .remove(&(sender.username, receiver.username));
+
+        Ok(())
+    }
+
+    async fn get_friend_requests_received(
+        &self,
+        username: &str,
+    ) -> Result<Vec<Profile>, DatabaseError> {
+        Ok(self
+            .data()
+            .friend_requests
+            .values()
+            .filter(|(_, receiver)| receiver.username == username)
+            .map(|(sender, _)| sender.clone())
+            .collect())
+    }
+
+    async fn get_friends(&self, username: &str) -> Result<Vec<FriendProfile>, DatabaseError> {
+        Ok(self
+            .data()
+            .friends
+            .get(username)
+            .map(|friends| friends.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    // accounts are made by the api, which --dev doesn't run, so everyone exists and goes by their username
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, DatabaseError> {
+        Ok(Some(Profile {
+            username: username.to_owned(),
+            name: username.to_owned(),
+        }))
+    }
+
+    // there's no user table here, so anyone who has been in a conversation or friendship so far can be found
+    async fn search_users(&self, prefix: &str, take: i8) -> Result<Vec<Profile>, DatabaseError> {
+        let data = self.data();
+
+        let usernames = data
+            .conversations
+            .values()
+            .flat_map(|conversation| {
+                [
+                    &conversation.chooser_username,
+                    &conversation.choosee_username,
+                ]
+            })
+            .chain(data.friends.keys())
+            .filter(|username| username.starts_with(prefix))
+            .collect::<BTreeSet<_>>();
+
+        Ok(usernames
+            .into_iter()
+            .take(take.max(0) as usize)
+            .map(|username| Profile {
+                username: username.clone(),
+                name: username.clone(),
+            })
+            .collect())
+    }
+
+    async fn set_avatar(&self, username: &str, object_key: &str) -> Result<(), DatabaseError> {
+        self.data()
+            .avatars
+            .insert(username.to_owned(), object_key.to_owned());
+
+        Ok(())
+    }
+
+    async fn get_conversations(
+        &self,
+        username: &str,
+    ) -> Result<Vec<ConversationSummary>, DatabaseError> {
+        let data = self.data();
+
+        let today = Utc::now().date_naive();
+
+        let mut conversation_vec = data
+            .conversations
+            .iter()
+            .filter(|(_, conversation)| {
+                conversation.chooser_username == username
+                    || conversation.choosee_username == username
+            })
+            .map(|(conversation_id, conversation)| {
+                let is_chooser = conversation.chooser_username == username;
+
+                let user_conversation = data
+                    .user_conversations
+                    .get(&(username.to_owned(), conversation_id.clone()))
+                    .cloned()
+                    .unwrap_or_default();
+
+                ConversationSummary {
+                    conversation_id: conversation_id.clone(),
+                    created_at: conversation.created_at,
+                    is_chooser,
+                    choosee_name: is_chooser.then(|| conversation.choosee_name.clone()),
+                    pinned: user_conversation.pinned,
+                    archived: user_conversation.archived,
+                    tombstoned: conversation.tombstoned_at.is_some(),
+                    streak: streaks::current_length(
+                        conversation.streak,
+                        conversation.streak_day,
+                        today,
+                    ),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        conversation_vec.sort_by(|a, b| {
+            b.pinned
+                .cmp(&a.pinned)
+                .then_with(|| b.created_at.cmp(&a.created_at))
+        });
+
+        Ok(conversation_vec)
+    }
+
+    async fn get_user_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+    ) -> Result<UserConversation, DatabaseError> {
+        Ok(self
+            .data()
+            .user_conversations
+            .get(&(username.to_owned(), conversation_id.to_owned()))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_conversation_pinned(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        pinned: bool,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .user_conversations
+            .entry((username.to_owned(), conversation_id.to_owned()))
+            .or_default()
+            .pinned = pinned;
+
+        Ok(())
+    }
+
+    async fn set_conversation_archived(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        archived: bool,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .user_conversations
+            .entry((username.to_owned(), conversation_id.to_owned()))
+            .or_default()
+            .archived = archived;
+
+        Ok(())
+    }
+
+    async fn clear_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        cleared_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .user_conversations
+            .entry((username.to_owned(), conversation_id.to_owned()))
+            .or_default()
+            .cleared_before = Some(cleared_before);
+
+        Ok(())
+    }
+
+    async fn set_conversation_notification_prefs(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        muted: bool,
+        mentions_only: bool,
+    ) -> Result<(), DatabaseError> {
+        let mut data = self.data();
+
+        let user_conversation = data
+            .user_conversations
+            .entry((username.to_owned(), conversation_id.to_owned()))
+            .or_default();
+
+        user_conversation.muted = muted;
+        user_conversation.mentions_only = mentions_only;
+
+        Ok(())
+    }
+
+    async fn get_notification_prefs(
+        &self,
+        username: &str,
+    ) -> Result<NotificationPrefs, DatabaseError> {
+        Ok(self
+            .data()
+            .notification_prefs
+            .get(username)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn set_notification_prefs(
+        &self,
+        username: &str,
+        notification_prefs: &NotificationPrefs,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .notification_prefs
+            .insert(username.to_owned(), notification_prefs.clone());
+
+        Ok(())
+    }
+
+    async fn pin_message(
+        &self,
+        conversation_id: &str,
+        pinned_message: &PinnedMessage,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .pinned_messages
+            .entry(conversation_id.to_owned())
+            .or_default()
+            .insert(pinned_message.sent_at, pinned_message.clone());
+
+        Ok(())
+    }
+
+    async fn unpin_message(
+        &self,
+        conversation_id: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        if let Some(pinned_messages) = self.data().pinned_messages.get_mut(conversation_id) {
+            pinned_messages.remove(&sent_at);
+        }
+
+        Ok(())
+    }
+
+    async fn get_pinned_messages(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<PinnedMessage>, DatabaseError> {
+        Ok(self
+            .data()
+            .pinned_messages
+            .get(conversation_id)
+            .map(|pinned_messages| pinned_messages.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn new_poll(&self, conversation_id: &str, poll: &Poll) -> Result<(), DatabaseError> {
+        self.data()
+            .polls
+            .entry(conversation_id.to_owned())
+            .or_default()
+            .push(poll.clone());
+
+        Ok(())
+    }
+
+    async fn vote_in_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+        from_chooser: bool,
+        option: u32,
+    ) -> Result<(), DatabaseError> {
+        let mut data = self.data();
+
+        let Some(poll) = data
+            .polls
+            .get_mut(conversation_id)
+            .and_then(|polls| polls.iter_mut().find(|poll| poll.poll_id == poll_id))
+        else {
+            return Ok(());
+        };
+
+        *poll = if from_chooser {
+            poll.clone().with_votes(Some(option), poll.choosee_vote)
+        } else {
+            poll.clone().with_votes(poll.chooser_vote, Some(option))
+        };
+
+        Ok(())
+    }
+
+    async fn get_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+    ) -> Result<Option<Poll>, DatabaseError> {
+        Ok(self
+            .data()
+            .polls
+            .get(conversation_id)
+            .and_then(|polls| polls.iter().find(|poll| poll.poll_id == poll_id))
+            .cloned())
+    }
+
+    async fn get_polls(&self, conversation_id: &str) -> Result<Vec<Poll>, DatabaseError> {
+        Ok(self
+            .data()
+            .polls
+            .get(conversation_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn get_conversation_stats(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationStats, DatabaseError> {
+        Ok(self
+            .data()
+            .conversation_stats
+            .get(conversation_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn add_key_distribution(
+        &self,
+        conversation_id: &str,
+        key_distribution: &KeyDistribution,
+        ttl: std::time::Duration,
+    ) -> Result<(), DatabaseError> {
+        let expires_at = key_distribution.sent_at
+            + Duration::from_std(ttl).unwrap_or_else(|_| Duration::max_value());
+
+        let mut data = self.data();
+
+        let key_distributions = data
+            .key_distributions
+            .entry(conversation_id.to_owned())
+            .or_default();
+
+        key_distributions.retain(|(_, expires_at)| !is_expired(Some(*expires_at)));
+        key_distributions.push((key_distribution.clone(), expires_at));
+
+        Ok(())
+    }
+
+    async fn get_key_distributions(
+        &self,
+        conversation_id: &str,
+        from_chooser: bool,
+    ) -> Result<Vec<KeyDistribution>, DatabaseError> {
+        let mut key_distributions = self
+            .data()
+            .key_distributions
+            .get(conversation_id)
+            .map(|key_distributions| {
+                key_distributions
+                    .iter()
+                    .filter(|(key_distribution, expires_at)| {
+                        key_distribution.from_chooser == from_chooser
+                            && !is_expired(Some(*expires_at))
+                    })
+                    .map(|(key_distribution, _)| key_distribution.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        key_distributions.sort_by_key(|key_distribution| key_distribution.sent_at);
+
+        Ok(key_distributions)
+    }
+
+    async fn add_mention(
+        &self,
+        username_hash: &str,
+        mention: &Mention,
+        ttl: std::time::Duration,
+    ) -> Result<(), DatabaseError> {
+        let expires_at =
+            mention.sent_at + Duration::from_std(ttl).unwrap_or_else(|_| Duration::max_value());
+
+        let mut data = self.data();
+
+        let mentions = data.mentions.entry(username_hash.to_owned()).or_default();
+
+        mentions.retain(|(_, expires_at)| !is_expired(Some(*expires_at)));
+        mentions.push((mention.clone(), expires_at));
+
+        Ok(())
+    }
+
+    async fn get_mentions(
+        &self,
+        username_hash: &str,
+        take: i32,
+    ) -> Result<Vec<Mention>, DatabaseError> {
+        let mut mentions = self
+            .data()
+            .mentions
+            .get(username_hash)
+            .map(|mentions| {
+                mentions
+                    .iter()
+                    .filter(|(_, expires_at)| !is_expired(Some(*expires_at)))
+                    .map(|(mention, _)| mention.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        mentions.sort_by_key(|mention| std::cmp::Reverse(mention.sent_at));
+        mentions.truncate(take.max(0) as usize);
+
+        Ok(mentions)
+    }
+
+    async fn remove_mentions(&self, username_hash: &str) -> Result<(), DatabaseError> {
+        self.data().mentions.remove(username_hash);
+
+        Ok(())
+    }
+
+    async fn add_push_token(
+        &self,
+        username_hash: &str,
+        push_token: &PushToken,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .push_tokens
+            .entry(username_hash.to_owned())
+            .or_default()
+            .insert(push_token.token.clone(), push_token.clone());
+
+        Ok(())
+    }
+
+    async fn get_push_tokens(&self, username_hash: &str) -> Result<Vec<PushToken>, DatabaseError> {
+        Ok(self
+            .data()
+            .push_tokens
+            .get(username_hash)
+            .map(|push_tokens| push_tokens.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn remove_push_token(
+        &self,
+        username_hash: &str,
+        token: &str,
+    ) -> Result<(), DatabaseError> {
+        if let Some(push_tokens) = self.data().push_tokens.get_mut(username_hash) {
+            push_tokens.remove(token);
+        }
+
+        Ok(())
+    }
+
+    async fn remove_push_tokens(&self, username_hash: &str) -> Result<(), DatabaseError> {
+        self.data().push_tokens.remove(username_hash);
+
+        Ok(())
+    }
+
+    async fn touch_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        seen_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .devices
+            .entry(username.to_owned())
+            .or_default()
+            .entry(device_id.to_owned())
+            .or_default()
+            .0 = Some(seen_at);
+
+        Ok(())
+    }
+
+    async fn touch_user(
+        &self,
+        username: &str,
+        connected_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .last_connected_at
+            .insert(username.to_owned(), connected_at);
+
+        Ok(())
+    }
+
+    // users who never connected aren't known here
+    async fn get_away_users(
+        &self,
+        connected_after: DateTime<Utc>,
+        connected_before: DateTime<Utc>,
+        after: Option<&str>,
+        take: i32,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, DatabaseError> {
+        Ok(self
+            .data()
+            .last_connected_at
+            .iter()
+            .filter(|(username, _)| after.is_none_or(|after| username.as_str() > after))
+            .filter(|(_, connected_at)| {
+                **connected_at > connected_after && **connected_at < connected_before
+            })
+            .take(take.max(0) as usize)
+            .map(|(username, connected_at)| (username.clone(), *connected_at))
+            .collect())
+    }
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, DatabaseError> {
+        let mut devices = self
+            .data()
+            .devices
+            .get(username)
+            .map(|devices| {
+                devices
+                    .iter()
+                    .filter_map(|(device_id, (last_seen_at, logged_out_at))| {
+                        let last_seen_at = (*last_seen_at)?;
+
+                        if logged_out_at.is_some_and(|logged_out_at| logged_out_at >= last_seen_at)
+                        {
+                            return None;
+                        }
+
+                        Some(Device {
+                            device_id: device_id.clone(),
+                            last_seen_at,
+                            connected: false,
+                            current: false,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        devices.sort_by_key(|device| std::cmp::Reverse(device.last_seen_at));
+
+        Ok(devices)
+    }
+
+    async fn log_out_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        logged_out_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .devices
+            .entry(username.to_owned())
+            .or_default()
+            .entry(device_id.to_owned())
+            .or_default()
+            .1 = Some(logged_out_at);
+
+        Ok(())
+    }
+
+    async fn get_device_logged_out_at(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        Ok(self
+            .data()
+            .devices
+            .get(username)
+            .and_then(|devices| devices.get(device_id))
+            .and_then(|device| device.1))
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<(), DatabaseError> {
+        self.data()
+            .reports
+            .insert(report.report_id.clone(), report.clone());
+
+        Ok(())
+    }
+
+    async fn create_friendship(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+        _receiver_friends: Vec<Profile>,
+    ) -> Result<(), DatabaseError> {
+        let mut data = self.data();
+
+        data.friend_requests
+            .remove(&(sender.username.clone(), receiver.username.clone()));
+
+        data.add_friends_of_friends(&sender, &receiver);
+        data.add_friends_of_friends(&receiver, &sender);
+
+        let friendship_started_on =
+            scylla::frame::value::Timestamp(Duration::milliseconds(Utc::now().timestamp_millis()));
+
+        for (profile, friend) in [(&sender, &receiver), (&receiver, &sender)] {
+            data.friends
+                .entry(profile.username.clone())
+                .or_default()
+                .insert(
+                    friend.username.clone(),
+                    FriendProfile {
+                        username: friend.username.clone(),
+                        name: friend.name.clone(),
+                        friendship_started_on,
+                    },
+                );
+        }
+
+        Ok(())
+    }
+
+    async fn add_to_outbox(
+        &self,
+        bucket: i32,
+        subject: &str,
+        data: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        let outbox_entry = OutboxEntry {
+            bucket,
+            created_at: Utc::now(),
+            entry_id: Uuid::new_v4().to_string(),
+            subject: subject.to_owned(),
+            data,
+        };
+
+        self.data().outbox.insert(
+            (
+                bucket,
+                outbox_entry.created_at,
+                outbox_entry.entry_id.clone(),
+            ),
+            outbox_entry,
+        );
+
+        Ok(())
+    }
+
+    async fn get_outbox(&self, bucket: i32) -> Result<Vec<OutboxEntry>, DatabaseError> {
+        Ok(self
+            .data()
+            .outbox
+            .range((bucket, DateTime::<Utc>::MIN_UTC, String::new())..)
+            .take_while(|((entry_bucket, _, _), _)| *entry_bucket == bucket)
+            .map(|(_, outbox_entry)| outbox_entry.clone())
+            .collect())
+    }
+
+    async fn remove_from_outbox(
+        &self,
+        bucket: i32,
+        created_at: DateTime<Utc>,
+        entry_id: &str,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .outbox
+            .remove(&(bucket, created_at, entry_id.to_owned()));
+
+        Ok(())
+    }
+
+    async fn adopt_legacy_outbox(&self, _bucket: i32) -> Result<u64, DatabaseError> {
+        Ok(0) // nothing survives a restart, so there's never anything left from before
+    }
+
+    async fn has_messages(&self, conversation_id: &str) -> Result<bool, DatabaseError> {
+        Ok(self
+            .data()
+            .messages
+            .get(conversation_id)
+            .map_or(false, |messages| {
+                messages
+                    .values()
+                    .any(|(_, expires_at)| !is_expired(*expires_at))
+            }))
+    }
+
+    async fn get_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+    ) -> Result<Option<String>, DatabaseError> {
+        Ok(self
+            .data()
+            .latest_conversation_ids
+            .get(&(chooser_hash.to_owned(), choosee_hash.to_owned()))
+            .cloned())
+    }
+
+    async fn set_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+        conversation_id: &str,
+    ) -> Result<(), DatabaseError> {
+        self.data().latest_conversation_ids.insert(
+            (chooser_hash.to_owned(), choosee_hash.to_owned()),
+            conversation_id.to_owned(),
+        );
+
+        Ok(())
+    }
+
+    async fn remove_friend(
+        &self,
+        username: &str,
+        friend_username: &str,
+    ) -> Result<(), DatabaseError> {
+        if let Some(friends) = self.data().friends.get_mut(username) {
+            friends.remove(friend_username);
+        }
+
+        Ok(())
+    }
+
+    async fn remove_friend_of_friend(
+        &self,
+        username: &str,
+        friend_of_friend_username: &str,
+    ) -> Result<(), DatabaseError> {
+        if let Some(friends_of_friends) = self.data().friends_of_friends.get_mut(username) {
+            friends_of_friends.remove(friend_of_friend_username);
+        }
+
+        Ok(())
+    }
+
+    async fn tombstone_conversation(
+        &self,
+        conversation_id: &str,
+        tombstoned_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        let mut data = self.data();
+
+        if let Some(conversation) = data.conversations.get_mut(conversation_id) {
+            conversation.tombstoned_at = Some(tombstoned_at);
+        }
+
+        data.messages.remove(conversation_id);
+        data.pinned_messages.remove(conversation_id);
+        data.polls.remove(conversation_id);
+
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), DatabaseError> {
+        let mut data = self.data();
+
+        data.friends.remove(username);
+        data.friends_of_friends.remove(username);
+        data.user_daily_messages
+            .retain(|(daily_username, _), _| daily_username != username);
+        data.avatars.remove(username);
+        data.devices.remove(username);
+        data.last_connected_at.remove(username);
+        data.user_conversations
+            .retain(|(user_conversation_username, _), _| user_conversation_username != username);
+        data.friend_requests
+            .retain(|(sender, receiver), _| sender != username && receiver != username);
+
+        Ok(())
+    }
+
+    async fn delete_conversations_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        take: i32,
+    ) -> Result<usize, DatabaseError> {
+        let mut data = self.data();
+
+        let conversation_ids = data
+            .conversations
+            .iter()
+            .filter(|(_, conversation)| conversation.created_at < cutoff)
+            .map(|(conversation_id, _)| conversation_id.clone())
+            .take(take.max(0) as usize)
+            .collect::<Vec<_>>();
+
+        for conversation_id in &conversation_ids {
+            data.messages.remove(conversation_id);
+            data.disappearing.remove(conversation_id);
+            data.choosee_presence.remove(conversation_id);
+            data.pinned_messages.remove(conversation_id);
+            data.polls.remove(conversation_id);
+            data.conversation_stats.remove(conversation_id);
+            data.user_conversations
+                .retain(|(_, user_conversation_id), _| user_conversation_id != conversation_id);
+            data.conversations.remove(conversation_id);
+        }
+
+        Ok(conversation_ids.len())
+    }
+
+    async fn end_streaks(&self, before: NaiveDate, take: i32) -> Result<usize, DatabaseError> {
+        let mut data = self.data();
+
+        let mut ended = 0;
+
+        for conversation in data
+            .conversations
+            .values_mut()
+            .filter(|conversation| {
+                conversation.streak > 0
+                    && conversation
+                        .streak_day
+                        .is_some_and(|streak_day| streak_day < before)
+            })
+            .take(take.max(0) as usize)
+        {
+            conversation.streak = 0;
+
+            ended += 1;
+        }
+
+        Ok(ended)
+    }
+
+    async fn get_revoked_before(
+        &self,
+        username: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        Ok(self.data().revoked_before.get(username).copied())
+    }
+
+    async fn revoke_tokens(
+        &self,
+        username: &str,
+        revoked_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .revoked_before
+            .insert(username.to_owned(), revoked_before);
+
+        Ok(())
+    }
+
+    async fn get_bot_key(&self, key_id: &str) -> Result<Option<BotKey>, DatabaseError> {
+        Ok(self.data().bot_keys.get(key_id).cloned())
+    }
+
+    fn message_retention(&self) -> Option<std::time::Duration> {
+        None // nothing expires here
+    }
+
+    async fn health_check(&self) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+
+    async fn add_failed_event(&self, failed_event: &FailedEvent) -> Result<(), DatabaseError> {
+        self.data().failed_events.insert(
+            (failed_event.subject.clone(), failed_event.failed_at),
+            failed_event.clone(),
+        );
+
+        Ok(())
+    }
+
+    async fn get_failed_events(&self) -> Result<Vec<FailedEvent>, DatabaseError> {
+        Ok(self.data().failed_events.values().cloned().collect())
+    }
+
+    async fn remove_failed_event(
+        &self,
+        subject: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.data()
+            .failed_events
+            .remove(&(subject.to_owned(), failed_at));
+
+        Ok(())
+    }
+
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), DatabaseError> {
+        self.data().audit_log.push(audit_entry.clone());
+
+        Ok(())
+    }
+
+    async fn get_audit_log(
+        &self,
+        day: NaiveDate,
+        take: i32,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let mut audit_log = self
+            .data()
+            .audit_log
+            .iter()
+            .filter(|audit_entry| audit_entry.occurred_at.date_naive() == day)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        audit_log.sort_by_key(|audit_entry| std::cmp::Reverse(audit_entry.occurred_at));
+
+        audit_log.truncate(take.max(0) as usize);
+
+        Ok(audit_log)
+    }
+
+    async fn set_job_status(&self, job_status: &JobStatus) -> Result<(), DatabaseError> {
+        self.data()
+            .job_statuses
+            .insert(job_status.job_id.clone(), job_status.clone());
+
+        Ok(())
+    }
+}
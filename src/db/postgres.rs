@@ -0,0 +1,1438 @@
+use async_trait::async_trait;
+use chrono::{prelude::*, Duration};
+use sqlx::{postgres::PgPoolOptions, Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{DatabaseError, Storage};
+use crate::models::{
+    audit_entry::{AuditAction, AuditEntry},
+    bot_key::BotKey,
+    conversation_stats::ConversationStats,
+    conversation_summary::ConversationSummary,
+    device::Device,
+    failed_event::FailedEvent,
+    friend_profile::FriendProfile,
+    job_status::JobStatus,
+    key_distribution::KeyDistribution,
+    mention::Mention,
+    message::Message,
+    notification_prefs::{DndWindow, NotificationPrefs},
+    outbox_entry::OutboxEntry,
+    pinned_message::PinnedMessage,
+    poll::Poll,
+    presence_event::PresenceEvent,
+    profile::Profile,
+    push_token::{PushPlatform, PushToken},
+    report::Report,
+    user_conversation::UserConversation,
+};
+use crate::streaks;
+
+// same data as the scylla keyspace, but the sets on the user row are normalized into tables of their own. the schema is
+// applied on connect so a fresh database works without any setup
+
+const SCHEMA: &str = include_str!("../../schema/postgres.sql");
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+type PollRow = (
+    String,
+    String,
+    Vec<String>,
+    bool,
+    DateTime<Utc>,
+    Option<i32>,
+    Option<i32>,
+);
+
+impl PostgresStorage {
+    pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
+        let pool = PgPoolOptions::new().connect(url).await?;
+
+        pool.execute(SCHEMA).await?;
+
+        Ok(Self { pool })
+    }
+
+    // both people become friends of friends of each other's friends
+    async fn add_friends_of_friends(
+        tx: &mut Transaction<'_, Postgres>,
+        profile: &Profile,
+        new_friend: &Profile,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO friend_of_friend (username, friend_of_friend_username, friend_of_friend_name) SELECT friend_username, $1, $2 FROM friend WHERE username = $3 AND friend_username <> $1 ON CONFLICT DO NOTHING")
+            .bind(&profile.username)
+            .bind(&profile.name)
+            .bind(&new_friend.username)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("INSERT INTO friend_of_friend (username, friend_of_friend_username, friend_of_friend_name) SELECT $1, friend_username, friend_name FROM friend WHERE username = $2 AND friend_username <> $1 ON CONFLICT DO NOTHING")
+            .bind(&profile.username)
+            .bind(&new_friend.username)
+            .execute(&mut *tx)
+            .await?;
+
+        Ok(())
+    }
+
+    fn poll_from_row(row: PollRow) -> Poll {
+        Poll::new(row.0, row.1, row.2, row.3, row.4)
+            .with_votes(row.5.map(|vote| vote as u32), row.6.map(|vote| vote as u32))
+    }
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn new_conversation(
+        &self,
+        chooser_username: &str,
+        choosee_username: &str,
+        chooser_name: &str,
+        choosee_name: &str,
+        conversation_id: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO conversation (chooser_username, choosee_username, chooser_name, choosee_name, id, created_at) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING")
+            .bind(chooser_username)
+            .bind(choosee_username)
+            .bind(chooser_name)
+            .bind(choosee_name)
+            .bind(conversation_id)
+            .bind(created_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error creating new conversation", err))
+    }
+
+    async fn new_message(
+        &self,
+        conversation_id: &str,
+        sender_username: &str,
+        message: &Message,
+    ) -> Result<Option<u32>, DatabaseError> {
+        let from_chooser = message.from_chooser;
+
+        let attachment = message
+            .attachment
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|err| DatabaseError::decode("Error encoding attachment", err))?;
+
+        let kind = message
+            .kind
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|err| DatabaseError::decode("Error encoding message kind", err))?;
+
+        let day = message.sent_at.date_naive();
+
+        async {
+            let mut tx = self.pool.begin().await?;
+
+            sqlx::query("INSERT INTO message (conversation_id, content, sent_at, from_chooser, expires_at, attachment, kind) VALUES ($1, $2, $4, $3, (SELECT $4 + disappearing_ttl_seconds * interval '1 second' FROM conversation WHERE id = $1), $5, $6) ON CONFLICT (conversation_id, sent_at) DO UPDATE SET content = EXCLUDED.content, from_chooser = EXCLUDED.from_chooser, attachment = EXCLUDED.attachment, kind = EXCLUDED.kind")
+                .bind(conversation_id)
+                .bind(&message.content)
+                .bind(from_chooser)
+                .bind(message.sent_at)
+                .bind(attachment)
+                .bind(kind)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query("INSERT INTO conversation_stats (conversation_id, from_chooser, messages) VALUES ($1, $2, 1) ON CONFLICT (conversation_id, from_chooser) DO UPDATE SET messages = conversation_stats.messages + 1")
+                .bind(conversation_id)
+                .bind(from_chooser)
+                .execute(&mut tx)
+                .await?;
+
+            sqlx::query("INSERT INTO user_daily_messages (username, day, messages) VALUES ($1, $2, 1) ON CONFLICT (username, day) DO UPDATE SET messages = user_daily_messages.messages + 1")
+                .bind(sender_username)
+                .bind(day)
+                .execute(&mut tx)
+                .await?;
+
+            // the update locks the row until the commit, so the other user's messages wait for this one's streak
+            let row = sqlx::query_as::<_, (Option<NaiveDate>, i32, Option<NaiveDate>)>(if from_chooser {
+                "UPDATE conversation SET chooser_messaged_on = $2 WHERE id = $1 RETURNING choosee_messaged_on, streak, streak_day"
+            } else {
+                "UPDATE conversation SET choosee_messaged_on = $2 WHERE id = $1 RETURNING chooser_messaged_on, streak, streak_day"
+            })
+            .bind(conversation_id)
+            .bind(day)
+            .fetch_optional(&mut tx)
+            .await?;
+
+            let streak = row.and_then(|(other_messaged_on, streak, streak_day)| {
+                streaks::extend(streak.max(0) as u32, streak_day, other_messaged_on, day)
+            });
+
+            if let Some(streak) = streak {
+                sqlx::query("UPDATE conversation SET streak = $2, streak_day = $3 WHERE id = $1")
+                    .bind(conversation_id)
+                    .bind(streak as i32)
+                    .bind(day)
+                    .execute(&mut tx)
+                    .await?;
+            }
+
+            tx.commit().await?;
+
+            Ok(streak)
+        }
+        .await
+        .map_err(|err| DatabaseError::postgres("Error creating new message", err))
+    }
+
+    async fn set_disappearing(
+        &self,
+        conversation_id: &str,
+        ttl: Option<std::time::Duration>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("UPDATE conversation SET disappearing_ttl_seconds = $1 WHERE id = $2")
+            .bind(ttl.map(|ttl| ttl.as_secs().min(i32::MAX as u64) as i32))
+            .bind(conversation_id)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error setting disappearing messages", err))
+    }
+
+    async fn update_choosee_last_presence_at(
+        &self,
+        conversation_id: &str,
+        occurred_at: DateTime<Utc>,
+        leaving: bool,
+        chooser_hash: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO choosee_presence (conversation_id, occurred_at, leaving, chooser_hash) VALUES ($1, $2, $3, $4) ON CONFLICT (conversation_id, occurred_at) DO UPDATE SET leaving = EXCLUDED.leaving")
+            .bind(conversation_id)
+            .bind(occurred_at)
+            .bind(leaving)
+            .bind(chooser_hash)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error updating choosee_last_presence_at", err))
+    }
+
+    async fn get_messages(
+        &self,
+        conversation_id: &str,
+        username: &str,
+        take: i8,
+        after_sent_at: DateTime<Utc>,
+    ) -> Result<Vec<Message>, DatabaseError> {
+        let rows = sqlx::query_as::<_, (String, DateTime<Utc>, bool, Option<String>, Option<String>)>("SELECT content, sent_at, from_chooser, attachment, kind FROM message WHERE conversation_id = $1 AND sent_at > $2 AND sent_at > COALESCE((SELECT cleared_before FROM user_conversation WHERE username = $4 AND conversation_id = $1), '-infinity') AND (expires_at IS NULL OR expires_at > now()) ORDER BY sent_at LIMIT $3")
+            .bind(conversation_id)
+            .bind(after_sent_at)
+            .bind(take as i64)
+            .bind(username)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| DatabaseError::postgres("Error getting messages", err))?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(Message {
+                    content: row.0,
+                    sent_at: row.1,
+                    from_chooser: row.2,
+                    attachment: row
+                        .3
+                        .map(|attachment| serde_json::from_str(&attachment))
+                        .transpose()
+                        .map_err(|err| DatabaseError::decode("Error getting messages", err))?,
+                    kind: row
+                        .4
+                        .map(|kind| serde_json::from_str(&kind))
+                        .transpose()
+                        .map_err(|err| DatabaseError::decode("Error getting messages", err))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn get_choosee_presence(
+        &self,
+        conversation_id: &str,
+        take: i8,
+    ) -> Result<Vec<PresenceEvent>, DatabaseError> {
+        sqlx::query_as::<_, (DateTime<Utc>, bool)>("SELECT occurred_at, leaving FROM choosee_presence WHERE conversation_id = $1 ORDER BY occurred_at DESC LIMIT $2")
+            .bind(conversation_id)
+            .bind(take as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| PresenceEvent {
+                        occurred_at: row.0,
+                        leaving: row.1,
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting choosee presence", err))
+    }
+
+    async fn create_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO friend_request (sender_username, sender_name, receiver_username, receiver_name) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING")
+            .bind(&sender.username)
+            .bind(&sender.name)
+            .bind(&receiver.username)
+            .bind(&receiver.name)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error creating friend request", err))
+    }
+
+    async fn delete_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            "DELETE FROM friend_request WHERE sender_username = $1 AND receiver_username = $2",
+        )
+        .bind(&sender.username)
+        .bind(&receiver.username)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| DatabaseError::postgres("Error deleting friend request", err))
+    }
+
+    async fn get_friend_requests_received(
+        &self,
+        username: &str,
+    ) -> Result<Vec<Profile>, DatabaseError> {
+        sqlx::query_as::<_, (String, String)>(
+            "SELECT sender_username, sender_name FROM friend_request WHERE receiver_username = $1",
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| Profile {
+                    username: row.0,
+                    name: row.1,
+                })
+                .collect()
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting friend requests", err))
+    }
+
+    async fn get_friends(&self, username: &str) -> Result<Vec<FriendProfile>, DatabaseError> {
+        sqlx::query_as::<_, (String, String, DateTime<Utc>)>("SELECT friend_username, friend_name, friendship_started_on FROM friend WHERE username = $1")
+            .bind(username)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| FriendProfile {
+                        username: row.0,
+                        name: row.1,
+                        friendship_started_on: scylla::frame::value::Timestamp(
+                            Duration::milliseconds(row.2.timestamp_millis()),
+                        ),
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error get friends of user", err))
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, DatabaseError> {
+        sqlx::query_as::<_, (String,)>("SELECT name FROM \"user\" WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await
+            .map(|row| {
+                row.map(|row| Profile {
+                    username: username.to_owned(),
+                    name: row.0,
+                })
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting profile", err))
+    }
+
+    async fn search_users(&self, prefix: &str, take: i8) -> Result<Vec<Profile>, DatabaseError> {
+        sqlx::query_as::<_, (String, String)>("SELECT username, name FROM \"user\" WHERE starts_with(username, $1) ORDER BY username LIMIT $2")
+            .bind(prefix)
+            .bind(take as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| Profile {
+                        username: row.0,
+                        name: row.1,
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error searching users", err))
+    }
+
+    async fn set_avatar(&self, username: &str, object_key: &str) -> Result<(), DatabaseError> {
+        sqlx::query("UPDATE \"user\" SET avatar_object_key = $1 WHERE username = $2")
+            .bind(object_key)
+            .bind(username)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error setting avatar", err))
+    }
+
+    async fn get_conversations(
+        &self,
+        username: &str,
+    ) -> Result<Vec<ConversationSummary>, DatabaseError> {
+        let today = Utc::now().date_naive();
+
+        sqlx::query_as::<_, (String, DateTime<Utc>, bool, String, bool, bool, bool, i32, Option<NaiveDate>)>("SELECT c.id, c.created_at, c.chooser_username = $1, c.choosee_name, COALESCE(uc.pinned, FALSE), COALESCE(uc.archived, FALSE), c.tombstoned_at IS NOT NULL, c.streak, c.streak_day FROM conversation c LEFT JOIN user_conversation uc ON uc.conversation_id = c.id AND uc.username = $1 WHERE c.chooser_username = $1 OR c.choosee_username = $1 ORDER BY 5 DESC, c.created_at DESC")
+            .bind(username)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| ConversationSummary {
+                        conversation_id: row.0,
+                        created_at: row.1,
+                        is_chooser: row.2,
+                        choosee_name: row.2.then_some(row.3),
+                        pinned: row.4,
+                        archived: row.5,
+                        tombstoned: row.6,
+                        streak: streaks::current_length(row.7.max(0) as u32, row.8, today),
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting conversations", err))
+    }
+
+    async fn get_user_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+    ) -> Result<UserConversation, DatabaseError> {
+        sqlx::query_as::<_, (bool, bool, Option<DateTime<Utc>>, bool, bool)>(
+            "SELECT pinned, archived, cleared_before, muted, mentions_only FROM user_conversation WHERE username = $1 AND conversation_id = $2",
+        )
+        .bind(username)
+        .bind(conversation_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| {
+            row.map_or_else(UserConversation::default, |row| UserConversation {
+                pinned: row.0,
+                archived: row.1,
+                cleared_before: row.2,
+                muted: row.3,
+                mentions_only: row.4,
+            })
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting user conversation", err))
+    }
+
+    async fn set_conversation_pinned(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        pinned: bool,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO user_conversation (username, conversation_id, pinned) VALUES ($1, $2, $3) ON CONFLICT (username, conversation_id) DO UPDATE SET pinned = EXCLUDED.pinned")
+            .bind(username)
+            .bind(conversation_id)
+            .bind(pinned)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error pinning conversation", err))
+    }
+
+    async fn set_conversation_archived(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        archived: bool,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO user_conversation (username, conversation_id, archived) VALUES ($1, $2, $3) ON CONFLICT (username, conversation_id) DO UPDATE SET archived = EXCLUDED.archived")
+            .bind(username)
+            .bind(conversation_id)
+            .bind(archived)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error archiving conversation", err))
+    }
+
+    async fn clear_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        cleared_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO user_conversation (username, conversation_id, cleared_before) VALUES ($1, $2, $3) ON CONFLICT (username, conversation_id) DO UPDATE SET cleared_before = EXCLUDED.cleared_before")
+            .bind(username)
+            .bind(conversation_id)
+            .bind(cleared_before)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error clearing conversation", err))
+    }
+
+    async fn set_conversation_notification_prefs(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        muted: bool,
+        mentions_only: bool,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO user_conversation (username, conversation_id, muted, mentions_only) VALUES ($1, $2, $3, $4) ON CONFLICT (username, conversation_id) DO UPDATE SET muted = EXCLUDED.muted, mentions_only = EXCLUDED.mentions_only")
+            .bind(username)
+            .bind(conversation_id)
+            .bind(muted)
+            .bind(mentions_only)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error setting conversation notification prefs", err))
+    }
+
+    async fn get_notification_prefs(
+        &self,
+        username: &str,
+    ) -> Result<NotificationPrefs, DatabaseError> {
+        sqlx::query_as::<_, (bool, bool, Option<i16>, Option<i16>, Option<i16>)>(
+            "SELECT muted, mentions_only, dnd_start_minute, dnd_end_minute, dnd_utc_offset_minutes FROM notification_prefs WHERE username = $1",
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| {
+            row.map_or_else(NotificationPrefs::default, |row| NotificationPrefs {
+                muted: row.0,
+                mentions_only: row.1,
+                dnd: match (row.2, row.3, row.4) {
+                    (Some(start_minute), Some(end_minute), Some(utc_offset_minutes)) => {
+                        Some(DndWindow {
+                            start_minute: start_minute as u16,
+                            end_minute: end_minute as u16,
+                            utc_offset_minutes,
+                        })
+                    }
+                    _ => None,
+                },
+            })
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting notification prefs", err))
+    }
+
+    async fn set_notification_prefs(
+        &self,
+        username: &str,
+        notification_prefs: &NotificationPrefs,
+    ) -> Result<(), DatabaseError> {
+        let dnd = notification_prefs.dnd;
+
+        sqlx::query("INSERT INTO notification_prefs (username, muted, mentions_only, dnd_start_minute, dnd_end_minute, dnd_utc_offset_minutes) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (username) DO UPDATE SET muted = EXCLUDED.muted, mentions_only = EXCLUDED.mentions_only, dnd_start_minute = EXCLUDED.dnd_start_minute, dnd_end_minute = EXCLUDED.dnd_end_minute, dnd_utc_offset_minutes = EXCLUDED.dnd_utc_offset_minutes")
+            .bind(username)
+            .bind(notification_prefs.muted)
+            .bind(notification_prefs.mentions_only)
+            .bind(dnd.map(|dnd| dnd.start_minute as i16))
+            .bind(dnd.map(|dnd| dnd.end_minute as i16))
+            .bind(dnd.map(|dnd| dnd.utc_offset_minutes))
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error setting notification prefs", err))
+    }
+
+    async fn pin_message(
+        &self,
+        conversation_id: &str,
+        pinned_message: &PinnedMessage,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO pinned_message (conversation_id, sent_at, pinned_at, pinned_by_chooser) VALUES ($1, $2, $3, $4) ON CONFLICT (conversation_id, sent_at) DO UPDATE SET pinned_at = EXCLUDED.pinned_at, pinned_by_chooser = EXCLUDED.pinned_by_chooser")
+            .bind(conversation_id)
+            .bind(pinned_message.sent_at)
+            .bind(pinned_message.pinned_at)
+            .bind(pinned_message.pinned_by_chooser)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error pinning message", err))
+    }
+
+    async fn unpin_message(
+        &self,
+        conversation_id: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("DELETE FROM pinned_message WHERE conversation_id = $1 AND sent_at = $2")
+            .bind(conversation_id)
+            .bind(sent_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error unpinning message", err))
+    }
+
+    async fn get_pinned_messages(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<PinnedMessage>, DatabaseError> {
+        sqlx::query_as::<_, (DateTime<Utc>, DateTime<Utc>, bool)>("SELECT sent_at, pinned_at, pinned_by_chooser FROM pinned_message WHERE conversation_id = $1 ORDER BY sent_at")
+            .bind(conversation_id)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| PinnedMessage {
+                        sent_at: row.0,
+                        pinned_at: row.1,
+                        pinned_by_chooser: row.2,
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting pinned messages", err))
+    }
+
+    async fn new_poll(&self, conversation_id: &str, poll: &Poll) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO poll (conversation_id, poll_id, question, options, created_by_chooser, created_at) VALUES ($1, $2, $3, $4, $5, $6)")
+            .bind(conversation_id)
+            .bind(&poll.poll_id)
+            .bind(&poll.question)
+            .bind(poll.options.iter().map(|option| option.text.clone()).collect::<Vec<_>>())
+            .bind(poll.created_by_chooser)
+            .bind(poll.created_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error creating poll", err))
+    }
+
+    async fn vote_in_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+        from_chooser: bool,
+        option: u32,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(if from_chooser {
+            "UPDATE poll SET chooser_vote = $3 WHERE conversation_id = $1 AND poll_id = $2"
+        } else {
+            "UPDATE poll SET choosee_vote = $3 WHERE conversation_id = $1 AND poll_id = $2"
+        })
+        .bind(conversation_id)
+        .bind(poll_id)
+        .bind(option as i32)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| DatabaseError::postgres("Error voting in poll", err))
+    }
+
+    async fn get_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+    ) -> Result<Option<Poll>, DatabaseError> {
+        sqlx::query_as::<_, PollRow>("SELECT poll_id, question, options, created_by_chooser, created_at, chooser_vote, choosee_vote FROM poll WHERE conversation_id = $1 AND poll_id = $2")
+            .bind(conversation_id)
+            .bind(poll_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map(|row| row.map(Self::poll_from_row))
+            .map_err(|err| DatabaseError::postgres("Error getting poll", err))
+    }
+
+    async fn get_polls(&self, conversation_id: &str) -> Result<Vec<Poll>, DatabaseError> {
+        sqlx::query_as::<_, PollRow>("SELECT poll_id, question, options, created_by_chooser, created_at, chooser_vote, choosee_vote FROM poll WHERE conversation_id = $1 ORDER BY created_at")
+            .bind(conversation_id)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| rows.into_iter().map(Self::poll_from_row).collect())
+            .map_err(|err| DatabaseError::postgres("Error getting polls", err))
+    }
+
+    async fn get_conversation_stats(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationStats, DatabaseError> {
+        sqlx::query_as::<_, (bool, i64)>(
+            "SELECT from_chooser, messages FROM conversation_stats WHERE conversation_id = $1",
+        )
+        .bind(conversation_id)
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter().fold(
+                ConversationStats::default(),
+                |mut stats, (from_chooser, messages)| {
+                    if from_chooser {
+                        stats.messages_from_chooser = messages as u64;
+                    } else {
+                        stats.messages_from_choosee = messages as u64;
+                    }
+
+                    stats
+                },
+            )
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting conversation stats", err))
+    }
+
+    async fn add_key_distribution(
+        &self,
+        conversation_id: &str,
+        key_distribution: &KeyDistribution,
+        ttl: std::time::Duration,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO key_distribution (conversation_id, sent_at, from_chooser, key_material, expires_at) VALUES ($1, $2, $3, $4, $2 + $5 * interval '1 second') ON CONFLICT (conversation_id, sent_at, from_chooser) DO UPDATE SET key_material = EXCLUDED.key_material, expires_at = EXCLUDED.expires_at")
+            .bind(conversation_id)
+            .bind(key_distribution.sent_at)
+            .bind(key_distribution.from_chooser)
+            .bind(&key_distribution.key_material)
+            .bind(ttl.as_secs_f64())
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error adding key distribution", err))
+    }
+
+    async fn get_key_distributions(
+        &self,
+        conversation_id: &str,
+        from_chooser: bool,
+    ) -> Result<Vec<KeyDistribution>, DatabaseError> {
+        sqlx::query_as::<_, (String, DateTime<Utc>, bool)>("SELECT key_material, sent_at, from_chooser FROM key_distribution WHERE conversation_id = $1 AND from_chooser = $2 AND expires_at > now() ORDER BY sent_at")
+            .bind(conversation_id)
+            .bind(from_chooser)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| KeyDistribution {
+                        key_material: row.0,
+                        sent_at: row.1,
+                        from_chooser: row.2,
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting key distributions", err))
+    }
+
+    async fn add_mention(
+        &self,
+        username_hash: &str,
+        mention: &Mention,
+        ttl: std::time::Duration,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO mention (username_hash, sent_at, conversation_id, expires_at) VALUES ($1, $2, $3, $2 + $4 * interval '1 second') ON CONFLICT (username_hash, sent_at, conversation_id) DO NOTHING")
+            .bind(username_hash)
+            .bind(mention.sent_at)
+            .bind(&mention.conversation_id)
+            .bind(ttl.as_secs_f64())
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error adding mention", err))
+    }
+
+    async fn get_mentions(
+        &self,
+        username_hash: &str,
+        take: i32,
+    ) -> Result<Vec<Mention>, DatabaseError> {
+        sqlx::query_as::<_, (String, DateTime<Utc>)>("SELECT conversation_id, sent_at FROM mention WHERE username_hash = $1 AND expires_at > now() ORDER BY sent_at DESC LIMIT $2")
+            .bind(username_hash)
+            .bind(take as i64)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|(conversation_id, sent_at)| Mention {
+                        conversation_id,
+                        sent_at,
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting mentions", err))
+    }
+
+    async fn remove_mentions(&self, username_hash: &str) -> Result<(), DatabaseError> {
+        sqlx::query("DELETE FROM mention WHERE username_hash = $1")
+            .bind(username_hash)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error removing mentions", err))
+    }
+
+    async fn add_push_token(
+        &self,
+        username_hash: &str,
+        push_token: &PushToken,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO push_token (username_hash, token, platform, username, registered_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (username_hash, token) DO UPDATE SET platform = EXCLUDED.platform, username = EXCLUDED.username, registered_at = EXCLUDED.registered_at")
+            .bind(username_hash)
+            .bind(&push_token.token)
+            .bind(push_token.platform.as_str())
+            .bind(&push_token.username)
+            .bind(push_token.registered_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error adding push token", err))
+    }
+
+    async fn get_push_tokens(&self, username_hash: &str) -> Result<Vec<PushToken>, DatabaseError> {
+        sqlx::query_as::<_, (String, String, String, DateTime<Utc>)>("SELECT token, platform, username, registered_at FROM push_token WHERE username_hash = $1")
+            .bind(username_hash)
+            .fetch_all(&self.pool)
+            .await
+            .map(|rows| {
+                rows.into_iter()
+                    .filter_map(|row| {
+                        Some(PushToken {
+                            platform: PushPlatform::parse(&row.1)?, // registered by a newer version
+                            token: row.0,
+                            username: row.2,
+                            registered_at: row.3,
+                        })
+                    })
+                    .collect()
+            })
+            .map_err(|err| DatabaseError::postgres("Error getting push tokens", err))
+    }
+
+    async fn remove_push_token(
+        &self,
+        username_hash: &str,
+        token: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("DELETE FROM push_token WHERE username_hash = $1 AND token = $2")
+            .bind(username_hash)
+            .bind(token)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error removing push token", err))
+    }
+
+    async fn remove_push_tokens(&self, username_hash: &str) -> Result<(), DatabaseError> {
+        sqlx::query("DELETE FROM push_token WHERE username_hash = $1")
+            .bind(username_hash)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error removing push tokens", err))
+    }
+
+    // receiver_friends is what scylla needs to fan out without a join. here the friend table already has it
+    async fn touch_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        seen_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO device (username, device_id, last_seen_at) VALUES ($1, $2, $3) ON CONFLICT (username, device_id) DO UPDATE SET last_seen_at = EXCLUDED.last_seen_at")
+            .bind(username)
+            .bind(device_id)
+            .bind(seen_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error touching device", err))
+    }
+
+    async fn touch_user(
+        &self,
+        username: &str,
+        connected_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("UPDATE \"user\" SET last_connected_at = $2 WHERE username = $1")
+            .bind(username)
+            .bind(connected_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error touching user", err))
+    }
+
+    async fn get_away_users(
+        &self,
+        connected_after: DateTime<Utc>,
+        connected_before: DateTime<Utc>,
+        after: Option<&str>,
+        take: i32,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, DatabaseError> {
+        sqlx::query_as::<_, (String, DateTime<Utc>)>("SELECT username, last_connected_at FROM \"user\" WHERE last_connected_at > $1 AND last_connected_at < $2 AND username > $3 ORDER BY username LIMIT $4")
+            .bind(connected_after)
+            .bind(connected_before)
+            .bind(after.unwrap_or_default())
+            .bind(i64::from(take))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| DatabaseError::postgres("Error getting away users", err))
+    }
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, DatabaseError> {
+        sqlx::query_as::<_, (String, DateTime<Utc>)>(
+            "SELECT device_id, last_seen_at FROM device WHERE username = $1 AND (logged_out_at IS NULL OR logged_out_at < last_seen_at) ORDER BY last_seen_at DESC",
+        )
+        .bind(username)
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| Device {
+                    device_id: row.0,
+                    last_seen_at: row.1,
+                    connected: false,
+                    current: false,
+                })
+                .collect()
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting devices", err))
+    }
+
+    async fn log_out_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        logged_out_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO device (username, device_id, last_seen_at, logged_out_at) VALUES ($1, $2, $3, $3) ON CONFLICT (username, device_id) DO UPDATE SET logged_out_at = EXCLUDED.logged_out_at")
+            .bind(username)
+            .bind(device_id)
+            .bind(logged_out_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error logging out device", err))
+    }
+
+    async fn get_device_logged_out_at(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        sqlx::query_as::<_, (Option<DateTime<Utc>>,)>(
+            "SELECT logged_out_at FROM device WHERE username = $1 AND device_id = $2",
+        )
+        .bind(username)
+        .bind(device_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.and_then(|row| row.0))
+        .map_err(|err| DatabaseError::postgres("Error getting device logout", err))
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO report (report_id, reporter_username, conversation_id, message_sent_at, reason, reported_at) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING")
+            .bind(&report.report_id)
+            .bind(&report.reporter_username)
+            .bind(&report.conversation_id)
+            .bind(report.message_sent_at)
+            .bind(&report.reason)
+            .bind(report.reported_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error adding report", err))
+    }
+
+    async fn create_friendship(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+        _receiver_friends: Vec<Profile>,
+    ) -> Result<(), DatabaseError> {
+        async {
+            let mut tx = self.pool.begin().await?;
+
+            sqlx::query("DELETE FROM friend_request WHERE sender_username = $1 AND receiver_username = $2")
+                .bind(&sender.username)
+                .bind(&receiver.username)
+                .execute(&mut tx)
+                .await?;
+
+            Self::add_friends_of_friends(&mut tx, &sender, &receiver).await?;
+            Self::add_friends_of_friends(&mut tx, &receiver, &sender).await?;
+
+            for (profile, friend) in [(&sender, &receiver), (&receiver, &sender)] {
+                sqlx::query("INSERT INTO friend (username, friend_username, friend_name, friendship_started_on) VALUES ($1, $2, $3, now()) ON CONFLICT DO NOTHING")
+                    .bind(&profile.username)
+                    .bind(&friend.username)
+                    .bind(&friend.name)
+                    .execute(&mut tx)
+                    .await?;
+            }
+
+            tx.commit().await
+        }
+        .await
+        .map_err(|err| DatabaseError::postgres("Error creating friendship", err))
+    }
+
+    async fn add_to_outbox(
+        &self,
+        bucket: i32,
+        subject: &str,
+        data: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            "INSERT INTO outbox_entry (bucket, created_at, entry_id, subject, data) VALUES ($1, now(), $2, $3, $4)",
+        )
+        .bind(bucket)
+        .bind(Uuid::new_v4().to_string())
+        .bind(subject)
+        .bind(data)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| DatabaseError::postgres("Error adding event to outbox", err))
+    }
+
+    async fn get_outbox(&self, bucket: i32) -> Result<Vec<OutboxEntry>, DatabaseError> {
+        sqlx::query_as::<_, (DateTime<Utc>, String, String, Vec<u8>)>(
+            "SELECT created_at, entry_id, subject, data FROM outbox_entry WHERE bucket = $1 ORDER BY created_at, entry_id",
+        )
+        .bind(bucket)
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| OutboxEntry {
+                    bucket,
+                    created_at: row.0,
+                    entry_id: row.1,
+                    subject: row.2,
+                    data: row.3,
+                })
+                .collect()
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting outbox", err))
+    }
+
+    async fn remove_from_outbox(
+        &self,
+        bucket: i32,
+        created_at: DateTime<Utc>,
+        entry_id: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            "DELETE FROM outbox_entry WHERE bucket = $1 AND created_at = $2 AND entry_id = $3",
+        )
+        .bind(bucket)
+        .bind(created_at)
+        .bind(entry_id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| DatabaseError::postgres("Error removing event from outbox", err))
+    }
+
+    async fn adopt_legacy_outbox(&self, bucket: i32) -> Result<u64, DatabaseError> {
+        async {
+            let mut tx = self.pool.begin().await?;
+
+            let (exists,) = sqlx::query_as::<_, (bool,)>("SELECT to_regclass('outbox') IS NOT NULL")
+                .fetch_one(&mut tx)
+                .await?;
+
+            if !exists {
+                return Ok(0);
+            }
+
+            // subject and created_at were the old key, so the subject is unique enough as an entry id
+            let adopted = sqlx::query("INSERT INTO outbox_entry (bucket, created_at, entry_id, subject, data) SELECT $1, created_at, subject, subject, data FROM outbox ON CONFLICT DO NOTHING")
+                .bind(bucket)
+                .execute(&mut tx)
+                .await?
+                .rows_affected();
+
+            sqlx::query("DROP TABLE outbox").execute(&mut tx).await?;
+
+            tx.commit().await?;
+
+            Ok(adopted)
+        }
+        .await
+        .map_err(|err| DatabaseError::postgres("Error adopting legacy outbox", err))
+    }
+
+    async fn has_messages(&self, conversation_id: &str) -> Result<bool, DatabaseError> {
+        sqlx::query_as::<_, (bool,)>(
+            "SELECT EXISTS (SELECT 1 FROM message WHERE conversation_id = $1 AND (expires_at IS NULL OR expires_at > now()))",
+        )
+        .bind(conversation_id)
+        .fetch_one(&self.pool)
+        .await
+        .map(|row| row.0)
+        .map_err(|err| DatabaseError::postgres("Error checking for messages", err))
+    }
+
+    async fn get_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+    ) -> Result<Option<String>, DatabaseError> {
+        sqlx::query_as::<_, (String,)>(
+            "SELECT conversation_id FROM conversation_link WHERE chooser_hash = $1 AND choosee_hash = $2",
+        )
+        .bind(chooser_hash)
+        .bind(choosee_hash)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(|row| row.0))
+        .map_err(|err| DatabaseError::postgres("Error getting latest conversation id", err))
+    }
+
+    async fn set_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+        conversation_id: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            "INSERT INTO conversation_link (chooser_hash, choosee_hash, conversation_id) VALUES ($1, $2, $3) ON CONFLICT (chooser_hash, choosee_hash) DO UPDATE SET conversation_id = EXCLUDED.conversation_id",
+        )
+        .bind(chooser_hash)
+        .bind(choosee_hash)
+        .bind(conversation_id)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| DatabaseError::postgres("Error setting latest conversation id", err))
+    }
+
+    async fn remove_friend(
+        &self,
+        username: &str,
+        friend_username: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("DELETE FROM friend WHERE username = $1 AND friend_username = $2")
+            .bind(username)
+            .bind(friend_username)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error removing friend", err))
+    }
+
+    async fn remove_friend_of_friend(
+        &self,
+        username: &str,
+        friend_of_friend_username: &str,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query(
+            "DELETE FROM friend_of_friend WHERE username = $1 AND friend_of_friend_username = $2",
+        )
+        .bind(username)
+        .bind(friend_of_friend_username)
+        .execute(&self.pool)
+        .await
+        .map(|_| ())
+        .map_err(|err| DatabaseError::postgres("Error removing friend of friend", err))
+    }
+
+    async fn tombstone_conversation(
+        &self,
+        conversation_id: &str,
+        tombstoned_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        async {
+            let mut tx = self.pool.begin().await?;
+
+            sqlx::query("UPDATE conversation SET tombstoned_at = $2 WHERE id = $1")
+                .bind(conversation_id)
+                .bind(tombstoned_at)
+                .execute(&mut tx)
+                .await?;
+
+            for statement in [
+                "DELETE FROM message WHERE conversation_id = $1",
+                "DELETE FROM pinned_message WHERE conversation_id = $1",
+                "DELETE FROM poll WHERE conversation_id = $1",
+            ] {
+                sqlx::query(statement)
+                    .bind(conversation_id)
+                    .execute(&mut tx)
+                    .await?;
+            }
+
+            tx.commit().await
+        }
+        .await
+        .map_err(|err| DatabaseError::postgres("Error tombstoning conversation", err))
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), DatabaseError> {
+        async {
+            let mut tx = self.pool.begin().await?;
+
+            for statement in [
+                "DELETE FROM friend WHERE username = $1",
+                "DELETE FROM friend_of_friend WHERE username = $1",
+                "DELETE FROM friend_request WHERE sender_username = $1 OR receiver_username = $1",
+                "DELETE FROM user_conversation WHERE username = $1",
+                "DELETE FROM device WHERE username = $1",
+                "DELETE FROM user_daily_messages WHERE username = $1",
+                "DELETE FROM \"user\" WHERE username = $1",
+            ] {
+                sqlx::query(statement)
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?;
+            }
+
+            tx.commit().await
+        }
+        .await
+        .map_err(|err| DatabaseError::postgres("Error deleting user", err))
+    }
+
+    async fn delete_conversations_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        take: i32,
+    ) -> Result<usize, DatabaseError> {
+        async {
+            let mut tx = self.pool.begin().await?;
+
+            let conversation_ids = sqlx::query_as::<_, (String,)>(
+                "SELECT id FROM conversation WHERE created_at < $1 LIMIT $2",
+            )
+            .bind(cutoff)
+            .bind(i64::from(take))
+            .fetch_all(&mut tx)
+            .await?
+            .into_iter()
+            .map(|row| row.0)
+            .collect::<Vec<_>>();
+
+            for statement in [
+                "DELETE FROM message WHERE conversation_id = ANY($1)",
+                "DELETE FROM choosee_presence WHERE conversation_id = ANY($1)",
+                "DELETE FROM pinned_message WHERE conversation_id = ANY($1)",
+                "DELETE FROM poll WHERE conversation_id = ANY($1)",
+                "DELETE FROM conversation_stats WHERE conversation_id = ANY($1)",
+                "DELETE FROM user_conversation WHERE conversation_id = ANY($1)",
+                "DELETE FROM conversation WHERE id = ANY($1)",
+            ] {
+                sqlx::query(statement)
+                    .bind(&conversation_ids)
+                    .execute(&mut tx)
+                    .await?;
+            }
+
+            tx.commit().await?;
+
+            Ok(conversation_ids.len())
+        }
+        .await
+        .map_err(|err| DatabaseError::postgres("Error deleting old conversations", err))
+    }
+
+    async fn end_streaks(&self, before: NaiveDate, take: i32) -> Result<usize, DatabaseError> {
+        sqlx::query("UPDATE conversation SET streak = 0 WHERE id IN (SELECT id FROM conversation WHERE streak > 0 AND streak_day < $1 LIMIT $2)")
+            .bind(before)
+            .bind(i64::from(take))
+            .execute(&self.pool)
+            .await
+            .map(|result| result.rows_affected() as usize)
+            .map_err(|err| DatabaseError::postgres("Error ending streaks", err))
+    }
+
+    async fn get_revoked_before(
+        &self,
+        username: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        sqlx::query_as::<_, (DateTime<Utc>,)>(
+            "SELECT revoked_before FROM token_revocation WHERE username = $1",
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| row.map(|row| row.0))
+        .map_err(|err| DatabaseError::postgres("Error getting token revocation", err))
+    }
+
+    async fn revoke_tokens(
+        &self,
+        username: &str,
+        revoked_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO token_revocation (username, revoked_before) VALUES ($1, $2) ON CONFLICT (username) DO UPDATE SET revoked_before = EXCLUDED.revoked_before")
+            .bind(username)
+            .bind(revoked_before)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error revoking tokens", err))
+    }
+
+    async fn get_bot_key(&self, key_id: &str) -> Result<Option<BotKey>, DatabaseError> {
+        sqlx::query_as::<_, (String, String, DateTime<Utc>, Option<DateTime<Utc>>)>(
+            "SELECT username, secret, created_at, revoked_at FROM bot_key WHERE key_id = $1",
+        )
+        .bind(key_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map(|row| {
+            row.map(|row| BotKey {
+                key_id: key_id.to_owned(),
+                username: row.0,
+                secret: row.1,
+                created_at: row.2,
+                revoked_at: row.3,
+            })
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting bot key", err))
+    }
+
+    fn message_retention(&self) -> Option<std::time::Duration> {
+        None // nothing expires here
+    }
+
+    async fn health_check(&self) -> Result<(), DatabaseError> {
+        sqlx::query("SELECT 1")
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error checking database health", err))
+    }
+
+    async fn add_failed_event(&self, failed_event: &FailedEvent) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO failed_event (subject, failed_at, created_at, data, reason) VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING")
+            .bind(&failed_event.subject)
+            .bind(failed_event.failed_at)
+            .bind(failed_event.created_at)
+            .bind(&failed_event.data)
+            .bind(&failed_event.reason)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error adding failed event", err))
+    }
+
+    async fn get_failed_events(&self) -> Result<Vec<FailedEvent>, DatabaseError> {
+        sqlx::query_as::<_, (String, DateTime<Utc>, DateTime<Utc>, Vec<u8>, String)>(
+            "SELECT subject, failed_at, created_at, data, reason FROM failed_event ORDER BY failed_at",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .map(|row| FailedEvent {
+                    subject: row.0,
+                    failed_at: row.1,
+                    created_at: row.2,
+                    data: row.3,
+                    reason: row.4,
+                })
+                .collect()
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting failed events", err))
+    }
+
+    async fn remove_failed_event(
+        &self,
+        subject: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        sqlx::query("DELETE FROM failed_event WHERE subject = $1 AND failed_at = $2")
+            .bind(subject)
+            .bind(failed_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error removing failed event", err))
+    }
+
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO audit_log (entry_id, action, actor, target, connection_id, detail, occurred_at) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT DO NOTHING")
+            .bind(&audit_entry.entry_id)
+            .bind(audit_entry.action.as_str())
+            .bind(&audit_entry.actor)
+            .bind(&audit_entry.target)
+            .bind(&audit_entry.connection_id)
+            .bind(&audit_entry.detail)
+            .bind(audit_entry.occurred_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error adding audit entry", err))
+    }
+
+    async fn get_audit_log(
+        &self,
+        day: NaiveDate,
+        take: i32,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let start =
+            Utc.from_utc_datetime(&day.and_hms_opt(0, 0, 0).expect("Midnight should exist"));
+
+        sqlx::query_as::<
+            _,
+            (
+                String,
+                String,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+                String,
+                DateTime<Utc>,
+            ),
+        >("SELECT entry_id, action, actor, target, connection_id, detail, occurred_at FROM audit_log WHERE occurred_at >= $1 AND occurred_at < $2 ORDER BY occurred_at DESC LIMIT $3")
+        .bind(start)
+        .bind(start + chrono::Duration::days(1))
+        .bind(i64::from(take))
+        .fetch_all(&self.pool)
+        .await
+        .map(|rows| {
+            rows.into_iter()
+                .filter_map(|row| {
+                    Some(AuditEntry {
+                        action: AuditAction::parse(&row.1)?, // recorded by a newer version
+                        entry_id: row.0,
+                        actor: row.2,
+                        target: row.3,
+                        connection_id: row.4,
+                        detail: row.5,
+                        occurred_at: row.6,
+                    })
+                })
+                .collect()
+        })
+        .map_err(|err| DatabaseError::postgres("Error getting audit log", err))
+    }
+
+    async fn set_job_status(&self, job_status: &JobStatus) -> Result<(), DatabaseError> {
+        sqlx::query("INSERT INTO job_status (job_id, kind, state, attempts, last_error, enqueued_at, updated_at) VALUES ($1, $2, $3, $4, $5, $6, $7) ON CONFLICT (job_id) DO UPDATE SET state = EXCLUDED.state, attempts = EXCLUDED.attempts, last_error = EXCLUDED.last_error, updated_at = EXCLUDED.updated_at")
+            .bind(&job_status.job_id)
+            .bind(&job_status.kind)
+            .bind(job_status.state.as_str())
+            .bind(job_status.attempts as i32)
+            .bind(&job_status.last_error)
+            .bind(job_status.enqueued_at)
+            .bind(job_status.updated_at)
+            .execute(&self.pool)
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::postgres("Error setting job status", err))
+    }
+}
@@ -0,0 +1,3618 @@
+use async_trait::async_trait;
+use chrono::{prelude::*, Duration};
+use futures_util::future::try_join_all;
+use scylla::transport::{
+    load_balancing::{
+        ChildLoadBalancingPolicy, DcAwareRoundRobinPolicy, LoadBalancingPolicy, RoundRobinPolicy,
+        TokenAwarePolicy,
+    },
+    session::PoolSize,
+};
+use scylla::{
+    frame::value::Counter,
+    prepared_statement::PreparedStatement,
+    statement::Consistency,
+    transport::errors::{DbError, QueryError},
+    QueryResult,
+};
+use std::collections::HashMap;
+#[cfg(feature = "scylla-tls")]
+use std::path::PathBuf;
+use std::{num::NonZeroUsize, sync::Arc, time::Duration as StdDuration};
+use thiserror::Error;
+use uuid::Uuid;
+
+pub use self::coalescer::CoalescerOptions;
+use self::coalescer::MessageCoalescer;
+use self::retry::{RetryBudget, RetryingSession};
+use super::{ContentKeyError, ContentKeys, DatabaseError, Storage};
+use crate::models::{
+    audit_entry::{AuditAction, AuditEntry},
+    bot_key::BotKey,
+    conversation_stats::ConversationStats,
+    conversation_summary::ConversationSummary,
+    device::Device,
+    failed_event::FailedEvent,
+    friend_profile::FriendProfile,
+    job_status::JobStatus,
+    key_distribution::KeyDistribution,
+    mention::Mention,
+    message::Message,
+    notification_prefs::{DndWindow, NotificationPrefs},
+    outbox_entry::OutboxEntry,
+    pinned_message::PinnedMessage,
+    poll::Poll,
+    presence_event::PresenceEvent,
+    profile::Profile,
+    push_token::{PushPlatform, PushToken},
+    report::Report,
+    user_conversation::UserConversation,
+};
+use crate::retry_policy::RetryPolicy;
+use crate::streaks;
+
+mod coalescer;
+mod migrations;
+mod retry;
+
+// user_by_prefix is partitioned by this many leading characters of the username, so a search reads one partition
+const USER_SEARCH_PARTITION_LENGTH: usize = 2;
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("Error connecting to scylla: {0}")]
+    Connect(#[from] scylla::transport::errors::NewSessionError),
+    #[cfg(feature = "scylla-tls")]
+    #[error("Error setting up tls: {0}")]
+    Tls(#[from] openssl::error::ErrorStack),
+    #[error("Error migrating schema: {0}")]
+    Migrate(#[from] DatabaseError),
+}
+
+pub struct SessionOptions {
+    pub known_nodes: Vec<String>, // contact points, the rest of the cluster is discovered from them
+    pub username: String,
+    pub password: String,
+    #[cfg(feature = "scylla-tls")]
+    pub tls: Option<TlsOptions>,
+    pub connections_per_shard: Option<NonZeroUsize>, // driver default when unset
+    pub connection_timeout: StdDuration,
+    pub request_timeout: Option<StdDuration>,
+    pub local_datacenter: Option<String>, // queries prefer nodes here, round robin over the whole cluster when unset
+    pub include_remote_nodes: bool, // fall back to other datacenters when every local node is down
+    pub token_aware: bool,
+    pub retry_policy: RetryPolicy, // for idempotent statements that timed out or hit an overloaded node
+    pub retry_budget_ratio: f64,   // retries allowed per statement, averaged over time
+    pub retry_budget_capacity: f64,
+}
+
+impl SessionOptions {
+    fn load_balancing(&self) -> Arc<dyn LoadBalancingPolicy> {
+        let child_policy: Box<dyn ChildLoadBalancingPolicy> = match &self.local_datacenter {
+            Some(local_datacenter) => {
+                let mut policy = DcAwareRoundRobinPolicy::new(local_datacenter.clone());
+                policy.set_include_remote_nodes(self.include_remote_nodes);
+                Box::new(policy)
+            }
+            None => Box::new(RoundRobinPolicy::new()),
+        };
+
+        if self.token_aware {
+            Arc::new(TokenAwarePolicy::new(child_policy)) // replicas first, in the order the child policy picks
+        } else {
+            Arc::from(child_policy as Box<dyn LoadBalancingPolicy>)
+        }
+    }
+}
+
+#[cfg(feature = "scylla-tls")]
+pub struct TlsOptions {
+    pub ca_cert: PathBuf,
+    pub client_cert: Option<(PathBuf, PathBuf)>, // cert and key, for clusters that require client authentication
+}
+
+#[cfg(feature = "scylla-tls")]
+impl TlsOptions {
+    fn ssl_context(&self) -> Result<openssl::ssl::SslContext, openssl::error::ErrorStack> {
+        use openssl::ssl::{SslContextBuilder, SslFiletype, SslMethod, SslVerifyMode};
+
+        let mut context = SslContextBuilder::new(SslMethod::tls())?;
+
+        context.set_ca_file(&self.ca_cert)?;
+        context.set_verify(SslVerifyMode::PEER);
+
+        if let Some((cert, key)) = &self.client_cert {
+            context.set_certificate_file(cert, SslFiletype::PEM)?;
+            context.set_private_key_file(key, SslFiletype::PEM)?;
+        }
+
+        Ok(context.build())
+    }
+}
+
+pub struct KeyspaceOptions {
+    pub name: String,
+    pub replication: Replication,
+    pub create: bool, // when it's missing. off for clusters where the keyspace is managed elsewhere
+}
+
+pub enum Replication {
+    Simple { replication_factor: u32 },
+    NetworkTopology { datacenters: Vec<(String, u32)> },
+}
+
+impl Replication {
+    fn to_cql(&self) -> String {
+        match self {
+            Self::Simple { replication_factor } => format!(
+                "{{'class': 'SimpleStrategy', 'replication_factor': {}}}",
+                replication_factor
+            ),
+            Self::NetworkTopology { datacenters } => format!(
+                "{{'class': 'NetworkTopologyStrategy'{}}}",
+                datacenters
+                    .iter()
+                    .map(|(datacenter, replication_factor)| format!(
+                        ", '{}': {}",
+                        datacenter, replication_factor
+                    ))
+                    .collect::<String>()
+            ),
+        }
+    }
+}
+
+// the health check keeps the driver default, it only needs any node to answer
+pub struct Consistencies {
+    pub writes: Consistency,
+    pub history_reads: Consistency, // messages and presence history, where a slightly stale answer is fine
+    pub reads: Consistency,
+}
+
+type PollRow = (
+    String,
+    String,
+    Vec<String>,
+    bool,
+    Duration,
+    Option<i32>,
+    Option<i32>,
+);
+
+pub struct ScyllaStorage {
+    db: Arc<RetryingSession>,
+    message_coalescer: Option<MessageCoalescer>, // new_message goes through this when it's on
+    message_retention: Option<StdDuration>,
+    content_keys: Option<ContentKeys>, // message content is encrypted when set
+    new_conversation_query: PreparedStatement,
+    new_message_query: PreparedStatement,
+    update_choosee_last_presence_at_query: PreparedStatement,
+    get_messages_query: PreparedStatement,
+    add_friend_request_on_sender_query: PreparedStatement,
+    add_friend_request_on_receiver_query: PreparedStatement,
+    get_friends_of_user_query: PreparedStatement,
+    remove_friend_request_on_sender_query: PreparedStatement,
+    remove_friend_request_on_receiver_query: PreparedStatement,
+    add_friend_query: PreparedStatement,
+    add_friends_of_friends_query: PreparedStatement,
+    remove_friend_query: PreparedStatement,
+    remove_friends_of_friends_query: PreparedStatement,
+    add_to_outbox_query: PreparedStatement,
+    get_outbox_query: PreparedStatement,
+    remove_from_outbox_query: PreparedStatement,
+    has_messages_query: PreparedStatement,
+    delete_user_query: PreparedStatement,
+    get_revoked_before_query: PreparedStatement,
+    revoke_tokens_query: PreparedStatement,
+    get_bot_key_query: PreparedStatement,
+    health_check_query: PreparedStatement,
+    add_report_query: PreparedStatement,
+    delete_pinned_messages_query: PreparedStatement,
+    delete_messages_query: PreparedStatement,
+    tombstone_conversation_query: PreparedStatement,
+    get_friends_of_friends_query: PreparedStatement,
+    get_device_logged_out_at_query: PreparedStatement,
+    log_out_device_query: PreparedStatement,
+    get_devices_query: PreparedStatement,
+    touch_device_query: PreparedStatement,
+    set_notification_prefs_query: PreparedStatement,
+    get_notification_prefs_query: PreparedStatement,
+    set_conversation_notification_prefs_query: PreparedStatement,
+    remove_push_tokens_query: PreparedStatement,
+    remove_push_token_query: PreparedStatement,
+    get_push_tokens_query: PreparedStatement,
+    add_push_token_query: PreparedStatement,
+    search_users_query: PreparedStatement,
+    clear_conversation_query: PreparedStatement,
+    set_conversation_archived_query: PreparedStatement,
+    get_pinned_messages_query: PreparedStatement,
+    unpin_message_query: PreparedStatement,
+    pin_message_query: PreparedStatement,
+    new_poll_query: PreparedStatement,
+    set_chooser_vote_query: PreparedStatement,
+    set_choosee_vote_query: PreparedStatement,
+    get_poll_query: PreparedStatement,
+    get_polls_query: PreparedStatement,
+    delete_polls_query: PreparedStatement,
+    add_key_distribution_query: PreparedStatement,
+    get_key_distributions_query: PreparedStatement,
+    add_mention_query: PreparedStatement,
+    get_mentions_query: PreparedStatement,
+    remove_mentions_query: PreparedStatement,
+    count_conversation_message_query: PreparedStatement,
+    count_user_message_query: PreparedStatement,
+    get_conversation_stats_query: PreparedStatement,
+    delete_conversation_stats_query: PreparedStatement,
+    delete_user_daily_messages_query: PreparedStatement,
+    set_chooser_messaged_on_query: PreparedStatement,
+    set_choosee_messaged_on_query: PreparedStatement,
+    get_streak_query: PreparedStatement,
+    extend_streak_query: PreparedStatement,
+    get_streaks_ended_before_query: PreparedStatement,
+    end_streak_query: PreparedStatement,
+    touch_user_query: PreparedStatement,
+    get_away_users_query: PreparedStatement,
+    get_away_users_after_query: PreparedStatement,
+    get_friend_requests_received_query: PreparedStatement,
+    set_conversation_pinned_query: PreparedStatement,
+    get_user_conversation_query: PreparedStatement,
+    get_user_conversations_query: PreparedStatement,
+    get_conversations_as_choosee_query: PreparedStatement,
+    get_conversations_as_chooser_query: PreparedStatement,
+    set_avatar_query: PreparedStatement,
+    get_profile_query: PreparedStatement,
+    get_choosee_presence_query: PreparedStatement,
+    get_disappearing_query: PreparedStatement,
+    set_disappearing_query: PreparedStatement,
+    add_failed_event_query: PreparedStatement,
+    get_failed_events_query: PreparedStatement,
+    remove_failed_event_query: PreparedStatement,
+    add_audit_entry_query: PreparedStatement,
+    get_audit_log_query: PreparedStatement,
+    set_job_status_query: PreparedStatement,
+    get_conversations_created_before_query: PreparedStatement,
+    delete_conversation_query: PreparedStatement,
+    delete_choosee_presence_query: PreparedStatement,
+    delete_user_conversation_query: PreparedStatement,
+    get_latest_conversation_id_query: PreparedStatement,
+    set_latest_conversation_id_query: PreparedStatement,
+}
+
+impl ScyllaStorage {
+    pub async fn build(
+        session: &SessionOptions,
+        keyspace: &KeyspaceOptions,
+        consistencies: &Consistencies,
+        message_coalescing: Option<CoalescerOptions>,
+        message_retention: Option<StdDuration>, // messages and presence expire after this, kept forever when unset
+        content_keys: Option<ContentKeys>,
+    ) -> Result<Self, BuildError> {
+        let mut builder = scylla::SessionBuilder::new()
+            .known_nodes(&session.known_nodes)
+            .user(&session.username, &session.password)
+            .connection_timeout(session.connection_timeout)
+            .request_timeout(session.request_timeout)
+            .load_balancing(session.load_balancing());
+
+        if let Some(connections_per_shard) = session.connections_per_shard {
+            builder = builder.pool_size(PoolSize::PerShard(connections_per_shard));
+        }
+
+        #[cfg(feature = "scylla-tls")]
+        if let Some(tls) = &session.tls {
+            builder = builder.ssl_context(Some(tls.ssl_context()?));
+        }
+
+        let db = builder.build().await?;
+
+        // the keyspace and tables have to exist before anything can be prepared against them
+        if keyspace.create {
+            migrations::create_keyspace(&db, &keyspace.name, &keyspace.replication.to_cql())
+                .await?;
+        }
+
+        db.use_keyspace(&keyspace.name, true)
+            .await
+            .map_err(|err| DatabaseError::query("Error using keyspace", err))?;
+
+        migrations::run(&db).await?;
+
+        let mut new_conversation_query = Self::prepare_new_conversation_query(&db).await;
+
+        let mut new_message_query = Self::prepare_new_message_query(&db).await;
+
+        let mut update_choosee_last_presence_at_query =
+            Self::prepare_update_choosee_last_presence_at_query(&db).await;
+
+        let mut get_messages_query = Self::prepare_get_messages_query(&db).await;
+
+        let mut add_friend_request_on_sender_query =
+            Self::prepare_add_friend_request_on_sender_query(&db).await;
+
+        let mut get_friends_of_user_query = Self::prepare_get_friends_of_user_query(&db).await;
+
+        let mut add_friend_request_on_receiver_query =
+            Self::prepare_add_friend_request_on_receiver_query(&db).await;
+
+        let mut remove_friend_request_on_sender_query =
+            Self::prepare_remove_friend_request_on_sender_query(&db).await;
+
+        let mut remove_friend_request_on_receiver_query =
+            Self::prepare_remove_friend_request_on_receiver_query(&db).await;
+
+        let mut add_friend_query = Self::prepare_add_friend_query(&db).await;
+
+        let mut add_friends_of_friends_query =
+            Self::prepare_add_friends_of_friends_query(&db).await;
+
+        let mut remove_friend_query = Self::prepare_remove_friend_query(&db).await;
+
+        let mut remove_friends_of_friends_query =
+            Self::prepare_remove_friends_of_friends_query(&db).await;
+
+        let mut add_to_outbox_query = Self::prepare_add_to_outbox_query(&db).await;
+
+        let mut get_outbox_query = Self::prepare_get_outbox_query(&db).await;
+
+        let mut remove_from_outbox_query = Self::prepare_remove_from_outbox_query(&db).await;
+
+        let mut has_messages_query = Self::prepare_has_messages_query(&db).await;
+
+        let mut delete_user_query = Self::prepare_delete_user_query(&db).await;
+
+        let mut get_revoked_before_query = Self::prepare_get_revoked_before_query(&db).await;
+
+        let mut revoke_tokens_query = Self::prepare_revoke_tokens_query(&db).await;
+
+        let mut get_bot_key_query = Self::prepare_get_bot_key_query(&db).await;
+
+        let health_check_query = Self::prepare_health_check_query(&db).await;
+
+        let mut add_report_query = Self::prepare_add_report_query(&db).await;
+
+        let mut delete_pinned_messages_query =
+            Self::prepare_delete_pinned_messages_query(&db).await;
+
+        let mut delete_messages_query = Self::prepare_delete_messages_query(&db).await;
+
+        let mut tombstone_conversation_query =
+            Self::prepare_tombstone_conversation_query(&db).await;
+
+        let mut get_friends_of_friends_query =
+            Self::prepare_get_friends_of_friends_query(&db).await;
+
+        let mut get_device_logged_out_at_query =
+            Self::prepare_get_device_logged_out_at_query(&db).await;
+
+        let mut log_out_device_query = Self::prepare_log_out_device_query(&db).await;
+
+        let mut get_devices_query = Self::prepare_get_devices_query(&db).await;
+
+        let mut touch_device_query = Self::prepare_touch_device_query(&db).await;
+
+        let mut set_notification_prefs_query =
+            Self::prepare_set_notification_prefs_query(&db).await;
+
+        let mut get_notification_prefs_query =
+            Self::prepare_get_notification_prefs_query(&db).await;
+
+        let mut set_conversation_notification_prefs_query =
+            Self::prepare_set_conversation_notification_prefs_query(&db).await;
+
+        let mut remove_push_tokens_query = Self::prepare_remove_push_tokens_query(&db).await;
+
+        let mut remove_push_token_query = Self::prepare_remove_push_token_query(&db).await;
+
+        let mut get_push_tokens_query = Self::prepare_get_push_tokens_query(&db).await;
+
+        let mut add_push_token_query = Self::prepare_add_push_token_query(&db).await;
+
+        let mut search_users_query = Self::prepare_search_users_query(&db).await;
+
+        let mut clear_conversation_query = Self::prepare_clear_conversation_query(&db).await;
+
+        let mut set_conversation_archived_query =
+            Self::prepare_set_conversation_archived_query(&db).await;
+
+        let mut get_pinned_messages_query = Self::prepare_get_pinned_messages_query(&db).await;
+
+        let mut unpin_message_query = Self::prepare_unpin_message_query(&db).await;
+
+        let mut pin_message_query = Self::prepare_pin_message_query(&db).await;
+
+        let mut new_poll_query = Self::prepare_new_poll_query(&db).await;
+
+        let mut set_chooser_vote_query = Self::prepare_set_chooser_vote_query(&db).await;
+
+        let mut set_choosee_vote_query = Self::prepare_set_choosee_vote_query(&db).await;
+
+        let mut get_poll_query = Self::prepare_get_poll_query(&db).await;
+
+        let mut get_polls_query = Self::prepare_get_polls_query(&db).await;
+
+        let mut delete_polls_query = Self::prepare_delete_polls_query(&db).await;
+
+        let mut add_key_distribution_query = Self::prepare_add_key_distribution_query(&db).await;
+
+        let mut get_key_distributions_query = Self::prepare_get_key_distributions_query(&db).await;
+
+        let mut add_mention_query = Self::prepare_add_mention_query(&db).await;
+
+        let mut get_mentions_query = Self::prepare_get_mentions_query(&db).await;
+
+        let mut remove_mentions_query = Self::prepare_remove_mentions_query(&db).await;
+
+        let mut count_conversation_message_query =
+            Self::prepare_count_conversation_message_query(&db).await;
+
+        let mut count_user_message_query = Self::prepare_count_user_message_query(&db).await;
+
+        let mut get_conversation_stats_query =
+            Self::prepare_get_conversation_stats_query(&db).await;
+
+        let mut delete_conversation_stats_query =
+            Self::prepare_delete_conversation_stats_query(&db).await;
+
+        let mut delete_user_daily_messages_query =
+            Self::prepare_delete_user_daily_messages_query(&db).await;
+
+        let mut set_chooser_messaged_on_query =
+            Self::prepare_set_chooser_messaged_on_query(&db).await;
+
+        let mut set_choosee_messaged_on_query =
+            Self::prepare_set_choosee_messaged_on_query(&db).await;
+
+        let mut get_streak_query = Self::prepare_get_streak_query(&db).await;
+
+        let mut extend_streak_query = Self::prepare_extend_streak_query(&db).await;
+
+        let mut get_streaks_ended_before_query =
+            Self::prepare_get_streaks_ended_before_query(&db).await;
+
+        let mut end_streak_query = Self::prepare_end_streak_query(&db).await;
+
+        let mut touch_user_query = Self::prepare_touch_user_query(&db).await;
+
+        let mut get_away_users_query = Self::prepare_get_away_users_query(&db).await;
+
+        let mut get_away_users_after_query = Self::prepare_get_away_users_after_query(&db).await;
+
+        let mut get_friend_requests_received_query =
+            Self::prepare_get_friend_requests_received_query(&db).await;
+
+        let mut set_conversation_pinned_query =
+            Self::prepare_set_conversation_pinned_query(&db).await;
+
+        let mut get_user_conversation_query = Self::prepare_get_user_conversation_query(&db).await;
+
+        let mut get_user_conversations_query =
+            Self::prepare_get_user_conversations_query(&db).await;
+
+        let mut get_conversations_as_choosee_query =
+            Self::prepare_get_conversations_as_choosee_query(&db).await;
+
+        let mut get_conversations_as_chooser_query =
+            Self::prepare_get_conversations_as_chooser_query(&db).await;
+
+        let mut set_avatar_query = Self::prepare_set_avatar_query(&db).await;
+
+        let mut get_profile_query = Self::prepare_get_profile_query(&db).await;
+
+        let mut get_choosee_presence_query = Self::prepare_get_choosee_presence_query(&db).await;
+
+        let mut get_disappearing_query = Self::prepare_get_disappearing_query(&db).await;
+
+        let mut set_disappearing_query = Self::prepare_set_disappearing_query(&db).await;
+
+        let mut add_failed_event_query = Self::prepare_add_failed_event_query(&db).await;
+
+        let mut get_failed_events_query = Self::prepare_get_failed_events_query(&db).await;
+
+        let mut remove_failed_event_query = Self::prepare_remove_failed_event_query(&db).await;
+
+        let mut add_audit_entry_query = Self::prepare_add_audit_entry_query(&db).await;
+
+        let mut get_audit_log_query = Self::prepare_get_audit_log_query(&db).await;
+
+        let mut set_job_status_query = Self::prepare_set_job_status_query(&db).await;
+
+        let mut get_conversations_created_before_query =
+            Self::prepare_get_conversations_created_before_query(&db).await;
+
+        let mut delete_conversation_query = Self::prepare_delete_conversation_query(&db).await;
+
+        let mut delete_choosee_presence_query =
+            Self::prepare_delete_choosee_presence_query(&db).await;
+
+        let mut delete_user_conversation_query =
+            Self::prepare_delete_user_conversation_query(&db).await;
+
+        let mut get_latest_conversation_id_query =
+            Self::prepare_get_latest_conversation_id_query(&db).await;
+
+        let mut set_latest_conversation_id_query =
+            Self::prepare_set_latest_conversation_id_query(&db).await;
+
+        for query in [
+            &mut new_conversation_query,
+            &mut new_message_query,
+            &mut update_choosee_last_presence_at_query,
+            &mut add_friend_request_on_sender_query,
+            &mut add_friend_request_on_receiver_query,
+            &mut remove_friend_request_on_sender_query,
+            &mut remove_friend_request_on_receiver_query,
+            &mut add_friend_query,
+            &mut add_friends_of_friends_query,
+            &mut remove_friend_query,
+            &mut remove_friends_of_friends_query,
+            &mut add_to_outbox_query,
+            &mut remove_from_outbox_query,
+            &mut delete_user_query,
+            &mut add_failed_event_query,
+            &mut remove_failed_event_query,
+            &mut set_disappearing_query,
+            &mut set_avatar_query,
+            &mut set_conversation_pinned_query,
+            &mut pin_message_query,
+            &mut unpin_message_query,
+            &mut set_conversation_archived_query,
+            &mut clear_conversation_query,
+            &mut add_push_token_query,
+            &mut remove_push_token_query,
+            &mut remove_push_tokens_query,
+            &mut set_conversation_notification_prefs_query,
+            &mut set_notification_prefs_query,
+            &mut touch_device_query,
+            &mut log_out_device_query,
+            &mut revoke_tokens_query,
+            &mut tombstone_conversation_query,
+            &mut delete_messages_query,
+            &mut delete_pinned_messages_query,
+            &mut new_poll_query,
+            &mut set_chooser_vote_query,
+            &mut set_choosee_vote_query,
+            &mut delete_polls_query,
+            &mut add_key_distribution_query,
+            &mut add_mention_query,
+            &mut remove_mentions_query,
+            &mut count_conversation_message_query,
+            &mut count_user_message_query,
+            &mut delete_conversation_stats_query,
+            &mut delete_user_daily_messages_query,
+            &mut set_chooser_messaged_on_query,
+            &mut set_choosee_messaged_on_query,
+            &mut extend_streak_query,
+            &mut end_streak_query,
+            &mut touch_user_query,
+            &mut add_report_query,
+            &mut add_audit_entry_query,
+            &mut set_job_status_query,
+            &mut delete_conversation_query,
+            &mut delete_choosee_presence_query,
+            &mut delete_user_conversation_query,
+            &mut set_latest_conversation_id_query,
+        ] {
+            query.set_consistency(consistencies.writes);
+        }
+
+        for query in [
+            &mut get_messages_query,
+            &mut has_messages_query,
+            &mut get_choosee_presence_query,
+        ] {
+            query.set_consistency(consistencies.history_reads);
+        }
+
+        for query in [
+            &mut get_friends_of_user_query,
+            &mut get_outbox_query,
+            &mut get_revoked_before_query,
+            &mut get_bot_key_query,
+            &mut get_failed_events_query,
+            &mut get_disappearing_query,
+            &mut get_profile_query,
+            &mut get_conversations_as_chooser_query,
+            &mut get_conversations_as_choosee_query,
+            &mut get_user_conversations_query,
+            &mut get_user_conversation_query,
+            &mut get_pinned_messages_query,
+            &mut get_poll_query,
+            &mut get_polls_query,
+            &mut get_key_distributions_query,
+            &mut get_mentions_query,
+            &mut get_conversation_stats_query,
+            &mut get_streak_query,
+            &mut get_streaks_ended_before_query,
+            &mut get_away_users_query,
+            &mut get_away_users_after_query,
+            &mut get_friend_requests_received_query,
+            &mut search_users_query,
+            &mut get_push_tokens_query,
+            &mut get_notification_prefs_query,
+            &mut get_devices_query,
+            &mut get_device_logged_out_at_query,
+            &mut get_friends_of_friends_query,
+            &mut get_audit_log_query,
+            &mut get_conversations_created_before_query,
+            &mut get_latest_conversation_id_query,
+        ] {
+            query.set_consistency(consistencies.reads);
+        }
+
+        let db = Arc::new(RetryingSession::new(
+            db,
+            session.retry_policy,
+            RetryBudget::new(session.retry_budget_ratio, session.retry_budget_capacity),
+        ));
+
+        let message_coalescer = message_coalescing
+            .map(|options| MessageCoalescer::new(db.clone(), new_message_query.clone(), options));
+
+        Ok(ScyllaStorage {
+            db,
+            message_coalescer,
+            message_retention,
+            content_keys,
+            new_conversation_query,
+            new_message_query,
+            update_choosee_last_presence_at_query,
+            get_messages_query,
+            add_friend_request_on_sender_query,
+            add_friend_request_on_receiver_query,
+            get_friends_of_user_query,
+            remove_friend_request_on_sender_query,
+            remove_friend_request_on_receiver_query,
+            add_friend_query,
+            add_friends_of_friends_query,
+            remove_friend_query,
+            remove_friends_of_friends_query,
+            add_to_outbox_query,
+            get_outbox_query,
+            remove_from_outbox_query,
+            has_messages_query,
+            delete_user_query,
+            get_revoked_before_query,
+            revoke_tokens_query,
+            get_bot_key_query,
+            health_check_query,
+            add_report_query,
+            delete_pinned_messages_query,
+            delete_messages_query,
+            tombstone_conversation_query,
+            get_friends_of_friends_query,
+            get_device_logged_out_at_query,
+            log_out_device_query,
+            get_devices_query,
+            touch_device_query,
+            set_notification_prefs_query,
+            get_notification_prefs_query,
+            set_conversation_notification_prefs_query,
+            remove_push_tokens_query,
+            remove_push_token_query,
+            get_push_tokens_query,
+            add_push_token_query,
+            search_users_query,
+            clear_conversation_query,
+            set_conversation_archived_query,
+            get_pinned_messages_query,
+            unpin_message_query,
+            pin_message_query,
+            new_poll_query,
+            set_chooser_vote_query,
+            set_choosee_vote_query,
+            get_poll_query,
+            get_polls_query,
+            delete_polls_query,
+            add_key_distribution_query,
+            get_key_distributions_query,
+            add_mention_query,
+            get_mentions_query,
+            remove_mentions_query,
+            count_conversation_message_query,
+            count_user_message_query,
+            get_conversation_stats_query,
+            delete_conversation_stats_query,
+            delete_user_daily_messages_query,
+            set_chooser_messaged_on_query,
+            set_choosee_messaged_on_query,
+            get_streak_query,
+            extend_streak_query,
+            get_streaks_ended_before_query,
+            end_streak_query,
+            touch_user_query,
+            get_away_users_query,
+            get_away_users_after_query,
+            get_friend_requests_received_query,
+            set_conversation_pinned_query,
+            get_user_conversation_query,
+            get_user_conversations_query,
+            get_conversations_as_choosee_query,
+            get_conversations_as_chooser_query,
+            set_avatar_query,
+            get_profile_query,
+            get_choosee_presence_query,
+            get_disappearing_query,
+            set_disappearing_query,
+            add_failed_event_query,
+            get_failed_events_query,
+            remove_failed_event_query,
+            add_audit_entry_query,
+            get_audit_log_query,
+            set_job_status_query,
+            get_conversations_created_before_query,
+            delete_conversation_query,
+            delete_choosee_presence_query,
+            delete_user_conversation_query,
+            get_latest_conversation_id_query,
+            set_latest_conversation_id_query,
+        })
+    }
+
+    async fn prepare_new_conversation_query(db: &scylla::Session) -> PreparedStatement {
+        let mut new_conversation_query = db.prepare("INSERT INTO conversation (chooser_username, choosee_username, chooser_name, choosee_name, id, created_at) values (?, ?, ?, ?, ?, ?)").await.expect("New conversation prepared query failed");
+        new_conversation_query.set_is_idempotent(true);
+        new_conversation_query
+    }
+
+    async fn prepare_new_message_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_messages_query = db
+            .prepare(
+                "INSERT INTO message (conversation_id, content, content_key_id, sent_at, from_chooser, attachment, kind) VALUES (?, ?, ?, ?, ?, ?, ?) USING TTL ?",
+            )
+            .await
+            .expect("Get messages prepared query failed");
+        get_messages_query.set_is_idempotent(true);
+        get_messages_query
+    }
+
+    async fn prepare_update_choosee_last_presence_at_query(
+        db: &scylla::Session,
+    ) -> PreparedStatement {
+        let mut update_choosee_last_presence_at_query = db
+            .prepare("INSERT INTO choosee_presence (conversation_id, occurred_at, leaving, chooser_hash) VALUES (?, ?, ?, ?) USING TTL ?")
+            .await
+            .expect("Update choosee last presence prepared query failed");
+        update_choosee_last_presence_at_query.set_is_idempotent(true);
+        update_choosee_last_presence_at_query
+    }
+
+    async fn prepare_get_messages_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_messages_query = db
+            .prepare(
+                "SELECT content, content_key_id, sent_at, from_chooser, attachment, kind FROM message WHERE conversation_id = ? AND sent_at > ? LIMIT ?",
+            )
+            .await
+            .expect("Get messages prepared query failed");
+        get_messages_query.set_is_idempotent(true);
+        get_messages_query
+    }
+
+    async fn prepare_add_friend_request_on_sender_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_friend_request_on_sender_query = db.prepare("UPDATE user SET friend_requests_sent = friend_requests_sent + { ? } WHERE username = ?").await.expect("Add friend request on sender prepared query failed");
+        add_friend_request_on_sender_query.set_is_idempotent(true);
+        add_friend_request_on_sender_query
+    }
+
+    async fn prepare_add_friend_request_on_receiver_query(
+        db: &scylla::Session,
+    ) -> PreparedStatement {
+        let mut add_friend_request_on_receiver_query = db.prepare("UPDATE user SET friend_requests_received = friend_requests_received + { ? } WHERE username = ?").await.expect("Add friend request on sender prepared query failed");
+        add_friend_request_on_receiver_query.set_is_idempotent(true);
+        add_friend_request_on_receiver_query
+    }
+
+    async fn prepare_remove_friend_request_on_sender_query(
+        db: &scylla::Session,
+    ) -> PreparedStatement {
+        let mut remove_friend_request_on_sender_query = db.prepare("UPDATE user SET friend_requests_sent = friend_requests_sent - { ? } WHERE username = ?").await.expect("Remove friend request on sender prepared query failed");
+        remove_friend_request_on_sender_query.set_is_idempotent(true);
+        remove_friend_request_on_sender_query
+    }
+
+    async fn prepare_remove_friend_request_on_receiver_query(
+        db: &scylla::Session,
+    ) -> PreparedStatement {
+        let mut remove_friend_request_on_receiver_query = db.prepare("UPDATE user SET friend_requests_received = friend_requests_received - { ? } WHERE username = ?").await.expect("Remove friend request on sender prepared query failed");
+        remove_friend_request_on_receiver_query.set_is_idempotent(true);
+        remove_friend_request_on_receiver_query
+    }
+
+    async fn prepare_get_friends_of_user_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_friends_of_user_query = db
+            .prepare("SELECT friends FROM user WHERE username = ?")
+            .await
+            .expect("Get friends of user prepared query failed");
+        get_friends_of_user_query.set_is_idempotent(true);
+        get_friends_of_user_query
+    }
+
+    async fn prepare_add_friend_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_friend_query = db
+            .prepare("UPDATE user SET friends = friends + ? WHERE username = ?")
+            .await
+            .expect("Add friend prepared query failed");
+        add_friend_query.set_is_idempotent(true);
+        add_friend_query
+    }
+
+    async fn prepare_add_friends_of_friends_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_friends_of_friends_query = db
+            .prepare(
+                "UPDATE user SET friends_of_friends = friends_of_friends + ? WHERE username = ?",
+            )
+            .await
+            .expect("Add friends of friends prepared query failed");
+        add_friends_of_friends_query.set_is_idempotent(true);
+        add_friends_of_friends_query
+    }
+
+    async fn prepare_remove_friend_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_friend_query = db
+            .prepare("UPDATE user SET friends = friends - ? WHERE username = ?")
+            .await
+            .expect("Remove friend prepared query failed");
+        remove_friend_query.set_is_idempotent(true);
+        remove_friend_query
+    }
+
+    async fn prepare_remove_friends_of_friends_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_friends_of_friends_query = db
+            .prepare(
+                "UPDATE user SET friends_of_friends = friends_of_friends - ? WHERE username IN ?",
+            )
+            .await
+            .expect("Add friends of friends prepared query failed");
+        remove_friends_of_friends_query.set_is_idempotent(true);
+        remove_friends_of_friends_query
+    }
+
+    async fn prepare_add_to_outbox_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_to_outbox_query = db
+            .prepare(
+                "INSERT INTO outbox_entry (bucket, created_at, entry_id, subject, data) VALUES (?, ?, ?, ?, ?)",
+            )
+            .await
+            .expect("Add to outbox prepared query failed");
+        add_to_outbox_query.set_is_idempotent(true);
+        add_to_outbox_query
+    }
+
+    async fn prepare_get_outbox_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_outbox_query = db
+            .prepare(
+                "SELECT created_at, entry_id, subject, data FROM outbox_entry WHERE bucket = ?",
+            )
+            .await
+            .expect("Get outbox prepared query failed");
+        get_outbox_query.set_is_idempotent(true);
+        get_outbox_query
+    }
+
+    async fn prepare_remove_from_outbox_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_from_outbox_query = db
+            .prepare(
+                "DELETE FROM outbox_entry WHERE bucket = ? AND created_at = ? AND entry_id = ?",
+            )
+            .await
+            .expect("Remove from outbox prepared query failed");
+        remove_from_outbox_query.set_is_idempotent(true);
+        remove_from_outbox_query
+    }
+
+    async fn prepare_has_messages_query(db: &scylla::Session) -> PreparedStatement {
+        let mut has_messages_query = db
+            .prepare("SELECT conversation_id FROM message WHERE conversation_id = ? LIMIT 1")
+            .await
+            .expect("Has messages prepared query failed");
+        has_messages_query.set_is_idempotent(true);
+        has_messages_query
+    }
+
+    async fn prepare_delete_user_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_user_query = db
+            .prepare("DELETE FROM user WHERE username = ?")
+            .await
+            .expect("Delete user prepared query failed");
+        delete_user_query.set_is_idempotent(true);
+        delete_user_query
+    }
+
+    async fn prepare_get_revoked_before_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_revoked_before_query = db
+            .prepare("SELECT revoked_before FROM token_revocation WHERE username = ?")
+            .await
+            .expect("Get revoked before prepared query failed");
+        get_revoked_before_query.set_is_idempotent(true);
+        get_revoked_before_query
+    }
+
+    async fn prepare_revoke_tokens_query(db: &scylla::Session) -> PreparedStatement {
+        let mut revoke_tokens_query = db
+            .prepare("INSERT INTO token_revocation (username, revoked_before) VALUES (?, ?)")
+            .await
+            .expect("Revoke tokens prepared query failed");
+        revoke_tokens_query.set_is_idempotent(true);
+        revoke_tokens_query
+    }
+
+    async fn prepare_get_bot_key_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_bot_key_query = db
+            .prepare(
+                "SELECT username, secret, created_at, revoked_at FROM bot_key WHERE key_id = ?",
+            )
+            .await
+            .expect("Get bot key prepared query failed");
+        get_bot_key_query.set_is_idempotent(true);
+        get_bot_key_query
+    }
+
+    async fn prepare_set_disappearing_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_disappearing_query = db
+            .prepare("UPDATE conversation SET disappearing_ttl = ? WHERE id = ?")
+            .await
+            .expect("Set disappearing prepared query failed");
+        set_disappearing_query.set_is_idempotent(true);
+        set_disappearing_query
+    }
+
+    async fn prepare_get_disappearing_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_disappearing_query = db
+            .prepare("SELECT disappearing_ttl FROM conversation WHERE id = ?")
+            .await
+            .expect("Get disappearing prepared query failed");
+        get_disappearing_query.set_is_idempotent(true);
+        get_disappearing_query
+    }
+
+    async fn prepare_get_choosee_presence_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_choosee_presence_query = db
+            .prepare("SELECT occurred_at, leaving FROM choosee_presence WHERE conversation_id = ? ORDER BY occurred_at DESC LIMIT ?")
+            .await
+            .expect("Get choosee presence prepared query failed");
+        get_choosee_presence_query.set_is_idempotent(true);
+        get_choosee_presence_query
+    }
+
+    async fn prepare_get_profile_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_profile_query = db
+            .prepare("SELECT name FROM user WHERE username = ?")
+            .await
+            .expect("Get profile prepared query failed");
+        get_profile_query.set_is_idempotent(true);
+        get_profile_query
+    }
+
+    async fn prepare_set_avatar_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_avatar_query = db
+            .prepare("UPDATE user SET avatar_object_key = ? WHERE username = ?")
+            .await
+            .expect("Set avatar prepared query failed");
+        set_avatar_query.set_is_idempotent(true);
+        set_avatar_query
+    }
+
+    async fn prepare_get_conversations_as_chooser_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_conversations_as_chooser_query = db
+            .prepare(
+                "SELECT id, created_at, choosee_name, tombstoned_at, streak, streak_day FROM conversation WHERE chooser_username = ?",
+            )
+            .await
+            .expect("Get conversations as chooser prepared query failed");
+        get_conversations_as_chooser_query.set_is_idempotent(true);
+        get_conversations_as_chooser_query
+    }
+
+    async fn prepare_get_conversations_as_choosee_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_conversations_as_choosee_query = db
+            .prepare(
+                "SELECT id, created_at, tombstoned_at, streak, streak_day FROM conversation WHERE choosee_username = ?",
+            )
+            .await
+            .expect("Get conversations as choosee prepared query failed");
+        get_conversations_as_choosee_query.set_is_idempotent(true);
+        get_conversations_as_choosee_query
+    }
+
+    async fn prepare_get_user_conversations_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_user_conversations_query = db
+            .prepare("SELECT conversation_id, pinned, archived, cleared_before, muted, mentions_only FROM user_conversation WHERE username = ?")
+            .await
+            .expect("Get user conversations prepared query failed");
+        get_user_conversations_query.set_is_idempotent(true);
+        get_user_conversations_query
+    }
+
+    async fn prepare_get_user_conversation_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_user_conversation_query = db
+            .prepare(
+                "SELECT pinned, archived, cleared_before, muted, mentions_only FROM user_conversation WHERE username = ? AND conversation_id = ?",
+            )
+            .await
+            .expect("Get user conversation prepared query failed");
+        get_user_conversation_query.set_is_idempotent(true);
+        get_user_conversation_query
+    }
+
+    async fn prepare_set_conversation_pinned_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_conversation_pinned_query = db
+            .prepare("UPDATE user_conversation SET pinned = ? WHERE username = ? AND conversation_id = ?")
+            .await
+            .expect("Set conversation pinned prepared query failed");
+        set_conversation_pinned_query.set_is_idempotent(true);
+        set_conversation_pinned_query
+    }
+
+    async fn prepare_pin_message_query(db: &scylla::Session) -> PreparedStatement {
+        let mut pin_message_query = db
+            .prepare("INSERT INTO pinned_message (conversation_id, sent_at, pinned_at, pinned_by_chooser) VALUES (?, ?, ?, ?)")
+            .await
+            .expect("Pin message prepared query failed");
+        pin_message_query.set_is_idempotent(true);
+        pin_message_query
+    }
+
+    async fn prepare_unpin_message_query(db: &scylla::Session) -> PreparedStatement {
+        let mut unpin_message_query = db
+            .prepare("DELETE FROM pinned_message WHERE conversation_id = ? AND sent_at = ?")
+            .await
+            .expect("Unpin message prepared query failed");
+        unpin_message_query.set_is_idempotent(true);
+        unpin_message_query
+    }
+
+    async fn prepare_get_pinned_messages_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_pinned_messages_query = db
+            .prepare("SELECT sent_at, pinned_at, pinned_by_chooser FROM pinned_message WHERE conversation_id = ?")
+            .await
+            .expect("Get pinned messages prepared query failed");
+        get_pinned_messages_query.set_is_idempotent(true);
+        get_pinned_messages_query
+    }
+
+    async fn prepare_new_poll_query(db: &scylla::Session) -> PreparedStatement {
+        let mut new_poll_query = db
+            .prepare("INSERT INTO poll (conversation_id, poll_id, question, options, created_by_chooser, created_at) VALUES (?, ?, ?, ?, ?, ?)")
+            .await
+            .expect("New poll prepared query failed");
+        new_poll_query.set_is_idempotent(true);
+        new_poll_query
+    }
+
+    // a plain update would write the row back into a poll that was deleted in the meantime
+    async fn prepare_set_chooser_vote_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_chooser_vote_query = db
+            .prepare("UPDATE poll SET chooser_vote = ? WHERE conversation_id = ? AND poll_id = ? IF EXISTS")
+            .await
+            .expect("Set chooser vote prepared query failed");
+        set_chooser_vote_query.set_is_idempotent(true);
+        set_chooser_vote_query
+    }
+
+    async fn prepare_set_choosee_vote_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_choosee_vote_query = db
+            .prepare("UPDATE poll SET choosee_vote = ? WHERE conversation_id = ? AND poll_id = ? IF EXISTS")
+            .await
+            .expect("Set choosee vote prepared query failed");
+        set_choosee_vote_query.set_is_idempotent(true);
+        set_choosee_vote_query
+    }
+
+    async fn prepare_get_poll_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_poll_query = db
+            .prepare("SELECT poll_id, question, options, created_by_chooser, created_at, chooser_vote, choosee_vote FROM poll WHERE conversation_id = ? AND poll_id = ?")
+            .await
+            .expect("Get poll prepared query failed");
+        get_poll_query.set_is_idempotent(true);
+        get_poll_query
+    }
+
+    async fn prepare_get_polls_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_polls_query = db
+            .prepare("SELECT poll_id, question, options, created_by_chooser, created_at, chooser_vote, choosee_vote FROM poll WHERE conversation_id = ?")
+            .await
+            .expect("Get polls prepared query failed");
+        get_polls_query.set_is_idempotent(true);
+        get_polls_query
+    }
+
+    async fn prepare_add_key_distribution_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_key_distribution_query = db
+            .prepare("INSERT INTO key_distribution (conversation_id, from_chooser, sent_at, key_material) VALUES (?, ?, ?, ?) USING TTL ?")
+            .await
+            .expect("Add key distribution prepared query failed");
+        add_key_distribution_query.set_is_idempotent(true);
+        add_key_distribution_query
+    }
+
+    async fn prepare_get_key_distributions_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_key_distributions_query = db
+            .prepare("SELECT key_material, sent_at, from_chooser FROM key_distribution WHERE conversation_id = ? AND from_chooser = ?")
+            .await
+            .expect("Get key distributions prepared query failed");
+        get_key_distributions_query.set_is_idempotent(true);
+        get_key_distributions_query
+    }
+
+    async fn prepare_add_mention_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_mention_query = db
+            .prepare("INSERT INTO mention (username_hash, sent_at, conversation_id) VALUES (?, ?, ?) USING TTL ?")
+            .await
+            .expect("Add mention prepared query failed");
+        add_mention_query.set_is_idempotent(true);
+        add_mention_query
+    }
+
+    async fn prepare_get_mentions_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_mentions_query = db
+            .prepare("SELECT conversation_id, sent_at FROM mention WHERE username_hash = ? LIMIT ?")
+            .await
+            .expect("Get mentions prepared query failed");
+        get_mentions_query.set_is_idempotent(true);
+        get_mentions_query
+    }
+
+    async fn prepare_remove_mentions_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_mentions_query = db
+            .prepare("DELETE FROM mention WHERE username_hash = ?")
+            .await
+            .expect("Remove mentions prepared query failed");
+        remove_mentions_query.set_is_idempotent(true);
+        remove_mentions_query
+    }
+
+    // counter updates aren't idempotent, a retry after a timeout could count the message twice
+    async fn prepare_count_conversation_message_query(db: &scylla::Session) -> PreparedStatement {
+        db.prepare("UPDATE conversation_stats SET messages = messages + 1 WHERE conversation_id = ? AND from_chooser = ?")
+            .await
+            .expect("Count conversation message prepared query failed")
+    }
+
+    async fn prepare_count_user_message_query(db: &scylla::Session) -> PreparedStatement {
+        db.prepare(
+            "UPDATE user_daily_messages SET messages = messages + 1 WHERE username = ? AND day = ?",
+        )
+        .await
+        .expect("Count user message prepared query failed")
+    }
+
+    async fn prepare_get_conversation_stats_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_conversation_stats_query = db
+            .prepare(
+                "SELECT from_chooser, messages FROM conversation_stats WHERE conversation_id = ?",
+            )
+            .await
+            .expect("Get conversation stats prepared query failed");
+        get_conversation_stats_query.set_is_idempotent(true);
+        get_conversation_stats_query
+    }
+
+    async fn prepare_delete_conversation_stats_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_conversation_stats_query = db
+            .prepare("DELETE FROM conversation_stats WHERE conversation_id = ?")
+            .await
+            .expect("Delete conversation stats prepared query failed");
+        delete_conversation_stats_query.set_is_idempotent(true);
+        delete_conversation_stats_query
+    }
+
+    async fn prepare_delete_user_daily_messages_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_user_daily_messages_query = db
+            .prepare("DELETE FROM user_daily_messages WHERE username = ?")
+            .await
+            .expect("Delete user daily messages prepared query failed");
+        delete_user_daily_messages_query.set_is_idempotent(true);
+        delete_user_daily_messages_query
+    }
+
+    async fn prepare_set_chooser_messaged_on_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_chooser_messaged_on_query = db
+            .prepare("UPDATE conversation SET chooser_messaged_on = ? WHERE id = ?")
+            .await
+            .expect("Set chooser messaged on prepared query failed");
+        set_chooser_messaged_on_query.set_is_idempotent(true);
+        set_chooser_messaged_on_query
+    }
+
+    async fn prepare_set_choosee_messaged_on_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_choosee_messaged_on_query = db
+            .prepare("UPDATE conversation SET choosee_messaged_on = ? WHERE id = ?")
+            .await
+            .expect("Set choosee messaged on prepared query failed");
+        set_choosee_messaged_on_query.set_is_idempotent(true);
+        set_choosee_messaged_on_query
+    }
+
+    async fn prepare_get_streak_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_streak_query = db
+            .prepare("SELECT chooser_messaged_on, choosee_messaged_on, streak, streak_day FROM conversation WHERE id = ?")
+            .await
+            .expect("Get streak prepared query failed");
+        get_streak_query.set_is_idempotent(true);
+        get_streak_query
+    }
+
+    // conditional on the streak's last day, so when both users' messages see the other's only one extends it. not
+    // idempotent, a retry of one that went through would find the condition false and report it as not applied
+    async fn prepare_extend_streak_query(db: &scylla::Session) -> PreparedStatement {
+        db.prepare(
+            "UPDATE conversation SET streak = ?, streak_day = ? WHERE id = ? IF streak_day = ?",
+        )
+        .await
+        .expect("Extend streak prepared query failed")
+    }
+
+    async fn prepare_get_streaks_ended_before_query(db: &scylla::Session) -> PreparedStatement {
+        // a full scan, which is fine for a rollover that runs in the background once a day
+        let mut get_streaks_ended_before_query = db
+            .prepare("SELECT id, streak_day FROM conversation WHERE streak > 0 AND streak_day < ? LIMIT ? ALLOW FILTERING")
+            .await
+            .expect("Get streaks ended before prepared query failed");
+        get_streaks_ended_before_query.set_is_idempotent(true);
+        get_streaks_ended_before_query
+    }
+
+    // conditional so a streak extended since it was read is left alone
+    async fn prepare_end_streak_query(db: &scylla::Session) -> PreparedStatement {
+        let mut end_streak_query = db
+            .prepare("UPDATE conversation SET streak = 0 WHERE id = ? IF streak_day = ?")
+            .await
+            .expect("End streak prepared query failed");
+        end_streak_query.set_is_idempotent(true);
+        end_streak_query
+    }
+
+    async fn prepare_set_conversation_archived_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_conversation_archived_query = db
+            .prepare("UPDATE user_conversation SET archived = ? WHERE username = ? AND conversation_id = ?")
+            .await
+            .expect("Set conversation archived prepared query failed");
+        set_conversation_archived_query.set_is_idempotent(true);
+        set_conversation_archived_query
+    }
+
+    async fn prepare_clear_conversation_query(db: &scylla::Session) -> PreparedStatement {
+        let mut clear_conversation_query = db
+            .prepare("UPDATE user_conversation SET cleared_before = ? WHERE username = ? AND conversation_id = ?")
+            .await
+            .expect("Clear conversation prepared query failed");
+        clear_conversation_query.set_is_idempotent(true);
+        clear_conversation_query
+    }
+
+    async fn prepare_search_users_query(db: &scylla::Session) -> PreparedStatement {
+        let mut search_users_query = db
+            .prepare("SELECT username, name FROM user_by_prefix WHERE prefix = ? AND username >= ? AND username < ? LIMIT ?")
+            .await
+            .expect("Search users prepared query failed");
+        search_users_query.set_is_idempotent(true);
+        search_users_query
+    }
+
+    async fn prepare_add_push_token_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_push_token_query = db
+            .prepare("INSERT INTO push_token (username_hash, token, platform, username, registered_at) VALUES (?, ?, ?, ?, ?)")
+            .await
+            .expect("Add push token prepared query failed");
+        add_push_token_query.set_is_idempotent(true);
+        add_push_token_query
+    }
+
+    async fn prepare_get_push_tokens_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_push_tokens_query = db
+            .prepare("SELECT token, platform, username, registered_at FROM push_token WHERE username_hash = ?")
+            .await
+            .expect("Get push tokens prepared query failed");
+        get_push_tokens_query.set_is_idempotent(true);
+        get_push_tokens_query
+    }
+
+    async fn prepare_remove_push_token_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_push_token_query = db
+            .prepare("DELETE FROM push_token WHERE username_hash = ? AND token = ?")
+            .await
+            .expect("Remove push token prepared query failed");
+        remove_push_token_query.set_is_idempotent(true);
+        remove_push_token_query
+    }
+
+    async fn prepare_remove_push_tokens_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_push_tokens_query = db
+            .prepare("DELETE FROM push_token WHERE username_hash = ?")
+            .await
+            .expect("Remove push tokens prepared query failed");
+        remove_push_tokens_query.set_is_idempotent(true);
+        remove_push_tokens_query
+    }
+
+    async fn prepare_set_conversation_notification_prefs_query(
+        db: &scylla::Session,
+    ) -> PreparedStatement {
+        let mut set_conversation_notification_prefs_query = db
+            .prepare("UPDATE user_conversation SET muted = ?, mentions_only = ? WHERE username = ? AND conversation_id = ?")
+            .await
+            .expect("Set conversation notification prefs prepared query failed");
+        set_conversation_notification_prefs_query.set_is_idempotent(true);
+        set_conversation_notification_prefs_query
+    }
+
+    async fn prepare_get_notification_prefs_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_notification_prefs_query = db
+            .prepare("SELECT muted, mentions_only, dnd_start_minute, dnd_end_minute, dnd_utc_offset_minutes FROM notification_prefs WHERE username = ?")
+            .await
+            .expect("Get notification prefs prepared query failed");
+        get_notification_prefs_query.set_is_idempotent(true);
+        get_notification_prefs_query
+    }
+
+    async fn prepare_set_notification_prefs_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_notification_prefs_query = db
+            .prepare("INSERT INTO notification_prefs (username, muted, mentions_only, dnd_start_minute, dnd_end_minute, dnd_utc_offset_minutes) VALUES (?, ?, ?, ?, ?, ?)")
+            .await
+            .expect("Set notification prefs prepared query failed");
+        set_notification_prefs_query.set_is_idempotent(true);
+        set_notification_prefs_query
+    }
+
+    async fn prepare_touch_device_query(db: &scylla::Session) -> PreparedStatement {
+        let mut touch_device_query = db
+            .prepare("UPDATE device SET last_seen_at = ? WHERE username = ? AND device_id = ?")
+            .await
+            .expect("Touch device prepared query failed");
+        touch_device_query.set_is_idempotent(true);
+        touch_device_query
+    }
+
+    async fn prepare_touch_user_query(db: &scylla::Session) -> PreparedStatement {
+        let mut touch_user_query = db
+            .prepare("UPDATE user SET last_connected_at = ? WHERE username = ?")
+            .await
+            .expect("Touch user prepared query failed");
+        touch_user_query.set_is_idempotent(true);
+        touch_user_query
+    }
+
+    // full scans in token order, which is fine for a digest that runs in the background once a day
+    async fn prepare_get_away_users_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_away_users_query = db
+            .prepare("SELECT username, last_connected_at FROM user WHERE last_connected_at > ? AND last_connected_at < ? LIMIT ? ALLOW FILTERING")
+            .await
+            .expect("Get away users prepared query failed");
+        get_away_users_query.set_is_idempotent(true);
+        get_away_users_query
+    }
+
+    async fn prepare_get_away_users_after_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_away_users_after_query = db
+            .prepare("SELECT username, last_connected_at FROM user WHERE token(username) > token(?) AND last_connected_at > ? AND last_connected_at < ? LIMIT ? ALLOW FILTERING")
+            .await
+            .expect("Get away users after prepared query failed");
+        get_away_users_after_query.set_is_idempotent(true);
+        get_away_users_after_query
+    }
+
+    async fn prepare_get_friend_requests_received_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_friend_requests_received_query = db
+            .prepare("SELECT friend_requests_received FROM user WHERE username = ?")
+            .await
+            .expect("Get friend requests received prepared query failed");
+        get_friend_requests_received_query.set_is_idempotent(true);
+        get_friend_requests_received_query
+    }
+
+    async fn prepare_get_devices_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_devices_query = db
+            .prepare("SELECT device_id, last_seen_at, logged_out_at FROM device WHERE username = ?")
+            .await
+            .expect("Get devices prepared query failed");
+        get_devices_query.set_is_idempotent(true);
+        get_devices_query
+    }
+
+    async fn prepare_log_out_device_query(db: &scylla::Session) -> PreparedStatement {
+        let mut log_out_device_query = db
+            .prepare("UPDATE device SET logged_out_at = ? WHERE username = ? AND device_id = ?")
+            .await
+            .expect("Log out device prepared query failed");
+        log_out_device_query.set_is_idempotent(true);
+        log_out_device_query
+    }
+
+    async fn prepare_get_device_logged_out_at_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_device_logged_out_at_query = db
+            .prepare("SELECT logged_out_at FROM device WHERE username = ? AND device_id = ?")
+            .await
+            .expect("Get device logged out at prepared query failed");
+        get_device_logged_out_at_query.set_is_idempotent(true);
+        get_device_logged_out_at_query
+    }
+
+    async fn prepare_get_friends_of_friends_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_friends_of_friends_query = db
+            .prepare("SELECT friends_of_friends FROM user WHERE username = ?")
+            .await
+            .expect("Get friends of friends prepared query failed");
+        get_friends_of_friends_query.set_is_idempotent(true);
+        get_friends_of_friends_query
+    }
+
+    async fn prepare_tombstone_conversation_query(db: &scylla::Session) -> PreparedStatement {
+        let mut tombstone_conversation_query = db
+            .prepare("UPDATE conversation SET tombstoned_at = ? WHERE id = ?")
+            .await
+            .expect("Tombstone conversation prepared query failed");
+        tombstone_conversation_query.set_is_idempotent(true);
+        tombstone_conversation_query
+    }
+
+    async fn prepare_delete_messages_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_messages_query = db
+            .prepare("DELETE FROM message WHERE conversation_id = ?")
+            .await
+            .expect("Delete messages prepared query failed");
+        delete_messages_query.set_is_idempotent(true);
+        delete_messages_query
+    }
+
+    async fn prepare_delete_pinned_messages_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_pinned_messages_query = db
+            .prepare("DELETE FROM pinned_message WHERE conversation_id = ?")
+            .await
+            .expect("Delete pinned messages prepared query failed");
+        delete_pinned_messages_query.set_is_idempotent(true);
+        delete_pinned_messages_query
+    }
+
+    async fn prepare_delete_polls_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_polls_query = db
+            .prepare("DELETE FROM poll WHERE conversation_id = ?")
+            .await
+            .expect("Delete polls prepared query failed");
+        delete_polls_query.set_is_idempotent(true);
+        delete_polls_query
+    }
+
+    async fn prepare_add_report_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_report_query = db
+            .prepare("INSERT INTO report (report_id, reporter_username, conversation_id, message_sent_at, reason, reported_at) VALUES (?, ?, ?, ?, ?, ?)")
+            .await
+            .expect("Add report prepared query failed");
+        add_report_query.set_is_idempotent(true);
+        add_report_query
+    }
+
+    async fn prepare_health_check_query(db: &scylla::Session) -> PreparedStatement {
+        let mut health_check_query = db
+            .prepare("SELECT now() FROM system.local")
+            .await
+            .expect("Health check prepared query failed");
+        health_check_query.set_is_idempotent(true);
+        health_check_query
+    }
+
+    async fn prepare_add_failed_event_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_failed_event_query = db
+            .prepare("INSERT INTO failed_event (subject, failed_at, created_at, data, reason) VALUES (?, ?, ?, ?, ?)")
+            .await
+            .expect("Add failed event prepared query failed");
+        add_failed_event_query.set_is_idempotent(true);
+        add_failed_event_query
+    }
+
+    async fn prepare_get_failed_events_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_failed_events_query = db
+            .prepare("SELECT subject, failed_at, created_at, data, reason FROM failed_event")
+            .await
+            .expect("Get failed events prepared query failed");
+        get_failed_events_query.set_is_idempotent(true);
+        get_failed_events_query
+    }
+
+    async fn prepare_remove_failed_event_query(db: &scylla::Session) -> PreparedStatement {
+        let mut remove_failed_event_query = db
+            .prepare("DELETE FROM failed_event WHERE subject = ? AND failed_at = ?")
+            .await
+            .expect("Remove failed event prepared query failed");
+        remove_failed_event_query.set_is_idempotent(true);
+        remove_failed_event_query
+    }
+
+    async fn prepare_add_audit_entry_query(db: &scylla::Session) -> PreparedStatement {
+        let mut add_audit_entry_query = db
+            .prepare("INSERT INTO audit_log (day, occurred_at, entry_id, action, actor, target, connection_id, detail) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+            .await
+            .expect("Add audit entry prepared query failed");
+        add_audit_entry_query.set_is_idempotent(true);
+        add_audit_entry_query
+    }
+
+    async fn prepare_get_audit_log_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_audit_log_query = db
+            .prepare("SELECT occurred_at, entry_id, action, actor, target, connection_id, detail FROM audit_log WHERE day = ? LIMIT ?")
+            .await
+            .expect("Get audit log prepared query failed");
+        get_audit_log_query.set_is_idempotent(true);
+        get_audit_log_query
+    }
+
+    async fn prepare_set_job_status_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_job_status_query = db
+            .prepare("INSERT INTO job_status (job_id, kind, state, attempts, last_error, enqueued_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)")
+            .await
+            .expect("Set job status prepared query failed");
+        set_job_status_query.set_is_idempotent(true);
+        set_job_status_query
+    }
+
+    async fn prepare_get_conversations_created_before_query(
+        db: &scylla::Session,
+    ) -> PreparedStatement {
+        // a full scan, which is fine for a sweep that runs in the background
+        let mut get_conversations_created_before_query = db
+            .prepare("SELECT id, chooser_username, choosee_username FROM conversation WHERE created_at < ? LIMIT ? ALLOW FILTERING")
+            .await
+            .expect("Get conversations created before prepared query failed");
+        get_conversations_created_before_query.set_is_idempotent(true);
+        get_conversations_created_before_query
+    }
+
+    async fn prepare_delete_conversation_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_conversation_query = db
+            .prepare("DELETE FROM conversation WHERE id = ?")
+            .await
+            .expect("Delete conversation prepared query failed");
+        delete_conversation_query.set_is_idempotent(true);
+        delete_conversation_query
+    }
+
+    async fn prepare_delete_choosee_presence_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_choosee_presence_query = db
+            .prepare("DELETE FROM choosee_presence WHERE conversation_id = ?")
+            .await
+            .expect("Delete choosee presence prepared query failed");
+        delete_choosee_presence_query.set_is_idempotent(true);
+        delete_choosee_presence_query
+    }
+
+    async fn prepare_delete_user_conversation_query(db: &scylla::Session) -> PreparedStatement {
+        let mut delete_user_conversation_query = db
+            .prepare("DELETE FROM user_conversation WHERE username = ? AND conversation_id = ?")
+            .await
+            .expect("Delete user conversation prepared query failed");
+        delete_user_conversation_query.set_is_idempotent(true);
+        delete_user_conversation_query
+    }
+
+    async fn prepare_get_latest_conversation_id_query(db: &scylla::Session) -> PreparedStatement {
+        let mut get_latest_conversation_id_query = db
+            .prepare("SELECT conversation_id FROM conversation_link WHERE chooser_hash = ? AND choosee_hash = ?")
+            .await
+            .expect("Get latest conversation id prepared query failed");
+        get_latest_conversation_id_query.set_is_idempotent(true);
+        get_latest_conversation_id_query
+    }
+
+    async fn prepare_set_latest_conversation_id_query(db: &scylla::Session) -> PreparedStatement {
+        let mut set_latest_conversation_id_query = db
+            .prepare("UPDATE conversation_link SET conversation_id = ? WHERE chooser_hash = ? AND choosee_hash = ?")
+            .await
+            .expect("Set latest conversation id prepared query failed");
+        set_latest_conversation_id_query.set_is_idempotent(true);
+        set_latest_conversation_id_query
+    }
+
+    async fn disappearing_ttl(&self, conversation_id: &str) -> Result<Option<i32>, DatabaseError> {
+        self.db
+            .execute(&self.get_disappearing_query, (conversation_id,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting disappearing messages", err))?
+            .rows_typed_or_empty::<(Option<i32>,)>()
+            .next()
+            .transpose()
+            .map(|row| row.and_then(|row| row.0).filter(|ttl| *ttl > 0))
+            .map_err(|err| DatabaseError::row("Error getting disappearing messages", err))
+    }
+
+    // writes down that the sender sent something on the day, then extends the streak if the other user already has
+    async fn extend_streak(
+        &self,
+        conversation_id: &str,
+        from_chooser: bool,
+        day: NaiveDate,
+    ) -> Result<Option<u32>, DatabaseError> {
+        let set_messaged_on_query = if from_chooser {
+            &self.set_chooser_messaged_on_query
+        } else {
+            &self.set_choosee_messaged_on_query
+        };
+
+        self.db
+            .execute(set_messaged_on_query, (day, conversation_id))
+            .await
+            .map_err(|err| DatabaseError::query("Error marking day messaged on", err))?;
+
+        let Some((chooser_messaged_on, choosee_messaged_on, streak, streak_day)) = self
+            .db
+            .execute(&self.get_streak_query, (conversation_id,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting streak", err))?
+            .rows_typed_or_empty::<(
+                Option<NaiveDate>,
+                Option<NaiveDate>,
+                Option<i32>,
+                Option<NaiveDate>,
+            )>()
+            .next()
+            .transpose()
+            .map_err(|err| DatabaseError::row("Error getting streak", err))?
+        else {
+            return Ok(None);
+        };
+
+        let other_messaged_on = if from_chooser {
+            choosee_messaged_on
+        } else {
+            chooser_messaged_on
+        };
+
+        let Some(extended) = streaks::extend(
+            streak.unwrap_or_default().max(0) as u32,
+            streak_day,
+            other_messaged_on,
+            day,
+        ) else {
+            return Ok(None);
+        };
+
+        let result = self
+            .db
+            .execute(
+                &self.extend_streak_query,
+                (extended as i32, day, conversation_id, streak_day),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error extending streak", err))?;
+
+        Ok(Self::applied(result).then_some(extended))
+    }
+
+    fn poll_from_row(row: PollRow) -> Poll {
+        Poll::new(
+            row.0,
+            row.1,
+            row.2,
+            row.3,
+            Self::datetime_from_timestamp(row.4),
+        )
+        .with_votes(row.5.map(|vote| vote as u32), row.6.map(|vote| vote as u32))
+    }
+
+    // the first column of a lightweight transaction's result
+    fn applied(result: QueryResult) -> bool {
+        result
+            .first_row()
+            .ok()
+            .and_then(|row| row.columns.into_iter().next().flatten())
+            .and_then(|applied| applied.as_boolean())
+            .unwrap_or_default()
+    }
+
+    // 0 is no ttl as far as scylla is concerned
+    fn ttl(&self) -> i32 {
+        self.message_retention.map_or(0, |retention| {
+            retention.as_secs().try_into().unwrap_or(i32::MAX)
+        })
+    }
+
+    fn decrypt_content(
+        &self,
+        conversation_id: &str,
+        content: String,
+        content_key_id: Option<String>,
+    ) -> Result<String, DatabaseError> {
+        match (content_key_id, &self.content_keys) {
+            (Some(content_key_id), Some(content_keys)) => content_keys
+                .decrypt(&content_key_id, conversation_id, &content)
+                .map_err(|err| DatabaseError::encryption("Error decrypting message", err)),
+            (Some(content_key_id), None) => Err(DatabaseError::encryption(
+                "Error decrypting message",
+                ContentKeyError::UnknownKey(content_key_id),
+            )),
+            (None, _) => Ok(content), // from before encryption was turned on
+        }
+    }
+
+    fn timestamp_from_datetime(datetime: DateTime<Utc>) -> scylla::frame::value::Timestamp {
+        scylla::frame::value::Timestamp(Duration::milliseconds(datetime.timestamp_millis()))
+    }
+
+    fn datetime_from_timestamp(timestamp: Duration) -> DateTime<Utc> {
+        DateTime::<Utc>::from_utc(
+            NaiveDateTime::from_timestamp_millis(timestamp.num_milliseconds())
+                .expect("Timestamp out of range"),
+            Utc,
+        )
+    }
+}
+
+#[async_trait]
+impl Storage for ScyllaStorage {
+    async fn new_conversation(
+        &self,
+        chooser_username: &str,
+        choosee_username: &str,
+        chooser_name: &str,
+        choosee_name: &str,
+        conversation_id: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.new_conversation_query,
+                (
+                    chooser_username,
+                    choosee_username,
+                    chooser_name,
+                    choosee_name,
+                    conversation_id.to_string(),
+                    Self::timestamp_from_datetime(created_at),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error creating new conversation", err))
+    }
+
+    async fn new_message(
+        &self,
+        conversation_id: &str,
+        sender_username: &str,
+        message: &Message,
+    ) -> Result<Option<u32>, DatabaseError> {
+        let from_chooser = message.from_chooser;
+        let day = message.sent_at.date_naive();
+        let sent_at = Self::timestamp_from_datetime(message.sent_at);
+
+        let attachment = message
+            .attachment
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|err| DatabaseError::decode("Error encoding attachment", err))?;
+
+        let kind = message
+            .kind
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|err| DatabaseError::decode("Error encoding message kind", err))?;
+
+        let (content, content_key_id) = match &self.content_keys {
+            Some(content_keys) => {
+                let (content_key_id, content) =
+                    content_keys.encrypt(conversation_id, &message.content);
+
+                (content, Some(content_key_id))
+            }
+            None => (message.content.clone(), None),
+        };
+
+        let ttl = match self.disappearing_ttl(conversation_id).await? {
+            Some(disappearing_ttl) if self.ttl() == 0 || disappearing_ttl < self.ttl() => {
+                disappearing_ttl
+            }
+            _ => self.ttl(),
+        };
+
+        match &self.message_coalescer {
+            Some(message_coalescer) => {
+                message_coalescer
+                    .insert((
+                        conversation_id.to_owned(),
+                        content,
+                        content_key_id,
+                        sent_at,
+                        from_chooser,
+                        attachment,
+                        kind,
+                        ttl,
+                    ))
+                    .await
+            }
+            None => self
+                .db
+                .execute(
+                    &self.new_message_query,
+                    (
+                        conversation_id,
+                        content,
+                        content_key_id,
+                        sent_at,
+                        from_chooser,
+                        attachment,
+                        kind,
+                        ttl,
+                    ),
+                )
+                .await
+                .map(|_| ()),
+        }
+        .map_err(|err| DatabaseError::query("Error creating new message", err))?;
+
+        // the message is saved by now, and failing here would have the client send it again, so a count that didn't
+        // make it is only logged
+        let results = tokio::join!(
+            self.db.execute(
+                &self.count_conversation_message_query,
+                (conversation_id, from_chooser),
+            ),
+            self.db
+                .execute(&self.count_user_message_query, (sender_username, day)),
+        );
+
+        if let Err(err) = results.0.and(results.1) {
+            warn!("Error counting message in {}: {}", conversation_id, err);
+        }
+
+        // the same goes for the streak
+        match self.extend_streak(conversation_id, from_chooser, day).await {
+            Ok(streak) => Ok(streak),
+            Err(err) => {
+                warn!("Error extending streak of {}: {}", conversation_id, err);
+
+                Ok(None)
+            }
+        }
+    }
+
+    async fn set_disappearing(
+        &self,
+        conversation_id: &str,
+        ttl: Option<StdDuration>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.set_disappearing_query,
+                (
+                    ttl.map(|ttl| ttl.as_secs().try_into().unwrap_or(i32::MAX)),
+                    conversation_id,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error setting disappearing messages", err))
+    }
+
+    async fn update_choosee_last_presence_at(
+        &self,
+        conversation_id: &str,
+        occurred_at: DateTime<Utc>,
+        leaving: bool,
+        chooser_hash: &str,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.update_choosee_last_presence_at_query,
+                (
+                    conversation_id,
+                    Self::timestamp_from_datetime(occurred_at),
+                    leaving,
+                    chooser_hash,
+                    self.ttl(),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error updating choosee_last_presence_at", err))
+    }
+
+    async fn get_messages(
+        &self,
+        conversation_id: &str,
+        username: &str,
+        take: i8,
+        after_sent_at: DateTime<Utc>,
+    ) -> Result<Vec<Message>, DatabaseError> {
+        let after_sent_at = match self
+            .get_user_conversation(username, conversation_id)
+            .await?
+            .cleared_before
+        {
+            Some(cleared_before) => after_sent_at.max(cleared_before),
+            None => after_sent_at,
+        };
+
+        let mut message_vec = Vec::<Message>::new();
+
+        for row in self
+            .db
+            .execute(
+                &self.get_messages_query,
+                (
+                    conversation_id,
+                    Self::timestamp_from_datetime(after_sent_at),
+                    take,
+                ),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error getting messages", err))?
+            .rows_typed_or_empty::<(
+                String,
+                Option<String>,
+                Duration,
+                bool,
+                Option<String>,
+                Option<String>,
+            )>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting messages", err))?;
+
+            message_vec.push(Message {
+                content: self.decrypt_content(conversation_id, row.0, row.1)?,
+                sent_at: Self::datetime_from_timestamp(row.2),
+                from_chooser: row.3,
+                attachment: row
+                    .4
+                    .map(|attachment| serde_json::from_str(&attachment))
+                    .transpose()
+                    .map_err(|err| DatabaseError::decode("Error getting messages", err))?,
+                kind: row
+                    .5
+                    .map(|kind| serde_json::from_str(&kind))
+                    .transpose()
+                    .map_err(|err| DatabaseError::decode("Error getting messages", err))?,
+            });
+        }
+
+        Ok(message_vec)
+    }
+
+    async fn get_choosee_presence(
+        &self,
+        conversation_id: &str,
+        take: i8,
+    ) -> Result<Vec<PresenceEvent>, DatabaseError> {
+        let mut presence_event_vec = Vec::<PresenceEvent>::new();
+
+        for row in self
+            .db
+            .execute(
+                &self.get_choosee_presence_query,
+                (conversation_id, i32::from(take)),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error getting choosee presence", err))?
+            .rows_typed_or_empty::<(Duration, bool)>()
+        {
+            let row =
+                row.map_err(|err| DatabaseError::row("Error getting choosee presence", err))?;
+
+            presence_event_vec.push(PresenceEvent {
+                occurred_at: Self::datetime_from_timestamp(row.0),
+                leaving: row.1,
+            });
+        }
+
+        Ok(presence_event_vec)
+    }
+
+    async fn create_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError> {
+        let sender_username_clone = sender.username.clone();
+        let receiver_username_clone = receiver.username.clone();
+
+        let (sender_result, receiver_result) = tokio::join!(
+            self.db.execute(
+                &self.add_friend_request_on_sender_query,
+                (receiver, sender_username_clone),
+            ),
+            self.db.execute(
+                &self.add_friend_request_on_receiver_query,
+                (sender, receiver_username_clone),
+            )
+        );
+
+        sender_result.map_err(|err| {
+            DatabaseError::query("Error adding friend requestee username to requester", err)
+        })?;
+
+        receiver_result.map_err(|err| {
+            DatabaseError::query("Error adding friend requester username to requestee", err)
+        })?;
+
+        Ok(())
+    }
+
+    async fn delete_friend_request(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+    ) -> Result<(), DatabaseError> {
+        let sender_username_clone = sender.username.clone();
+        let receiver_username_clone = receiver.username.clone();
+
+        let (sender_result, receiver_result) = tokio::join!(
+            self.db.execute(
+                &self.remove_friend_request_on_sender_query,
+                (receiver, sender_username_clone),
+            ),
+            self.db.execute(
+                &self.remove_friend_request_on_receiver_query,
+                (sender, receiver_username_clone),
+            )
+        );
+
+        sender_result.map_err(|err| {
+            DatabaseError::query(
+                "Error removing friend requestee username from requester",
+                err,
+            )
+        })?;
+
+        receiver_result.map_err(|err| {
+            DatabaseError::query(
+                "Error removing friend requester username from requestee",
+                err,
+            )
+        })?;
+
+        Ok(())
+    }
+
+    async fn get_friend_requests_received(
+        &self,
+        username: &str,
+    ) -> Result<Vec<Profile>, DatabaseError> {
+        self.db
+            .execute(&self.get_friend_requests_received_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting friend requests", err))?
+            .rows_typed_or_empty::<(Option<Vec<Profile>>,)>()
+            .next()
+            .transpose()
+            .map(|row| row.and_then(|row| row.0).unwrap_or_default())
+            .map_err(|err| DatabaseError::row("Error getting friend requests", err))
+    }
+
+    async fn get_friends(&self, username: &str) -> Result<Vec<FriendProfile>, DatabaseError> {
+        let mut friend_vec = Vec::<FriendProfile>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_friends_of_user_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error get friends of user", err))?
+            .rows_typed_or_empty::<(FriendProfile,)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error get friends of user", err))?;
+
+            friend_vec.push(row.0);
+        }
+
+        Ok(friend_vec)
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<Profile>, DatabaseError> {
+        self.db
+            .execute(&self.get_profile_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting profile", err))?
+            .rows_typed_or_empty::<(Option<String>,)>()
+            .next()
+            .transpose()
+            .map(|row| {
+                row.map(|row| Profile {
+                    username: username.to_owned(),
+                    name: row.0.unwrap_or_default(),
+                })
+            })
+            .map_err(|err| DatabaseError::row("Error getting profile", err))
+    }
+
+    async fn search_users(&self, prefix: &str, take: i8) -> Result<Vec<Profile>, DatabaseError> {
+        let partition = prefix
+            .chars()
+            .take(USER_SEARCH_PARTITION_LENGTH)
+            .collect::<String>();
+
+        let mut profile_vec = Vec::<Profile>::new();
+
+        // text compares by its utf-8 bytes, and nothing sorts after the last char
+        for row in self
+            .db
+            .execute(
+                &self.search_users_query,
+                (partition, prefix, format!("{}{}", prefix, char::MAX), take),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error searching users", err))?
+            .rows_typed_or_empty::<(String, Option<String>)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error searching users", err))?;
+
+            profile_vec.push(Profile {
+                username: row.0,
+                name: row.1.unwrap_or_default(),
+            });
+        }
+
+        Ok(profile_vec)
+    }
+
+    async fn set_avatar(&self, username: &str, object_key: &str) -> Result<(), DatabaseError> {
+        self.db
+            .execute(&self.set_avatar_query, (object_key, username))
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error setting avatar", err))
+    }
+
+    async fn get_conversations(
+        &self,
+        username: &str,
+    ) -> Result<Vec<ConversationSummary>, DatabaseError> {
+        let mut user_conversations = HashMap::<String, UserConversation>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_user_conversations_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting conversations", err))?
+            .rows_typed_or_empty::<(
+                String,
+                Option<bool>,
+                Option<bool>,
+                Option<Duration>,
+                Option<bool>,
+                Option<bool>,
+            )>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting conversations", err))?;
+
+            user_conversations.insert(
+                row.0,
+                UserConversation {
+                    pinned: row.1.unwrap_or_default(),
+                    archived: row.2.unwrap_or_default(),
+                    cleared_before: row.3.map(Self::datetime_from_timestamp),
+                    muted: row.4.unwrap_or_default(),
+                    mentions_only: row.5.unwrap_or_default(),
+                },
+            );
+        }
+
+        let mut conversation_vec = Vec::<ConversationSummary>::new();
+
+        let today = Utc::now().date_naive();
+
+        for row in self
+            .db
+            .execute(&self.get_conversations_as_chooser_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting conversations", err))?
+            .rows_typed_or_empty::<(
+                String,
+                Duration,
+                Option<String>,
+                Option<Duration>,
+                Option<i32>,
+                Option<NaiveDate>,
+            )>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting conversations", err))?;
+
+            let user_conversation = user_conversations.remove(&row.0).unwrap_or_default();
+
+            conversation_vec.push(ConversationSummary {
+                conversation_id: row.0,
+                created_at: Self::datetime_from_timestamp(row.1),
+                is_chooser: true,
+                choosee_name: row.2,
+                pinned: user_conversation.pinned,
+                archived: user_conversation.archived,
+                tombstoned: row.3.is_some(),
+                streak: streaks::current_length(
+                    row.4.unwrap_or_default().max(0) as u32,
+                    row.5,
+                    today,
+                ),
+            });
+        }
+
+        for row in self
+            .db
+            .execute(&self.get_conversations_as_choosee_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting conversations", err))?
+            .rows_typed_or_empty::<(
+                String,
+                Duration,
+                Option<Duration>,
+                Option<i32>,
+                Option<NaiveDate>,
+            )>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting conversations", err))?;
+
+            let user_conversation = user_conversations.remove(&row.0).unwrap_or_default();
+
+            conversation_vec.push(ConversationSummary {
+                conversation_id: row.0,
+                created_at: Self::datetime_from_timestamp(row.1),
+                is_chooser: false,
+                choosee_name: None,
+                pinned: user_conversation.pinned,
+                archived: user_conversation.archived,
+                tombstoned: row.2.is_some(),
+                streak: streaks::current_length(
+                    row.3.unwrap_or_default().max(0) as u32,
+                    row.4,
+                    today,
+                ),
+            });
+        }
+
+        conversation_vec.sort_by(|a, b| {
+            b.pinned
+                .cmp(&a.pinned)
+                .then_with(|| b.created_at.cmp(&a.created_at))
+        });
+
+        Ok(conversation_vec)
+    }
+
+    async fn get_user_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+    ) -> Result<UserConversation, DatabaseError> {
+        self.db
+            .execute(
+                &self.get_user_conversation_query,
+                (username, conversation_id),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error getting user conversation", err))?
+            .rows_typed_or_empty::<(
+                Option<bool>,
+                Option<bool>,
+                Option<Duration>,
+                Option<bool>,
+                Option<bool>,
+            )>()
+            .next()
+            .transpose()
+            .map(|row| {
+                row.map_or_else(UserConversation::default, |row| UserConversation {
+                    pinned: row.0.unwrap_or_default(),
+                    archived: row.1.unwrap_or_default(),
+                    cleared_before: row.2.map(Self::datetime_from_timestamp),
+                    muted: row.3.unwrap_or_default(),
+                    mentions_only: row.4.unwrap_or_default(),
+                })
+            })
+            .map_err(|err| DatabaseError::row("Error getting user conversation", err))
+    }
+
+    async fn set_conversation_pinned(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        pinned: bool,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.set_conversation_pinned_query,
+                (pinned, username, conversation_id),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error pinning conversation", err))
+    }
+
+    async fn set_conversation_archived(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        archived: bool,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.set_conversation_archived_query,
+                (archived, username, conversation_id),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error archiving conversation", err))
+    }
+
+    async fn clear_conversation(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        cleared_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.clear_conversation_query,
+                (
+                    Self::timestamp_from_datetime(cleared_before),
+                    username,
+                    conversation_id,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error clearing conversation", err))
+    }
+
+    async fn set_conversation_notification_prefs(
+        &self,
+        username: &str,
+        conversation_id: &str,
+        muted: bool,
+        mentions_only: bool,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.set_conversation_notification_prefs_query,
+                (muted, mentions_only, username, conversation_id),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| {
+                DatabaseError::query("Error setting conversation notification prefs", err)
+            })
+    }
+
+    async fn get_notification_prefs(
+        &self,
+        username: &str,
+    ) -> Result<NotificationPrefs, DatabaseError> {
+        self.db
+            .execute(&self.get_notification_prefs_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting notification prefs", err))?
+            .rows_typed_or_empty::<(
+                Option<bool>,
+                Option<bool>,
+                Option<i16>,
+                Option<i16>,
+                Option<i16>,
+            )>()
+            .next()
+            .transpose()
+            .map(|row| {
+                row.map_or_else(NotificationPrefs::default, |row| NotificationPrefs {
+                    muted: row.0.unwrap_or_default(),
+                    mentions_only: row.1.unwrap_or_default(),
+                    dnd: match (row.2, row.3, row.4) {
+                        (Some(start_minute), Some(end_minute), Some(utc_offset_minutes)) => {
+                            Some(DndWindow {
+                                start_minute: start_minute as u16,
+                                end_minute: end_minute as u16,
+                                utc_offset_minutes,
+                            })
+                        }
+                        _ => None,
+                    },
+                })
+            })
+            .map_err(|err| DatabaseError::row("Error getting notification prefs", err))
+    }
+
+    async fn set_notification_prefs(
+        &self,
+        username: &str,
+        notification_prefs: &NotificationPrefs,
+    ) -> Result<(), DatabaseError> {
+        let dnd = notification_prefs.dnd;
+
+        self.db
+            .execute(
+                &self.set_notification_prefs_query,
+                (
+                    username,
+                    notification_prefs.muted,
+                    notification_prefs.mentions_only,
+                    dnd.map(|dnd| dnd.start_minute as i16),
+                    dnd.map(|dnd| dnd.end_minute as i16),
+                    dnd.map(|dnd| dnd.utc_offset_minutes),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error setting notification prefs", err))
+    }
+
+    async fn pin_message(
+        &self,
+        conversation_id: &str,
+        pinned_message: &PinnedMessage,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.pin_message_query,
+                (
+                    conversation_id,
+                    Self::timestamp_from_datetime(pinned_message.sent_at),
+                    Self::timestamp_from_datetime(pinned_message.pinned_at),
+                    pinned_message.pinned_by_chooser,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error pinning message", err))
+    }
+
+    async fn unpin_message(
+        &self,
+        conversation_id: &str,
+        sent_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.unpin_message_query,
+                (conversation_id, Self::timestamp_from_datetime(sent_at)),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error unpinning message", err))
+    }
+
+    async fn get_pinned_messages(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<PinnedMessage>, DatabaseError> {
+        let mut pinned_message_vec = Vec::<PinnedMessage>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_pinned_messages_query, (conversation_id,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting pinned messages", err))?
+            .rows_typed_or_empty::<(Duration, Duration, bool)>()
+        {
+            let row =
+                row.map_err(|err| DatabaseError::row("Error getting pinned messages", err))?;
+
+            pinned_message_vec.push(PinnedMessage {
+                sent_at: Self::datetime_from_timestamp(row.0),
+                pinned_at: Self::datetime_from_timestamp(row.1),
+                pinned_by_chooser: row.2,
+            });
+        }
+
+        Ok(pinned_message_vec)
+    }
+
+    async fn new_poll(&self, conversation_id: &str, poll: &Poll) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.new_poll_query,
+                (
+                    conversation_id,
+                    &poll.poll_id,
+                    &poll.question,
+                    poll.options
+                        .iter()
+                        .map(|option| option.text.as_str())
+                        .collect::<Vec<_>>(),
+                    poll.created_by_chooser,
+                    Self::timestamp_from_datetime(poll.created_at),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error creating poll", err))
+    }
+
+    async fn vote_in_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+        from_chooser: bool,
+        option: u32,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                if from_chooser {
+                    &self.set_chooser_vote_query
+                } else {
+                    &self.set_choosee_vote_query
+                },
+                (option as i32, conversation_id, poll_id),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error voting in poll", err))
+    }
+
+    async fn get_poll(
+        &self,
+        conversation_id: &str,
+        poll_id: &str,
+    ) -> Result<Option<Poll>, DatabaseError> {
+        self.db
+            .execute(&self.get_poll_query, (conversation_id, poll_id))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting poll", err))?
+            .rows_typed_or_empty::<PollRow>()
+            .next()
+            .transpose()
+            .map(|row| row.map(Self::poll_from_row))
+            .map_err(|err| DatabaseError::row("Error getting poll", err))
+    }
+
+    async fn get_polls(&self, conversation_id: &str) -> Result<Vec<Poll>, DatabaseError> {
+        let mut poll_vec = Vec::<Poll>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_polls_query, (conversation_id,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting polls", err))?
+            .rows_typed_or_empty::<PollRow>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting polls", err))?;
+
+            poll_vec.push(Self::poll_from_row(row));
+        }
+
+        // clustered by id, which is random
+        poll_vec.sort_by_key(|poll| poll.created_at);
+
+        Ok(poll_vec)
+    }
+
+    async fn get_conversation_stats(
+        &self,
+        conversation_id: &str,
+    ) -> Result<ConversationStats, DatabaseError> {
+        let mut stats = ConversationStats::default();
+
+        for row in self
+            .db
+            .execute(&self.get_conversation_stats_query, (conversation_id,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting conversation stats", err))?
+            .rows_typed_or_empty::<(bool, Counter)>()
+        {
+            let (from_chooser, messages) =
+                row.map_err(|err| DatabaseError::row("Error getting conversation stats", err))?;
+
+            if from_chooser {
+                stats.messages_from_chooser = messages.0.max(0) as u64;
+            } else {
+                stats.messages_from_choosee = messages.0.max(0) as u64;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    async fn add_key_distribution(
+        &self,
+        conversation_id: &str,
+        key_distribution: &KeyDistribution,
+        ttl: StdDuration,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_key_distribution_query,
+                (
+                    conversation_id,
+                    key_distribution.from_chooser,
+                    Self::timestamp_from_datetime(key_distribution.sent_at),
+                    &key_distribution.key_material,
+                    ttl.as_secs().clamp(1, i32::MAX as u64) as i32, // 0 would keep it forever
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding key distribution", err))
+    }
+
+    async fn get_key_distributions(
+        &self,
+        conversation_id: &str,
+        from_chooser: bool,
+    ) -> Result<Vec<KeyDistribution>, DatabaseError> {
+        let mut key_distribution_vec = Vec::<KeyDistribution>::new();
+
+        for row in self
+            .db
+            .execute(
+                &self.get_key_distributions_query,
+                (conversation_id, from_chooser),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error getting key distributions", err))?
+            .rows_typed_or_empty::<(String, Duration, bool)>()
+        {
+            let row =
+                row.map_err(|err| DatabaseError::row("Error getting key distributions", err))?;
+
+            key_distribution_vec.push(KeyDistribution {
+                key_material: row.0,
+                sent_at: Self::datetime_from_timestamp(row.1),
+                from_chooser: row.2,
+            });
+        }
+
+        Ok(key_distribution_vec)
+    }
+
+    async fn add_mention(
+        &self,
+        username_hash: &str,
+        mention: &Mention,
+        ttl: StdDuration,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_mention_query,
+                (
+                    username_hash,
+                    Self::timestamp_from_datetime(mention.sent_at),
+                    &mention.conversation_id,
+                    ttl.as_secs().clamp(1, i32::MAX as u64) as i32, // 0 would keep it forever
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding mention", err))
+    }
+
+    async fn get_mentions(
+        &self,
+        username_hash: &str,
+        take: i32,
+    ) -> Result<Vec<Mention>, DatabaseError> {
+        let mut mention_vec = Vec::<Mention>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_mentions_query, (username_hash, take))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting mentions", err))?
+            .rows_typed_or_empty::<(String, Duration)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting mentions", err))?;
+
+            mention_vec.push(Mention {
+                conversation_id: row.0,
+                sent_at: Self::datetime_from_timestamp(row.1),
+            });
+        }
+
+        Ok(mention_vec)
+    }
+
+    async fn remove_mentions(&self, username_hash: &str) -> Result<(), DatabaseError> {
+        self.db
+            .execute(&self.remove_mentions_query, (username_hash,))
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing mentions", err))
+    }
+
+    async fn add_push_token(
+        &self,
+        username_hash: &str,
+        push_token: &PushToken,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_push_token_query,
+                (
+                    username_hash,
+                    &push_token.token,
+                    push_token.platform.as_str(),
+                    &push_token.username,
+                    Self::timestamp_from_datetime(push_token.registered_at),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding push token", err))
+    }
+
+    async fn get_push_tokens(&self, username_hash: &str) -> Result<Vec<PushToken>, DatabaseError> {
+        let mut push_token_vec = Vec::<PushToken>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_push_tokens_query, (username_hash,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting push tokens", err))?
+            .rows_typed_or_empty::<(String, String, String, Duration)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting push tokens", err))?;
+
+            // a platform this version doesn't know about was registered by a newer one
+            let Some(platform) = PushPlatform::parse(&row.1) else {
+                continue;
+            };
+
+            push_token_vec.push(PushToken {
+                token: row.0,
+                platform,
+                username: row.2,
+                registered_at: Self::datetime_from_timestamp(row.3),
+            });
+        }
+
+        Ok(push_token_vec)
+    }
+
+    async fn remove_push_token(
+        &self,
+        username_hash: &str,
+        token: &str,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(&self.remove_push_token_query, (username_hash, token))
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing push token", err))
+    }
+
+    async fn remove_push_tokens(&self, username_hash: &str) -> Result<(), DatabaseError> {
+        self.db
+            .execute(&self.remove_push_tokens_query, (username_hash,))
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing push tokens", err))
+    }
+
+    async fn touch_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        seen_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.touch_device_query,
+                (Self::timestamp_from_datetime(seen_at), username, device_id),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error touching device", err))
+    }
+
+    async fn touch_user(
+        &self,
+        username: &str,
+        connected_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.touch_user_query,
+                (Self::timestamp_from_datetime(connected_at), username),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error touching user", err))
+    }
+
+    // pages are in token order rather than by username, after only has to pick up where the last page left off
+    async fn get_away_users(
+        &self,
+        connected_after: DateTime<Utc>,
+        connected_before: DateTime<Utc>,
+        after: Option<&str>,
+        take: i32,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, DatabaseError> {
+        let connected_after = Self::timestamp_from_datetime(connected_after);
+        let connected_before = Self::timestamp_from_datetime(connected_before);
+
+        match after {
+            Some(after) => {
+                self.db
+                    .execute(
+                        &self.get_away_users_after_query,
+                        (after, connected_after, connected_before, take),
+                    )
+                    .await
+            }
+            None => {
+                self.db
+                    .execute(
+                        &self.get_away_users_query,
+                        (connected_after, connected_before, take),
+                    )
+                    .await
+            }
+        }
+        .map_err(|err| DatabaseError::query("Error getting away users", err))?
+        .rows_typed_or_empty::<(String, Duration)>()
+        .map(|row| {
+            row.map(|(username, connected_at)| {
+                (username, Self::datetime_from_timestamp(connected_at))
+            })
+            .map_err(|err| DatabaseError::row("Error getting away users", err))
+        })
+        .collect()
+    }
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, DatabaseError> {
+        let mut devices = Vec::<Device>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_devices_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting devices", err))?
+            .rows_typed_or_empty::<(String, Option<Duration>, Option<Duration>)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting devices", err))?;
+
+            // logging out a device that never connected leaves a row without last_seen_at
+            let Some(last_seen_at) = row.1.map(Self::datetime_from_timestamp) else {
+                continue;
+            };
+
+            if row.2.is_some_and(|logged_out_at| {
+                Self::datetime_from_timestamp(logged_out_at) >= last_seen_at
+            }) {
+                continue;
+            }
+
+            devices.push(Device {
+                device_id: row.0,
+                last_seen_at,
+                connected: false,
+                current: false,
+            });
+        }
+
+        devices.sort_by_key(|device| std::cmp::Reverse(device.last_seen_at));
+
+        Ok(devices)
+    }
+
+    async fn log_out_device(
+        &self,
+        username: &str,
+        device_id: &str,
+        logged_out_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.log_out_device_query,
+                (
+                    Self::timestamp_from_datetime(logged_out_at),
+                    username,
+                    device_id,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error logging out device", err))
+    }
+
+    async fn get_device_logged_out_at(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        self.db
+            .execute(&self.get_device_logged_out_at_query, (username, device_id))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting device logout", err))?
+            .rows_typed_or_empty::<(Option<Duration>,)>()
+            .next()
+            .transpose()
+            .map(|row| row.and_then(|row| row.0.map(Self::datetime_from_timestamp)))
+            .map_err(|err| DatabaseError::row("Error getting device logout", err))
+    }
+
+    async fn add_report(&self, report: &Report) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_report_query,
+                (
+                    &report.report_id,
+                    &report.reporter_username,
+                    &report.conversation_id,
+                    report.message_sent_at.map(Self::timestamp_from_datetime),
+                    &report.reason,
+                    Self::timestamp_from_datetime(report.reported_at),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding report", err))
+    }
+
+    async fn create_friendship(
+        &self,
+        sender: Profile,
+        receiver: Profile,
+        receiver_friends: Vec<Profile>,
+    ) -> Result<(), DatabaseError> {
+        let db = self.db.clone();
+        let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
+        let receiver_friends_clone = receiver_friends.clone();
+        let sender_username_clone = sender.username.clone();
+
+        tokio::spawn(async move {
+            db.execute(
+                &add_friends_of_friends_query,
+                (receiver_friends_clone, sender_username_clone),
+            )
+            .await
+        });
+
+        for receiver_friend in receiver_friends.iter() {
+            let db = self.db.clone();
+            let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
+            let sender_clone = sender.clone();
+            let receiver_friend_username = receiver_friend.username.to_owned();
+
+            tokio::spawn(async move {
+                db.execute(
+                    &add_friends_of_friends_query,
+                    (vec![sender_clone], receiver_friend_username),
+                )
+                .await
+            });
+        }
+
+        let db = self.db.clone();
+
+        let add_friends_of_friends_query = self.add_friends_of_friends_query.clone();
+        let get_friends_of_user_query = self.get_friends_of_user_query.clone();
+
+        let sender_clone = sender.clone();
+        let receiver_clone = receiver.clone();
+
+        tokio::spawn(async move {
+            if let Ok(sender_friends) = db
+                .execute(&get_friends_of_user_query, (&sender_clone.username,))
+                .await
+            {
+                let sender_friends = sender_friends
+                    .rows_typed_or_empty::<(FriendProfile,)>()
+                    .filter_map(|row| {
+                        row.ok().map(|row| Profile {
+                            username: row.0.username,
+                            name: row.0.name,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                let db_clone = db.clone();
+                let add_friends_of_friends_query_clone = add_friends_of_friends_query.clone();
+
+                let sender_friends_clone = sender_friends.clone();
+                let receiver_username = receiver_clone.username.clone();
+
+                tokio::spawn(async move {
+                    db_clone
+                        .execute(
+                            &add_friends_of_friends_query_clone,
+                            (sender_friends_clone, receiver_username),
+                        )
+                        .await
+                });
+
+                for sender_friend in sender_friends.iter() {
+                    let db = db.clone();
+                    let add_friends_of_friends_query = add_friends_of_friends_query.clone();
+
+                    let reciever = receiver_clone.clone();
+                    let sender_friend = sender_friend.clone();
+
+                    tokio::spawn(async move {
+                        let _ = db
+                            .execute(
+                                &add_friends_of_friends_query,
+                                (vec![reciever], sender_friend),
+                            )
+                            .await;
+                    });
+                }
+            }
+        });
+
+        let sender_clone = sender.clone();
+        let receiver_clone = receiver.clone();
+
+        let results = tokio::join!(
+            self.delete_friend_request(sender, receiver),
+            self.db.execute(
+                &self.add_friend_query,
+                (&sender_clone, &receiver_clone.username)
+            ),
+            self.db.execute(
+                &self.add_friend_query,
+                (&receiver_clone, &sender_clone.username)
+            ),
+        );
+
+        results.0?;
+
+        results.1.map_err(|err| {
+            DatabaseError::query("Error adding sender username to receiver's friends", err)
+        })?;
+
+        results.2.map_err(|err| {
+            DatabaseError::query("Error adding receiver username to sender's friends", err)
+        })?;
+
+        Ok(())
+    }
+
+    async fn add_to_outbox(
+        &self,
+        bucket: i32,
+        subject: &str,
+        data: Vec<u8>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_to_outbox_query,
+                (
+                    bucket,
+                    Self::timestamp_from_datetime(Utc::now()),
+                    Uuid::new_v4().to_string(),
+                    subject,
+                    data,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding event to outbox", err))
+    }
+
+    async fn get_outbox(&self, bucket: i32) -> Result<Vec<OutboxEntry>, DatabaseError> {
+        let mut outbox_entry_vec = Vec::<OutboxEntry>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_outbox_query, (bucket,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting outbox", err))?
+            .rows_typed_or_empty::<(Duration, String, String, Vec<u8>)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting outbox", err))?;
+
+            outbox_entry_vec.push(OutboxEntry {
+                bucket,
+                created_at: Self::datetime_from_timestamp(row.0),
+                entry_id: row.1,
+                subject: row.2,
+                data: row.3,
+            });
+        }
+
+        Ok(outbox_entry_vec)
+    }
+
+    async fn remove_from_outbox(
+        &self,
+        bucket: i32,
+        created_at: DateTime<Utc>,
+        entry_id: &str,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.remove_from_outbox_query,
+                (bucket, Self::timestamp_from_datetime(created_at), entry_id),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing event from outbox", err))
+    }
+
+    async fn adopt_legacy_outbox(&self, bucket: i32) -> Result<u64, DatabaseError> {
+        let rows = match self
+            .db
+            .query("SELECT subject, created_at, data FROM outbox", ())
+            .await
+        {
+            Ok(result) => result,
+            Err(QueryError::DbError(DbError::Invalid, message))
+                if message.contains("unconfigured table") =>
+            {
+                return Ok(0); // already adopted, by this node or another one
+            }
+            Err(err) => return Err(DatabaseError::query("Error getting legacy outbox", err)),
+        };
+
+        let mut adopted = 0;
+
+        for row in rows.rows_typed_or_empty::<(String, Duration, Vec<u8>)>() {
+            let row = row.map_err(|err| DatabaseError::row("Error getting legacy outbox", err))?;
+
+            // subject and created_at were the old key, so the subject is unique enough as an entry id. running this
+            // again after a failed drop just overwrites the same entries
+            self.db
+                .execute(
+                    &self.add_to_outbox_query,
+                    (
+                        bucket,
+                        scylla::frame::value::Timestamp(row.1),
+                        &row.0,
+                        &row.0,
+                        row.2,
+                    ),
+                )
+                .await
+                .map_err(|err| DatabaseError::query("Error adopting legacy outbox", err))?;
+
+            adopted += 1;
+        }
+
+        self.db
+            .query("DROP TABLE IF EXISTS outbox", ())
+            .await
+            .map_err(|err| DatabaseError::query("Error dropping legacy outbox", err))?;
+
+        Ok(adopted)
+    }
+
+    async fn has_messages(&self, conversation_id: &str) -> Result<bool, DatabaseError> {
+        self.db
+            .execute(&self.has_messages_query, (conversation_id,))
+            .await
+            .map(|result| result.rows.is_some_and(|rows| !rows.is_empty()))
+            .map_err(|err| DatabaseError::query("Error checking for messages", err))
+    }
+
+    async fn get_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+    ) -> Result<Option<String>, DatabaseError> {
+        self.db
+            .execute(
+                &self.get_latest_conversation_id_query,
+                (chooser_hash, choosee_hash),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error getting latest conversation id", err))?
+            .rows_typed_or_empty::<(String,)>()
+            .next()
+            .transpose()
+            .map(|row| row.map(|row| row.0))
+            .map_err(|err| DatabaseError::row("Error getting latest conversation id", err))
+    }
+
+    async fn set_latest_conversation_id(
+        &self,
+        chooser_hash: &str,
+        choosee_hash: &str,
+        conversation_id: &str,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.set_latest_conversation_id_query,
+                (conversation_id, chooser_hash, choosee_hash),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error setting latest conversation id", err))
+    }
+
+    async fn remove_friend(
+        &self,
+        username: &str,
+        friend_username: &str,
+    ) -> Result<(), DatabaseError> {
+        let friend_profiles = self
+            .get_friends(username)
+            .await?
+            .into_iter()
+            .filter(|friend_profile| friend_profile.username == friend_username)
+            .collect::<Vec<_>>(); // set elements have to match exactly to be removed, so they're read back first
+
+        if friend_profiles.is_empty() {
+            return Ok(());
+        }
+
+        self.db
+            .execute(&self.remove_friend_query, (friend_profiles, username))
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing friend", err))
+    }
+
+    async fn remove_friend_of_friend(
+        &self,
+        username: &str,
+        friend_of_friend_username: &str,
+    ) -> Result<(), DatabaseError> {
+        let mut profiles = Vec::<Profile>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_friends_of_friends_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting friends of friends", err))?
+            .rows_typed_or_empty::<(Option<Vec<Profile>>,)>()
+        {
+            let row =
+                row.map_err(|err| DatabaseError::row("Error getting friends of friends", err))?;
+
+            profiles.extend(
+                row.0
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|profile| profile.username == friend_of_friend_username),
+            );
+        }
+
+        if profiles.is_empty() {
+            return Ok(());
+        }
+
+        self.db
+            .execute(
+                &self.remove_friends_of_friends_query,
+                (profiles, vec![username]),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing friend of friend", err))
+    }
+
+    async fn tombstone_conversation(
+        &self,
+        conversation_id: &str,
+        tombstoned_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        let results = tokio::join!(
+            self.db.execute(
+                &self.tombstone_conversation_query,
+                (
+                    Self::timestamp_from_datetime(tombstoned_at),
+                    conversation_id
+                ),
+            ),
+            self.db
+                .execute(&self.delete_messages_query, (conversation_id,)),
+            self.db
+                .execute(&self.delete_pinned_messages_query, (conversation_id,)),
+            self.db
+                .execute(&self.delete_polls_query, (conversation_id,)),
+        );
+
+        results
+            .0
+            .map_err(|err| DatabaseError::query("Error tombstoning conversation", err))?;
+
+        results
+            .1
+            .map_err(|err| DatabaseError::query("Error deleting messages", err))?;
+
+        results
+            .2
+            .map_err(|err| DatabaseError::query("Error deleting pinned messages", err))?;
+
+        results
+            .3
+            .map_err(|err| DatabaseError::query("Error deleting polls", err))?;
+
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), DatabaseError> {
+        self.db
+            .execute(&self.delete_user_daily_messages_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error deleting user daily messages", err))?;
+
+        self.db
+            .execute(&self.delete_user_query, (username,))
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error deleting user", err))
+    }
+
+    async fn delete_conversations_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        take: i32,
+    ) -> Result<usize, DatabaseError> {
+        let mut deleted = 0;
+
+        for row in self
+            .db
+            .execute(
+                &self.get_conversations_created_before_query,
+                (Self::timestamp_from_datetime(cutoff), take),
+            )
+            .await
+            .map_err(|err| DatabaseError::query("Error getting old conversations", err))?
+            .rows_typed_or_empty::<(String, Option<String>, Option<String>)>()
+        {
+            let (conversation_id, chooser_username, choosee_username) =
+                row.map_err(|err| DatabaseError::row("Error getting old conversations", err))?;
+
+            let results = tokio::join!(
+                self.db
+                    .execute(&self.delete_messages_query, (&conversation_id,)),
+                self.db
+                    .execute(&self.delete_pinned_messages_query, (&conversation_id,)),
+                self.db
+                    .execute(&self.delete_choosee_presence_query, (&conversation_id,)),
+                self.db
+                    .execute(&self.delete_conversation_stats_query, (&conversation_id,)),
+                self.db
+                    .execute(&self.delete_polls_query, (&conversation_id,)),
+                try_join_all(chooser_username.iter().chain(choosee_username.iter()).map(
+                    |username| self.db.execute(
+                        &self.delete_user_conversation_query,
+                        (username, &conversation_id)
+                    )
+                )),
+            );
+
+            results
+                .0
+                .map_err(|err| DatabaseError::query("Error deleting messages", err))?;
+
+            results
+                .1
+                .map_err(|err| DatabaseError::query("Error deleting pinned messages", err))?;
+
+            results
+                .2
+                .map_err(|err| DatabaseError::query("Error deleting choosee presence", err))?;
+
+            results
+                .3
+                .map_err(|err| DatabaseError::query("Error deleting conversation stats", err))?;
+
+            results
+                .4
+                .map_err(|err| DatabaseError::query("Error deleting polls", err))?;
+
+            results
+                .5
+                .map_err(|err| DatabaseError::query("Error deleting user conversations", err))?;
+
+            self.db
+                .execute(&self.delete_conversation_query, (&conversation_id,))
+                .await
+                .map_err(|err| DatabaseError::query("Error deleting conversation", err))?;
+
+            deleted += 1;
+        }
+
+        Ok(deleted)
+    }
+
+    async fn end_streaks(&self, before: NaiveDate, take: i32) -> Result<usize, DatabaseError> {
+        let mut ended = 0;
+
+        for row in self
+            .db
+            .execute(&self.get_streaks_ended_before_query, (before, take))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting ended streaks", err))?
+            .rows_typed_or_empty::<(String, NaiveDate)>()
+        {
+            let (conversation_id, streak_day) =
+                row.map_err(|err| DatabaseError::row("Error getting ended streaks", err))?;
+
+            let result = self
+                .db
+                .execute(&self.end_streak_query, (&conversation_id, streak_day))
+                .await
+                .map_err(|err| DatabaseError::query("Error ending streak", err))?;
+
+            if Self::applied(result) {
+                ended += 1;
+            }
+        }
+
+        Ok(ended)
+    }
+
+    async fn get_revoked_before(
+        &self,
+        username: &str,
+    ) -> Result<Option<DateTime<Utc>>, DatabaseError> {
+        self.db
+            .execute(&self.get_revoked_before_query, (username,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting token revocation", err))?
+            .rows_typed_or_empty::<(Duration,)>()
+            .next()
+            .transpose()
+            .map(|row| row.map(|row| Self::datetime_from_timestamp(row.0)))
+            .map_err(|err| DatabaseError::row("Error getting token revocation", err))
+    }
+
+    async fn revoke_tokens(
+        &self,
+        username: &str,
+        revoked_before: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.revoke_tokens_query,
+                (username, Self::timestamp_from_datetime(revoked_before)),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error revoking tokens", err))
+    }
+
+    async fn get_bot_key(&self, key_id: &str) -> Result<Option<BotKey>, DatabaseError> {
+        self.db
+            .execute(&self.get_bot_key_query, (key_id,))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting bot key", err))?
+            .rows_typed_or_empty::<(String, String, Duration, Option<Duration>)>()
+            .next()
+            .transpose()
+            .map(|row| {
+                row.map(|row| BotKey {
+                    key_id: key_id.to_owned(),
+                    username: row.0,
+                    secret: row.1,
+                    created_at: Self::datetime_from_timestamp(row.2),
+                    revoked_at: row.3.map(Self::datetime_from_timestamp),
+                })
+            })
+            .map_err(|err| DatabaseError::row("Error getting bot key", err))
+    }
+
+    fn message_retention(&self) -> Option<StdDuration> {
+        self.message_retention
+    }
+
+    async fn health_check(&self) -> Result<(), DatabaseError> {
+        self.db
+            .execute(&self.health_check_query, &[])
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error checking database health", err))
+    }
+
+    async fn add_failed_event(&self, failed_event: &FailedEvent) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_failed_event_query,
+                (
+                    &failed_event.subject,
+                    Self::timestamp_from_datetime(failed_event.failed_at),
+                    Self::timestamp_from_datetime(failed_event.created_at),
+                    &failed_event.data,
+                    &failed_event.reason,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding failed event", err))
+    }
+
+    async fn get_failed_events(&self) -> Result<Vec<FailedEvent>, DatabaseError> {
+        let mut failed_event_vec = Vec::<FailedEvent>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_failed_events_query, &[])
+            .await
+            .map_err(|err| DatabaseError::query("Error getting failed events", err))?
+            .rows_typed_or_empty::<(String, Duration, Duration, Vec<u8>, String)>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting failed events", err))?;
+
+            failed_event_vec.push(FailedEvent {
+                subject: row.0,
+                failed_at: Self::datetime_from_timestamp(row.1),
+                created_at: Self::datetime_from_timestamp(row.2),
+                data: row.3,
+                reason: row.4,
+            });
+        }
+
+        Ok(failed_event_vec)
+    }
+
+    async fn remove_failed_event(
+        &self,
+        subject: &str,
+        failed_at: DateTime<Utc>,
+    ) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.remove_failed_event_query,
+                (subject, Self::timestamp_from_datetime(failed_at)),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error removing failed event", err))
+    }
+
+    async fn add_audit_entry(&self, audit_entry: &AuditEntry) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.add_audit_entry_query,
+                (
+                    audit_entry.occurred_at.date_naive().to_string(),
+                    Self::timestamp_from_datetime(audit_entry.occurred_at),
+                    &audit_entry.entry_id,
+                    audit_entry.action.as_str(),
+                    &audit_entry.actor,
+                    &audit_entry.target,
+                    &audit_entry.connection_id,
+                    &audit_entry.detail,
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error adding audit entry", err))
+    }
+
+    async fn get_audit_log(
+        &self,
+        day: NaiveDate,
+        take: i32,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let mut audit_entry_vec = Vec::<AuditEntry>::new();
+
+        for row in self
+            .db
+            .execute(&self.get_audit_log_query, (day.to_string(), take))
+            .await
+            .map_err(|err| DatabaseError::query("Error getting audit log", err))?
+            .rows_typed_or_empty::<(
+                Duration,
+                String,
+                String,
+                Option<String>,
+                Option<String>,
+                Option<String>,
+                String,
+            )>()
+        {
+            let row = row.map_err(|err| DatabaseError::row("Error getting audit log", err))?;
+
+            // an action this version doesn't know about was recorded by a newer one
+            let Some(action) = AuditAction::parse(&row.2) else {
+                continue;
+            };
+
+            audit_entry_vec.push(AuditEntry {
+                occurred_at: Self::datetime_from_timestamp(row.0),
+                entry_id: row.1,
+                action,
+                actor: row.3,
+                target: row.4,
+                connection_id: row.5,
+                detail: row.6,
+            });
+        }
+
+        Ok(audit_entry_vec)
+    }
+
+    async fn set_job_status(&self, job_status: &JobStatus) -> Result<(), DatabaseError> {
+        self.db
+            .execute(
+                &self.set_job_status_query,
+                (
+                    &job_status.job_id,
+                    &job_status.kind,
+                    job_status.state.as_str(),
+                    job_status.attempts as i32,
+                    &job_status.last_error,
+                    Self::timestamp_from_datetime(job_status.enqueued_at),
+                    Self::timestamp_from_datetime(job_status.updated_at),
+                ),
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| DatabaseError::query("Error setting job status", err))
+    }
+}
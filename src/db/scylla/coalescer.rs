@@ -0,0 +1,145 @@
+use scylla::{
+    batch::{Batch, BatchType},
+    frame::value::Timestamp,
+    prepared_statement::PreparedStatement,
+    transport::errors::QueryError,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+use super::retry::RetryingSession;
+
+// during a spike most inserts land in a handful of busy conversations, so messages arriving within a few
+// milliseconds of each other are written as one unlogged batch per conversation. every message in a batch shares a
+// partition, which is the only case where batching takes load off scylla instead of adding to it
+
+const QUEUE_CAPACITY: usize = 4096;
+
+#[derive(Clone, Copy)]
+pub struct CoalescerOptions {
+    pub flush_interval: Duration, // how long the first message of a batch can wait for company
+    pub max_batch_size: usize,
+}
+
+type Row = (
+    String,
+    String,
+    Option<String>,
+    Timestamp,
+    bool,
+    Option<String>,
+    Option<String>,
+    i32,
+); // conversation_id, content, content_key_id, sent_at, from_chooser, attachment, kind, ttl
+
+struct Pending {
+    row: Row,
+    done: oneshot::Sender<Result<(), QueryError>>,
+}
+
+pub struct MessageCoalescer {
+    tx: mpsc::Sender<Pending>,
+}
+
+impl MessageCoalescer {
+    pub fn new(
+        db: Arc<RetryingSession>,
+        new_message_query: PreparedStatement,
+        options: CoalescerOptions,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+
+        tokio::task::spawn(Self::run(db, new_message_query, options, rx));
+
+        Self { tx }
+    }
+
+    // resolves once the batch holding this message has been written
+    pub async fn insert(&self, row: Row) -> Result<(), QueryError> {
+        let (done, result) = oneshot::channel();
+
+        self.tx
+            .send(Pending { row, done })
+            .await
+            .expect("Message coalescer stopped");
+
+        result.await.expect("Message coalescer dropped a message")
+    }
+
+    async fn run(
+        db: Arc<RetryingSession>,
+        new_message_query: PreparedStatement,
+        options: CoalescerOptions,
+        mut rx: mpsc::Receiver<Pending>,
+    ) {
+        while let Some(first) = rx.recv().await {
+            let deadline = Instant::now() + options.flush_interval;
+
+            let mut partitions = HashMap::<String, Vec<Pending>>::new();
+
+            partitions
+                .entry(first.row.0.clone())
+                .or_default()
+                .push(first);
+
+            while let Ok(Some(pending)) = tokio::time::timeout_at(deadline, rx.recv()).await {
+                let partition = partitions.entry(pending.row.0.clone()).or_default();
+
+                partition.push(pending);
+
+                if partition.len() >= options.max_batch_size {
+                    let full = std::mem::take(partition);
+
+                    tokio::task::spawn(Self::write(db.clone(), new_message_query.clone(), full));
+                }
+            }
+
+            for (_, partition) in partitions {
+                if !partition.is_empty() {
+                    tokio::task::spawn(Self::write(
+                        db.clone(),
+                        new_message_query.clone(),
+                        partition,
+                    ));
+                }
+            }
+        }
+    }
+
+    async fn write(
+        db: Arc<RetryingSession>,
+        new_message_query: PreparedStatement,
+        partition: Vec<Pending>,
+    ) {
+        let (rows, dones): (Vec<_>, Vec<_>) = partition
+            .into_iter()
+            .map(|pending| (pending.row, pending.done))
+            .unzip();
+
+        let result = if rows.len() == 1 {
+            db.execute(&new_message_query, &rows[0]).await
+        } else {
+            let mut batch = Batch::new(BatchType::Unlogged);
+
+            for _ in &rows {
+                batch.append_statement(new_message_query.clone());
+            }
+
+            batch.set_is_idempotent(new_message_query.get_is_idempotent());
+
+            if let Some(consistency) = new_message_query.get_consistency() {
+                batch.set_consistency(consistency);
+            }
+
+            db.batch(&batch, &rows).await
+        }
+        .map(|_| ()); // QueryResult isn't Clone
+
+        for done in dones {
+            let _ = done.send(result.clone()); // the caller may have timed out and gone away
+        }
+    }
+}
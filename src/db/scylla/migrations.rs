@@ -0,0 +1,171 @@
+use chrono::{prelude::*, Duration};
+use scylla::{
+    frame::value::Timestamp,
+    transport::errors::{DbError, QueryError},
+    Session,
+};
+
+use crate::db::DatabaseError;
+
+// applied in order on startup, and each version is recorded in schema_migration once it's done. add new ones to the
+// end and never edit one that has shipped. two gateways starting at once can both apply the same migration, so every
+// statement has to be safe to run twice
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("../../../schema/scylla/0001_initial.cql")),
+    (2, include_str!("../../../schema/scylla/0002_delivery.cql")),
+    (
+        3,
+        include_str!("../../../schema/scylla/0003_disappearing.cql"),
+    ),
+    (4, include_str!("../../../schema/scylla/0004_avatar.cql")),
+    (
+        5,
+        include_str!("../../../schema/scylla/0005_attachment.cql"),
+    ),
+    (6, include_str!("../../../schema/scylla/0006_pins.cql")),
+    (7, include_str!("../../../schema/scylla/0007_archive.cql")),
+    (8, include_str!("../../../schema/scylla/0008_clear.cql")),
+    (
+        9,
+        include_str!("../../../schema/scylla/0009_user_search.cql"),
+    ),
+    (10, include_str!("../../../schema/scylla/0010_push.cql")),
+    (
+        11,
+        include_str!("../../../schema/scylla/0011_notification_prefs.cql"),
+    ),
+    (12, include_str!("../../../schema/scylla/0012_devices.cql")),
+    (
+        13,
+        include_str!("../../../schema/scylla/0013_tombstone.cql"),
+    ),
+    (14, include_str!("../../../schema/scylla/0014_reports.cql")),
+    (
+        15,
+        include_str!("../../../schema/scylla/0015_audit_log.cql"),
+    ),
+    (16, include_str!("../../../schema/scylla/0016_jobs.cql")),
+    (17, include_str!("../../../schema/scylla/0017_bot_keys.cql")),
+    (
+        18,
+        include_str!("../../../schema/scylla/0018_content_encryption.cql"),
+    ),
+    (
+        19,
+        include_str!("../../../schema/scylla/0019_key_distribution.cql"),
+    ),
+    (20, include_str!("../../../schema/scylla/0020_stats.cql")),
+    (21, include_str!("../../../schema/scylla/0021_streaks.cql")),
+    (
+        22,
+        include_str!("../../../schema/scylla/0022_last_connected.cql"),
+    ),
+    (23, include_str!("../../../schema/scylla/0023_mention.cql")),
+    (
+        24,
+        include_str!("../../../schema/scylla/0024_message_kind.cql"),
+    ),
+    (25, include_str!("../../../schema/scylla/0025_poll.cql")),
+    (
+        26,
+        include_str!("../../../schema/scylla/0026_outbox_bucket.cql"),
+    ),
+    (
+        27,
+        include_str!("../../../schema/scylla/0027_conversation_link.cql"),
+    ),
+    (
+        28,
+        include_str!("../../../schema/scylla/0028_choosee_presence_hash.cql"),
+    ),
+];
+
+pub async fn create_keyspace(
+    db: &Session,
+    keyspace: &str,
+    replication: &str,
+) -> Result<(), DatabaseError> {
+    db.query(
+        format!(
+            "CREATE KEYSPACE IF NOT EXISTS \"{}\" WITH replication = {}",
+            keyspace, replication
+        ),
+        &[],
+    )
+    .await
+    .map_err(|err| DatabaseError::query("Error creating keyspace", err))?;
+
+    db.await_schema_agreement()
+        .await
+        .map_err(|err| DatabaseError::query("Error waiting for schema agreement", err))
+}
+
+// expects the session to already be using the keyspace
+pub async fn run(db: &Session) -> Result<(), DatabaseError> {
+    db.query(
+        "CREATE TABLE IF NOT EXISTS schema_migration (version int PRIMARY KEY, applied_at timestamp)",
+        &[],
+    )
+    .await
+    .map_err(|err| DatabaseError::query("Error creating schema_migration table", err))?;
+
+    let mut applied = Vec::new();
+
+    for row in db
+        .query("SELECT version FROM schema_migration", &[])
+        .await
+        .map_err(|err| DatabaseError::query("Error getting applied migrations", err))?
+        .rows_typed_or_empty::<(i32,)>()
+    {
+        let row = row.map_err(|err| DatabaseError::row("Error getting applied migrations", err))?;
+
+        applied.push(row.0);
+    }
+
+    for (version, cql) in MIGRATIONS {
+        if applied.contains(version) {
+            continue;
+        }
+
+        info!("Applying scylla migration {}", version);
+
+        for statement in statements(cql) {
+            match db.query(statement.as_str(), &[]).await {
+                Ok(_) => {}
+                Err(QueryError::DbError(DbError::Invalid, message))
+                    if statement.starts_with("ALTER TABLE") && message.contains("already") => {} // cql has no ADD IF NOT EXISTS, so a column another gateway just added is fine
+                Err(err) => return Err(DatabaseError::query("Error applying migration", err)),
+            }
+        }
+
+        db.await_schema_agreement()
+            .await
+            .map_err(|err| DatabaseError::query("Error waiting for schema agreement", err))?;
+
+        db.query(
+            "INSERT INTO schema_migration (version, applied_at) VALUES (?, ?)",
+            (
+                version,
+                Timestamp(Duration::milliseconds(Utc::now().timestamp_millis())),
+            ),
+        )
+        .await
+        .map_err(|err| DatabaseError::query("Error recording migration", err))?;
+    }
+
+    Ok(())
+}
+
+// cql has no way to run a whole file, so split it on semicolons. comments are stripped first so they can mention
+// semicolons
+fn statements(cql: &str) -> Vec<String> {
+    cql.lines()
+        .map(|line| line.split("--").next().unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join("\n")
+        .split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
@@ -0,0 +1,152 @@
+use scylla::{
+    batch::Batch,
+    frame::value::ValueList,
+    prepared_statement::PreparedStatement,
+    transport::errors::{DbError, QueryError},
+    QueryResult, Session,
+};
+use std::future::Future;
+use std::sync::Mutex;
+
+use crate::metrics;
+use crate::retry_policy::RetryPolicy;
+
+// retries idempotent statements that timed out or hit an overloaded node. the budget caps retries at a fraction of
+// overall traffic, so when the cluster is struggling for real we don't multiply the load on it
+
+pub struct RetryingSession {
+    session: Session,
+    retry_policy: RetryPolicy,
+    budget: RetryBudget,
+}
+
+pub struct RetryBudget {
+    tokens: Mutex<f64>,
+    ratio: f64,    // earned by every statement, a retry costs one
+    capacity: f64, // also what it starts with, so a cold gateway can still retry
+}
+
+impl RetryBudget {
+    pub fn new(ratio: f64, capacity: f64) -> Self {
+        Self {
+            tokens: Mutex::new(capacity),
+            ratio,
+            capacity,
+        }
+    }
+
+    fn deposit(&self) {
+        let mut tokens = self.tokens.lock().unwrap();
+
+        *tokens = (*tokens + self.ratio).min(self.capacity);
+    }
+
+    fn withdraw(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+
+        if *tokens < 1.0 {
+            return false;
+        }
+
+        *tokens -= 1.0;
+
+        true
+    }
+}
+
+impl RetryingSession {
+    pub fn new(session: Session, retry_policy: RetryPolicy, budget: RetryBudget) -> Self {
+        Self {
+            session,
+            retry_policy,
+            budget,
+        }
+    }
+
+    pub async fn execute(
+        &self,
+        prepared: &PreparedStatement,
+        values: impl ValueList,
+    ) -> Result<QueryResult, QueryError> {
+        let values = values.serialized()?; // serialized once, not on every attempt
+
+        self.with_retries(prepared.get_is_idempotent(), || {
+            self.session.execute(prepared, &values)
+        })
+        .await
+    }
+
+    // for one-off statements that aren't worth preparing. never retried
+    pub async fn query(
+        &self,
+        query: &str,
+        values: impl ValueList,
+    ) -> Result<QueryResult, QueryError> {
+        let values = values.serialized()?;
+
+        self.with_retries(false, || self.session.query(query, &values))
+            .await
+    }
+
+    pub async fn batch<T: ValueList>(
+        &self,
+        batch: &Batch,
+        values: &[T],
+    ) -> Result<QueryResult, QueryError> {
+        self.with_retries(batch.get_is_idempotent(), || {
+            self.session.batch(batch, values)
+        })
+        .await
+    }
+
+    async fn with_retries<F: Future<Output = Result<QueryResult, QueryError>>>(
+        &self,
+        is_idempotent: bool,
+        mut attempt_once: impl FnMut() -> F,
+    ) -> Result<QueryResult, QueryError> {
+        self.budget.deposit();
+
+        let mut attempt = 0;
+
+        loop {
+            let err = match attempt_once().await {
+                Ok(result) => return Ok(result),
+                Err(err) if !is_idempotent || !is_retryable(&err) => return Err(err),
+                Err(err) => err,
+            };
+
+            if attempt + 1 >= self.retry_policy.max_attempts {
+                metrics::DATABASE_RETRIES_EXHAUSTED_ATTEMPTS.increment();
+
+                return Err(err);
+            }
+
+            if !self.budget.withdraw() {
+                metrics::DATABASE_RETRIES_EXHAUSTED_BUDGET.increment();
+
+                return Err(err);
+            }
+
+            metrics::DATABASE_RETRIES.increment();
+
+            debug!("Retrying statement: {}", err);
+
+            tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+
+            attempt += 1;
+        }
+    }
+}
+
+// only what another attempt could plausibly fix. anything else would fail the same way again
+fn is_retryable(err: &QueryError) -> bool {
+    matches!(
+        err,
+        QueryError::TimeoutError
+            | QueryError::RequestTimeout(_)
+            | QueryError::DbError(
+                DbError::Overloaded | DbError::ReadTimeout { .. } | DbError::WriteTimeout { .. },
+                _
+            )
+    )
+}
@@ -0,0 +1,46 @@
+use chrono::prelude::*;
+use std::sync::Arc;
+
+use crate::db::{DatabaseError, Storage};
+use crate::models::failed_event::FailedEvent;
+
+// events that never made it out of the outbox end up in the failed_event table instead of being retried forever.
+// operators can look through it with cqlsh and replay it with the replayFailedEvents admin operation
+
+pub async fn dead_letter(
+    db: &dyn Storage,
+    subject: String,
+    created_at: DateTime<Utc>,
+    data: Vec<u8>,
+    reason: String,
+) -> Result<(), DatabaseError> {
+    warn!("Dead lettering event for {}: {}", subject, reason);
+
+    db.add_failed_event(&FailedEvent {
+        subject,
+        failed_at: Utc::now(),
+        created_at,
+        data,
+        reason,
+    })
+    .await
+}
+
+// moves every failed event back into the given bucket of the outbox, returning how many were replayed
+pub async fn replay(db: Arc<dyn Storage>, outbox_bucket: i32) -> Result<usize, DatabaseError> {
+    let failed_events = db.get_failed_events().await?;
+
+    for failed_event in failed_events.iter() {
+        db.add_to_outbox(
+            outbox_bucket,
+            &failed_event.subject,
+            failed_event.data.clone(),
+        )
+        .await?;
+
+        db.remove_failed_event(&failed_event.subject, failed_event.failed_at)
+            .await?;
+    }
+
+    Ok(failed_events.len())
+}
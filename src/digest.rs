@@ -0,0 +1,163 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::connection::{nats_message::NatsMessage, user_event::UserEvent};
+use crate::db::Storage;
+use crate::hash::Hasher;
+use crate::jobs::{self, Job, JobError, JobHandler, JobQueue};
+use crate::message_bus::MessageBus;
+
+// a push notification once a day for users who haven't connected in a while, with how many conversations have
+// messages they haven't seen and how many friend requests are waiting on them. nothing keeps track of what's been
+// read, so a conversation is unread when the other user sent something after the user last connected
+//
+// friend requests aren't stored with when they were sent, so the count is every request still pending rather than
+// the ones that came in while the user was away. the same requests are counted again each day until they're answered
+//
+// the daily job only finds the users, and enqueues a job for each with an id that's the same for the user all day, so
+// the queue drops the copies a retried daily job enqueues again. each of those publishes a digest event, which the
+// push workers turn into a notification like any other event. so it's sent by one of them, only if the user still
+// isn't connected and only if their notification prefs allow it
+
+pub const JOB_KIND: &str = "daily_digest";
+pub const USER_JOB_KIND: &str = "user_digest";
+
+// lined up from the epoch like every periodic job, so it runs shortly after midnight utc
+pub const INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// anyone who connected more recently than this has seen it all already
+const MIN_AWAY_DAYS: i64 = 1;
+
+// anyone away longer has most likely stopped using the app, and a notification every day would only nag them
+const MAX_AWAY_DAYS: i64 = 30;
+
+// users looked up per storage call
+const PAGE_SIZE: i32 = 500;
+
+#[derive(Serialize, Deserialize)]
+pub struct UserDigestJob {
+    pub username: String,
+    pub since: DateTime<Utc>, // when they last connected
+}
+
+impl UserDigestJob {
+    pub fn job(self, day: NaiveDate) -> Job {
+        let key = format!("{}-{}", self.username, day);
+
+        Job::keyed(USER_JOB_KIND, &key, self)
+    }
+}
+
+pub struct DailyDigest {
+    pub db: Arc<dyn Storage>,
+    pub job_queue: Arc<dyn JobQueue>,
+}
+
+#[async_trait]
+impl JobHandler for DailyDigest {
+    async fn handle(&self, _job: &Job) -> Result<(), JobError> {
+        let now = Utc::now();
+
+        let mut after = None::<String>;
+        let mut enqueued = 0;
+
+        loop {
+            let away_users = self
+                .db
+                .get_away_users(
+                    now - chrono::Duration::days(MAX_AWAY_DAYS),
+                    now - chrono::Duration::days(MIN_AWAY_DAYS),
+                    after.as_deref(),
+                    PAGE_SIZE,
+                )
+                .await?;
+
+            for (username, since) in &away_users {
+                let job = UserDigestJob {
+                    username: username.clone(),
+                    since: *since,
+                }
+                .job(now.date_naive());
+
+                jobs::enqueue(self.job_queue.as_ref(), self.db.as_ref(), job).await?;
+            }
+
+            enqueued += away_users.len();
+
+            if away_users.len() < PAGE_SIZE as usize {
+                break;
+            }
+
+            after = away_users.last().map(|(username, _)| username.clone());
+        }
+
+        info!("Enqueued digests for {} users", enqueued);
+
+        Ok(())
+    }
+}
+
+pub struct UserDigest {
+    pub db: Arc<dyn Storage>,
+    pub message_bus: Arc<dyn MessageBus>,
+    pub hasher: Arc<Hasher>,
+}
+
+#[async_trait]
+impl JobHandler for UserDigest {
+    async fn handle(&self, job: &Job) -> Result<(), JobError> {
+        let UserDigestJob { username, since } = job.payload()?;
+
+        let username_hash = self.hasher.hash(&username);
+
+        // nowhere to send it
+        if self.db.get_push_tokens(&username_hash).await?.is_empty() {
+            return Ok(());
+        }
+
+        let mut unread_conversations = 0;
+
+        for conversation in self.db.get_conversations(&username).await? {
+            if conversation.archived || conversation.tombstoned {
+                continue;
+            }
+
+            let messages = self
+                .db
+                .get_messages(&conversation.conversation_id, &username, i8::MAX, since)
+                .await?;
+
+            if messages
+                .iter()
+                .any(|message| message.from_chooser != conversation.is_chooser)
+            {
+                unread_conversations += 1;
+            }
+        }
+
+        let pending_friend_requests =
+            self.db.get_friend_requests_received(&username).await?.len() as u32;
+
+        if unread_conversations == 0 && pending_friend_requests == 0 {
+            return Ok(());
+        }
+
+        let nats_message = NatsMessage {
+            to_username_hash: username_hash,
+            user_event: UserEvent::Digest {
+                unread_conversations,
+                pending_friend_requests,
+                since,
+            },
+        };
+
+        self.message_bus
+            .publish(&nats_message.subject(), &nats_message.data())
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use serde::Serialize;
+
+// shared vocabulary for deciding whether a failed operation is worth retrying and what to tell the client about it
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCategory {
+    Transient,
+    Permanent,
+    Auth,
+    Validation,
+}
+
+impl ErrorCategory {
+    pub fn is_retryable(self) -> bool {
+        self == Self::Transient
+    }
+}
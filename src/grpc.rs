@@ -0,0 +1,339 @@
+use futures_util::{
+    stream::{self, BoxStream},
+    SinkExt, Stream, StreamExt,
+};
+use prost::Message as _;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tonic::{metadata::MetadataMap, transport::Server, Request, Response, Status, Streaming};
+use tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use uuid::Uuid;
+
+use crate::auth::{
+    self,
+    bot::{BotAuthError, BotCredentials},
+};
+use crate::connection::{
+    proto::{self, operation::Op, response, server_frame::Frame, ErrorCode, ServerFrame},
+    CloseReason,
+};
+use crate::server::{self, Shared};
+
+use gateway::gateway_server::{Gateway, GatewayServer};
+
+// for backend services that would rather not speak the websocket protocol, see proto/gateway.proto. every call gets a
+// connection of its own, bridged over an in memory websocket, so it's handled exactly like a client's would be,
+// rate limits and all. unary calls open one, send their operation and close it again once the response comes back
+
+pub mod gateway {
+    tonic::include_proto!("gateway");
+}
+
+// unary calls give up on their response after this
+const UNARY_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub async fn serve(port: u16, shared: Arc<Shared>) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port)); // callers are other services, not on this host
+
+    info!("Serving grpc on {}", addr);
+
+    if let Err(err) = Server::builder()
+        .add_service(GatewayServer::new(GrpcApi { shared }))
+        .serve(addr)
+        .await
+    {
+        error!("Grpc server error: {}", err);
+    }
+}
+
+struct GrpcApi {
+    shared: Arc<Shared>,
+}
+
+#[tonic::async_trait]
+impl Gateway for GrpcApi {
+    type EventStreamStream = BoxStream<'static, Result<ServerFrame, Status>>;
+
+    async fn event_stream(
+        &self,
+        request: Request<Streaming<proto::Operation>>,
+    ) -> Result<Response<Self::EventStreamStream>, Status> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+
+        let (metadata, _, mut operations) = request.into_parts();
+
+        let (mut user_tx, user_rx) = self.open(&metadata, ip).await?.split();
+
+        // dropped along with the response stream, when the caller cancels the call
+        let (receiving_tx, receiving_rx) = oneshot::channel::<()>();
+
+        // the caller can stop sending and keep receiving events, so the connection is only closed once it's done
+        // with both
+        tokio::task::spawn(async move {
+            while let Ok(Some(operation)) = operations.message().await {
+                if user_tx
+                    .send(Message::Binary(operation.encode_to_vec()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+
+            let _ = receiving_rx.await;
+            let _ = user_tx.close().await;
+        });
+
+        let frames = stream::unfold(Some((user_rx, receiving_tx)), |state| async move {
+            let (mut user_rx, receiving_tx) = state?;
+
+            match next_frame(&mut user_rx).await {
+                Ok(Some(frame)) => Some((
+                    Ok(ServerFrame { frame: Some(frame) }),
+                    Some((user_rx, receiving_tx)),
+                )),
+                Ok(None) => None,
+                Err(status) => Some((Err(status), None)),
+            }
+        });
+
+        Ok(Response::new(frames.boxed()))
+    }
+
+    async fn send_message(
+        &self,
+        request: Request<proto::SendMutation>,
+    ) -> Result<Response<proto::SentResponse>, Status> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+
+        let (metadata, _, mutation) = request.into_parts();
+
+        match self.call(&metadata, ip, Op::Send(mutation)).await? {
+            response::Op::Sent(sent) => Ok(Response::new(sent)),
+            _ => Err(Status::internal("Unexpected response")),
+        }
+    }
+
+    async fn get_messages(
+        &self,
+        request: Request<proto::MessagesQuery>,
+    ) -> Result<Response<proto::MessagesResponse>, Status> {
+        let ip = request.remote_addr().map(|addr| addr.ip());
+
+        let (metadata, _, query) = request.into_parts();
+
+        match self.call(&metadata, ip, Op::Messages(query)).await? {
+            response::Op::Messages(messages) => Ok(Response::new(messages)),
+            _ => Err(Status::internal("Unexpected response")),
+        }
+    }
+}
+
+impl GrpcApi {
+    // checks the address, then the token or api key, the same way the websocket handshake does. every call counts as
+    // a handshake, so a service making lots of unary calls from one address needs a bigger handshake budget
+    async fn open(
+        &self,
+        metadata: &MetadataMap,
+        ip: Option<IpAddr>,
+    ) -> Result<tokio_tungstenite::WebSocketStream<tokio::io::DuplexStream>, Status> {
+        let connection_id = Uuid::new_v4().to_string();
+
+        if let Some(ip) = ip {
+            if self.shared.handshake_limiter.is_banned(ip) {
+                return Err(Status::permission_denied("Address banned"));
+            }
+
+            if let Some(ban) = self.shared.handshake_limiter.handshake(ip) {
+                self.shared.audit_log.banned(&ban);
+
+                return Err(Status::resource_exhausted("Too many connection attempts"));
+            }
+        }
+
+        let authenticated = match BotCredentials::from_headers(&metadata.clone().into_headers()) {
+            Ok(Some(credentials)) => match credentials.verify(self.shared.db.as_ref()).await {
+                Ok(payload) => Ok((payload, true)),
+                Err(BotAuthError::DatabaseError(err)) => {
+                    error!("Error checking api key: {}", err);
+
+                    return Err(Status::unavailable("Unable to verify api key"));
+                }
+                Err(err) => Err(err.to_string()),
+            },
+            Ok(None) => {
+                let token = metadata
+                    .get("authorization")
+                    .ok_or_else(|| Status::unauthenticated("Access token required"))?
+                    .to_str()
+                    .ok()
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .ok_or_else(|| Status::unauthenticated("Malformed authorization metadata"))?;
+
+                self.shared
+                    .jwt_auth
+                    .verify_token(token)
+                    .map(|payload| (payload, false))
+                    .map_err(|err| err.to_string())
+            }
+            Err(err) => Err(err.to_string()),
+        };
+
+        let (payload, is_bot) = match authenticated {
+            Ok(authenticated) => authenticated,
+            Err(err) => {
+                server::handshake_auth_failure(
+                    &self.shared.audit_log,
+                    &self.shared.handshake_limiter,
+                    ip,
+                    None,
+                    &connection_id,
+                    err.clone(),
+                );
+
+                return Err(Status::unauthenticated(err));
+            }
+        };
+
+        // the token's claim wins over whatever the caller said
+        let device_id = payload.device_id.clone().or_else(|| {
+            metadata
+                .get("x-device-id")
+                .and_then(|device_id| device_id.to_str().ok())
+                .map(|device_id| device_id.to_owned())
+        });
+
+        match tokio::time::timeout(
+            self.shared.settings.database_timeout,
+            auth::is_revoked(self.shared.db.as_ref(), &payload, device_id.as_deref()),
+        )
+        .await
+        {
+            Ok(Ok(false)) => {}
+            Ok(Ok(true)) => {
+                server::handshake_auth_failure(
+                    &self.shared.audit_log,
+                    &self.shared.handshake_limiter,
+                    ip,
+                    Some(payload.username),
+                    &connection_id,
+                    "Access token revoked".to_owned(),
+                );
+
+                return Err(Status::unauthenticated("Access token revoked"));
+            }
+            Ok(Err(err)) => {
+                error!("Error checking access token revocation: {}", err);
+
+                return Err(Status::unavailable("Unable to verify access token"));
+            }
+            Err(_) => return Err(Status::unavailable("Unable to verify access token")),
+        }
+
+        Ok(server::bridge(
+            self.shared.clone(),
+            connection_id,
+            payload,
+            device_id,
+            is_bot,
+        )
+        .await)
+    }
+
+    // skips the hello event and anything else sent before the response
+    async fn call(
+        &self,
+        metadata: &MetadataMap,
+        ip: Option<IpAddr>,
+        op: Op,
+    ) -> Result<response::Op, Status> {
+        let mut websocket = self.open(metadata, ip).await?;
+
+        websocket
+            .send(Message::Binary(
+                proto::Operation { op: Some(op) }.encode_to_vec(),
+            ))
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))?;
+
+        let response = tokio::time::timeout(UNARY_TIMEOUT, async {
+            loop {
+                match next_frame(&mut websocket).await? {
+                    Some(Frame::Response(response)) => return Ok(response),
+                    Some(Frame::Event(_)) => {}
+                    None => return Err(Status::unavailable("Connection closed before responding")),
+                }
+            }
+        })
+        .await
+        .map_err(|_| Status::deadline_exceeded("Timed out waiting for a response"))??;
+
+        let _ = websocket.close(None).await;
+
+        match response.op {
+            Some(response::Op::Error(error)) => Err(error_status(error)),
+            Some(op) => Ok(op),
+            None => Err(Status::internal("Empty response")),
+        }
+    }
+}
+
+// None once the connection closed cleanly
+async fn next_frame(
+    websocket: &mut (impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin),
+) -> Result<Option<Frame>, Status> {
+    while let Some(message) = websocket.next().await {
+        match message.map_err(|err| Status::unavailable(err.to_string()))? {
+            Message::Binary(message) => {
+                if let Some(frame) = ServerFrame::decode(message.as_slice())
+                    .map_err(|err| Status::internal(err.to_string()))?
+                    .frame
+                {
+                    return Ok(Some(frame));
+                }
+            }
+            Message::Close(Some(close_frame))
+                if !matches!(close_frame.code, CloseCode::Normal | CloseCode::Away) =>
+            {
+                return Err(close_status(close_frame))
+            }
+            Message::Close(_) => return Ok(None),
+            _ => {}
+        }
+    }
+
+    Ok(None)
+}
+
+fn close_status(close_frame: CloseFrame) -> Status {
+    let reason = close_frame.reason.into_owned();
+
+    match close_frame.code {
+        CloseCode::Policy => Status::unauthenticated(reason),
+        code if code == CloseReason::TokenExpired.code() => Status::unauthenticated(reason),
+        code if code == CloseReason::SessionReplaced.code() => Status::unauthenticated(reason),
+        code if code == CloseReason::RateLimited.code() => Status::resource_exhausted(reason),
+        code if code == CloseReason::ProtocolError.code() => Status::invalid_argument(reason),
+        _ => Status::unavailable(reason),
+    }
+}
+
+fn error_status(error: proto::ErrorResponse) -> Status {
+    match ErrorCode::from_i32(error.code) {
+        Some(ErrorCode::Unavailable) | Some(ErrorCode::DeliveryFailed) => {
+            Status::unavailable(error.message)
+        }
+        Some(ErrorCode::Forbidden) => Status::permission_denied(error.message),
+        Some(ErrorCode::InvalidRequest) | Some(ErrorCode::ContentTooLong) => {
+            Status::invalid_argument(error.message)
+        }
+        Some(ErrorCode::RateLimited) => Status::resource_exhausted(error.message),
+        Some(ErrorCode::Timeout) => Status::deadline_exceeded(error.message),
+        Some(ErrorCode::Internal) | None => Status::internal(error.message),
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::models::ip_ban::IpBan;
+use crate::rate_limit::Budget;
+
+// per address limits on the way in, checked before a connection costs anything more than the accept. an address that
+// opens websockets faster than its budget refills or fails auth too often in a window is banned for a while, and
+// everything from it is dropped until the ban ends. admins can ban and unban addresses too, which applies on every
+// node, while the automatic bans stay on the node that saw the traffic
+//
+// the address is the peer's, or the client's from the proxy protocol header when PROXY_PROTOCOL is set. without that
+// every connection through a load balancer comes from the balancer, and one ban would lock everyone out
+
+// addresses with a full budget and no recent failures carry no information, so they get dropped once the map grows
+// past this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Copy)]
+pub struct HandshakeLimits {
+    pub handshakes: Budget,
+    pub auth_failure_limit: u32, // how many are allowed within the window, the next one bans
+    pub auth_failure_window: Duration,
+    pub ban_duration: Duration,
+}
+
+struct Address {
+    tokens: f64,
+    refilled_at: Instant,
+    auth_failures: u32,
+    failures_since: Instant, // when the current window started
+}
+
+pub struct HandshakeLimiter {
+    limits: HandshakeLimits,
+    addresses: Mutex<HashMap<IpAddr, Address>>,
+    bans: Mutex<HashMap<IpAddr, IpBan>>,
+}
+
+// what's published for admin bans so every node applies them, the one that published it included
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum BanUpdate {
+    Ban(IpBan),
+    Unban { ip: IpAddr },
+}
+
+impl HandshakeLimiter {
+    pub fn new(limits: HandshakeLimits) -> Self {
+        Self {
+            limits,
+            addresses: Mutex::new(HashMap::new()),
+            bans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+
+        match bans.get(&ip) {
+            Some(ban) if ban.until > Utc::now() => true,
+            Some(_) => {
+                bans.remove(&ip);
+
+                false
+            }
+            None => false,
+        }
+    }
+
+    // for every websocket upgrade. returns the ban if this one was over budget
+    pub fn handshake(&self, ip: IpAddr) -> Option<IpBan> {
+        let budget = self.limits.handshakes;
+
+        let now = Instant::now();
+
+        let allowed = self.with_address(ip, now, |address| {
+            address.tokens = (address.tokens
+                + now.duration_since(address.refilled_at).as_secs_f64() * budget.per_second)
+                .min(budget.burst);
+            address.refilled_at = now;
+
+            if address.tokens >= 1.0 {
+                address.tokens -= 1.0;
+
+                true
+            } else {
+                false
+            }
+        });
+
+        (!allowed).then(|| {
+            self.ban(
+                ip,
+                self.limits.ban_duration,
+                "Too many connection attempts".to_owned(),
+            )
+        })
+    }
+
+    // returns the ban if this was one failure too many
+    pub fn auth_failed(&self, ip: IpAddr) -> Option<IpBan> {
+        let now = Instant::now();
+
+        let exceeded = self.with_address(ip, now, |address| {
+            if now.duration_since(address.failures_since) > self.limits.auth_failure_window {
+                address.auth_failures = 0;
+                address.failures_since = now;
+            }
+
+            address.auth_failures += 1;
+
+            address.auth_failures > self.limits.auth_failure_limit
+        });
+
+        exceeded.then(|| {
+            self.ban(
+                ip,
+                self.limits.ban_duration,
+                "Too many failed authentications".to_owned(),
+            )
+        })
+    }
+
+    pub fn ban(&self, ip: IpAddr, duration: Duration, reason: String) -> IpBan {
+        let banned_at = Utc::now();
+
+        let ban = IpBan {
+            ip,
+            reason,
+            banned_at,
+            until: chrono::Duration::from_std(duration)
+                .ok()
+                .and_then(|duration| banned_at.checked_add_signed(duration))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        };
+
+        self.apply(BanUpdate::Ban(ban.clone()));
+
+        ban
+    }
+
+    // either way the address starts over with a full budget once it's let back in
+    pub fn apply(&self, update: BanUpdate) {
+        match update {
+            BanUpdate::Ban(ban) => {
+                self.addresses.lock().unwrap().remove(&ban.ip);
+                self.bans.lock().unwrap().insert(ban.ip, ban);
+            }
+            BanUpdate::Unban { ip } => {
+                self.addresses.lock().unwrap().remove(&ip);
+                self.bans.lock().unwrap().remove(&ip);
+            }
+        }
+    }
+
+    // the ones still in effect, ordered by address
+    pub fn bans(&self) -> Vec<IpBan> {
+        let now = Utc::now();
+
+        let mut bans = self.bans.lock().unwrap();
+
+        bans.retain(|_, ban| ban.until > now);
+
+        let mut bans = bans.values().cloned().collect::<Vec<_>>();
+
+        bans.sort_by_key(|ban| ban.ip);
+
+        bans
+    }
+
+    fn with_address<T>(&self, ip: IpAddr, now: Instant, f: impl FnOnce(&mut Address) -> T) -> T {
+        let budget = self.limits.handshakes;
+
+        let mut addresses = self.addresses.lock().unwrap();
+
+        if addresses.len() > PRUNE_THRESHOLD {
+            addresses.retain(|_, address| {
+                address.tokens
+                    + now.duration_since(address.refilled_at).as_secs_f64() * budget.per_second
+                    < budget.burst
+                    || (address.auth_failures > 0
+                        && now.duration_since(address.failures_since)
+                            <= self.limits.auth_failure_window)
+            });
+        }
+
+        f(addresses.entry(ip).or_insert(Address {
+            tokens: budget.burst,
+            refilled_at: now,
+            auth_failures: 0,
+            failures_since: now,
+        }))
+    }
+}
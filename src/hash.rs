@@ -1,8 +1,88 @@
 use base64::{engine::general_purpose, Engine as _};
-use std::env;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
-pub fn base64_encoded_md5_hash_with_secret(input: String) -> String {
-    general_purpose::STANDARD
-        .encode(&md5::compute(input + &env::var("CONVERSATION_ID_SECRET").unwrap()).0)[0..22]
-        .to_owned()
+// usernames only leave the server hashed, in conversation ids and message bus subjects. keyed with a secret so
+// nobody can hash a username themselves to find its conversations. built once in Init
+
+const HASH_LENGTH: usize = 22; // same length md5 hashes were cut to, so ids don't change shape
+
+#[derive(Clone, Copy)]
+pub enum HashAlgorithm {
+    HmacSha256,
+    Md5,
+}
+
+#[derive(Clone, Copy)]
+pub enum HashEncoding {
+    Base64Url,
+    Base64, // has + and /, which need escaping in urls and aren't allowed in some message bus subjects
+}
+
+pub struct Hasher {
+    secret: String,
+    algorithm: HashAlgorithm,
+    encoding: HashEncoding,
+    accept_legacy: bool, // also match and subscribe to md5 base64 hashes, for ids and subjects from before the switch
+}
+
+impl Hasher {
+    pub fn new(
+        secret: String,
+        algorithm: HashAlgorithm,
+        encoding: HashEncoding,
+        accept_legacy: bool,
+    ) -> Self {
+        Self {
+            secret,
+            algorithm,
+            encoding,
+            accept_legacy,
+        }
+    }
+
+    pub fn hash(&self, username: &str) -> String {
+        self.hash_with(self.algorithm, self.encoding, username)
+    }
+
+    // every hash the username could appear under during a rollout, current one first
+    pub fn hashes(&self, username: &str) -> Vec<String> {
+        let mut hashes = vec![self.hash(username)];
+
+        if self.accept_legacy {
+            let legacy_hash = self.hash_with(HashAlgorithm::Md5, HashEncoding::Base64, username);
+
+            if !hashes.contains(&legacy_hash) {
+                hashes.push(legacy_hash);
+            }
+        }
+
+        hashes
+    }
+
+    fn hash_with(
+        &self,
+        algorithm: HashAlgorithm,
+        encoding: HashEncoding,
+        username: &str,
+    ) -> String {
+        let digest = match algorithm {
+            HashAlgorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+                    .expect("HMAC should accept keys of any length");
+
+                mac.update(username.as_bytes());
+
+                mac.finalize().into_bytes().to_vec()
+            }
+            HashAlgorithm::Md5 => md5::compute(username.to_owned() + &self.secret).0.to_vec(),
+        };
+
+        let encoded = match encoding {
+            HashEncoding::Base64Url => general_purpose::URL_SAFE_NO_PAD.encode(digest),
+            HashEncoding::Base64 => general_purpose::STANDARD.encode(digest),
+        };
+
+        encoded[..HASH_LENGTH].to_owned()
+    }
 }
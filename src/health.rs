@@ -0,0 +1,87 @@
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::Storage;
+use crate::message_bus::MessageBus;
+use crate::{metrics, runtime_metrics};
+
+// probed by kubernetes. liveness only says the process is serving, readiness also checks scylla and the message bus are reachable.
+// /metrics is for prometheus
+
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn serve(port: u16, db: Arc<dyn Storage>, message_bus: Arc<dyn MessageBus>) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port)); // probes come from outside the pod
+
+    let make_service = make_service_fn(move |_| {
+        let db = db.clone();
+        let message_bus = message_bus.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                handle(req, db.clone(), message_bus.clone())
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(err) => {
+            error!("Failed to bind health check server: {}", err);
+
+            return;
+        }
+    };
+
+    info!("Serving health checks on {}", server.local_addr()); // the port picked when it was 0
+
+    if let Err(err) = server.await {
+        error!("Health check server error: {}", err);
+    }
+}
+
+async fn handle(
+    req: Request<Body>,
+    db: Arc<dyn Storage>,
+    message_bus: Arc<dyn MessageBus>,
+) -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => respond(StatusCode::OK, "ok"),
+        (&Method::GET, "/readyz") => match readiness(db.as_ref(), message_bus.as_ref()).await {
+            Ok(()) => respond(StatusCode::OK, "ready"),
+            Err(reason) => {
+                warn!("Not ready: {}", reason);
+
+                respond(StatusCode::SERVICE_UNAVAILABLE, reason)
+            }
+        },
+        (&Method::GET, "/metrics") => {
+            Response::new(Body::from(runtime_metrics::render() + &metrics::render()))
+        }
+        _ => respond(StatusCode::NOT_FOUND, "not found"),
+    })
+}
+
+async fn readiness(db: &dyn Storage, message_bus: &dyn MessageBus) -> Result<(), &'static str> {
+    match tokio::time::timeout(READINESS_TIMEOUT, db.health_check()).await {
+        Ok(Ok(())) => {}
+        _ => return Err("scylla unavailable"),
+    }
+
+    message_bus
+        .flush(READINESS_TIMEOUT)
+        .await
+        .map_err(|_| "message bus unavailable")
+}
+
+fn respond(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+}
@@ -1,47 +1,915 @@
-use crate::db::Database;
-use std::{env, sync::Arc};
+use crate::analytics::Analytics;
+use crate::auth::JWTValidationConfig;
+use crate::challenge::{ChallengeVerifier, SiteVerifyChallenges, SiteVerifyOptions};
+use crate::config::{Cli, Config};
+use crate::connection::schema;
+use crate::db::{self, Storage};
+use crate::handshake_limit::HandshakeLimits;
+use crate::hash::{HashAlgorithm, HashEncoding, Hasher};
+use crate::jobs::{JetStreamJobQueue, JobQueue, MemoryJobQueue};
+use crate::message_bus::{self, MessageBus};
+use crate::origin::OriginAllowlist;
+use crate::presence::{MemoryPresence, NatsKvPresence, Presence};
+use crate::push::{
+    apns::{ApnsClient, ApnsOptions},
+    fcm::{FcmClient, FcmOptions},
+    PushProviders,
+};
+use crate::rate_limit::Budget;
+use crate::retry_policy::RetryPolicy;
+use crate::runtime_config::{RuntimeConfig, SharedRuntimeConfig};
+use crate::server::Settings;
+use crate::spam::SpamThresholds;
+use crate::storage::object_store::{ObjectStore, ObjectStoreOptions};
+use crate::webhook::{WebhookEventType, WebhookOptions, Webhooks};
+use arc_swap::ArcSwap;
+use clap::Parser;
+use futures_util::future::BoxFuture;
+use scylla::statement::Consistency;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{filter::LevelFilter, prelude::*, reload, Registry};
+use uuid::Uuid;
 
 pub struct Init {
-    pub db: Arc<Database>,
-    pub nc: Arc<nats::asynk::Connection>,
-    pub port: u16,
+    pub db: Arc<dyn Storage>,
+    pub message_bus: Arc<dyn MessageBus>,
+    pub hasher: Arc<Hasher>,
+    pub object_store: Option<Arc<ObjectStore>>,
+    pub analytics: Option<Arc<Analytics>>,
+    pub webhooks: Option<Arc<Webhooks>>,
+    pub challenges: Option<Arc<dyn ChallengeVerifier>>,
+    pub presence: Arc<dyn Presence>,
+    pub job_queue: Arc<dyn JobQueue>,
+    pub worker: bool, // from --worker
+    pub listen_addrs: Vec<SocketAddr>,
+    pub listen_uds_path: Option<PathBuf>, // in addition to tcp
     pub access_token_secret: String,
+    pub jwt_validation_config: JWTValidationConfig,
+    pub settings: Settings,
+    pub reloader: Reloader,
+}
+
+// rereads the tunables in RuntimeConfig on SIGHUP without touching live connections. the environment of a running
+// process can't change, so in practice it's the config file and --set flags that matter here
+pub struct Reloader {
+    cli: Cli,
+    runtime: SharedRuntimeConfig,
+    log_level: reload::Handle<LevelFilter, Registry>,
+}
+
+impl Reloader {
+    pub async fn reload_on_hangup(self) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(err) => {
+                error!(
+                    "Failed to listen for SIGHUP, config won't be reloadable: {}",
+                    err
+                );
+
+                return;
+            }
+        };
+
+        while hangups.recv().await.is_some() {
+            self.reload();
+        }
+    }
+
+    // a config with problems is rejected as a whole and the running one is kept
+    pub fn reload(&self) {
+        let config = Config::load(&self.cli);
+
+        let runtime = Init::runtime_config(&config);
+
+        if let Err(err) = config.finish() {
+            error!("Not reloading config: {}", err);
+
+            return;
+        }
+
+        if let Err(problem) = runtime.validate() {
+            error!("Not reloading config: {}", problem);
+
+            return;
+        }
+
+        if let Err(err) = self.log_level.reload(runtime.log_level) {
+            error!("Failed to reload log level: {}", err);
+        }
+
+        self.runtime.store(Arc::new(runtime));
+
+        info!("Reloaded config");
+    }
+}
+
+// SIGTERM is what orchestrators send, SIGINT is ctrl-c when running it by hand
+pub async fn shutdown_signal() {
+    let mut terminates = match signal(SignalKind::terminate()) {
+        Ok(terminates) => terminates,
+        Err(err) => {
+            error!(
+                "Failed to listen for SIGTERM, won't shut down gracefully: {}",
+                err
+            );
+
+            return std::future::pending().await;
+        }
+    };
+
+    tokio::select! {
+        _ = terminates.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
 }
 
 impl Init {
     pub async fn init() -> Self {
-        dotenv::dotenv().expect("Failed to load .env");
+        let cli = Cli::parse();
+
+        // needs no config at all, not even a .env
+        if cli.dump_schema {
+            println!("{}", schema::dump());
+
+            std::process::exit(0);
+        }
+
+        if cli.dev {
+            let _ = dotenv::dotenv(); // optional in dev mode
+        } else {
+            dotenv::dotenv().expect("Failed to load .env");
+        }
+
+        let mut config = Config::load(&cli);
+
+        if cli.dev {
+            Self::set_dev_defaults(&mut config);
+        }
+
+        let runtime = Self::runtime_config(&config);
+
+        // the level only filters what's printed, tokio-console still sees everything
+        let (log_level, log_level_handle) = reload::Layer::new(runtime.log_level);
+
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_subscriber::fmt::layer().with_filter(log_level));
 
-        tracing_subscriber::fmt::init();
+        #[cfg(feature = "console")]
+        subscriber.with(console_subscriber::spawn()).init();
 
-        let db = Database::build(
-            &env::var("SCYLLA_URL").expect("Must set SCYLLA_URL environment variable"),
-            &env::var("SCYLLA_USERNAME").expect("Must set SCYLLA_USERNAME environment variable"),
-            &env::var("SCYLLA_PASSWORD").expect("Must set SCYLLA_PASSWORD environment variable"),
-            "zap",
-        )
-        .await
-        .expect("Failed to connect to scylla cluster");
+        #[cfg(not(feature = "console"))]
+        subscriber.init();
 
-        let nc = nats::asynk::Options::with_credentials(
-            env::var("NATS_CRED_PATH").expect("Must set NATS_CRED_PATH environment variable"),
-        )
-        .connect(env::var("NATS_URL").expect("Must set NATS_URL environment variable"))
-        .await
-        .expect("Failed to connect to nats server");
+        // everything is read before connecting to anything, so a bad config fails fast and all at once
+        let connect = (!cli.dev).then(|| (Self::storage(&config), Self::message_bus(&config)));
+        let presence = Self::presence(&config);
+        let job_queue = Self::job_queue(&config);
 
-        env::var("CONVERSATION_ID_SECRET")
-            .expect("Must set CONVERSATION_ID_SECRET environment variable");
+        let hasher = Arc::new(Hasher::new(
+            config.required("CONVERSATION_ID_SECRET"),
+            if config.or("USERNAME_HASH_MD5", false) {
+                HashAlgorithm::Md5
+            } else {
+                HashAlgorithm::HmacSha256
+            },
+            match config.get("USERNAME_HASH_ENCODING") {
+                Some("base64url") | None => HashEncoding::Base64Url,
+                Some("base64") => HashEncoding::Base64,
+                Some(_) => {
+                    config.problem("USERNAME_HASH_ENCODING must be base64url or base64".to_owned());
+
+                    HashEncoding::Base64Url
+                }
+            },
+            // while ids and subjects from before the switch to hmac base64url are still around
+            config.or("USERNAME_HASH_ACCEPT_LEGACY", false),
+        ));
+
+        let object_store = Self::object_store(&config);
+        let analytics = Self::analytics(&config);
+        let webhooks = Self::webhooks(&config);
+        let challenges = Self::challenges(&config);
+
+        let apns = Self::apns(&config);
+        let fcm = Self::fcm(&config);
+
+        let listen_addrs = Self::listen_addrs(&config);
+        let listen_uds_path = config.get("LISTEN_UDS_PATH").map(PathBuf::from);
+        let access_token_secret = config.required("ACCESS_TOKEN_SECRET");
+
+        let jwt_validation_config = JWTValidationConfig {
+            issuer: config.get("JWT_ISSUER").map(str::to_owned),
+            audience: config.get("JWT_AUDIENCE").map(str::to_owned),
+            leeway: Duration::from_secs(config.or("JWT_LEEWAY_SECONDS", 60)),
+            max_token_age: config
+                .optional("JWT_MAX_AGE_SECONDS")
+                .map(Duration::from_secs),
+        };
+
+        let settings = Settings {
+            runtime: Arc::new(ArcSwap::from_pointee(runtime)),
+            spam_thresholds: SpamThresholds {
+                burst_window: Duration::from_secs(config.or("SPAM_BURST_WINDOW_SECONDS", 10)),
+                burst_limit: config.or("SPAM_BURST_LIMIT", 30),
+                duplicate_sample: config.or("SPAM_DUPLICATE_SAMPLE", 10),
+                duplicate_ratio: config.or("SPAM_DUPLICATE_RATIO", 0.6),
+                distinct_choosees_per_hour: config.or("SPAM_DISTINCT_CHOOSEES_PER_HOUR", 20),
+                mute_after_strikes: config.or("SPAM_MUTE_AFTER_STRIKES", 3),
+                close_after_strikes: config.or("SPAM_CLOSE_AFTER_STRIKES", 5),
+                mute_duration: Duration::from_secs(config.or("SPAM_MUTE_SECONDS", 300)),
+                strike_decay: Duration::from_secs(config.or("SPAM_STRIKE_DECAY_SECONDS", 600)),
+            },
+            handshake_limits: HandshakeLimits {
+                handshakes: Budget {
+                    burst: config.or("HANDSHAKE_RATE_LIMIT_BURST", 20.0),
+                    per_second: config.or("HANDSHAKE_RATE_LIMIT_PER_SECOND", 2.0),
+                },
+                auth_failure_limit: config.or("AUTH_FAILURE_LIMIT", 10),
+                auth_failure_window: Duration::from_secs(
+                    config.or("AUTH_FAILURE_WINDOW_SECONDS", 60),
+                ),
+                ban_duration: Duration::from_secs(config.or("IP_BAN_SECONDS", 300)),
+            },
+            // only behind a proxy that sends it on every connection, or clients could say they're anyone
+            proxy_protocol: config.or("PROXY_PROTOCOL", false),
+            max_attachment_size: config.or("MAX_ATTACHMENT_SIZE", 25 << 20),
+            key_distribution_ttl: Duration::from_secs(
+                config.or("KEY_DISTRIBUTION_TTL_SECONDS", 86_400),
+            ),
+            mention_ttl: Duration::from_secs(config.or("MENTION_TTL_SECONDS", 2_592_000)),
+            max_frame_size: config.or("MAX_FRAME_SIZE", 64 << 10),
+            outbox_drain_interval: Duration::from_millis(
+                config.or("OUTBOX_DRAIN_INTERVAL_MS", 5000),
+            ),
+            outbox_max_age: Duration::from_secs(config.or("OUTBOX_MAX_AGE_SECONDS", 3600)),
+            operation_concurrency: config.or("OPERATION_CONCURRENCY", 16),
+            operation_queue_limit: config.or("OPERATION_QUEUE_LIMIT", 64),
+            database_timeout: Duration::from_millis(config.or("DATABASE_TIMEOUT_MS", 5000)),
+            nats_timeout: Duration::from_millis(config.or("NATS_TIMEOUT_MS", 2000)),
+            nats_outage_limit: Duration::from_millis(config.or("NATS_OUTAGE_LIMIT_MS", 30_000)),
+            nats_publish_max_attempts: config.or("NATS_PUBLISH_MAX_ATTEMPTS", 4),
+            send_timeout: Duration::from_millis(config.or("SLOW_CONSUMER_TIMEOUT_MS", 10_000)),
+            shutdown_grace: Duration::from_secs(config.or("SHUTDOWN_GRACE_SECONDS", 10)),
+            identify_deadline: Duration::from_millis(config.or("IDENTIFY_DEADLINE_MS", 5000)),
+            push_providers: Arc::new(PushProviders {
+                apns: None,
+                fcm: None,
+            }),
+            push_worker: config.or("PUSH_WORKER", false),
+            health_port: Some(config.or("HEALTH_PORT", 8081)),
+            grpc_port: config.optional("GRPC_PORT"),
+            node_id: config
+                .get("NODE_ID")
+                .map_or_else(|| Uuid::new_v4().to_string(), str::to_owned),
+            announce_interval: Duration::from_millis(config.or("ANNOUNCE_INTERVAL_MS", 10_000)),
+            publish_audit_log: config.or("AUDIT_LOG_PUBLISH", false),
+            job_workers: config.or("JOB_WORKERS", 4),
+            job_retry_policy: RetryPolicy {
+                max_attempts: config.or("JOB_MAX_ATTEMPTS", 5),
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(300),
+            },
+            presence_timeout_interval: Duration::from_secs(
+                config.or("PRESENCE_TIMEOUT_INTERVAL_SECONDS", 60),
+            ),
+            conversation_retention: config
+                .optional("CONVERSATION_RETENTION_SECONDS")
+                .map(Duration::from_secs),
+            retention_sweep_interval: Duration::from_secs(
+                config.or("RETENTION_SWEEP_INTERVAL_SECONDS", 3600),
+            ),
+            daily_digest: config.or("DAILY_DIGEST", false),
+        };
+
+        if let Err(err) = config.finish() {
+            panic!("{}", err);
+        }
+
+        let (db, message_bus): (Arc<dyn Storage>, Arc<dyn MessageBus>) = match connect {
+            Some((storage, message_bus)) => (storage.await, message_bus.await),
+            None => {
+                warn!("Running in dev mode, storage and the message bus are in memory and nothing is persisted");
+
+                (
+                    Arc::new(db::MemoryStorage::default()),
+                    Arc::new(message_bus::MemoryBus::default()),
+                )
+            }
+        };
+
+        let worker = cli.worker;
+
+        let reloader = Reloader {
+            runtime: settings.runtime.clone(),
+            log_level: log_level_handle,
+            cli,
+        };
 
         Self {
-            db: Arc::new(db),
-            nc: Arc::new(nc),
-            port: env::var("PORT")
-                .expect("Must set PORT environment variable")
-                .parse()
-                .expect("PORT environment variable could not be parsed to integer"),
-            access_token_secret: env::var("ACCESS_TOKEN_SECRET")
-                .expect("Must set ACCESS_TOKEN_SECRET environment variable"),
+            db,
+            message_bus,
+            hasher,
+            object_store,
+            analytics,
+            webhooks,
+            challenges,
+            presence: presence.await,
+            job_queue: job_queue.await,
+            worker,
+            listen_addrs,
+            listen_uds_path,
+            access_token_secret,
+            jwt_validation_config,
+            settings: Settings {
+                push_providers: Arc::new(PushProviders {
+                    apns: apns.map(|options| {
+                        ApnsClient::new(options).expect("Failed to create apns client")
+                    }),
+                    fcm: fcm.map(|options| {
+                        FcmClient::new(options).expect("Failed to create fcm client")
+                    }),
+                }),
+                ..settings
+            },
+            reloader,
+        }
+    }
+
+    // everything in RuntimeConfig, the settings that can be reloaded
+    fn runtime_config(config: &Config) -> RuntimeConfig {
+        let defaults = RuntimeConfig::default();
+
+        // tokio's intervals don't take a zero period
+        let heartbeat_interval_ms = config.or("HEARTBEAT_INTERVAL_MS", 30_000);
+
+        if heartbeat_interval_ms == 0 {
+            config.problem("HEARTBEAT_INTERVAL_MS must be above 0".to_owned());
+        }
+
+        RuntimeConfig {
+            send_budget: Self::budget(config, "SEND", defaults.send_budget),
+            choose_budget: Self::budget(config, "CHOOSE", defaults.choose_budget),
+            query_budget: Self::budget(config, "QUERY", defaults.query_budget),
+            bot_send_budget: Self::budget(config, "BOT_SEND", defaults.bot_send_budget),
+            bot_choose_budget: Self::budget(config, "BOT_CHOOSE", defaults.bot_choose_budget),
+            bot_query_budget: Self::budget(config, "BOT_QUERY", defaults.bot_query_budget),
+            max_content_length: config.or("MAX_CONTENT_LENGTH", 4096),
+            heartbeat_interval: Duration::from_millis(heartbeat_interval_ms),
+            origin_allowlist: OriginAllowlist::new(
+                config
+                    .get("ALLOWED_ORIGINS")
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(|origin| origin.to_owned()),
+            ),
+            log_level: config.or("LOG_LEVEL", LevelFilter::INFO),
         }
     }
+
+    // <NAME>_RATE_LIMIT_BURST and <NAME>_RATE_LIMIT_PER_SECOND. whoever is over budget is told to wait for a token
+    // to refill, which takes forever at 0 per second
+    fn budget(config: &Config, name: &str, default: Budget) -> Budget {
+        let burst_key = format!("{}_RATE_LIMIT_BURST", name);
+        let per_second_key = format!("{}_RATE_LIMIT_PER_SECOND", name);
+
+        let budget = Budget {
+            burst: config.or(&burst_key, default.burst),
+            per_second: config.or(&per_second_key, default.per_second),
+        };
+
+        if !(budget.burst.is_finite() && budget.burst >= 0.0) {
+            config.problem(format!("{} must be 0 or more", burst_key));
+        }
+
+        if !(budget.per_second.is_finite() && budget.per_second > 0.0) {
+            config.problem(format!("{} must be above 0", per_second_key));
+        }
+
+        budget
+    }
+
+    // HOST takes any address, like 0.0.0.0 or ::. LISTEN takes several host:port pairs separated by commas instead,
+    // like 0.0.0.0:8080,[::]:8080, and wins over HOST and PORT. port 0 binds any free port, the one picked is logged
+    fn listen_addrs(config: &Config) -> Vec<SocketAddr> {
+        match config.get("LISTEN") {
+            Some(listen) => listen
+                .split(',')
+                .filter_map(|addr| match addr.trim().parse() {
+                    Ok(addr) => Some(addr),
+                    Err(_) => {
+                        config.invalid("LISTEN", addr);
+
+                        None
+                    }
+                })
+                .collect(),
+            None => vec![SocketAddr::new(
+                config.or("HOST", IpAddr::from([127, 0, 0, 1])),
+                config.required("PORT"),
+            )],
+        }
+    }
+
+    // PRESENCE picks where which node each user is on is kept. memory only knows about this node, so running more than
+    // one needs nats
+    fn presence(config: &Config) -> BoxFuture<'static, Arc<dyn Presence>> {
+        let ttl = Duration::from_secs(config.or("PRESENCE_TTL_SECONDS", 90));
+
+        match config.get("PRESENCE").unwrap_or("memory") {
+            "memory" => {
+                Box::pin(async move { Arc::new(MemoryPresence::new(ttl)) as Arc<dyn Presence> })
+            }
+            "nats" => {
+                let cred_path: String = config.required("NATS_CRED_PATH");
+                let url: String = config.required("NATS_URL");
+                let bucket = config.or("PRESENCE_BUCKET", "presence".to_owned());
+
+                Box::pin(async move {
+                    Arc::new(
+                        NatsKvPresence::connect(cred_path, url, bucket, ttl)
+                            .await
+                            .expect("Failed to connect to nats key value"),
+                    ) as Arc<dyn Presence>
+                })
+            }
+            presence => {
+                config.problem(format!("Unsupported PRESENCE: {}", presence));
+
+                Box::pin(async { unreachable!("config problems are reported before connecting") })
+            }
+        }
+    }
+
+    // JOB_QUEUE picks where background jobs wait for a worker. memory only hands them to workers in this process, so
+    // with more than one node, or workers running on their own with --worker, it needs to be nats
+    fn job_queue(config: &Config) -> BoxFuture<'static, Arc<dyn JobQueue>> {
+        match config.get("JOB_QUEUE").unwrap_or("memory") {
+            "memory" => {
+                Box::pin(async { Arc::new(MemoryJobQueue::default()) as Arc<dyn JobQueue> })
+            }
+            "nats" => {
+                let cred_path: String = config.required("NATS_CRED_PATH");
+                let url: String = config.required("NATS_URL");
+                let stream = config.or("JOB_STREAM", "jobs".to_owned());
+                // has to be longer than any job takes, or it's handed to another worker while still running
+                let ack_wait = Duration::from_secs(config.or("JOB_ACK_WAIT_SECONDS", 300));
+
+                Box::pin(async move {
+                    Arc::new(
+                        JetStreamJobQueue::connect(cred_path, url, stream, ack_wait)
+                            .await
+                            .expect("Failed to connect to jetstream"),
+                    ) as Arc<dyn JobQueue>
+                })
+            }
+            job_queue => {
+                config.problem(format!("Unsupported JOB_QUEUE: {}", job_queue));
+
+                Box::pin(async { unreachable!("config problems are reported before connecting") })
+            }
+        }
+    }
+
+    // --dev runs without scylla, nats or any config, for trying a client against locally
+    fn set_dev_defaults(config: &mut Config) {
+        for (key, value) in [
+            ("PORT", "8080"),
+            ("ACCESS_TOKEN_SECRET", "dev"),
+            ("CONVERSATION_ID_SECRET", "dev"),
+        ] {
+            config.set_default(key, value);
+        }
+    }
+
+    // uploads are turned off unless a bucket is set
+    fn object_store(config: &Config) -> Option<Arc<ObjectStore>> {
+        let bucket = config.get("OBJECT_STORE_BUCKET")?.to_owned();
+
+        Some(Arc::new(ObjectStore::new(ObjectStoreOptions {
+            endpoint: config.required_with("OBJECT_STORE_ENDPOINT", "OBJECT_STORE_BUCKET"),
+            region: config.or("OBJECT_STORE_REGION", "us-east-1".to_owned()),
+            bucket,
+            access_key_id: config
+                .required_with("OBJECT_STORE_ACCESS_KEY_ID", "OBJECT_STORE_BUCKET"),
+            secret_access_key: config
+                .required_with("OBJECT_STORE_SECRET_ACCESS_KEY", "OBJECT_STORE_BUCKET"),
+            upload_expiry: Duration::from_secs(
+                config.or("OBJECT_STORE_UPLOAD_EXPIRY_SECONDS", 900),
+            ),
+        })))
+    }
+
+    // stored messages and chooses are only mirrored to kafka when ANALYTICS_BROKERS is set, which needs the kafka
+    // feature
+    fn analytics(config: &Config) -> Option<Arc<Analytics>> {
+        let brokers = config.get("ANALYTICS_BROKERS")?;
+        let topic = config.or("ANALYTICS_TOPIC", "realtime-analytics".to_owned());
+        let include_content = config.or("ANALYTICS_INCLUDE_CONTENT", false);
+
+        #[cfg(feature = "kafka")]
+        match crate::analytics::KafkaSink::connect(brokers, &topic) {
+            Ok(sink) => Some(Arc::new(Analytics::new(Arc::new(sink), include_content))),
+            Err(err) => {
+                config.problem(format!("ANALYTICS_BROKERS could not be used: {}", err));
+
+                None
+            }
+        }
+
+        #[cfg(not(feature = "kafka"))]
+        {
+            let _ = (brokers, topic, include_content);
+
+            config.problem("ANALYTICS_BROKERS is set but kafka wasn't compiled in, build with the kafka feature".to_owned());
+
+            None
+        }
+    }
+
+    // webhooks are only sent when WEBHOOK_URLS is set. WEBHOOK_EVENTS picks which event types, all of them unless set
+    fn webhooks(config: &Config) -> Option<Arc<Webhooks>> {
+        let urls = config
+            .get("WEBHOOK_URLS")?
+            .split(',')
+            .map(|url| url.trim().to_owned())
+            .collect::<Vec<_>>();
+
+        for url in urls.iter() {
+            if !url.starts_with("https://") {
+                config.problem(format!("WEBHOOK_URLS has to be https, got {}", url));
+            }
+        }
+
+        let event_types = match config.get("WEBHOOK_EVENTS") {
+            Some(event_types) => event_types
+                .split(',')
+                .filter_map(|event_type| event_type.parse().map_err(|err| config.problem(err)).ok())
+                .collect(),
+            None => vec![
+                WebhookEventType::ConversationCreated,
+                WebhookEventType::ReportFiled,
+            ],
+        };
+
+        Some(Arc::new(Webhooks::new(WebhookOptions {
+            urls,
+            secret: config.required_with("WEBHOOK_SECRET", "WEBHOOK_URLS"),
+            event_types,
+            timeout: Duration::from_millis(config.or("WEBHOOK_TIMEOUT_MS", 10_000)),
+            retry_policy: RetryPolicy {
+                max_attempts: config.or("WEBHOOK_MAX_ATTEMPTS", 6),
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(60),
+            },
+        })))
+    }
+
+    // nobody is challenged unless CHALLENGE_VERIFY_URL is set, which is meant for the length of an abuse wave
+    fn challenges(config: &Config) -> Option<Arc<dyn ChallengeVerifier>> {
+        let url = config.get("CHALLENGE_VERIFY_URL")?.to_owned();
+
+        if !url.starts_with("https://") {
+            config.problem(format!("CHALLENGE_VERIFY_URL has to be https, got {}", url));
+        }
+
+        Some(Arc::new(SiteVerifyChallenges::new(SiteVerifyOptions {
+            url,
+            secret: config.required_with("CHALLENGE_SECRET", "CHALLENGE_VERIFY_URL"),
+            site_key: config.required_with("CHALLENGE_SITE_KEY", "CHALLENGE_VERIFY_URL"),
+            timeout: Duration::from_millis(config.or("CHALLENGE_TIMEOUT_MS", 5_000)),
+            pass_duration: Duration::from_secs(config.or("CHALLENGE_PASS_SECONDS", 86_400)),
+        })))
+    }
+
+    // push notifications to ios are turned off unless a key is set
+    fn apns(config: &Config) -> Option<ApnsOptions> {
+        let key_path = config.get("APNS_KEY_PATH")?;
+
+        Some(ApnsOptions {
+            key_pem: std::fs::read_to_string(key_path).unwrap_or_else(|err| {
+                config.problem(format!("APNS_KEY_PATH could not be read: {}", err));
+
+                String::new()
+            }),
+            key_id: config.required_with("APNS_KEY_ID", "APNS_KEY_PATH"),
+            team_id: config.required_with("APNS_TEAM_ID", "APNS_KEY_PATH"),
+            topic: config.required_with("APNS_TOPIC", "APNS_KEY_PATH"),
+            sandbox: config.or("APNS_SANDBOX", false),
+        })
+    }
+
+    // and to android unless a service account is set
+    fn fcm(config: &Config) -> Option<FcmOptions> {
+        let service_account_path = config.get("FCM_SERVICE_ACCOUNT_PATH")?;
+
+        Some(FcmOptions {
+            service_account_json: std::fs::read_to_string(service_account_path).unwrap_or_else(
+                |err| {
+                    config.problem(format!(
+                        "FCM_SERVICE_ACCOUNT_PATH could not be read: {}",
+                        err
+                    ));
+
+                    String::new()
+                },
+            ),
+            data_messages: config.or("FCM_DATA_MESSAGES", false),
+            retry_policy: RetryPolicy {
+                max_attempts: config.or("FCM_MAX_ATTEMPTS", 3),
+                base_delay: Duration::from_millis(config.or("FCM_RETRY_BASE_DELAY_MS", 500)),
+                max_delay: Duration::from_millis(config.or("FCM_RETRY_MAX_DELAY_MS", 10_000)),
+            },
+        })
+    }
+
+    // message content is stored in plaintext unless CONTENT_ENCRYPTION_KEYS is set, as comma separated id:key pairs
+    // of base64 aes-256 keys. CONTENT_ENCRYPTION_KEYS_PATH reads the same from a file instead, for keys written out by
+    // a kms or secrets manager agent. new messages are encrypted with CONTENT_ENCRYPTION_KEY_ID, the rest are there
+    // so messages from before a rotation can still be read
+    fn content_keys(config: &Config) -> Option<db::ContentKeys> {
+        let keys = match config.get("CONTENT_ENCRYPTION_KEYS_PATH") {
+            Some(path) => match std::fs::read_to_string(path) {
+                Ok(keys) => keys.replace('\n', ","),
+                Err(err) => {
+                    config.problem(format!(
+                        "CONTENT_ENCRYPTION_KEYS_PATH could not be read: {}",
+                        err
+                    ));
+
+                    return None;
+                }
+            },
+            None => config.get("CONTENT_ENCRYPTION_KEYS")?.to_owned(),
+        };
+
+        let current = config.required_with("CONTENT_ENCRYPTION_KEY_ID", "CONTENT_ENCRYPTION_KEYS");
+
+        db::ContentKeys::parse(current, &keys)
+            .map_err(|err| config.problem(format!("CONTENT_ENCRYPTION_KEYS: {}", err)))
+            .ok()
+    }
+
+    // STORAGE picks the backend, scylla unless set. postgres has to be compiled in with its cargo feature
+    fn storage(config: &Config) -> BoxFuture<'static, Arc<dyn Storage>> {
+        #[cfg(not(feature = "scylla-tls"))]
+        if config.is_set("SCYLLA_CA_CERT") {
+            config.problem("SCYLLA_CA_CERT is set but scylla tls wasn't compiled in, build with the scylla-tls feature".to_owned());
+        }
+
+        match config.get("STORAGE").unwrap_or("scylla") {
+            "scylla" => {
+                let session = db::SessionOptions {
+                    known_nodes: config
+                        .required::<String>("SCYLLA_URL")
+                        .split(',')
+                        .map(|node| node.trim().to_owned())
+                        .collect(),
+                    username: config.required("SCYLLA_USERNAME"),
+                    password: config.required("SCYLLA_PASSWORD"),
+                    #[cfg(feature = "scylla-tls")]
+                    tls: config.get("SCYLLA_CA_CERT").map(|ca_cert| db::TlsOptions {
+                        ca_cert: ca_cert.into(),
+                        client_cert: config.get("SCYLLA_CLIENT_CERT").map(|cert| {
+                            (
+                                cert.into(),
+                                config
+                                    .required_with::<String>(
+                                        "SCYLLA_CLIENT_KEY",
+                                        "SCYLLA_CLIENT_CERT",
+                                    )
+                                    .into(),
+                            )
+                        }),
+                    }),
+                    connections_per_shard: config.optional("SCYLLA_CONNECTIONS_PER_SHARD"),
+                    connection_timeout: Duration::from_millis(
+                        config.or("SCYLLA_CONNECTION_TIMEOUT_MS", 5000),
+                    ),
+                    request_timeout: Some(Duration::from_millis(
+                        config.or("SCYLLA_REQUEST_TIMEOUT_MS", 30_000),
+                    )),
+                    local_datacenter: config.get("SCYLLA_LOCAL_DATACENTER").map(str::to_owned),
+                    include_remote_nodes: config.or("SCYLLA_INCLUDE_REMOTE_NODES", true),
+                    token_aware: config.or("SCYLLA_TOKEN_AWARE", true),
+                    retry_policy: RetryPolicy {
+                        max_attempts: config.or("SCYLLA_RETRY_MAX_ATTEMPTS", 3u32).max(1),
+                        base_delay: Duration::from_millis(
+                            config.or("SCYLLA_RETRY_BASE_DELAY_MS", 20),
+                        ),
+                        max_delay: Duration::from_millis(
+                            config.or("SCYLLA_RETRY_MAX_DELAY_MS", 500),
+                        ),
+                    },
+                    retry_budget_ratio: config.or("SCYLLA_RETRY_BUDGET_RATIO", 0.1),
+                    retry_budget_capacity: config.or("SCYLLA_RETRY_BUDGET_CAPACITY", 100.0),
+                };
+
+                let keyspace = db::KeyspaceOptions {
+                    name: config.or("SCYLLA_KEYSPACE", "zap".to_owned()),
+                    replication: Self::replication(config),
+                    create: config.or("SCYLLA_CREATE_KEYSPACE", true),
+                };
+
+                let consistencies = db::Consistencies {
+                    writes: consistency(
+                        config,
+                        "SCYLLA_WRITE_CONSISTENCY",
+                        Consistency::LocalQuorum,
+                    ),
+                    history_reads: consistency(
+                        config,
+                        "SCYLLA_HISTORY_READ_CONSISTENCY",
+                        Consistency::LocalOne,
+                    ),
+                    reads: consistency(config, "SCYLLA_READ_CONSISTENCY", Consistency::LocalQuorum),
+                };
+
+                // 0 writes every message on its own
+                let message_coalescing = match config.or("SCYLLA_MESSAGE_FLUSH_INTERVAL_MS", 2) {
+                    0 => None,
+                    flush_interval => Some(db::CoalescerOptions {
+                        flush_interval: Duration::from_millis(flush_interval),
+                        max_batch_size: config.or("SCYLLA_MESSAGE_MAX_BATCH_SIZE", 32usize).max(1),
+                    }),
+                };
+
+                // 0 keeps messages forever
+                let message_retention = match config.or("MESSAGE_RETENTION_SECONDS", 0) {
+                    0 => None,
+                    retention => Some(Duration::from_secs(retention)),
+                };
+
+                let content_keys = Self::content_keys(config);
+
+                Box::pin(async move {
+                    Arc::new(
+                        db::ScyllaStorage::build(
+                            &session,
+                            &keyspace,
+                            &consistencies,
+                            message_coalescing,
+                            message_retention,
+                            content_keys,
+                        )
+                        .await
+                        .expect("Failed to connect to scylla cluster"),
+                    ) as Arc<dyn Storage>
+                })
+            }
+            #[cfg(feature = "postgres")]
+            "postgres" => {
+                if Self::content_keys(config).is_some() {
+                    config.problem(
+                        "Content encryption is only supported with scylla storage".to_owned(),
+                    );
+                }
+
+                let database_url: String = config.required("DATABASE_URL");
+
+                Box::pin(async move {
+                    Arc::new(
+                        db::PostgresStorage::connect(&database_url)
+                            .await
+                            .expect("Failed to connect to postgres"),
+                    ) as Arc<dyn Storage>
+                })
+            }
+            storage => {
+                config.problem(format!("Unsupported STORAGE: {}", storage));
+
+                Box::pin(async { unreachable!("config problems are reported before connecting") })
+            }
+        }
+    }
+
+    // SimpleStrategy takes SCYLLA_REPLICATION_FACTOR as a number, NetworkTopologyStrategy as datacenter:factor pairs
+    // separated by commas, like dc1:3,dc2:3
+    fn replication(config: &Config) -> db::Replication {
+        match config
+            .get("SCYLLA_REPLICATION_STRATEGY")
+            .unwrap_or("SimpleStrategy")
+        {
+            "SimpleStrategy" => db::Replication::Simple {
+                replication_factor: config.or("SCYLLA_REPLICATION_FACTOR", 1),
+            },
+            "NetworkTopologyStrategy" => db::Replication::NetworkTopology {
+                datacenters: config
+                    .required_with::<String>(
+                        "SCYLLA_REPLICATION_FACTOR",
+                        "SCYLLA_REPLICATION_STRATEGY=NetworkTopologyStrategy",
+                    )
+                    .split(',')
+                    .filter_map(|datacenter| {
+                        let parsed = datacenter.split_once(':').and_then(
+                            |(datacenter, replication_factor)| {
+                                Some((
+                                    datacenter.trim().to_owned(),
+                                    replication_factor.trim().parse().ok()?,
+                                ))
+                            },
+                        );
+
+                        if parsed.is_none() {
+                            config.invalid("SCYLLA_REPLICATION_FACTOR", datacenter);
+                        }
+
+                        parsed
+                    })
+                    .collect(),
+            },
+            strategy => {
+                config.problem(format!(
+                    "Unsupported SCYLLA_REPLICATION_STRATEGY: {}",
+                    strategy
+                ));
+
+                db::Replication::Simple {
+                    replication_factor: 1,
+                }
+            }
+        }
+    }
+
+    // MESSAGE_BUS picks the backend, nats unless set. redis and kafka have to be compiled in with their cargo features
+    fn message_bus(config: &Config) -> BoxFuture<'static, Arc<dyn MessageBus>> {
+        match config.get("MESSAGE_BUS").unwrap_or("nats") {
+            "nats" => {
+                let cred_path: String = config.required("NATS_CRED_PATH");
+                let url: String = config.required("NATS_URL");
+                let reconnect_buffer_size = config.or("NATS_RECONNECT_BUFFER_BYTES", 8 << 20);
+
+                Box::pin(async move {
+                    let nc = nats::asynk::Options::with_credentials(cred_path)
+                        .max_reconnects(None) // connections give up on their own after NATS_OUTAGE_LIMIT_MS instead
+                        .reconnect_buffer_size(reconnect_buffer_size) // publishes made while reconnecting are held here
+                        .disconnect_callback(|| warn!("Disconnected from nats"))
+                        .reconnect_callback(|| info!("Reconnected to nats"))
+                        .connect(url)
+                        .await
+                        .expect("Failed to connect to nats server");
+
+                    Arc::new(message_bus::NatsBus::new(nc)) as Arc<dyn MessageBus>
+                })
+            }
+            #[cfg(feature = "redis")]
+            "redis" => {
+                let url: String = config.required("REDIS_URL");
+
+                Box::pin(async move {
+                    Arc::new(
+                        message_bus::RedisBus::connect(&url)
+                            .await
+                            .expect("Failed to connect to redis"),
+                    ) as Arc<dyn MessageBus>
+                })
+            }
+            #[cfg(feature = "kafka")]
+            "kafka" => {
+                let brokers: String = config.required("KAFKA_BROKERS");
+                let topic = config.or("KAFKA_TOPIC", "realtime".to_owned());
+
+                Box::pin(async move {
+                    Arc::new(
+                        message_bus::KafkaBus::connect(&brokers, &topic)
+                            .expect("Failed to connect to kafka"),
+                    ) as Arc<dyn MessageBus>
+                })
+            }
+            message_bus => {
+                config.problem(format!("Unsupported MESSAGE_BUS: {}", message_bus));
+
+                Box::pin(async { unreachable!("config problems are reported before connecting") })
+            }
+        }
+    }
+}
+
+// named the way cql names them, like LOCAL_QUORUM
+fn consistency(config: &Config, key: &str, default: Consistency) -> Consistency {
+    match config.get(key) {
+        Some(value) => match value.to_uppercase().as_str() {
+            "ANY" => Consistency::Any,
+            "ONE" => Consistency::One,
+            "TWO" => Consistency::Two,
+            "THREE" => Consistency::Three,
+            "QUORUM" => Consistency::Quorum,
+            "ALL" => Consistency::All,
+            "LOCAL_QUORUM" => Consistency::LocalQuorum,
+            "EACH_QUORUM" => Consistency::EachQuorum,
+            "LOCAL_ONE" => Consistency::LocalOne,
+            _ => {
+                config.invalid(key, value);
+
+                default
+            }
+        },
+        None => default,
+    }
 }
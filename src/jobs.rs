@@ -0,0 +1,279 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures_util::FutureExt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+use crate::db::{DatabaseError, Storage};
+use crate::message_bus::MessageBusError;
+use crate::models::job_status::{JobState, JobStatus};
+use crate::retry_policy::RetryPolicy;
+
+mod jetstream;
+mod memory;
+
+pub use self::jetstream::JetStreamJobQueue;
+pub use self::memory::MemoryJobQueue;
+
+// work that outlives whatever asked for it, like purging a deleted account. anything can enqueue a job, and workers
+// take them off a queue shared by the whole cluster, so each job is handled by one worker no matter how many nodes
+// there are
+//
+// workers run inside every gateway unless JOB_WORKERS is 0, or in processes of their own started with --worker. a job
+// that fails is retried with backoff until it runs out of attempts. every change of a job's state is written to the
+// job_status table, operators can look through it with cqlsh
+//
+// a worker dying partway through a job means it runs again, so handlers have to be safe to run twice
+
+const NEXT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Job {
+    pub job_id: String,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub enqueued_at: DateTime<Utc>,
+}
+
+impl Job {
+    pub fn new(kind: &str, payload: impl Serialize) -> Self {
+        Self {
+            job_id: Uuid::new_v4().to_string(),
+            kind: kind.to_owned(),
+            payload: serde_json::to_value(payload).expect("Job payload should serialize"),
+            enqueued_at: Utc::now(),
+        }
+    }
+
+    // the same id for every node enqueueing it for the same period, so the queue can drop all but the first. periodic
+    // jobs have no payload, and only show up in job_status once a worker picks them up
+    pub fn periodic(kind: &str, period_start: DateTime<Utc>) -> Self {
+        Self {
+            job_id: format!("{}-{}", kind, period_start.timestamp()),
+            kind: kind.to_owned(),
+            payload: serde_json::Value::Null,
+            enqueued_at: Utc::now(),
+        }
+    }
+
+    // the same id for every job enqueued with the key, so the queue drops copies enqueued shortly after the first
+    pub fn keyed(kind: &str, key: &str, payload: impl Serialize) -> Self {
+        Self {
+            job_id: format!("{}-{}", kind, key),
+            kind: kind.to_owned(),
+            payload: serde_json::to_value(payload).expect("Job payload should serialize"),
+            enqueued_at: Utc::now(),
+        }
+    }
+
+    pub fn payload<T: DeserializeOwned>(&self) -> Result<T, JobError> {
+        Ok(serde_json::from_value(self.payload.clone())?)
+    }
+}
+
+// a job a worker took off the queue, which has to be acked, retried or discarded once it's handled
+pub struct Delivery {
+    pub job: Job,
+    pub attempt: u32, // 1 the first time
+    receipt: Receipt,
+}
+
+// what the queue needs back to settle the delivery
+enum Receipt {
+    Memory,
+    JetStream(nats::Message),
+}
+
+#[async_trait]
+pub trait JobQueue: Send + Sync {
+    // a job with the same id as one enqueued shortly before may be dropped
+    async fn enqueue(&self, job: &Job) -> Result<(), MessageBusError>;
+
+    // waits until there's a job
+    async fn next(&self) -> Result<Delivery, MessageBusError>;
+
+    async fn ack(&self, delivery: Delivery) -> Result<(), MessageBusError>;
+
+    // hands the job out again once the delay is up
+    async fn retry(&self, delivery: Delivery, delay: Duration) -> Result<(), MessageBusError>;
+
+    // gives up on the job, it won't be handed out again
+    async fn discard(&self, delivery: Delivery) -> Result<(), MessageBusError>;
+}
+
+#[async_trait]
+pub trait JobHandler: Send + Sync {
+    async fn handle(&self, job: &Job) -> Result<(), JobError>;
+}
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("Invalid job payload: {0}")]
+    Payload(#[from] serde_json::Error),
+    #[error("{0}")]
+    DatabaseError(#[from] DatabaseError),
+    #[error("{0}")]
+    MessageBusError(#[from] MessageBusError),
+}
+
+// stored as queued before it's enqueued, so a job shows up in job_status even if no worker ever gets to it
+pub async fn enqueue(queue: &dyn JobQueue, db: &dyn Storage, job: Job) -> Result<(), JobError> {
+    db.set_job_status(&JobStatus {
+        job_id: job.job_id.clone(),
+        kind: job.kind.clone(),
+        state: JobState::Queued,
+        attempts: 0,
+        last_error: None,
+        enqueued_at: job.enqueued_at,
+        updated_at: Utc::now(),
+    })
+    .await?;
+
+    queue.enqueue(&job).await?;
+
+    Ok(())
+}
+
+pub struct Workers {
+    queue: Arc<dyn JobQueue>,
+    db: Arc<dyn Storage>,
+    handlers: HashMap<&'static str, Arc<dyn JobHandler>>,
+    retry_policy: RetryPolicy,
+}
+
+impl Workers {
+    pub fn new(queue: Arc<dyn JobQueue>, db: Arc<dyn Storage>, retry_policy: RetryPolicy) -> Self {
+        Self {
+            queue,
+            db,
+            handlers: HashMap::new(),
+            retry_policy,
+        }
+    }
+
+    pub fn handle(mut self, kind: &'static str, handler: Arc<dyn JobHandler>) -> Self {
+        self.handlers.insert(kind, handler);
+        self
+    }
+
+    // handles up to concurrency jobs at once, never returns
+    pub async fn run(self, concurrency: usize) {
+        let workers = Arc::new(self);
+
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+
+        loop {
+            let permit = semaphore
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Job semaphore should never be closed");
+
+            let delivery = match workers.queue.next().await {
+                Ok(delivery) => delivery,
+                Err(err) => {
+                    warn!("Failed to take the next job: {}", err);
+
+                    tokio::time::sleep(NEXT_RETRY_DELAY).await;
+
+                    continue;
+                }
+            };
+
+            let workers = workers.clone();
+
+            tokio::task::spawn(async move {
+                workers.work(delivery).await;
+
+                drop(permit);
+            });
+        }
+    }
+
+    async fn work(&self, delivery: Delivery) {
+        let Some(handler) = self.handlers.get(delivery.job.kind.as_str()) else {
+            // enqueued by a newer version, or the kind was removed
+            self.discard(delivery, "No handler for this kind of job".to_owned())
+                .await;
+
+            return;
+        };
+
+        self.set_status(&delivery, JobState::Running, None).await;
+
+        let Ok(result) = AssertUnwindSafe(handler.handle(&delivery.job))
+            .catch_unwind()
+            .await
+        else {
+            // not retried, it would most likely panic again
+            self.discard(delivery, "Job panicked".to_owned()).await;
+
+            return;
+        };
+
+        match result {
+            Ok(()) => {
+                self.set_status(&delivery, JobState::Succeeded, None).await;
+
+                if let Err(err) = self.queue.ack(delivery).await {
+                    warn!("Failed to ack job: {}", err);
+                }
+            }
+            Err(err) if delivery.attempt < self.retry_policy.max_attempts => {
+                warn!(
+                    "Job {} of kind {} failed on attempt {}, retrying: {}",
+                    delivery.job.job_id, delivery.job.kind, delivery.attempt, err
+                );
+
+                self.set_status(&delivery, JobState::Queued, Some(err.to_string()))
+                    .await;
+
+                let delay = self.retry_policy.delay(delivery.attempt - 1);
+
+                if let Err(err) = self.queue.retry(delivery, delay).await {
+                    warn!("Failed to retry job: {}", err);
+                }
+            }
+            Err(err) => self.discard(delivery, err.to_string()).await,
+        }
+    }
+
+    async fn discard(&self, delivery: Delivery, reason: String) {
+        error!(
+            "Job {} of kind {} failed for good after {} attempts: {}",
+            delivery.job.job_id, delivery.job.kind, delivery.attempt, reason
+        );
+
+        self.set_status(&delivery, JobState::Failed, Some(reason))
+            .await;
+
+        if let Err(err) = self.queue.discard(delivery).await {
+            warn!("Failed to discard job: {}", err);
+        }
+    }
+
+    // a status that fails to save doesn't stop the job, it's only for looking into them
+    async fn set_status(&self, delivery: &Delivery, state: JobState, last_error: Option<String>) {
+        if let Err(err) = self
+            .db
+            .set_job_status(&JobStatus {
+                job_id: delivery.job.job_id.clone(),
+                kind: delivery.job.kind.clone(),
+                state,
+                attempts: delivery.attempt,
+                last_error,
+                enqueued_at: delivery.job.enqueued_at,
+                updated_at: Utc::now(),
+            })
+            .await
+        {
+            warn!("Failed to save job status: {}", err);
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use async_trait::async_trait;
+use nats::jetstream::{
+    AckKind, AckPolicy, ConsumerConfig, JetStream, PublishOptions, PullSubscribeOptions,
+    PullSubscription, RetentionPolicy, StreamConfig,
+};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Delivery, Job, JobQueue, Receipt};
+use crate::message_bus::MessageBusError;
+
+// a work queue stream, so each job is removed once it's acked and only one consumer can read it. every worker in the
+// cluster pulls from the same durable consumer, which is what hands each job to just one of them. like presence, the
+// nats client only has a blocking api for jetstream, so this keeps a blocking connection of its own
+//
+// a job that isn't acked within ack_wait is handed out again, so it has to be longer than any job takes. jobs are
+// published with their id as the message id, so the stream drops one enqueued again within DUPLICATE_WINDOW
+
+const DURABLE_NAME: &str = "workers";
+
+// longer than periodic jobs are jittered by, so every node enqueueing one for the same period lands in it
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(600);
+
+// how long a pull waits for a job before asking again
+const PULL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct JetStreamJobQueue {
+    jetstream: JetStream,
+    subscription: Arc<PullSubscription>,
+    stream: String,
+}
+
+impl JetStreamJobQueue {
+    pub async fn connect(
+        cred_path: String,
+        url: String,
+        stream: String,
+        ack_wait: Duration,
+    ) -> Result<Self, MessageBusError> {
+        let (jetstream, subscription) = tokio::task::spawn_blocking({
+            let stream = stream.clone();
+
+            move || -> io::Result<_> {
+                let jetstream =
+                    nats::jetstream::new(nats::Options::with_credentials(cred_path).connect(url)?);
+
+                if jetstream.stream_info(&stream).is_err() {
+                    jetstream.add_stream(StreamConfig {
+                        name: stream.clone(),
+                        subjects: vec![format!("{}.>", stream)],
+                        retention: RetentionPolicy::WorkQueue,
+                        duplicate_window: DUPLICATE_WINDOW.as_nanos() as i64,
+                        ..Default::default()
+                    })?;
+                }
+
+                // the client only binds to durable consumers that already exist
+                if jetstream.consumer_info(&stream, DURABLE_NAME).is_err() {
+                    jetstream.add_consumer(
+                        &stream,
+                        ConsumerConfig {
+                            durable_name: Some(DURABLE_NAME.to_owned()),
+                            ack_policy: AckPolicy::Explicit,
+                            ack_wait,
+                            filter_subject: format!("{}.>", stream),
+                            ..Default::default()
+                        },
+                    )?;
+                }
+
+                let subscription = jetstream.pull_subscribe_with_options(
+                    &format!("{}.>", stream),
+                    &PullSubscribeOptions::new()
+                        .bind_stream(stream.clone())
+                        .durable_name(DURABLE_NAME.to_owned()),
+                )?;
+
+                Ok((jetstream, subscription))
+            }
+        })
+        .await
+        .expect("Connecting to jetstream panicked")?;
+
+        Ok(Self {
+            jetstream,
+            subscription: Arc::new(subscription),
+            stream,
+        })
+    }
+
+    async fn blocking<T: Send + 'static>(
+        f: impl FnOnce() -> io::Result<T> + Send + 'static,
+    ) -> Result<T, MessageBusError> {
+        Ok(tokio::task::spawn_blocking(f)
+            .await
+            .expect("Jetstream operation panicked")?)
+    }
+}
+
+fn message(delivery: Delivery) -> nats::Message {
+    match delivery.receipt {
+        Receipt::JetStream(message) => message,
+        Receipt::Memory => unreachable!("Delivery should have come from jetstream"),
+    }
+}
+
+#[async_trait]
+impl JobQueue for JetStreamJobQueue {
+    async fn enqueue(&self, job: &Job) -> Result<(), MessageBusError> {
+        let jetstream = self.jetstream.clone();
+        let subject = format!("{}.{}", self.stream, job.kind);
+        let data = serde_json::to_vec(job).expect("Job should serialize");
+        let options = PublishOptions {
+            id: Some(job.job_id.clone()),
+            ..Default::default()
+        };
+
+        Self::blocking(move || {
+            jetstream
+                .publish_with_options(&subject, data, &options)
+                .map(|_| ())
+        })
+        .await
+    }
+
+    async fn next(&self) -> Result<Delivery, MessageBusError> {
+        loop {
+            let subscription = self.subscription.clone();
+
+            let message = Self::blocking(move || {
+                subscription.request_batch(1)?;
+
+                match subscription.next_timeout(PULL_TIMEOUT) {
+                    Ok(message) => Ok(Some(message)),
+                    Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(None),
+                    Err(err) => Err(err),
+                }
+            })
+            .await?;
+
+            let Some(message) = message else {
+                continue;
+            };
+
+            let attempt = message
+                .jetstream_message_info()
+                .map_or(1, |info| info.delivered as u32);
+
+            match serde_json::from_slice::<Job>(&message.data) {
+                Ok(job) => {
+                    return Ok(Delivery {
+                        job,
+                        attempt,
+                        receipt: Receipt::JetStream(message),
+                    })
+                }
+                Err(err) => {
+                    warn!("Dropping job that couldn't be read: {}", err);
+
+                    Self::blocking(move || message.ack_kind(AckKind::Term)).await?;
+                }
+            }
+        }
+    }
+
+    async fn ack(&self, delivery: Delivery) -> Result<(), MessageBusError> {
+        let message = message(delivery);
+
+        Self::blocking(move || message.ack()).await
+    }
+
+    // this version of the client can't nak with a delay, so the nak waits instead. ack_wait still runs in the
+    // meantime, so a delay longer than it gets the job handed out early
+    async fn retry(&self, delivery: Delivery, delay: Duration) -> Result<(), MessageBusError> {
+        let message = message(delivery);
+
+        tokio::task::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            if let Err(err) = Self::blocking(move || message.ack_kind(AckKind::Nak)).await {
+                warn!("Failed to nak job: {}", err);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn discard(&self, delivery: Delivery) -> Result<(), MessageBusError> {
+        let message = message(delivery);
+
+        Self::blocking(move || message.ack_kind(AckKind::Term)).await
+    }
+}
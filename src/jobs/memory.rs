@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+
+use super::{Delivery, Job, JobQueue, Receipt};
+use crate::message_bus::MessageBusError;
+
+// for --dev and single node deployments. jobs only reach workers in this process and are lost on restart
+
+pub struct MemoryJobQueue {
+    tx: mpsc::UnboundedSender<(Job, u32)>, // with the attempt it'll be handed out as
+    rx: Mutex<mpsc::UnboundedReceiver<(Job, u32)>>,
+}
+
+impl Default for MemoryJobQueue {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        Self {
+            tx,
+            rx: Mutex::new(rx),
+        }
+    }
+}
+
+#[async_trait]
+impl JobQueue for MemoryJobQueue {
+    async fn enqueue(&self, job: &Job) -> Result<(), MessageBusError> {
+        let _ = self.tx.send((job.clone(), 1)); // the receiver lives as long as the sender
+
+        Ok(())
+    }
+
+    async fn next(&self) -> Result<Delivery, MessageBusError> {
+        let (job, attempt) = self
+            .rx
+            .lock()
+            .await
+            .recv()
+            .await
+            .expect("Job queue sender should live as long as the receiver");
+
+        Ok(Delivery {
+            job,
+            attempt,
+            receipt: Receipt::Memory,
+        })
+    }
+
+    async fn ack(&self, _delivery: Delivery) -> Result<(), MessageBusError> {
+        Ok(())
+    }
+
+    async fn retry(&self, delivery: Delivery, delay: Duration) -> Result<(), MessageBusError> {
+        let tx = self.tx.clone();
+
+        tokio::task::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            let _ = tx.send((delivery.job, delivery.attempt + 1));
+        });
+
+        Ok(())
+    }
+
+    async fn discard(&self, _delivery: Delivery) -> Result<(), MessageBusError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+#[macro_use]
+extern crate tracing;
+
+pub use server::{Server, ServerBuilder, ServerError, Settings};
+
+// the parsers the targets in fuzz/ feed arbitrary bytes to. cargo fuzz builds with --cfg fuzzing
+#[cfg(fuzzing)]
+pub mod fuzzing {
+    pub use crate::connection::{user_event::UserEvent, Encoding, Operation};
+    pub use crate::conversation_id::ConversationId;
+}
+
+mod account_deletion;
+pub mod analytics;
+mod audit;
+pub mod auth;
+pub mod challenge;
+mod cluster;
+pub mod config;
+mod connection;
+pub mod conversation_id;
+pub mod db;
+mod dead_letter;
+mod digest;
+mod error;
+mod grpc;
+pub mod handshake_limit;
+pub mod hash;
+mod health;
+pub mod init;
+pub mod jobs;
+mod maintenance;
+pub mod message_bus;
+mod metrics;
+pub mod models;
+pub mod origin;
+mod outbox;
+pub mod presence;
+pub mod push;
+pub mod rate_limit;
+mod rest;
+pub mod retry_policy;
+pub mod runtime_config;
+mod runtime_metrics;
+mod server;
+pub mod spam;
+pub mod storage;
+mod streaks;
+mod transport;
+pub mod webhook;
@@ -1,109 +1,59 @@
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tungstenite::http::{Request, Response, StatusCode};
-extern crate tracing_subscriber;
-#[macro_use]
-extern crate tracing;
-
-use auth::{AccessTokenPayload, JWTAuth};
-use connection::Connection;
-use init::Init;
-
-mod auth;
-mod connection;
-mod conversation_id;
-mod db;
-mod hash;
-mod init;
-mod models;
+use realtime::{
+    auth::JWTAuth,
+    init::{self, Init},
+    Server, ServerError,
+};
 
 // todo - try to eliminated clones and unwraps and make every error logged
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
+async fn main() -> Result<(), ServerError> {
     let Init {
         db,
-        nc,
-        port,
+        message_bus,
+        hasher,
+        object_store,
+        analytics,
+        webhooks,
+        challenges,
+        presence,
+        job_queue,
+        worker,
+        listen_addrs,
+        listen_uds_path,
         access_token_secret,
+        jwt_validation_config,
+        settings,
+        reloader,
     } = Init::init().await;
 
-    let server_addr = SocketAddr::from(([127, 0, 0, 1], port));
-
-    let server = TcpListener::bind(server_addr)
-        .await
-        .expect("Failed to bind");
-
-    info!(
-        "Listening on {}",
-        server
-            .local_addr()
-            .expect("Error getting address server is listening on")
-    );
-
-    let jwt_auth = Arc::new(JWTAuth::new(&access_token_secret));
-
-    loop {
-        let db = db.clone();
-        let nc = nc.clone();
-
-        let jwt_auth = jwt_auth.clone();
-
-        match server.accept().await {
-            Ok((stream, _addr)) => {
-                tokio::task::spawn(async move {
-                    let mut access_token_payload: Option<AccessTokenPayload> = None;
-
-                    match tokio_tungstenite::accept_hdr_async(
-                        stream,
-                        |req: &Request<()>, mut res: Response<()>| {
-                            return match jwt_auth.veryify_req(req) {
-                                Ok(payload) => {
-                                    access_token_payload = Some(payload);
-
-                                    Ok(res)
-                                }
-                                Err(_) => {
-                                    *res.status_mut() = StatusCode::UNAUTHORIZED;
-
-                                    Err(Response::from_parts(
-                                        res.into_parts().0,
-                                        Some("Valid access token required".to_owned()),
-                                    ))
-                                }
-                            };
-                        },
-                    )
-                    .await
-                    {
-                        Ok(websocket) => {
-                            let access_token_payload = access_token_payload.expect("This error should not happen because access_token_payload should be set if websocket handshake is successful");
-
-                            let username = access_token_payload.username.clone();
-
-                            let conn = Connection {
-                                websocket,
-                                db,
-                                nc,
-                                phone_number: access_token_payload.phone_number,
-                                username,
-                            };
+    tokio::task::spawn(reloader.reload_on_hangup());
+
+    let mut builder = Server::builder()
+        .with_storage(db)
+        .with_bus(message_bus)
+        .with_auth(JWTAuth::new(&access_token_secret, jwt_validation_config))
+        .with_hasher(hasher)
+        .with_object_store(object_store)
+        .with_analytics(analytics)
+        .with_webhooks(webhooks)
+        .with_challenges(challenges)
+        .with_presence(presence)
+        .with_job_queue(job_queue)
+        .with_settings(settings)
+        .with_shutdown(init::shutdown_signal());
+
+    if worker {
+        builder = builder.worker();
+    }
 
-                            if let Err(fatal_connection_error) = conn.handle().await {
-                                error!("Error during websocket connection for user with username {}: {}", access_token_payload.username,  fatal_connection_error);
-                            };
-                        }
-                        Err(err) => {
-                            error!("Error during websocket handshake: {}", err);
-                        }
-                    }
-                });
-            }
-            Err(_) => {
-                error!("Error accepting tcp connection");
-                continue;
-            }
-        }
+    if let Some(listen_uds_path) = listen_uds_path {
+        builder = builder.bind_unix(listen_uds_path);
     }
+
+    listen_addrs
+        .into_iter()
+        .fold(builder, |builder, addr| builder.bind(addr))
+        .run()
+        .await
 }
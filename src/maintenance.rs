@@ -0,0 +1,118 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::db::Storage;
+use crate::jobs::{Job, JobError, JobHandler, JobQueue};
+use crate::metrics;
+use crate::presence::Presence;
+
+// upkeep that runs on an interval, as jobs so each run is handled by one worker in the cluster. every node schedules
+// every task, enqueueing a job for each period shortly after it starts. the jobs share an id per period, so the queue
+// drops the copies from all but the first node to get there, and the jitter keeps the nodes from all getting there at
+// the same moment
+//
+// the memory job queue doesn't drop copies, but then there's only one node enqueueing to it
+
+pub const PRESENCE_TIMEOUT_JOB_KIND: &str = "presence_timeout";
+pub const RETENTION_SWEEP_JOB_KIND: &str = "retention_sweep";
+
+// at most a quarter of the interval, and well within the jetstream queue's duplicate window
+const MAX_JITTER: Duration = Duration::from_secs(120);
+
+// conversations deleted per storage call, the sweep keeps going until a call comes back short
+const SWEEP_BATCH: i32 = 500;
+
+// enqueues a job of the kind once per interval, never returns
+pub async fn schedule(job_queue: Arc<dyn JobQueue>, kind: &'static str, interval: Duration) {
+    let interval_ms = (interval.as_millis() as i64).max(1);
+    let max_jitter = (interval / 4).min(MAX_JITTER);
+
+    loop {
+        // periods line up across nodes since they're counted from the epoch
+        let next_period = (Utc::now().timestamp_millis() / interval_ms + 1) * interval_ms;
+
+        let until_next_period =
+            Duration::from_millis((next_period - Utc::now().timestamp_millis()).max(0) as u64);
+
+        let jitter = max_jitter.mul_f64(rand::thread_rng().gen_range(0.0..1.0));
+
+        tokio::time::sleep(until_next_period + jitter).await;
+
+        let period_start = Utc
+            .timestamp_millis_opt(next_period)
+            .single()
+            .expect("Period start should be a valid timestamp");
+
+        if let Err(err) = job_queue.enqueue(&Job::periodic(kind, period_start)).await {
+            warn!("Failed to enqueue {} job: {}", kind, err);
+        }
+    }
+}
+
+// users whose heartbeats stopped without their connection being closed cleanly, like when their node died
+pub struct PresenceTimeout {
+    pub presence: Arc<dyn Presence>,
+}
+
+#[async_trait]
+impl JobHandler for PresenceTimeout {
+    async fn handle(&self, _job: &Job) -> Result<(), JobError> {
+        let expired = self.presence.expire().await?;
+
+        metrics::PRESENCE_TIMEOUT_RUNS.increment();
+        metrics::PRESENCE_TIMEOUT_REMOVED.add(expired as u64);
+
+        debug!("Expired {} presence entries", expired);
+
+        Ok(())
+    }
+}
+
+// conversations roll over every hour, so old ones are never written to again. deleting one takes its messages,
+// pins and choosee presence with it
+pub struct RetentionSweep {
+    pub db: Arc<dyn Storage>,
+    pub retention: Duration,
+}
+
+#[async_trait]
+impl JobHandler for RetentionSweep {
+    async fn handle(&self, _job: &Job) -> Result<(), JobError> {
+        let started_at = Instant::now();
+
+        let cutoff = Utc::now()
+            - chrono::Duration::from_std(self.retention)
+                .expect("Conversation retention should be in range");
+
+        let mut deleted = 0;
+
+        loop {
+            let batch = self
+                .db
+                .delete_conversations_before(cutoff, SWEEP_BATCH)
+                .await?;
+
+            deleted += batch;
+
+            metrics::RETENTION_SWEEP_REMOVED.add(batch as u64);
+
+            if batch < SWEEP_BATCH as usize {
+                break;
+            }
+        }
+
+        metrics::RETENTION_SWEEP_RUNS.increment();
+
+        info!(
+            "Deleted {} conversations created before {} in {:?}",
+            deleted,
+            cutoff,
+            started_at.elapsed()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+use async_trait::async_trait;
+use futures_util::{future, stream, stream::BoxStream, StreamExt};
+use std::time::Duration;
+use thiserror::Error;
+
+#[cfg(feature = "kafka")]
+mod kafka;
+mod memory;
+mod nats;
+#[cfg(feature = "redis")]
+mod redis;
+
+#[cfg(feature = "kafka")]
+pub use self::kafka::KafkaBus;
+pub use self::memory::MemoryBus;
+pub use self::nats::NatsBus;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisBus;
+
+// everything that publishes or subscribes goes through this so deployments can pick whichever bus they already run.
+// subjects are nats style (dot separated, * matches one token), other backends translate them
+
+#[async_trait]
+pub trait MessageBus: Send + Sync {
+    async fn publish(&self, subject: &str, data: &[u8]) -> Result<(), MessageBusError>;
+
+    async fn subscribe(&self, subject: &str) -> Result<Subscription, MessageBusError>;
+
+    // each message goes to only one of the subscribers sharing the queue across every server, for work that has to
+    // happen once cluster wide. backends without queue groups hand it to all of them, so with those only one server
+    // should subscribe
+    async fn queue_subscribe(
+        &self,
+        subject: &str,
+        _queue: &str,
+    ) -> Result<Subscription, MessageBusError> {
+        self.subscribe(subject).await
+    }
+
+    // for readiness checks, succeeds once the bus has acknowledged everything published so far
+    async fn flush(&self, timeout: Duration) -> Result<(), MessageBusError>;
+}
+
+// ends when the bus gives up on the subscription. dropping it unsubscribes
+pub struct Subscription(BoxStream<'static, BusMessage>);
+
+// subject is the one it was published to, which tells wildcard subscribers who it's for
+pub struct BusMessage {
+    pub subject: String,
+    pub data: Vec<u8>,
+}
+
+impl Subscription {
+    pub async fn next(&mut self) -> Option<BusMessage> {
+        self.0.next().await
+    }
+
+    // interleaves several subscriptions, ending as soon as any of them does so the caller resubscribes to all of them
+    pub fn merge(subscriptions: Vec<Subscription>) -> Self {
+        Self(Box::pin(
+            stream::select_all(subscriptions.into_iter().map(|subscription| {
+                subscription
+                    .0
+                    .map(Some)
+                    .chain(stream::once(future::ready(None)))
+            }))
+            .take_while(|data| future::ready(data.is_some()))
+            .filter_map(future::ready),
+        ))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MessageBusError {
+    #[error("Nats error: {0}")]
+    Nats(#[from] std::io::Error),
+    #[cfg(feature = "redis")]
+    #[error("Redis error: {0}")]
+    Redis(#[from] ::redis::RedisError),
+    #[cfg(feature = "kafka")]
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    #[error("Timed out")]
+    Timeout,
+}
+
+// for backends that have to match subjects themselves. nats semantics, * matches exactly one token and > matches the rest
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut subject_tokens = subject.split('.');
+
+    for pattern_token in pattern.split('.') {
+        match (pattern_token, subject_tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (pattern_token, Some(subject_token)) if pattern_token == subject_token => {}
+            _ => return false,
+        }
+    }
+
+    subject_tokens.next().is_none()
+}
@@ -0,0 +1,160 @@
+use async_trait::async_trait;
+use futures_util::stream;
+use rdkafka::{
+    consumer::{Consumer, StreamConsumer},
+    producer::{FutureProducer, FutureRecord, Producer},
+    util::Timeout,
+    ClientConfig, Message,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use uuid::Uuid;
+
+use super::{subject_matches, BusMessage, MessageBus, MessageBusError, Subscription};
+
+// everything goes to one topic keyed by subject, so a user's events stay ordered within a partition. kafka has no
+// per-subject subscriptions, so each server reads the whole topic in a consumer group of its own and hands messages
+// to whichever local subscriptions match
+
+type Subscribers = Arc<Mutex<Vec<(String, UnboundedSender<BusMessage>)>>>;
+
+pub struct KafkaBus {
+    brokers: String,
+    topic: String,
+    producer: FutureProducer,
+    subscribers: Subscribers,
+}
+
+impl KafkaBus {
+    pub fn connect(brokers: &str, topic: &str) -> Result<Self, MessageBusError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create::<FutureProducer>()?;
+
+        let consumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", format!("realtime-{}", Uuid::new_v4())) // every server needs every message
+            .set("auto.offset.reset", "latest")
+            .set("enable.auto.commit", "false")
+            .create::<StreamConsumer>()?;
+
+        consumer.subscribe(&[topic])?;
+
+        let subscribers = Subscribers::default();
+
+        tokio::task::spawn(Self::route(consumer, subscribers.clone(), false));
+
+        Ok(Self {
+            brokers: brokers.to_owned(),
+            topic: topic.to_owned(),
+            producer,
+            subscribers,
+        })
+    }
+
+    // a queue consumer stops once its subscription is dropped, so it leaves the group instead of holding on to
+    // partitions nobody reads
+    async fn route(consumer: StreamConsumer, subscribers: Subscribers, queue: bool) {
+        loop {
+            let message = match consumer.recv().await {
+                Ok(message) => message,
+                Err(err) => {
+                    warn!("Error receiving from kafka: {}", err);
+
+                    continue;
+                }
+            };
+
+            let (Some(subject), Some(data)) = (
+                message.key().and_then(|key| std::str::from_utf8(key).ok()),
+                message.payload(),
+            ) else {
+                continue;
+            };
+
+            let mut subscribers = subscribers.lock().unwrap();
+
+            subscribers.retain(|(_, tx)| !tx.is_closed());
+
+            if queue && subscribers.is_empty() {
+                return;
+            }
+
+            for (pattern, tx) in subscribers.iter() {
+                if subject_matches(pattern, subject) {
+                    let _ = tx.send(BusMessage {
+                        subject: subject.to_owned(),
+                        data: data.to_vec(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBus for KafkaBus {
+    async fn publish(&self, subject: &str, data: &[u8]) -> Result<(), MessageBusError> {
+        self.producer
+            .send(
+                FutureRecord::to(&self.topic).key(subject).payload(data),
+                Timeout::Never,
+            )
+            .await
+            .map(|_| ())
+            .map_err(|(err, _)| MessageBusError::from(err))
+    }
+
+    async fn subscribe(&self, subject: &str) -> Result<Subscription, MessageBusError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push((subject.to_owned(), tx));
+
+        Ok(Subscription(Box::pin(stream::unfold(
+            rx,
+            |mut rx| async move { rx.recv().await.map(|data| (data, rx)) },
+        ))))
+    }
+
+    // a consumer group shared by every server in the queue instead of one per server, so kafka hands each message to
+    // only one of them. one group per subject too, since each consumer drops whatever doesn't match its subject
+    async fn queue_subscribe(
+        &self,
+        subject: &str,
+        queue: &str,
+    ) -> Result<Subscription, MessageBusError> {
+        let consumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", format!("realtime-{}-{}", queue, subject))
+            .set("auto.offset.reset", "latest")
+            .create::<StreamConsumer>()?;
+
+        consumer.subscribe(&[&self.topic])?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::task::spawn(Self::route(
+            consumer,
+            Arc::new(Mutex::new(vec![(subject.to_owned(), tx)])),
+            true,
+        ));
+
+        Ok(Subscription(Box::pin(stream::unfold(
+            rx,
+            |mut rx| async move { rx.recv().await.map(|data| (data, rx)) },
+        ))))
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), MessageBusError> {
+        let producer = self.producer.clone();
+
+        tokio::task::spawn_blocking(move || producer.flush(timeout))
+            .await
+            .expect("Kafka flush panicked")
+            .map_err(MessageBusError::from)
+    }
+}
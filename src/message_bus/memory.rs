@@ -0,0 +1,55 @@
+use async_trait::async_trait;
+use futures_util::stream;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::{subject_matches, BusMessage, MessageBus, MessageBusError, Subscription};
+
+// for --dev. only reaches subscribers in this process, which is all there is when running locally
+
+const CAPACITY: usize = 1024;
+
+pub struct MemoryBus {
+    tx: broadcast::Sender<(String, Vec<u8>)>,
+}
+
+impl Default for MemoryBus {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBus for MemoryBus {
+    async fn publish(&self, subject: &str, data: &[u8]) -> Result<(), MessageBusError> {
+        let _ = self.tx.send((subject.to_owned(), data.to_vec())); // only fails when nobody is subscribed
+
+        Ok(())
+    }
+
+    async fn subscribe(&self, subject: &str) -> Result<Subscription, MessageBusError> {
+        Ok(Subscription(Box::pin(stream::unfold(
+            (self.tx.subscribe(), subject.to_owned()),
+            |(mut rx, pattern)| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok((subject, data)) if subject_matches(&pattern, &subject) => {
+                            return Some((BusMessage { subject, data }, (rx, pattern)))
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Subscription to {} skipped {} messages", pattern, skipped)
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        ))))
+    }
+
+    async fn flush(&self, _timeout: Duration) -> Result<(), MessageBusError> {
+        Ok(())
+    }
+}
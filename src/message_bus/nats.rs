@@ -0,0 +1,52 @@
+use async_trait::async_trait;
+use futures_util::stream;
+use std::time::Duration;
+
+use super::{BusMessage, MessageBus, MessageBusError, Subscription};
+
+pub struct NatsBus(::nats::asynk::Connection);
+
+impl NatsBus {
+    pub fn new(nc: ::nats::asynk::Connection) -> Self {
+        Self(nc)
+    }
+}
+
+#[async_trait]
+impl MessageBus for NatsBus {
+    async fn publish(&self, subject: &str, data: &[u8]) -> Result<(), MessageBusError> {
+        Ok(self.0.publish(subject, data).await?)
+    }
+
+    async fn subscribe(&self, subject: &str) -> Result<Subscription, MessageBusError> {
+        Ok(Self::stream(self.0.subscribe(subject).await?))
+    }
+
+    async fn queue_subscribe(
+        &self,
+        subject: &str,
+        queue: &str,
+    ) -> Result<Subscription, MessageBusError> {
+        Ok(Self::stream(self.0.queue_subscribe(subject, queue).await?))
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), MessageBusError> {
+        Ok(self.0.flush_timeout(timeout).await?)
+    }
+}
+
+impl NatsBus {
+    fn stream(sub: ::nats::asynk::Subscription) -> Subscription {
+        Subscription(Box::pin(stream::unfold(sub, |sub| async move {
+            sub.next().await.map(|message| {
+                (
+                    BusMessage {
+                        subject: message.subject,
+                        data: message.data,
+                    },
+                    sub,
+                )
+            })
+        })))
+    }
+}
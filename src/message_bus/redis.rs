@@ -0,0 +1,63 @@
+use ::redis::{aio::ConnectionManager, AsyncCommands, Client};
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::time::Duration;
+
+use super::{BusMessage, MessageBus, MessageBusError, Subscription};
+
+// redis pub/sub. publishes share one connection that reconnects on its own, but each subscription needs a connection of
+// its own because a connection in subscriber mode can't do anything else. nats wildcards become psubscribe globs, which
+// works because hashes never contain dots or glob characters
+
+pub struct RedisBus {
+    client: Client,
+    publisher: ConnectionManager,
+}
+
+impl RedisBus {
+    pub async fn connect(url: &str) -> Result<Self, MessageBusError> {
+        let client = Client::open(url)?;
+
+        let publisher = ConnectionManager::new(client.clone()).await?;
+
+        Ok(Self { client, publisher })
+    }
+}
+
+#[async_trait]
+impl MessageBus for RedisBus {
+    async fn publish(&self, subject: &str, data: &[u8]) -> Result<(), MessageBusError> {
+        let mut publisher = self.publisher.clone();
+
+        Ok(publisher.publish(subject, data).await?)
+    }
+
+    async fn subscribe(&self, subject: &str) -> Result<Subscription, MessageBusError> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+
+        if subject.contains('*') {
+            pubsub.psubscribe(subject).await?;
+        } else {
+            pubsub.subscribe(subject).await?;
+        }
+
+        Ok(Subscription(Box::pin(pubsub.into_on_message().map(
+            |message| BusMessage {
+                subject: message.get_channel_name().to_owned(),
+                data: message.get_payload_bytes().to_vec(),
+            },
+        ))))
+    }
+
+    async fn flush(&self, timeout: Duration) -> Result<(), MessageBusError> {
+        let mut publisher = self.publisher.clone();
+
+        tokio::time::timeout(
+            timeout,
+            ::redis::cmd("PING").query_async::<_, ()>(&mut publisher),
+        )
+        .await
+        .map_err(|_| MessageBusError::Timeout)?
+        .map_err(MessageBusError::from)
+    }
+}
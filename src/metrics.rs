@@ -0,0 +1,192 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// application counters, served in prometheus text format on /metrics next to the tokio runtime metrics
+
+pub struct Counter {
+    name: &'static str,
+    labels: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, labels: &'static str) -> Self {
+        Self {
+            name,
+            labels,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+// observations in seconds, rendered with cumulative buckets like prometheus expects
+pub struct Histogram<const N: usize> {
+    name: &'static str,
+    bounds: [f64; N],
+    buckets: [AtomicU64; N],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl<const N: usize> Histogram<N> {
+    const fn new(name: &'static str, bounds: [f64; N]) -> Self {
+        Self {
+            name,
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; N],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+
+        if let Some(bucket) = self.bounds.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# TYPE {} histogram", self.name);
+
+        let mut cumulative = 0;
+
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name, bound, cumulative
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            self.name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", self.name, count);
+    }
+}
+
+pub static DATABASE_TIMEOUTS: Counter =
+    Counter::new("realtime_timeouts_total", "dependency=\"scylla\"");
+pub static NATS_TIMEOUTS: Counter = Counter::new("realtime_timeouts_total", "dependency=\"nats\"");
+
+pub static OPERATIONS_REJECTED: Counter =
+    Counter::new("realtime_operations_rejected_total", "reason=\"saturated\"");
+
+pub static DATABASE_RETRIES: Counter =
+    Counter::new("realtime_retries_total", "dependency=\"scylla\"");
+
+pub static DATABASE_RETRIES_EXHAUSTED_ATTEMPTS: Counter = Counter::new(
+    "realtime_retries_exhausted_total",
+    "dependency=\"scylla\",reason=\"attempts\"",
+);
+pub static DATABASE_RETRIES_EXHAUSTED_BUDGET: Counter = Counter::new(
+    "realtime_retries_exhausted_total",
+    "dependency=\"scylla\",reason=\"budget\"",
+);
+
+pub static SPAM_THROTTLED: Counter =
+    Counter::new("realtime_spam_decisions_total", "action=\"throttle\"");
+pub static SPAM_MUTED: Counter = Counter::new("realtime_spam_decisions_total", "action=\"mute\"");
+pub static SPAM_CLOSED: Counter = Counter::new("realtime_spam_decisions_total", "action=\"close\"");
+
+pub static WEBHOOKS_DELIVERED: Counter =
+    Counter::new("realtime_webhook_deliveries_total", "result=\"delivered\"");
+pub static WEBHOOKS_RETRIED: Counter =
+    Counter::new("realtime_webhook_deliveries_total", "result=\"retried\"");
+pub static WEBHOOKS_FAILED: Counter =
+    Counter::new("realtime_webhook_deliveries_total", "result=\"failed\"");
+
+pub static ANALYTICS_DROPPED: Counter = Counter::new("realtime_analytics_dropped_total", "");
+
+pub static PRESENCE_TIMEOUT_RUNS: Counter = Counter::new(
+    "realtime_maintenance_runs_total",
+    "task=\"presence_timeout\"",
+);
+pub static RETENTION_SWEEP_RUNS: Counter = Counter::new(
+    "realtime_maintenance_runs_total",
+    "task=\"retention_sweep\"",
+);
+pub static PRESENCE_TIMEOUT_REMOVED: Counter = Counter::new(
+    "realtime_maintenance_removed_total",
+    "task=\"presence_timeout\"",
+);
+pub static RETENTION_SWEEP_REMOVED: Counter = Counter::new(
+    "realtime_maintenance_removed_total",
+    "task=\"retention_sweep\"",
+);
+
+// round trip times of heartbeat pings, one observation per pong from any connection
+pub static CONNECTION_RTT: Histogram<8> = Histogram::new(
+    "realtime_connection_rtt_seconds",
+    [0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0],
+);
+
+// counters sharing a name have to be next to each other so the type line is only written once
+static COUNTERS: [&Counter; 17] = [
+    &DATABASE_TIMEOUTS,
+    &NATS_TIMEOUTS,
+    &OPERATIONS_REJECTED,
+    &DATABASE_RETRIES,
+    &DATABASE_RETRIES_EXHAUSTED_ATTEMPTS,
+    &DATABASE_RETRIES_EXHAUSTED_BUDGET,
+    &SPAM_THROTTLED,
+    &SPAM_MUTED,
+    &SPAM_CLOSED,
+    &WEBHOOKS_DELIVERED,
+    &WEBHOOKS_RETRIED,
+    &WEBHOOKS_FAILED,
+    &ANALYTICS_DROPPED,
+    &PRESENCE_TIMEOUT_RUNS,
+    &RETENTION_SWEEP_RUNS,
+    &PRESENCE_TIMEOUT_REMOVED,
+    &RETENTION_SWEEP_REMOVED,
+];
+
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let mut previous_name = "";
+
+    for counter in COUNTERS {
+        if counter.name != previous_name {
+            let _ = writeln!(out, "# TYPE {} counter", counter.name);
+
+            previous_name = counter.name;
+        }
+
+        let _ = writeln!(
+            out,
+            "{}{{{}}} {}",
+            counter.name,
+            counter.labels,
+            counter.value.load(Ordering::Relaxed)
+        );
+    }
+
+    CONNECTION_RTT.render(&mut out);
+
+    out
+}
@@ -1,3 +1,25 @@
+pub mod attachment;
+pub mod audit_entry;
+pub mod bot_key;
+pub mod connection_summary;
+pub mod conversation_stats;
+pub mod conversation_summary;
+pub mod device;
+pub mod failed_event;
 pub mod friend_profile;
+pub mod ip_ban;
+pub mod job_status;
+pub mod key_distribution;
+pub mod mention;
 pub mod message;
+pub mod message_kind;
+pub mod node_summary;
+pub mod notification_prefs;
+pub mod outbox_entry;
+pub mod pinned_message;
+pub mod poll;
+pub mod presence_event;
 pub mod profile;
+pub mod push_token;
+pub mod report;
+pub mod user_conversation;
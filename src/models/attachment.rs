@@ -0,0 +1,30 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// a file uploaded to the object store with a url from requestAttachmentUpload, sent along with a message
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Attachment {
+    #[serde(default)]
+    pub kind: AttachmentKind,
+    pub content_type: String,
+    pub object_key: String,
+    pub size: u64, // bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>, // images and video
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbhash: Option<String>, // base64, for a placeholder while the file loads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u32>, // voice only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waveform: Option<Vec<u8>>, // voice only, amplitudes for drawing the player before the audio loads
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum AttachmentKind {
+    #[default]
+    File,
+    Voice, // recorded in the app, played inline instead of shown as a file
+}
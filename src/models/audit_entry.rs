@@ -0,0 +1,65 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// one row of the append-only audit log. actor and target are usernames, except that a report's target is the
+// conversation, and a failed auth has no actor when the token couldn't be read
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub entry_id: String,
+    pub action: AuditAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_id: Option<String>, // unset for what happens outside of any connection, like the friend removals of an account deletion
+    pub detail: String,
+    pub occurred_at: DateTime<Utc>,
+}
+
+// users can't block each other or be revealed through the gateway yet, so neither is here until they can. spelled the
+// same way everywhere, json and protobuf included
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    FriendRemoved,
+    AccountDeleted,
+    Reported,
+    Kicked,
+    AuthFailed,
+    Banned, // an address, by an admin or automatically for tripping a limit
+    Unbanned,
+    TokensRevoked,
+}
+
+impl AuditAction {
+    // how it's stored
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::FriendRemoved => "friend_removed",
+            Self::AccountDeleted => "account_deleted",
+            Self::Reported => "reported",
+            Self::Kicked => "kicked",
+            Self::AuthFailed => "auth_failed",
+            Self::Banned => "banned",
+            Self::Unbanned => "unbanned",
+            Self::TokensRevoked => "tokens_revoked",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "friend_removed" => Some(Self::FriendRemoved),
+            "account_deleted" => Some(Self::AccountDeleted),
+            "reported" => Some(Self::Reported),
+            "kicked" => Some(Self::Kicked),
+            "auth_failed" => Some(Self::AuthFailed),
+            "banned" => Some(Self::Banned),
+            "unbanned" => Some(Self::Unbanned),
+            "tokens_revoked" => Some(Self::TokensRevoked),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use chrono::prelude::*;
+
+// an api key for a bot or integration account, issued by the api. the account itself is a user like any other, so
+// it has a profile, can be chosen and can send in its conversations. the secret signs handshakes, see auth::bot
+#[derive(Clone)]
+pub struct BotKey {
+    pub key_id: String,
+    pub username: String,
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
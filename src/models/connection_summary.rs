@@ -0,0 +1,12 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct ConnectionSummary {
+    pub id: String,
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub connected_at: DateTime<Utc>,
+}
@@ -0,0 +1,9 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// every message ever sent in the conversation, including ones that have since expired or been cleared
+#[derive(Serialize, Clone, Default, JsonSchema)]
+pub struct ConversationStats {
+    pub messages_from_chooser: u64,
+    pub messages_from_choosee: u64,
+}
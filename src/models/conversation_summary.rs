@@ -0,0 +1,17 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// a row of the user's conversation list. choosees aren't told who chose them, so only the chooser gets a name
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct ConversationSummary {
+    pub conversation_id: String,
+    pub created_at: DateTime<Utc>,
+    pub is_chooser: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub choosee_name: Option<String>,
+    pub pinned: bool,
+    pub archived: bool,
+    pub tombstoned: bool, // the other user deleted their account
+    pub streak: u32,      // days in a row both users sent something, see streaks
+}
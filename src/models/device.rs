@@ -0,0 +1,14 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// somewhere the user has connected from, identified by the id in its access token or the one its client sent during
+// the handshake. connections that didn't say which device they're on aren't tracked
+#[derive(Serialize, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Device {
+    pub device_id: String,
+    pub last_seen_at: DateTime<Utc>, // when it last connected
+    pub connected: bool, // filled in by the gateway, which only knows about connections to itself
+    pub current: bool,   // the device asking
+}
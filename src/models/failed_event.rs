@@ -0,0 +1,10 @@
+use chrono::prelude::*;
+
+#[derive(Clone)]
+pub struct FailedEvent {
+    pub subject: String,
+    pub failed_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub data: Vec<u8>,
+    pub reason: String,
+}
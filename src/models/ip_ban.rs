@@ -0,0 +1,13 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+// an address everything is dropped from until the ban ends, whether it tripped a limit or an admin banned it
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct IpBan {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub banned_at: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
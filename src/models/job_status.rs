@@ -0,0 +1,43 @@
+use chrono::prelude::*;
+
+// where a background job is at, overwritten every time it moves along
+#[derive(Clone)]
+pub struct JobStatus {
+    pub job_id: String,
+    pub kind: String,
+    pub state: JobState,
+    pub attempts: u32,
+    pub last_error: Option<String>, // from the most recent failed attempt
+    pub enqueued_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum JobState {
+    Queued, // including while waiting to be retried
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    // how it's stored
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<Self> {
+        match state {
+            "queued" => Some(Self::Queued),
+            "running" => Some(Self::Running),
+            "succeeded" => Some(Self::Succeeded),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// key material one participant sent the other, kept for a while so it reaches them even if they were offline. what's
+// in it is up to the clients, the server never looks inside
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct KeyDistribution {
+    pub key_material: String,
+    pub sent_at: DateTime<Utc>,
+    pub from_chooser: bool,
+}
@@ -0,0 +1,11 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// a message sent with the mention flag. only where to find it, so a mention never outlives the message itself or
+// keeps a copy of its content around
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct Mention {
+    pub conversation_id: String,
+    pub sent_at: DateTime<Utc>,
+}
@@ -1,9 +1,16 @@
 use chrono::prelude::*;
+use schemars::JsonSchema;
 use serde::Serialize;
 
-#[derive(Serialize)]
+use super::{attachment::Attachment, message_kind::MessageKind};
+
+#[derive(Serialize, Clone, JsonSchema)]
 pub struct Message {
     pub content: String,
     pub sent_at: DateTime<Utc>,
     pub from_chooser: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<Attachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<MessageKind>,
 }
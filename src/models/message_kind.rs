@@ -0,0 +1,36 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// what a message is when it isn't only text, for clients to render natively. the content is still sent along with
+// it, as what clients that don't know the kind show instead
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum MessageKind {
+    // from a pack the clients ship with or download themselves, the server only passes the ids along
+    Sticker {
+        pack_id: String,
+        sticker_id: String,
+    },
+    Gif {
+        provider: GifProvider,
+        id: String,  // the provider's id for it
+        url: String, // has to be on the provider's own domain
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum GifProvider {
+    Giphy,
+    Tenor,
+}
+
+impl GifProvider {
+    // the domain its media is served from, subdomains included
+    pub fn domain(&self) -> &'static str {
+        match self {
+            GifProvider::Giphy => "giphy.com",
+            GifProvider::Tenor => "tenor.com",
+        }
+    }
+}
@@ -0,0 +1,13 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// what each node announces about itself to the rest of the cluster
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
+pub struct NodeSummary {
+    pub node_id: String,
+    pub version: String,
+    pub connections: usize,
+    pub started_at: DateTime<Utc>,
+    pub announced_at: DateTime<Utc>,
+}
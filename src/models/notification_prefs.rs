@@ -0,0 +1,68 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::user_conversation::UserConversation;
+
+// what a user wants to be interrupted by, across all their conversations. silenced events still reach connected
+// devices, flagged so they don't make a sound, but aren't pushed to ones that aren't connected
+#[derive(Deserialize, Serialize, Clone, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationPrefs {
+    #[serde(default)]
+    pub muted: bool,
+    #[serde(default)]
+    pub mentions_only: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dnd: Option<DndWindow>,
+}
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+const MAX_UTC_OFFSET_MINUTES: u16 = 14 * 60;
+
+// do not disturb, every day between two times of day in the user's time zone. wraps past midnight when end is before
+// start
+#[derive(Deserialize, Serialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DndWindow {
+    pub start_minute: u16, // minutes since midnight
+    pub end_minute: u16,
+    pub utc_offset_minutes: i16, // sent by the client, so it's on them to update it when the user travels or dst changes
+}
+
+impl DndWindow {
+    pub fn is_valid(&self) -> bool {
+        self.start_minute < MINUTES_PER_DAY
+            && self.end_minute < MINUTES_PER_DAY
+            && self.utc_offset_minutes.unsigned_abs() <= MAX_UTC_OFFSET_MINUTES
+    }
+
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        let minute = (at.hour() as i32 * 60 + at.minute() as i32 + self.utc_offset_minutes as i32)
+            .rem_euclid(MINUTES_PER_DAY as i32) as u16;
+
+        if self.start_minute <= self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            self.start_minute <= minute || minute < self.end_minute
+        }
+    }
+}
+
+impl NotificationPrefs {
+    // whether a message or new conversation at this time should be silenced, given what the user set on the
+    // conversation itself. mentions only lets the mention events through, see silences_mention
+    pub fn silences(&self, user_conversation: &UserConversation, at: DateTime<Utc>) -> bool {
+        self.muted
+            || self.mentions_only
+            || user_conversation.muted
+            || user_conversation.mentions_only
+            || self.dnd.is_some_and(|dnd| dnd.contains(at))
+    }
+
+    // mentions get through a muted conversation, it's what they're for. muting everything still silences them
+    pub fn silences_mention(&self, at: DateTime<Utc>) -> bool {
+        self.muted || self.dnd.is_some_and(|dnd| dnd.contains(at))
+    }
+}
@@ -0,0 +1,10 @@
+use chrono::prelude::*;
+
+#[derive(Clone)]
+pub struct OutboxEntry {
+    pub bucket: i32,
+    pub created_at: DateTime<Utc>,
+    pub entry_id: String, // so events stored within the same millisecond don't overwrite each other
+    pub subject: String,
+    pub data: Vec<u8>,
+}
@@ -0,0 +1,11 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+// messages are keyed by when they were sent, so that's what identifies the pinned one
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct PinnedMessage {
+    pub sent_at: DateTime<Utc>,
+    pub pinned_at: DateTime<Utc>,
+    pub pinned_by_chooser: bool,
+}
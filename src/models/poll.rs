@@ -0,0 +1,64 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// a question either user asks in a conversation. both of them get one vote, which they can change
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct Poll {
+    pub poll_id: String,
+    pub question: String,
+    pub options: Vec<PollOption>,
+    pub created_by_chooser: bool,
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chooser_vote: Option<u32>, // index into options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub choosee_vote: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub struct PollOption {
+    pub text: String,
+    pub votes: u32,
+}
+
+impl Poll {
+    pub fn new(
+        poll_id: String,
+        question: String,
+        options: Vec<String>,
+        created_by_chooser: bool,
+        created_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            poll_id,
+            question,
+            options: options
+                .into_iter()
+                .map(|text| PollOption { text, votes: 0 })
+                .collect(),
+            created_by_chooser,
+            created_at,
+            chooser_vote: None,
+            choosee_vote: None,
+        }
+    }
+
+    // the counts are only ever worked out from the votes, so they can't disagree
+    pub fn with_votes(mut self, chooser_vote: Option<u32>, choosee_vote: Option<u32>) -> Self {
+        self.chooser_vote = chooser_vote;
+        self.choosee_vote = choosee_vote;
+
+        for option in &mut self.options {
+            option.votes = 0;
+        }
+
+        for vote in [chooser_vote, choosee_vote].into_iter().flatten() {
+            if let Some(option) = self.options.get_mut(vote as usize) {
+                option.votes += 1;
+            }
+        }
+
+        self
+    }
+}
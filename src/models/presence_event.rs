@@ -0,0 +1,9 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct PresenceEvent {
+    pub leaving: bool,
+    pub occurred_at: DateTime<Utc>,
+}
@@ -1,9 +1,11 @@
+use schemars::JsonSchema;
 use scylla::{
     cql_to_rust::FromCqlVal,
     macros::{FromUserType, IntoUserType},
 };
+use serde::Serialize;
 
-#[derive(FromUserType, IntoUserType, Clone)]
+#[derive(FromUserType, IntoUserType, Serialize, Clone, JsonSchema)]
 pub struct Profile {
     pub username: String,
     pub name: String,
@@ -0,0 +1,38 @@
+use chrono::prelude::*;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// a device's token for its platform's push service. stored under the username hash, since that's all the push worker
+// learns from a subject
+#[derive(Clone)]
+pub struct PushToken {
+    pub token: String,
+    pub platform: PushPlatform,
+    pub username: String,
+    pub registered_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PushPlatform {
+    Apns,
+    Fcm,
+}
+
+impl PushPlatform {
+    // how it's stored
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Apns => "apns",
+            Self::Fcm => "fcm",
+        }
+    }
+
+    pub fn parse(platform: &str) -> Option<Self> {
+        match platform {
+            "apns" => Some(Self::Apns),
+            "fcm" => Some(Self::Fcm),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,16 @@
+use chrono::prelude::*;
+use serde::Serialize;
+
+// stored, and published to moderation.reports for a trust and safety consumer to act on. who was reported isn't part
+// of it, the consumer looks the conversation up for that
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    pub report_id: String,
+    pub reporter_username: String,
+    pub conversation_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_sent_at: Option<DateTime<Utc>>, // unset when it's the conversation as a whole being reported
+    pub reason: String,
+    pub reported_at: DateTime<Utc>,
+}
@@ -0,0 +1,12 @@
+use chrono::prelude::*;
+use serde::Serialize;
+
+// what one of the two users has set on a conversation for themselves, the other user never sees it
+#[derive(Serialize, Clone, Default)]
+pub struct UserConversation {
+    pub pinned: bool,
+    pub archived: bool,
+    pub cleared_before: Option<DateTime<Utc>>, // messages sent before this are hidden from the user
+    pub muted: bool,
+    pub mentions_only: bool,
+}
@@ -0,0 +1,30 @@
+use std::collections::HashSet;
+use tungstenite::handshake::server::Request;
+
+// browsers always send an Origin header on websocket upgrades, native clients don't, so only requests that have one are checked
+
+pub struct OriginAllowlist {
+    allowed_origins: HashSet<String>,
+}
+
+impl OriginAllowlist {
+    pub fn new(allowed_origins: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            allowed_origins: allowed_origins
+                .into_iter()
+                .map(|origin| origin.trim().trim_end_matches('/').to_lowercase())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+        }
+    }
+
+    pub fn allows(&self, req: &Request) -> bool {
+        match req.headers().get("Origin") {
+            Some(origin) => origin.to_str().is_ok_and(|origin| {
+                self.allowed_origins
+                    .contains(&origin.trim_end_matches('/').to_lowercase())
+            }),
+            None => true,
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use chrono::prelude::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::Storage;
+use crate::dead_letter;
+use crate::message_bus::MessageBus;
+
+// events that couldn't be published while the message bus was unreachable get stored in the outbox and are republished from here once it's back
+//
+// the outbox is split into buckets so draining reads a partition rather than the whole table. each node writes to a
+// bucket picked from its id and drains it every tick, which keeps nodes from republishing each other's events unless
+// their ids land in the same bucket. entries left behind by a node that's gone are picked up by the rest, which also
+// drain one other bucket each tick, taking turns through all of them
+pub const BUCKETS: i32 = 16;
+
+// stable for as long as the node runs, which is all that matters since a restarted node gets a new id anyway
+pub fn home_bucket(node_id: &str) -> i32 {
+    let mut hasher = DefaultHasher::new();
+
+    node_id.hash(&mut hasher);
+
+    (hasher.finish() % BUCKETS as u64) as i32
+}
+
+pub async fn drain_periodically(
+    db: Arc<dyn Storage>,
+    message_bus: Arc<dyn MessageBus>,
+    node_id: String,
+    interval: Duration,
+    max_age: Duration,
+) {
+    let home_bucket = home_bucket(&node_id);
+
+    // events stored before the outbox was split into buckets would otherwise never be drained
+    match db.adopt_legacy_outbox(home_bucket).await {
+        Ok(0) => {}
+        Ok(adopted) => info!("Moved {} events from the legacy outbox", adopted),
+        Err(err) => warn!(
+            "Failed to move events from the legacy outbox, trying again on the next start: {}",
+            err
+        ),
+    }
+
+    let mut other_bucket = home_bucket;
+
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        other_bucket = (other_bucket + 1) % BUCKETS;
+
+        if other_bucket == home_bucket {
+            other_bucket = (other_bucket + 1) % BUCKETS;
+        }
+
+        for bucket in [home_bucket, other_bucket] {
+            if !drain(db.as_ref(), message_bus.as_ref(), bucket, max_age).await {
+                break; // no point trying the other bucket until the next tick
+            }
+        }
+    }
+}
+
+// false when the message bus is still unavailable
+async fn drain(
+    db: &dyn Storage,
+    message_bus: &dyn MessageBus,
+    bucket: i32,
+    max_age: Duration,
+) -> bool {
+    let outbox_entries = match db.get_outbox(bucket).await {
+        Ok(outbox_entries) => outbox_entries,
+        Err(err) => {
+            warn!("Failed to read outbox: {}", err);
+
+            return true;
+        }
+    };
+
+    for outbox_entry in outbox_entries {
+        if (Utc::now() - outbox_entry.created_at)
+            .to_std()
+            .is_ok_and(|age| age > max_age)
+        {
+            match dead_letter::dead_letter(
+                db,
+                outbox_entry.subject.clone(),
+                outbox_entry.created_at,
+                outbox_entry.data,
+                format!("Not published within {}s", max_age.as_secs()),
+            )
+            .await
+            {
+                Ok(()) => {
+                    if let Err(err) = db
+                        .remove_from_outbox(
+                            outbox_entry.bucket,
+                            outbox_entry.created_at,
+                            &outbox_entry.entry_id,
+                        )
+                        .await
+                    {
+                        warn!("Failed to remove dead lettered event from outbox: {}", err);
+                    }
+                }
+                Err(err) => warn!("Failed to dead letter event: {}", err), // stays in the outbox until the next tick
+            }
+
+            continue;
+        }
+
+        if let Err(err) = message_bus
+            .publish(&outbox_entry.subject, &outbox_entry.data)
+            .await
+        {
+            warn!(
+                "Message bus still unavailable while draining outbox: {}",
+                err
+            );
+
+            return false;
+        }
+
+        if let Err(err) = db
+            .remove_from_outbox(
+                outbox_entry.bucket,
+                outbox_entry.created_at,
+                &outbox_entry.entry_id,
+            )
+            .await
+        {
+            warn!("Failed to remove published event from outbox: {}", err); // will get published again next tick, which clients have to tolerate anyway
+        }
+    }
+
+    true
+}
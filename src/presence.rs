@@ -0,0 +1,30 @@
+use async_trait::async_trait;
+
+use crate::message_bus::MessageBusError;
+
+mod memory;
+mod nats_kv;
+
+pub use self::memory::MemoryPresence;
+pub use self::nats_kv::NatsKvPresence;
+
+// which node each user is connected to across the whole cluster. connections refresh their user's entry on every
+// heartbeat and entries expire on their own after a ttl, so users on a node that died without cleaning up stop
+// showing as online soon after
+//
+// there's one entry per user, so a user connected to several nodes at once shows up on whichever refreshed last
+
+#[async_trait]
+pub trait Presence: Send + Sync {
+    async fn refresh(&self, username: &str, node_id: &str) -> Result<(), MessageBusError>;
+
+    // only if the entry is still this node's, the user may have connected to another one since
+    async fn remove(&self, username: &str, node_id: &str) -> Result<(), MessageBusError>;
+
+    // None when the user isn't connected anywhere
+    async fn node_of(&self, username: &str) -> Result<Option<String>, MessageBusError>;
+
+    // removes entries that outlived the ttl, the users of which show as offline from then on, and says how many there
+    // were. lapsed entries are never returned by node_of either way, this is only so they don't pile up
+    async fn expire(&self) -> Result<usize, MessageBusError>;
+}
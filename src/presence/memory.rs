@@ -0,0 +1,73 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Presence;
+use crate::message_bus::MessageBusError;
+
+// for --dev and single node deployments, where this node is the whole cluster
+
+pub struct MemoryPresence {
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (String, Instant)>>, // node id and when it was refreshed
+}
+
+impl MemoryPresence {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl Presence for MemoryPresence {
+    async fn refresh(&self, username: &str, node_id: &str) -> Result<(), MessageBusError> {
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.retain(|_, (_, refreshed_at)| now.duration_since(*refreshed_at) < self.ttl);
+
+        entries.insert(username.to_owned(), (node_id.to_owned(), now));
+
+        Ok(())
+    }
+
+    async fn remove(&self, username: &str, node_id: &str) -> Result<(), MessageBusError> {
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries
+            .get(username)
+            .is_some_and(|(entry_node_id, _)| entry_node_id == node_id)
+        {
+            entries.remove(username);
+        }
+
+        Ok(())
+    }
+
+    async fn node_of(&self, username: &str) -> Result<Option<String>, MessageBusError> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap()
+            .get(username)
+            .filter(|(_, refreshed_at)| refreshed_at.elapsed() < self.ttl)
+            .map(|(node_id, _)| node_id.clone()))
+    }
+
+    async fn expire(&self) -> Result<usize, MessageBusError> {
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+
+        let before = entries.len();
+
+        entries.retain(|_, (_, refreshed_at)| now.duration_since(*refreshed_at) < self.ttl);
+
+        Ok(before - entries.len())
+    }
+}
@@ -0,0 +1,94 @@
+use async_trait::async_trait;
+use nats::kv::{Config, Store};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::time::Duration;
+
+use super::Presence;
+use crate::message_bus::MessageBusError;
+
+// a jetstream key value bucket whose max age is the ttl. the nats client only has a blocking api for key value, so
+// this keeps a blocking connection of its own next to the bus's and runs everything on the blocking pool
+
+pub struct NatsKvPresence(Store);
+
+impl NatsKvPresence {
+    pub async fn connect(
+        cred_path: String,
+        url: String,
+        bucket: String,
+        ttl: Duration,
+    ) -> Result<Self, MessageBusError> {
+        let store = tokio::task::spawn_blocking(move || {
+            let jetstream =
+                nats::jetstream::new(nats::Options::with_credentials(cred_path).connect(url)?);
+
+            jetstream.key_value(&bucket).or_else(|_| {
+                jetstream.create_key_value(&Config {
+                    bucket: bucket.clone(),
+                    history: 1,
+                    max_age: ttl,
+                    ..Default::default()
+                })
+            })
+        })
+        .await
+        .expect("Connecting to nats key value panicked")?;
+
+        Ok(Self(store))
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(Store) -> io::Result<T> + Send + 'static,
+    ) -> Result<T, MessageBusError> {
+        let store = self.0.clone();
+
+        Ok(tokio::task::spawn_blocking(move || f(store))
+            .await
+            .expect("Nats key value operation panicked")?)
+    }
+}
+
+// keys can't have most of the characters usernames can
+fn key(username: &str) -> String {
+    hex::encode(Sha256::digest(username.as_bytes()))
+}
+
+#[async_trait]
+impl Presence for NatsKvPresence {
+    async fn refresh(&self, username: &str, node_id: &str) -> Result<(), MessageBusError> {
+        let key = key(username);
+        let node_id = node_id.to_owned();
+
+        self.blocking(move |store| store.put(&key, node_id).map(|_| ()))
+            .await
+    }
+
+    async fn remove(&self, username: &str, node_id: &str) -> Result<(), MessageBusError> {
+        let key = key(username);
+        let node_id = node_id.to_owned();
+
+        self.blocking(move |store| match store.get(&key)? {
+            Some(entry_node_id) if entry_node_id == node_id.as_bytes() => store.delete(&key),
+            _ => Ok(()),
+        })
+        .await
+    }
+
+    async fn node_of(&self, username: &str) -> Result<Option<String>, MessageBusError> {
+        let key = key(username);
+
+        self.blocking(move |store| {
+            Ok(store
+                .get(&key)?
+                .and_then(|node_id| String::from_utf8(node_id).ok()))
+        })
+        .await
+    }
+
+    // the bucket's max age already removes them on the server
+    async fn expire(&self) -> Result<usize, MessageBusError> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,286 @@
+use chrono::prelude::*;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::cluster;
+use crate::connection::{
+    nats_message::{event_type_wildcard, username_hash_of},
+    user_event::UserEvent,
+    ConnectionRegistry,
+};
+use crate::db::{DatabaseError, Storage};
+use crate::message_bus::MessageBus;
+use crate::models::{
+    message_kind::MessageKind,
+    push_token::{PushPlatform, PushToken},
+};
+use crate::presence::Presence;
+
+pub mod apns;
+pub mod fcm;
+
+use apns::{ApnsClient, ApnsError};
+use fcm::{FcmClient, FcmError};
+
+// turns events for users that aren't connected to any node into push notifications. only runs on nodes with
+// PUSH_WORKER set, which share a queue group so each notification is sent by one of them
+
+// apns and fcm payloads are capped at 4kb, and lock screens only show a couple lines anyway
+const MAX_BODY_LENGTH: usize = 200;
+
+// whichever are configured. tokens for a platform that isn't are left alone
+#[derive(Default)]
+pub struct PushProviders {
+    pub apns: Option<ApnsClient>,
+    pub fcm: Option<FcmClient>,
+}
+
+impl PushProviders {
+    pub fn is_empty(&self) -> bool {
+        self.apns.is_none() && self.fcm.is_none()
+    }
+}
+
+pub struct PushNotification {
+    pub title: Option<String>,
+    pub body: String,
+    pub conversation_id: String, // notifications for the same conversation replace each other
+    pub mention: bool,           // gets past the user's conversation mutes
+}
+
+// not a conversation, only so each day's digest replaces the one before
+const DIGEST_CONVERSATION_ID: &str = "digest";
+
+impl PushNotification {
+    // None for events that aren't worth interrupting someone over. never says who sent what, chosees aren't
+    // supposed to know who chose them
+    fn from_user_event(user_event: UserEvent) -> Option<Self> {
+        match user_event {
+            UserEvent::Chosen {
+                conversation_id,
+                content,
+                ..
+            } => Some(Self {
+                title: Some("Someone chose you".to_string()),
+                body: truncate(content),
+                conversation_id,
+                mention: false,
+            }),
+            // the mention event that follows is pushed instead
+            UserEvent::Message { mention: true, .. } => None,
+            UserEvent::Message {
+                conversation_id,
+                content,
+                attachment,
+                kind,
+                ..
+            } => Some(Self {
+                title: None,
+                body: match (&kind, &attachment) {
+                    _ if !content.is_empty() => truncate(content),
+                    (Some(MessageKind::Sticker { .. }), _) => "Sent a sticker".to_string(),
+                    (Some(MessageKind::Gif { .. }), _) => "Sent a GIF".to_string(),
+                    (None, Some(_)) => "Sent an attachment".to_string(),
+                    (None, None) => truncate(content),
+                },
+                conversation_id,
+                mention: false,
+            }),
+            UserEvent::Mention {
+                conversation_id,
+                content,
+                ..
+            } => Some(Self {
+                title: Some("You were mentioned".to_string()),
+                body: truncate(content),
+                conversation_id,
+                mention: true,
+            }),
+            UserEvent::Digest {
+                unread_conversations,
+                pending_friend_requests,
+                ..
+            } => Some(Self {
+                title: Some("While you were away".to_string()),
+                body: [
+                    counted(unread_conversations, "unread conversation"),
+                    counted(pending_friend_requests, "pending friend request"),
+                ]
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" and "),
+                conversation_id: DIGEST_CONVERSATION_ID.to_string(),
+                mention: false,
+            }),
+            _ => None,
+        }
+    }
+
+    // hashed since conversation ids are longer than apns allows collapse ids to be
+    pub fn collapse_id(&self) -> String {
+        hex::encode(Sha256::digest(self.conversation_id.as_bytes()))
+    }
+}
+
+// "1 friend request", "2 friend requests", or None for none
+fn counted(count: u32, noun: &str) -> Option<String> {
+    match count {
+        0 => None,
+        1 => Some(format!("1 {}", noun)),
+        count => Some(format!("{} {}s", count, noun)),
+    }
+}
+
+fn truncate(mut content: String) -> String {
+    if let Some((index, _)) = content.char_indices().nth(MAX_BODY_LENGTH) {
+        content.truncate(index);
+        content.push('…');
+    }
+
+    content
+}
+
+pub async fn deliver(
+    db: Arc<dyn Storage>,
+    message_bus: Arc<dyn MessageBus>,
+    presence: Arc<dyn Presence>,
+    registry: Arc<ConnectionRegistry>,
+    providers: Arc<PushProviders>,
+) {
+    let subjects = [
+        event_type_wildcard("message"),
+        event_type_wildcard("conversation"),
+        event_type_wildcard("digest"),
+    ];
+
+    cluster::consume(
+        message_bus.as_ref(),
+        &subjects,
+        cluster::PUSH_QUEUE,
+        |bus_message| {
+            let Some(username_hash) = username_hash_of(&bus_message.subject) else {
+                return;
+            };
+
+            let notification = match UserEvent::from_slice(&bus_message.data) {
+                Ok(user_event) => match PushNotification::from_user_event(user_event) {
+                    Some(notification) => notification,
+                    None => return,
+                },
+                Err(err) => {
+                    warn!("Push worker received undecodable event: {}", err);
+
+                    return;
+                }
+            };
+
+            tokio::task::spawn(notify(
+                db.clone(),
+                presence.clone(),
+                registry.clone(),
+                providers.clone(),
+                username_hash.to_string(),
+                notification,
+            ));
+        },
+    )
+    .await;
+}
+
+async fn notify(
+    db: Arc<dyn Storage>,
+    presence: Arc<dyn Presence>,
+    registry: Arc<ConnectionRegistry>,
+    providers: Arc<PushProviders>,
+    username_hash: String,
+    notification: PushNotification,
+) {
+    let push_tokens = match db.get_push_tokens(&username_hash).await {
+        Ok(push_tokens) => push_tokens,
+        Err(err) => {
+            warn!("Failed to get push tokens: {}", err);
+
+            return;
+        }
+    };
+
+    // the tokens under a hash all belong to the one user
+    let Some(username) = push_tokens
+        .first()
+        .map(|push_token| push_token.username.clone())
+    else {
+        return;
+    };
+
+    // they'll get it over the websocket. this node's registry is all there is to go on when presence can't be reached
+    let connected = match presence.node_of(&username).await {
+        Ok(node_id) => node_id.is_some(),
+        Err(err) => {
+            warn!("Failed to check presence, checking this node only: {}", err);
+
+            registry.is_connected(&username)
+        }
+    };
+
+    if connected {
+        return;
+    }
+
+    match silences(db.as_ref(), &username, &notification).await {
+        Ok(false) => {}
+        Ok(true) => return,
+        Err(err) => warn!("Failed to get notification prefs, pushing anyway: {}", err),
+    }
+
+    for PushToken {
+        token, platform, ..
+    } in push_tokens
+    {
+        let unregistered = match (platform, &providers.apns, &providers.fcm) {
+            (PushPlatform::Apns, Some(apns), _) => match apns.send(&token, &notification).await {
+                Ok(()) => false,
+                Err(ApnsError::Unregistered) => true,
+                Err(err) => {
+                    warn!("Failed to send apns notification: {}", err);
+
+                    false
+                }
+            },
+            (PushPlatform::Fcm, _, Some(fcm)) => match fcm.send(&token, &notification).await {
+                Ok(()) => false,
+                Err(FcmError::Unregistered) => true,
+                Err(err) => {
+                    warn!("Failed to send fcm notification: {}", err);
+
+                    false
+                }
+            },
+            _ => false,
+        };
+
+        if unregistered {
+            if let Err(err) = db.remove_push_token(&username_hash, &token).await {
+                warn!("Failed to remove unregistered push token: {}", err);
+            }
+        }
+    }
+}
+
+async fn silences(
+    db: &dyn Storage,
+    username: &str,
+    notification: &PushNotification,
+) -> Result<bool, DatabaseError> {
+    let prefs = db.get_notification_prefs(username).await?;
+
+    if notification.mention {
+        return Ok(prefs.silences_mention(Utc::now()));
+    }
+
+    let user_conversation = db
+        .get_user_conversation(username, &notification.conversation_id)
+        .await?;
+
+    Ok(prefs.silences(&user_conversation, Utc::now()))
+}
@@ -0,0 +1,152 @@
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use super::PushNotification;
+
+// talks to apns over http/2 with token based auth, signing its own provider tokens with the .p8 key from the apple
+// developer account
+
+const PRODUCTION_ENDPOINT: &str = "https://api.push.apple.com";
+
+const SANDBOX_ENDPOINT: &str = "https://api.sandbox.push.apple.com";
+
+// apns rejects provider tokens older than an hour, and also ones refreshed more than every 20 minutes
+const PROVIDER_TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+
+pub struct ApnsOptions {
+    pub key_pem: String, // contents of the .p8 file
+    pub key_id: String,
+    pub team_id: String,
+    pub topic: String, // the app's bundle id
+    pub sandbox: bool, // for development builds of the app
+}
+
+pub struct ApnsClient {
+    client: reqwest::Client,
+    endpoint: &'static str,
+    key: EncodingKey,
+    key_id: String,
+    team_id: String,
+    topic: String,
+    provider_token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Debug, Error)]
+pub enum ApnsError {
+    #[error("Invalid apns key: {0}")]
+    Key(#[from] jsonwebtoken::errors::Error),
+    #[error("Error sending to apns: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Device token is no longer valid")]
+    Unregistered,
+    #[error("Apns rejected notification with status {status}: {reason}")]
+    Rejected { status: u16, reason: String },
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    iat: i64,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    reason: String,
+}
+
+impl ApnsClient {
+    pub fn new(options: ApnsOptions) -> Result<Self, ApnsError> {
+        Ok(Self {
+            client: reqwest::Client::builder().http2_prior_knowledge().build()?,
+            endpoint: if options.sandbox {
+                SANDBOX_ENDPOINT
+            } else {
+                PRODUCTION_ENDPOINT
+            },
+            key: EncodingKey::from_ec_pem(options.key_pem.as_bytes())?,
+            key_id: options.key_id,
+            team_id: options.team_id,
+            topic: options.topic,
+            provider_token: Mutex::new(None),
+        })
+    }
+
+    pub async fn send(
+        &self,
+        device_token: &str,
+        notification: &PushNotification,
+    ) -> Result<(), ApnsError> {
+        let collapse_id = notification.collapse_id();
+
+        let payload = json!({
+            "aps": {
+                "alert": {
+                    "title": notification.title,
+                    "body": notification.body,
+                },
+                "sound": "default",
+                "thread-id": collapse_id,
+            },
+            "conversationId": notification.conversation_id,
+        });
+
+        let response = self
+            .client
+            .post(format!("{}/3/device/{}", self.endpoint, device_token))
+            .bearer_auth(self.provider_token()?)
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "alert")
+            .header("apns-priority", "10")
+            .header("apns-collapse-id", &collapse_id)
+            .json(&payload)
+            .send()
+            .await?;
+
+        let status = response.status().as_u16();
+
+        if status == 200 {
+            return Ok(());
+        }
+
+        let reason = response
+            .json::<ErrorBody>()
+            .await
+            .map(|body| body.reason)
+            .unwrap_or_default();
+
+        match (status, reason.as_str()) {
+            (410, _) | (400, "BadDeviceToken") => Err(ApnsError::Unregistered),
+            _ => Err(ApnsError::Rejected { status, reason }),
+        }
+    }
+
+    fn provider_token(&self) -> Result<String, ApnsError> {
+        let mut provider_token = self.provider_token.lock().unwrap();
+
+        if let Some((token, issued_at)) = provider_token.as_ref() {
+            if issued_at.elapsed() < PROVIDER_TOKEN_LIFETIME {
+                return Ok(token.clone());
+            }
+        }
+
+        let token = jsonwebtoken::encode(
+            &Header {
+                kid: Some(self.key_id.clone()),
+                ..Header::new(Algorithm::ES256)
+            },
+            &Claims {
+                iss: &self.team_id,
+                iat: chrono::Utc::now().timestamp(),
+            },
+            &self.key,
+        )?;
+
+        *provider_token = Some((token.clone(), Instant::now()));
+
+        Ok(token)
+    }
+}
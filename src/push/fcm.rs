@@ -0,0 +1,270 @@
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+use super::PushNotification;
+use crate::retry_policy::RetryPolicy;
+
+// talks to the fcm http v1 api, authenticating as a google service account by trading a self signed jwt for an
+// oauth access token
+
+const SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+
+// access tokens last an hour, refreshed a bit early so one never expires mid request
+const ACCESS_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+pub struct FcmOptions {
+    pub service_account_json: String, // the key file downloaded from the firebase console
+    // data messages are handed to the app to display itself, notification messages are displayed by android
+    pub data_messages: bool,
+    pub retry_policy: RetryPolicy, // for 5xx responses
+}
+
+pub struct FcmClient {
+    client: reqwest::Client,
+    endpoint: String,
+    client_email: String,
+    token_uri: String,
+    key: EncodingKey,
+    data_messages: bool,
+    retry_policy: RetryPolicy,
+    access_token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Debug, Error)]
+pub enum FcmError {
+    #[error("Invalid fcm service account: {0}")]
+    ServiceAccount(#[from] serde_json::Error),
+    #[error("Invalid fcm service account key: {0}")]
+    Key(#[from] jsonwebtoken::errors::Error),
+    #[error("Error sending to fcm: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Fcm access token request failed with status {0}")]
+    Auth(u16),
+    #[error("Device token is no longer valid")]
+    Unregistered,
+    #[error("Fcm rejected message with status {status}: {reason}")]
+    Rejected { status: u16, reason: String },
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: i64,
+    exp: i64,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    status: String,
+    #[serde(default)]
+    details: Vec<ErrorDetail>,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    #[serde(rename = "errorCode")]
+    error_code: Option<String>,
+}
+
+impl FcmClient {
+    pub fn new(options: FcmOptions) -> Result<Self, FcmError> {
+        let service_account: ServiceAccount = serde_json::from_str(&options.service_account_json)?;
+
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint: format!(
+                "https://fcm.googleapis.com/v1/projects/{}/messages:send",
+                service_account.project_id
+            ),
+            key: EncodingKey::from_rsa_pem(service_account.private_key.as_bytes())?,
+            client_email: service_account.client_email,
+            token_uri: service_account.token_uri,
+            data_messages: options.data_messages,
+            retry_policy: options.retry_policy,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    pub async fn send(
+        &self,
+        device_token: &str,
+        notification: &PushNotification,
+    ) -> Result<(), FcmError> {
+        let message = self.message(device_token, notification);
+
+        let mut attempt = 0;
+
+        loop {
+            match self.send_once(&message).await {
+                Err(FcmError::Rejected { status, reason })
+                    if status >= 500 && attempt + 1 < self.retry_policy.max_attempts =>
+                {
+                    debug!("Retrying fcm send after {}: {}", status, reason);
+
+                    tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn message(&self, device_token: &str, notification: &PushNotification) -> Value {
+        let collapse_id = notification.collapse_id();
+
+        if self.data_messages {
+            let mut data = json!({
+                "body": notification.body,
+                "conversationId": notification.conversation_id,
+            });
+
+            if let Some(title) = &notification.title {
+                data["title"] = json!(title);
+            }
+
+            json!({
+                "message": {
+                    "token": device_token,
+                    "data": data,
+                    "android": {
+                        "collapse_key": collapse_id,
+                        "priority": "high", // so the app gets woken up to show it
+                    },
+                },
+            })
+        } else {
+            json!({
+                "message": {
+                    "token": device_token,
+                    "notification": {
+                        "title": notification.title,
+                        "body": notification.body,
+                    },
+                    "data": {
+                        "conversationId": notification.conversation_id,
+                    },
+                    "android": {
+                        "collapse_key": collapse_id,
+                        "notification": {
+                            "tag": collapse_id, // replaces the conversation's notification already in the tray
+                        },
+                    },
+                },
+            })
+        }
+    }
+
+    async fn send_once(&self, message: &Value) -> Result<(), FcmError> {
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .bearer_auth(self.access_token().await?)
+            .json(message)
+            .send()
+            .await?;
+
+        let status = response.status().as_u16();
+
+        if status == 200 {
+            return Ok(());
+        }
+
+        let error = response
+            .json::<ErrorResponse>()
+            .await
+            .ok()
+            .map(|response| response.error);
+
+        let unregistered = error.as_ref().is_some_and(|error| {
+            error
+                .details
+                .iter()
+                .any(|detail| detail.error_code.as_deref() == Some("UNREGISTERED"))
+        });
+
+        if status == 404 || unregistered {
+            return Err(FcmError::Unregistered);
+        }
+
+        Err(FcmError::Rejected {
+            status,
+            reason: error.map(|error| error.status).unwrap_or_default(),
+        })
+    }
+
+    async fn access_token(&self) -> Result<String, FcmError> {
+        let mut access_token = self.access_token.lock().await; // held while refreshing so only one request does it
+
+        if let Some((token, expires_at)) = access_token.as_ref() {
+            if Instant::now() + ACCESS_TOKEN_REFRESH_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let now = chrono::Utc::now().timestamp();
+
+        let assertion = jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &Claims {
+                iss: &self.client_email,
+                scope: SCOPE,
+                aud: &self.token_uri,
+                iat: now,
+                exp: now + 3600,
+            },
+            &self.key,
+        )?;
+
+        let response = self
+            .client
+            .post(&self.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(FcmError::Auth(response.status().as_u16()));
+        }
+
+        let AccessTokenResponse {
+            access_token: token,
+            expires_in,
+        } = response.json().await?;
+
+        *access_token = Some((
+            token.clone(),
+            Instant::now() + Duration::from_secs(expires_in),
+        ));
+
+        Ok(token)
+    }
+}
@@ -0,0 +1,137 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::runtime_config::{RuntimeConfig, SharedRuntimeConfig};
+
+// buckets that have fully refilled carry no information, so they get dropped once the map grows past this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperationClass {
+    Send,
+    Choose,
+    Query,
+}
+
+#[derive(Clone, Copy)]
+pub struct Budget {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+// what's left of each budget, for showing users why they're being limited
+#[derive(Serialize, JsonSchema)]
+pub struct RemainingTokens {
+    pub send: f64,
+    pub choose: f64,
+    pub query: f64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+    is_bot: bool, // which budgets it refills from
+}
+
+// budgets are read from the runtime config on every check so a reload applies to buckets that already exist
+pub struct RateLimiter {
+    runtime: SharedRuntimeConfig,
+    buckets: Mutex<HashMap<(String, OperationClass), TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(runtime: SharedRuntimeConfig) -> Self {
+        Self {
+            runtime,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // returns how long the user has to wait before the operation would be allowed if they're over budget
+    pub fn check(
+        &self,
+        username: &str,
+        class: OperationClass,
+        is_bot: bool,
+    ) -> Result<(), Duration> {
+        let runtime = self.runtime.load();
+
+        let budget = Self::budget(&runtime, class, is_bot);
+
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|(_, class), bucket| {
+                let budget = Self::budget(&runtime, *class, bucket.is_bot);
+
+                bucket.tokens + Self::refilled_tokens(bucket, budget, now) < budget.burst
+            });
+        }
+
+        let bucket = buckets
+            .entry((username.to_owned(), class))
+            .or_insert(TokenBucket {
+                tokens: budget.burst,
+                refilled_at: now,
+                is_bot,
+            });
+
+        bucket.tokens =
+            (bucket.tokens + Self::refilled_tokens(bucket, budget, now)).min(budget.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / budget.per_second,
+            ))
+        }
+    }
+
+    pub fn remaining(&self, username: &str, is_bot: bool) -> RemainingTokens {
+        let runtime = self.runtime.load();
+
+        let now = Instant::now();
+
+        let buckets = self.buckets.lock().unwrap();
+
+        let remaining = |class| {
+            let budget = Self::budget(&runtime, class, is_bot);
+
+            buckets
+                .get(&(username.to_owned(), class))
+                .map_or(budget.burst, |bucket| {
+                    (bucket.tokens + Self::refilled_tokens(bucket, budget, now)).min(budget.burst)
+                })
+        };
+
+        RemainingTokens {
+            send: remaining(OperationClass::Send),
+            choose: remaining(OperationClass::Choose),
+            query: remaining(OperationClass::Query),
+        }
+    }
+
+    fn budget(runtime: &RuntimeConfig, class: OperationClass, is_bot: bool) -> Budget {
+        match (class, is_bot) {
+            (OperationClass::Send, false) => runtime.send_budget,
+            (OperationClass::Choose, false) => runtime.choose_budget,
+            (OperationClass::Query, false) => runtime.query_budget,
+            (OperationClass::Send, true) => runtime.bot_send_budget,
+            (OperationClass::Choose, true) => runtime.bot_choose_budget,
+            (OperationClass::Query, true) => runtime.bot_query_budget,
+        }
+    }
+
+    fn refilled_tokens(bucket: &TokenBucket, budget: Budget, now: Instant) -> f64 {
+        now.duration_since(bucket.refilled_at).as_secs_f64() * budget.per_second
+    }
+}
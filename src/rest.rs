@@ -0,0 +1,311 @@
+use chrono::prelude::*;
+use hyper::{server::conn::Http, service::service_fn, Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::audit::AuditLog;
+use crate::auth::{
+    self,
+    permissions::{Permission, Permissions},
+    AccessTokenPayload, JWTAuth,
+};
+use crate::conversation_id::{ConversationId, ConversationRole};
+use crate::db::{DatabaseError, Storage};
+use crate::error::ErrorCategory;
+use crate::handshake_limit::HandshakeLimiter;
+use crate::hash::Hasher;
+use crate::models::message::Message;
+use crate::server;
+use crate::transport::Transport;
+
+// read only http endpoints for clients that can't hold a websocket, like share extensions and support tools. served
+// on the same listeners as websockets, any request that isn't an upgrade ends up here. tokens are checked the same way
+// as on the handshake, so ?token= works too
+//
+//   GET /conversations/:id/messages?take=50&afterSentAt=2024-01-01T00:00:00Z
+//   GET /friends
+//
+// bodies are json shaped like the matching websocket responses, errors are {"message": ...} with a status to match
+
+const DEFAULT_TAKE: i8 = 50;
+
+pub struct RestApi {
+    pub db: Arc<dyn Storage>,
+    pub jwt_auth: Arc<JWTAuth>,
+    pub hasher: Arc<Hasher>,
+    pub audit_log: Arc<AuditLog>,
+    pub handshake_limiter: Arc<HandshakeLimiter>, // failed authentications count toward a ban like on websockets
+    pub database_timeout: Duration,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Messages {
+    conversation_id: String,
+    messages: Vec<Message>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Friend {
+    username: String,
+    name: String,
+    friendship_started_on: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct Friends {
+    friends: Vec<Friend>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    message: &'a str,
+}
+
+impl RestApi {
+    // keeps the connection open for more requests until the client closes it
+    pub async fn serve(
+        self: Arc<Self>,
+        transport: Transport,
+        ip: Option<IpAddr>,
+        connection_id: String,
+    ) {
+        let service = service_fn(move |req| {
+            let api = self.clone();
+            let connection_id = connection_id.clone();
+
+            async move { Ok::<_, Infallible>(api.handle(req, ip, &connection_id).await) }
+        });
+
+        if let Err(err) = Http::new()
+            .http1_only(true)
+            .serve_connection(transport, service)
+            .await
+        {
+            debug!("Http connection ended with an error: {}", err);
+        }
+    }
+
+    async fn handle(
+        &self,
+        req: Request<Body>,
+        ip: Option<IpAddr>,
+        connection_id: &str,
+    ) -> Response<Body> {
+        if req.method() != Method::GET {
+            return error(StatusCode::METHOD_NOT_ALLOWED, "Only GET is supported");
+        }
+
+        let payload = match self.authenticate(&req, ip, connection_id).await {
+            Ok(payload) => payload,
+            Err(response) => return response,
+        };
+
+        let segments = req
+            .uri()
+            .path()
+            .trim_matches('/')
+            .split('/')
+            .collect::<Vec<_>>();
+
+        match segments.as_slice() {
+            ["conversations", conversation_id, "messages"] => {
+                self.messages(&payload, conversation_id, req.uri().query())
+                    .await
+            }
+            ["friends"] => self.friends(&payload).await,
+            _ => error(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    async fn authenticate(
+        &self,
+        req: &Request<Body>,
+        ip: Option<IpAddr>,
+        connection_id: &str,
+    ) -> Result<AccessTokenPayload, Response<Body>> {
+        let mut parts = Request::new(());
+        *parts.uri_mut() = req.uri().clone();
+        *parts.headers_mut() = req.headers().clone();
+
+        let payload = match self.jwt_auth.veryify_req(&parts) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return Err(error(StatusCode::UNAUTHORIZED, "Access token required")),
+            Err(err) => {
+                server::handshake_auth_failure(
+                    &self.audit_log,
+                    &self.handshake_limiter,
+                    ip,
+                    None,
+                    connection_id,
+                    err.to_string(),
+                );
+
+                return Err(error(StatusCode::UNAUTHORIZED, &err.to_string()));
+            }
+        };
+
+        match self
+            .database(auth::is_revoked(
+                self.db.as_ref(),
+                &payload,
+                payload.device_id.as_deref(),
+            ))
+            .await
+        {
+            // everything here is a read, which websockets don't allow tokens scoped to write only either
+            Ok(false) if !Permissions::from_payload(&payload).allows(Permission::Read) => {
+                Err(error(
+                    StatusCode::FORBIDDEN,
+                    "Access token does not permit reading",
+                ))
+            }
+            Ok(false) => Ok(payload),
+            Ok(true) => {
+                server::handshake_auth_failure(
+                    &self.audit_log,
+                    &self.handshake_limiter,
+                    ip,
+                    Some(payload.username),
+                    connection_id,
+                    "Access token revoked".to_owned(),
+                );
+
+                Err(error(StatusCode::UNAUTHORIZED, "Access token revoked"))
+            }
+            Err(response) => Err(response),
+        }
+    }
+
+    async fn messages(
+        &self,
+        payload: &AccessTokenPayload,
+        conversation_id: &str,
+        query: Option<&str>,
+    ) -> Response<Body> {
+        let conversation_id = match ConversationId::try_from(
+            percent_decode_str(conversation_id)
+                .decode_utf8_lossy()
+                .into_owned(),
+        ) {
+            Ok(conversation_id) => conversation_id,
+            Err(err) => return error(StatusCode::BAD_REQUEST, &err.to_string()),
+        };
+
+        if conversation_id.get_role_of_username(&self.hasher, &payload.username)
+            == ConversationRole::NotInConversation
+        {
+            return error(StatusCode::FORBIDDEN, "Not in this conversation");
+        }
+
+        let take = match query_param(query, "take").map(|take| take.parse::<i8>()) {
+            None => DEFAULT_TAKE,
+            Some(Ok(take)) if take > 0 => take,
+            Some(_) => return error(StatusCode::BAD_REQUEST, "take must be from 1 to 127"),
+        };
+
+        let after_sent_at = match query_param(query, "afterSentAt").map(|after_sent_at| {
+            DateTime::parse_from_rfc3339(&after_sent_at).map(|after_sent_at| after_sent_at.into())
+        }) {
+            None => Utc.timestamp_opt(0, 0).unwrap(),
+            Some(Ok(after_sent_at)) => after_sent_at,
+            Some(Err(_)) => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "afterSentAt must be an RFC 3339 timestamp",
+                )
+            }
+        };
+
+        let conversation_id = conversation_id.to_string();
+
+        match self
+            .database(self.db.get_messages(
+                &conversation_id,
+                &payload.username,
+                take,
+                after_sent_at,
+            ))
+            .await
+        {
+            Ok(messages) => json(&Messages {
+                conversation_id,
+                messages,
+            }),
+            Err(response) => response,
+        }
+    }
+
+    async fn friends(&self, payload: &AccessTokenPayload) -> Response<Body> {
+        match self.database(self.db.get_friends(&payload.username)).await {
+            Ok(friend_profiles) => json(&Friends {
+                friends: friend_profiles
+                    .into_iter()
+                    .map(|friend_profile| Friend {
+                        username: friend_profile.username,
+                        name: friend_profile.name,
+                        friendship_started_on: Utc
+                            .timestamp_millis_opt(
+                                friend_profile.friendship_started_on.0.num_milliseconds(),
+                            )
+                            .single()
+                            .unwrap_or_default(),
+                    })
+                    .collect(),
+            }),
+            Err(response) => response,
+        }
+    }
+
+    // the same timeout connections use, and the error already turned into a response
+    async fn database<T>(
+        &self,
+        future: impl Future<Output = Result<T, DatabaseError>>,
+    ) -> Result<T, Response<Body>> {
+        match tokio::time::timeout(self.database_timeout, future).await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(err)) => {
+                error!("Database error serving http request: {}", err);
+
+                Err(match err.category() {
+                    ErrorCategory::Transient => {
+                        error(StatusCode::SERVICE_UNAVAILABLE, "Temporarily unavailable")
+                    }
+                    _ => error(StatusCode::INTERNAL_SERVER_ERROR, "Internal error"),
+                })
+            }
+            Err(_) => Err(error(StatusCode::GATEWAY_TIMEOUT, "Timed out")),
+        }
+    }
+}
+
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+
+        (key == name).then(|| percent_decode_str(value).decode_utf8_lossy().into_owned())
+    })
+}
+
+fn json(body: &impl Serialize) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            serde_json::to_vec(body).expect("Response body should serialize"),
+        ))
+        .expect("Response should build")
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = json(&ErrorBody { message });
+
+    *response.status_mut() = status;
+
+    response
+}
@@ -0,0 +1,23 @@
+use rand::Rng;
+use std::time::Duration;
+
+// jittered so a nats or scylla blip doesn't have every connection retrying in lockstep
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // full jitter: anywhere between nothing and the exponential delay for this attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay);
+
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
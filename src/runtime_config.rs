@@ -0,0 +1,92 @@
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::origin::OriginAllowlist;
+use crate::rate_limit::Budget;
+
+// the settings that can change without a restart. connections load them each time they're used instead of copying
+// them when they start, so a reload applies to the ones already open too
+
+pub struct RuntimeConfig {
+    pub send_budget: Budget,
+    pub choose_budget: Budget,
+    pub query_budget: Budget,
+    // bots get their own, usually bigger since one account answers many users
+    pub bot_send_budget: Budget,
+    pub bot_choose_budget: Budget,
+    pub bot_query_budget: Budget,
+    pub max_content_length: usize,
+    pub heartbeat_interval: Duration,
+    pub origin_allowlist: OriginAllowlist,
+    pub log_level: LevelFilter, // applied by whoever installed the subscriber, the server doesn't own logging
+}
+
+pub type SharedRuntimeConfig = Arc<ArcSwap<RuntimeConfig>>;
+
+impl RuntimeConfig {
+    // what every open connection would panic on once it picked the config up, checked before a reload replaces the
+    // running one. init reports the same problems by setting name when it reads them
+    pub fn validate(&self) -> Result<(), String> {
+        if self.heartbeat_interval.is_zero() {
+            return Err("heartbeat interval must be above 0".to_owned());
+        }
+
+        let budgets = [
+            ("send", self.send_budget),
+            ("choose", self.choose_budget),
+            ("query", self.query_budget),
+            ("bot send", self.bot_send_budget),
+            ("bot choose", self.bot_choose_budget),
+            ("bot query", self.bot_query_budget),
+        ];
+
+        for (name, budget) in budgets {
+            if !(budget.burst.is_finite() && budget.burst >= 0.0) {
+                return Err(format!("{} rate limit burst must be 0 or more", name));
+            }
+
+            if !(budget.per_second.is_finite() && budget.per_second > 0.0) {
+                return Err(format!("{} rate limit refill must be above 0", name));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            send_budget: Budget {
+                burst: 20.0,
+                per_second: 5.0,
+            },
+            choose_budget: Budget {
+                burst: 5.0,
+                per_second: 0.1,
+            },
+            query_budget: Budget {
+                burst: 30.0,
+                per_second: 10.0,
+            },
+            bot_send_budget: Budget {
+                burst: 100.0,
+                per_second: 25.0,
+            },
+            bot_choose_budget: Budget {
+                burst: 20.0,
+                per_second: 1.0,
+            },
+            bot_query_budget: Budget {
+                burst: 100.0,
+                per_second: 25.0,
+            },
+            max_content_length: 4096,
+            heartbeat_interval: Duration::from_millis(30_000),
+            origin_allowlist: OriginAllowlist::new([]),
+            log_level: LevelFilter::INFO,
+        }
+    }
+}
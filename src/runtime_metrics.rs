@@ -0,0 +1,82 @@
+use std::fmt::Write;
+
+// tokio runtime metrics in prometheus text format, served on the health port. tokio only exposes them when built
+// with RUSTFLAGS="--cfg tokio_unstable"
+
+#[cfg(tokio_unstable)]
+pub fn render() -> String {
+    let metrics = tokio::runtime::Handle::current().metrics();
+
+    let mut out = String::new();
+
+    gauge(&mut out, "tokio_workers", metrics.num_workers());
+    gauge(&mut out, "tokio_active_tasks", metrics.active_tasks_count());
+    gauge(
+        &mut out,
+        "tokio_injection_queue_depth",
+        metrics.injection_queue_depth(),
+    );
+    gauge(
+        &mut out,
+        "tokio_blocking_queue_depth",
+        metrics.blocking_queue_depth(),
+    );
+
+    let _ = writeln!(out, "# TYPE tokio_worker_local_queue_depth gauge");
+    let _ = writeln!(out, "# TYPE tokio_worker_polls_total counter");
+    let _ = writeln!(out, "# TYPE tokio_worker_busy_seconds_total counter");
+    let _ = writeln!(out, "# TYPE tokio_worker_mean_poll_seconds gauge");
+
+    for worker in 0..metrics.num_workers() {
+        let polls = metrics.worker_poll_count(worker);
+        let busy = metrics.worker_total_busy_duration(worker);
+
+        let _ = writeln!(
+            out,
+            "tokio_worker_local_queue_depth{{worker=\"{}\"}} {}",
+            worker,
+            metrics.worker_local_queue_depth(worker)
+        );
+        let _ = writeln!(
+            out,
+            "tokio_worker_polls_total{{worker=\"{}\"}} {}",
+            worker, polls
+        );
+        let _ = writeln!(
+            out,
+            "tokio_worker_busy_seconds_total{{worker=\"{}\"}} {}",
+            worker,
+            busy.as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "tokio_worker_mean_poll_seconds{{worker=\"{}\"}} {}",
+            worker,
+            if polls == 0 {
+                0.0
+            } else {
+                busy.as_secs_f64() / polls as f64
+            }
+        );
+    }
+
+    out
+}
+
+#[cfg(not(tokio_unstable))]
+pub fn render() -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(
+        out,
+        "# tokio runtime metrics need RUSTFLAGS=\"--cfg tokio_unstable\""
+    );
+
+    out
+}
+
+#[cfg(tokio_unstable)]
+fn gauge(out: &mut String, name: &str, value: usize) {
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
@@ -0,0 +1,1117 @@
+use arc_swap::ArcSwap;
+use futures_util::future::{join_all, BoxFuture};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::DuplexStream;
+use tokio::net::TcpListener;
+use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
+use tungstenite::{
+    http::{HeaderValue, Request, Response, StatusCode},
+    protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+};
+use uuid::Uuid;
+
+use crate::account_deletion::{self, AccountDeletion};
+use crate::analytics::Analytics;
+use crate::audit::AuditLog;
+use crate::auth::{
+    self,
+    bot::{BotAuthError, BotCredentials},
+    identify::IdentifyError,
+    permissions::Permissions,
+    AccessTokenPayload, JWTAuth,
+};
+use crate::challenge::ChallengeVerifier;
+use crate::cluster::Membership;
+use crate::connection::{Connection, ConnectionRegistry, Encoding};
+use crate::db::{DatabaseError, MemoryStorage, Storage};
+use crate::digest::{self, DailyDigest, UserDigest};
+use crate::handshake_limit::{HandshakeLimiter, HandshakeLimits};
+use crate::hash::Hasher;
+use crate::jobs::{JobQueue, MemoryJobQueue, Workers};
+use crate::maintenance::{self, PresenceTimeout, RetentionSweep};
+use crate::message_bus::{MemoryBus, MessageBus};
+use crate::presence::{MemoryPresence, Presence};
+use crate::push::PushProviders;
+use crate::rate_limit::{Budget, RateLimiter};
+use crate::rest::RestApi;
+use crate::retry_policy::RetryPolicy;
+use crate::runtime_config::{RuntimeConfig, SharedRuntimeConfig};
+use crate::spam::{SpamDetector, SpamThresholds};
+use crate::storage::object_store::ObjectStore;
+use crate::streaks::{self, StreakRollover};
+use crate::transport::{Listener, Transport};
+use crate::webhook::Webhooks;
+use crate::{cluster, grpc, health, outbox, push};
+
+// the gateway itself, so it can be embedded in integration tests and other binaries. main just fills this in from
+// the environment
+//
+// storage and the message bus default to in memory ones like --dev uses. auth and the hasher have no sensible
+// default since they depend on secrets shared with the api
+
+// entries in the default in memory presence outlive a few missed heartbeats
+const PRESENCE_TTL: Duration = Duration::from_secs(90);
+
+// how often to check whether every connection has closed while shutting down
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Settings {
+    pub runtime: SharedRuntimeConfig, // keep a clone to reload it while the server is running
+    pub spam_thresholds: SpamThresholds,
+    pub handshake_limits: HandshakeLimits,
+    pub proxy_protocol: bool, // every connection starts with a proxy protocol header, which says whose it is
+    pub max_attachment_size: u64,
+    pub key_distribution_ttl: Duration, // how long key material waits for a participant who's offline
+    pub mention_ttl: Duration,          // how long a mention stays in the user's list of them
+    pub max_frame_size: usize,
+    pub outbox_drain_interval: Duration,
+    pub outbox_max_age: Duration,
+    pub operation_concurrency: usize,
+    pub operation_queue_limit: usize,
+    pub database_timeout: Duration,
+    pub nats_timeout: Duration,
+    pub nats_outage_limit: Duration,
+    pub nats_publish_max_attempts: u32,
+    pub send_timeout: Duration, // connections that can't take a frame in this long are closed as slow consumers
+    pub shutdown_grace: Duration, // how long connections get to close after a shutdown signal
+    pub identify_deadline: Duration,
+    pub push_providers: Arc<PushProviders>,
+    pub push_worker: bool,
+    pub health_port: Option<u16>,    // no health server when unset
+    pub grpc_port: Option<u16>,      // no grpc service when unset
+    pub node_id: String, // how other nodes address this one, has to be unique in the cluster
+    pub announce_interval: Duration, // how often this node tells the rest of the cluster about itself
+    pub publish_audit_log: bool,     // to the message bus, on top of storing it
+    pub job_workers: usize,          // how many jobs this node handles at once, none when 0
+    pub job_retry_policy: RetryPolicy,
+    pub presence_timeout_interval: Duration,
+    pub conversation_retention: Option<Duration>, // conversations are kept forever when unset
+    pub retention_sweep_interval: Duration,
+    pub daily_digest: bool, // a push once a day for users who have been away, see digest
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            runtime: Arc::new(ArcSwap::from_pointee(RuntimeConfig::default())),
+            spam_thresholds: SpamThresholds {
+                burst_window: Duration::from_secs(10),
+                burst_limit: 30,
+                duplicate_sample: 10,
+                duplicate_ratio: 0.6,
+                distinct_choosees_per_hour: 20,
+                mute_after_strikes: 3,
+                close_after_strikes: 5,
+                mute_duration: Duration::from_secs(300),
+                strike_decay: Duration::from_secs(600),
+            },
+            handshake_limits: HandshakeLimits {
+                handshakes: Budget {
+                    burst: 20.0,
+                    per_second: 2.0,
+                },
+                auth_failure_limit: 10,
+                auth_failure_window: Duration::from_secs(60),
+                ban_duration: Duration::from_secs(300),
+            },
+            proxy_protocol: false,
+            max_attachment_size: 25 << 20,
+            key_distribution_ttl: Duration::from_secs(86_400),
+            mention_ttl: Duration::from_secs(2_592_000),
+            max_frame_size: 64 << 10,
+            outbox_drain_interval: Duration::from_millis(5000),
+            outbox_max_age: Duration::from_secs(3600),
+            operation_concurrency: 16,
+            operation_queue_limit: 64,
+            database_timeout: Duration::from_millis(5000),
+            nats_timeout: Duration::from_millis(2000),
+            nats_outage_limit: Duration::from_millis(30_000),
+            nats_publish_max_attempts: 4,
+            send_timeout: Duration::from_secs(10),
+            shutdown_grace: Duration::from_secs(10),
+            identify_deadline: Duration::from_millis(5000),
+            push_providers: Arc::new(PushProviders {
+                apns: None,
+                fcm: None,
+            }),
+            push_worker: false,
+            health_port: None,
+            grpc_port: None,
+            node_id: Uuid::new_v4().to_string(),
+            announce_interval: Duration::from_secs(10),
+            publish_audit_log: false,
+            job_workers: 4,
+            job_retry_policy: RetryPolicy {
+                max_attempts: 5,
+                base_delay: Duration::from_secs(1),
+                max_delay: Duration::from_secs(300),
+            },
+            presence_timeout_interval: Duration::from_secs(60),
+            conversation_retention: None,
+            retention_sweep_interval: Duration::from_secs(3600),
+            daily_digest: false,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error("Server builder is missing {0}")]
+    Missing(&'static str),
+    #[error("Failed to bind: {0}")]
+    Bind(std::io::Error),
+    #[error("Invalid runtime config: {0}")]
+    InvalidRuntimeConfig(String), // the same check a reload gets, since connections would panic on it
+}
+
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            db: None,
+            message_bus: None,
+            jwt_auth: None,
+            hasher: None,
+            object_store: None,
+            analytics: None,
+            webhooks: None,
+            presence: None,
+            job_queue: None,
+            challenges: None,
+            worker: false,
+            addrs: Vec::new(),
+            unix_path: None,
+            on_bound: None,
+            shutdown: None,
+            settings: Settings::default(),
+        }
+    }
+}
+
+pub struct ServerBuilder {
+    db: Option<Arc<dyn Storage>>,
+    message_bus: Option<Arc<dyn MessageBus>>,
+    jwt_auth: Option<Arc<JWTAuth>>,
+    hasher: Option<Arc<Hasher>>,
+    object_store: Option<Arc<ObjectStore>>,
+    analytics: Option<Arc<Analytics>>,
+    webhooks: Option<Arc<Webhooks>>,
+    presence: Option<Arc<dyn Presence>>,
+    job_queue: Option<Arc<dyn JobQueue>>,
+    challenges: Option<Arc<dyn ChallengeVerifier>>,
+    worker: bool,
+    addrs: Vec<SocketAddr>, // 127.0.0.1:8080 when nothing is bound
+    unix_path: Option<PathBuf>,
+    on_bound: Option<BoundCallback>,
+    shutdown: Option<BoxFuture<'static, ()>>,
+    settings: Settings,
+}
+
+type BoundCallback = Box<dyn FnOnce(&[SocketAddr]) + Send>;
+
+// everything a connection needs that's shared between all of them
+pub(crate) struct Shared {
+    pub(crate) db: Arc<dyn Storage>,
+    message_bus: Arc<dyn MessageBus>,
+    pub(crate) jwt_auth: Arc<JWTAuth>,
+    hasher: Arc<Hasher>,
+    object_store: Option<Arc<ObjectStore>>,
+    analytics: Option<Arc<Analytics>>,
+    webhooks: Option<Arc<Webhooks>>,
+    presence: Arc<dyn Presence>,
+    membership: Arc<Membership>,
+    pub(crate) audit_log: Arc<AuditLog>,
+    job_queue: Arc<dyn JobQueue>,
+    rest: Arc<RestApi>,
+    rate_limiter: Arc<RateLimiter>,
+    spam_detector: Arc<SpamDetector>,
+    pub(crate) handshake_limiter: Arc<HandshakeLimiter>,
+    challenges: Option<Arc<dyn ChallengeVerifier>>,
+    registry: Arc<ConnectionRegistry>,
+    websocket_config: WebSocketConfig,
+    pub(crate) settings: Settings,
+}
+
+impl ServerBuilder {
+    pub fn with_storage(mut self, db: Arc<dyn Storage>) -> Self {
+        self.db = Some(db);
+        self
+    }
+
+    pub fn with_bus(mut self, message_bus: Arc<dyn MessageBus>) -> Self {
+        self.message_bus = Some(message_bus);
+        self
+    }
+
+    pub fn with_auth(mut self, jwt_auth: JWTAuth) -> Self {
+        self.jwt_auth = Some(Arc::new(jwt_auth));
+        self
+    }
+
+    pub fn with_hasher(mut self, hasher: Arc<Hasher>) -> Self {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    // uploads are turned off without one
+    pub fn with_object_store(mut self, object_store: Option<Arc<ObjectStore>>) -> Self {
+        self.object_store = object_store;
+        self
+    }
+
+    // nothing is mirrored for analytics without one
+    pub fn with_analytics(mut self, analytics: Option<Arc<Analytics>>) -> Self {
+        self.analytics = analytics;
+        self
+    }
+
+    // no webhooks are sent without one
+    pub fn with_webhooks(mut self, webhooks: Option<Arc<Webhooks>>) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    // in memory unless given, which only knows about this node's connections
+    pub fn with_presence(mut self, presence: Arc<dyn Presence>) -> Self {
+        self.presence = Some(presence);
+        self
+    }
+
+    // in memory unless given, which only hands jobs to this node's workers
+    pub fn with_job_queue(mut self, job_queue: Arc<dyn JobQueue>) -> Self {
+        self.job_queue = Some(job_queue);
+        self
+    }
+
+    // nobody has to solve a challenge without one
+    pub fn with_challenges(mut self, challenges: Option<Arc<dyn ChallengeVerifier>>) -> Self {
+        self.challenges = challenges;
+        self
+    }
+
+    // only handles jobs, nothing is bound and no connections are accepted
+    pub fn worker(mut self) -> Self {
+        self.worker = true;
+        self
+    }
+
+    pub fn with_settings(mut self, settings: Settings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    // can be called more than once to listen on several addresses, like both 0.0.0.0 and ::
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.addrs.push(addr);
+        self
+    }
+
+    // for a proxy on the same host. with only this there's no tcp listener
+    pub fn bind_unix(mut self, path: PathBuf) -> Self {
+        self.unix_path = Some(path);
+        self
+    }
+
+    // called with the addresses actually bound once every listener is up, which is how a test harness that bound
+    // port 0 finds out where to connect
+    pub fn on_bound(mut self, on_bound: impl FnOnce(&[SocketAddr]) + Send + 'static) -> Self {
+        self.on_bound = Some(Box::new(on_bound));
+        self
+    }
+
+    // once this resolves nothing new is accepted and every connection is closed as the server shutting down, so
+    // clients reconnect to another node straight away. without it the server runs until the process is killed
+    pub fn with_shutdown(mut self, shutdown: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Box::pin(shutdown));
+        self
+    }
+
+    // only returns if the server couldn't start, or after shutting down
+    pub async fn run(self) -> Result<(), ServerError> {
+        let db = self
+            .db
+            .unwrap_or_else(|| Arc::new(MemoryStorage::default()));
+        let message_bus = self
+            .message_bus
+            .unwrap_or_else(|| Arc::new(MemoryBus::default()));
+        let presence = self
+            .presence
+            .unwrap_or_else(|| Arc::new(MemoryPresence::new(PRESENCE_TTL)));
+        let jwt_auth = self.jwt_auth.ok_or(ServerError::Missing("auth"))?;
+        let hasher = self.hasher.ok_or(ServerError::Missing("hasher"))?;
+        let job_queue = self
+            .job_queue
+            .unwrap_or_else(|| Arc::new(MemoryJobQueue::default()));
+        let settings = self.settings;
+
+        settings
+            .runtime
+            .load()
+            .validate()
+            .map_err(ServerError::InvalidRuntimeConfig)?;
+
+        let audit_log = Arc::new(AuditLog::new(
+            db.clone(),
+            message_bus.clone(),
+            settings.publish_audit_log,
+        ));
+
+        let mut workers = Workers::new(job_queue.clone(), db.clone(), settings.job_retry_policy)
+            .handle(
+                account_deletion::JOB_KIND,
+                Arc::new(AccountDeletion {
+                    db: db.clone(),
+                    message_bus: message_bus.clone(),
+                    hasher: hasher.clone(),
+                    audit_log: audit_log.clone(),
+                }),
+            )
+            .handle(
+                maintenance::PRESENCE_TIMEOUT_JOB_KIND,
+                Arc::new(PresenceTimeout {
+                    presence: presence.clone(),
+                }),
+            )
+            .handle(
+                streaks::JOB_KIND,
+                Arc::new(StreakRollover { db: db.clone() }),
+            );
+
+        tokio::task::spawn(maintenance::schedule(
+            job_queue.clone(),
+            maintenance::PRESENCE_TIMEOUT_JOB_KIND,
+            settings.presence_timeout_interval,
+        ));
+
+        tokio::task::spawn(maintenance::schedule(
+            job_queue.clone(),
+            streaks::JOB_KIND,
+            streaks::ROLLOVER_INTERVAL,
+        ));
+
+        if let Some(retention) = settings.conversation_retention {
+            workers = workers.handle(
+                maintenance::RETENTION_SWEEP_JOB_KIND,
+                Arc::new(RetentionSweep {
+                    db: db.clone(),
+                    retention,
+                }),
+            );
+
+            tokio::task::spawn(maintenance::schedule(
+                job_queue.clone(),
+                maintenance::RETENTION_SWEEP_JOB_KIND,
+                settings.retention_sweep_interval,
+            ));
+        }
+
+        if settings.daily_digest {
+            workers = workers
+                .handle(
+                    digest::JOB_KIND,
+                    Arc::new(DailyDigest {
+                        db: db.clone(),
+                        job_queue: job_queue.clone(),
+                    }),
+                )
+                .handle(
+                    digest::USER_JOB_KIND,
+                    Arc::new(UserDigest {
+                        db: db.clone(),
+                        message_bus: message_bus.clone(),
+                        hasher: hasher.clone(),
+                    }),
+                );
+
+            tokio::task::spawn(maintenance::schedule(
+                job_queue.clone(),
+                digest::JOB_KIND,
+                digest::INTERVAL,
+            ));
+        }
+
+        if self.worker {
+            if let Some(health_port) = settings.health_port {
+                tokio::task::spawn(health::serve(health_port, db.clone(), message_bus.clone()));
+            }
+
+            info!(
+                "Handling jobs only, with {} workers",
+                settings.job_workers.max(1)
+            );
+
+            workers.run(settings.job_workers).await;
+
+            return Ok(());
+        }
+
+        if settings.job_workers > 0 {
+            tokio::task::spawn(workers.run(settings.job_workers));
+        }
+
+        let addrs = if self.addrs.is_empty() && self.unix_path.is_none() {
+            vec![SocketAddr::from(([127, 0, 0, 1], 8080))]
+        } else {
+            self.addrs
+        };
+
+        let mut listeners = Vec::with_capacity(addrs.len());
+        let mut bound_addrs = Vec::with_capacity(addrs.len());
+
+        for addr in addrs {
+            let listener = TcpListener::bind(addr).await.map_err(ServerError::Bind)?;
+
+            let bound_addr = listener.local_addr().map_err(ServerError::Bind)?;
+
+            info!("Listening on {}", bound_addr);
+
+            listeners.push(Listener::Tcp(listener));
+            bound_addrs.push(bound_addr);
+        }
+
+        if let Some(unix_path) = &self.unix_path {
+            listeners.push(
+                Listener::bind_unix(unix_path)
+                    .await
+                    .map_err(ServerError::Bind)?,
+            );
+
+            info!("Listening on {}", unix_path.display());
+        }
+
+        if let Some(on_bound) = self.on_bound {
+            on_bound(&bound_addrs);
+        }
+
+        if let Some(health_port) = settings.health_port {
+            tokio::task::spawn(health::serve(health_port, db.clone(), message_bus.clone()));
+        }
+
+        tokio::task::spawn(outbox::drain_periodically(
+            db.clone(),
+            message_bus.clone(),
+            settings.node_id.clone(),
+            settings.outbox_drain_interval,
+            settings.outbox_max_age,
+        ));
+
+        let registry = Arc::new(ConnectionRegistry::new());
+
+        if settings.push_worker {
+            if settings.push_providers.is_empty() {
+                warn!("PUSH_WORKER is set but no push provider is configured");
+            } else {
+                tokio::task::spawn(push::deliver(
+                    db.clone(),
+                    message_bus.clone(),
+                    presence.clone(),
+                    registry.clone(),
+                    settings.push_providers.clone(),
+                ));
+            }
+        }
+
+        tokio::task::spawn(cluster::handle_kicks(
+            message_bus.clone(),
+            settings.node_id.clone(),
+            registry.clone(),
+        ));
+
+        let handshake_limiter = Arc::new(HandshakeLimiter::new(settings.handshake_limits));
+
+        tokio::task::spawn(cluster::handle_bans(
+            message_bus.clone(),
+            handshake_limiter.clone(),
+        ));
+
+        let membership = Arc::new(Membership::new(settings.announce_interval));
+
+        tokio::task::spawn(cluster::announce(
+            message_bus.clone(),
+            settings.node_id.clone(),
+            registry.clone(),
+            membership.clone(),
+        ));
+
+        tokio::task::spawn(cluster::track_membership(
+            message_bus.clone(),
+            membership.clone(),
+        ));
+
+        let rest = Arc::new(RestApi {
+            db: db.clone(),
+            jwt_auth: jwt_auth.clone(),
+            hasher: hasher.clone(),
+            audit_log: audit_log.clone(),
+            handshake_limiter: handshake_limiter.clone(),
+            database_timeout: settings.database_timeout,
+        });
+
+        let shared = Arc::new(Shared {
+            db,
+            message_bus,
+            jwt_auth,
+            hasher,
+            object_store: self.object_store,
+            analytics: self.analytics,
+            webhooks: self.webhooks,
+            presence,
+            membership,
+            audit_log,
+            job_queue,
+            rest,
+            rate_limiter: Arc::new(RateLimiter::new(settings.runtime.clone())),
+            spam_detector: Arc::new(SpamDetector::new(settings.spam_thresholds)),
+            handshake_limiter,
+            challenges: self.challenges,
+            registry,
+            websocket_config: WebSocketConfig {
+                max_message_size: Some(settings.max_frame_size),
+                max_frame_size: Some(settings.max_frame_size),
+                ..Default::default()
+            },
+            settings,
+        });
+
+        if let Some(grpc_port) = shared.settings.grpc_port {
+            tokio::task::spawn(grpc::serve(grpc_port, shared.clone()));
+        }
+
+        let shutdown = self
+            .shutdown
+            .unwrap_or_else(|| Box::pin(std::future::pending()));
+
+        tokio::select! {
+            _ = join_all(
+                listeners
+                    .into_iter()
+                    .map(|listener| accept_loop(shared.clone(), listener)),
+            ) => return Ok(()),
+            _ = shutdown => {}
+        }
+
+        // the listeners are gone with the accept loops, connections run in their own tasks and close themselves
+        let closing = shared.registry.shutdown();
+
+        info!("Shutting down, closing {} connections", closing);
+
+        let deadline = tokio::time::Instant::now() + shared.settings.shutdown_grace;
+
+        while shared.registry.count() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+        }
+
+        Ok(())
+    }
+}
+
+// for every way in, the websocket handshake, grpc and the rest api, so failures count against the address they came
+// from whichever one it tried
+pub(crate) fn handshake_auth_failure(
+    audit_log: &Arc<AuditLog>,
+    handshake_limiter: &HandshakeLimiter,
+    ip: Option<IpAddr>,
+    username: Option<String>,
+    connection_id: &str,
+    detail: String,
+) {
+    if let Some(ban) = ip.and_then(|ip| handshake_limiter.auth_failed(ip)) {
+        audit_log.banned(&ban);
+    }
+
+    audit_log.auth_failed(username, connection_id, detail);
+}
+
+fn is_websocket_upgrade(head: &[u8]) -> bool {
+    String::from_utf8_lossy(head).lines().any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("upgrade")
+                && value.trim().eq_ignore_ascii_case("websocket")
+        })
+    })
+}
+
+// enough of an upgrade request to read the access token and device id out of, the same way the handshake callback does.
+// None when it doesn't parse, leaving tungstenite to reject it
+fn upgrade_request(head: &[u8]) -> Option<Request<()>> {
+    let mut lines = std::str::from_utf8(head).ok()?.split("\r\n");
+
+    let mut builder = Request::builder().uri(lines.next()?.split(' ').nth(1)?);
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+
+        builder = builder.header(name.trim(), value.trim());
+    }
+
+    builder.body(()).ok()
+}
+
+// None when the request has no valid access token to check, which the handshake callback deals with
+async fn revoked_before_upgrade(
+    shared: &Shared,
+    req: &Request<()>,
+) -> Option<Result<bool, DatabaseError>> {
+    let payload = shared.jwt_auth.veryify_req(req).ok()??;
+
+    let device_id = payload
+        .device_id
+        .clone()
+        .or_else(|| JWTAuth::requested_device_id(req));
+
+    let revoked = auth::is_revoked(shared.db.as_ref(), &payload, device_id.as_deref()).await;
+
+    if let Err(err) = &revoked {
+        error!("Error checking access token revocation: {}", err);
+    }
+
+    Some(revoked)
+}
+
+async fn accept_loop(shared: Arc<Shared>, listener: Listener) {
+    loop {
+        match listener.accept().await {
+            Ok(stream) => {
+                let connection_id = Uuid::new_v4().to_string();
+
+                let span = info_span!("connection", connection_id = %connection_id);
+
+                tokio::task::spawn(accept(shared.clone(), stream, connection_id).instrument(span));
+            }
+            Err(_) => {
+                error!("Error accepting connection");
+                continue;
+            }
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)] // the handshake callback's error type is tungstenite's
+async fn accept(shared: Arc<Shared>, mut stream: Transport, connection_id: String) {
+    if shared.settings.proxy_protocol {
+        match tokio::time::timeout(
+            shared.settings.identify_deadline,
+            stream.read_proxy_header(),
+        )
+        .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                debug!("Error reading proxy protocol header: {}", err);
+
+                return;
+            }
+            Err(_) => {
+                debug!("Proxy did not send a header before the deadline");
+
+                return;
+            }
+        }
+    }
+
+    let ip = stream.peer_addr();
+
+    // dropped without a response, so a ban costs as little as possible
+    if ip.is_some_and(|ip| shared.handshake_limiter.is_banned(ip)) {
+        return;
+    }
+
+    // anything other than a websocket upgrade is for the rest api
+    let upgrade_request =
+        match tokio::time::timeout(shared.settings.identify_deadline, stream.peek_head()).await {
+            Ok(Ok(head)) if !is_websocket_upgrade(head) => {
+                shared.rest.clone().serve(stream, ip, connection_id).await;
+
+                return;
+            }
+            Ok(Ok(head)) => upgrade_request(head),
+            Ok(Err(err)) => {
+                debug!("Error reading request: {}", err);
+
+                return;
+            }
+            Err(_) => {
+                debug!("Client did not send a request before the deadline");
+
+                return;
+            }
+        };
+
+    // the handshake callback can't wait on storage, so a token that came with the request is checked for revocation
+    // before the handshake starts. one sent in the identify frame can only be checked once the upgrade is done
+    let revoked = match &upgrade_request {
+        Some(req) => revoked_before_upgrade(&shared, req).await,
+        None => None,
+    };
+
+    let mut revocation_checked = false;
+    let mut access_token_payload: Option<AccessTokenPayload> = None;
+    let mut bot_credentials: Option<BotCredentials> = None;
+    let mut device_id: Option<String> = None;
+    let mut encoding = Encoding::Json;
+
+    match tokio_tungstenite::accept_hdr_async_with_config(
+        stream,
+        |req: &Request<()>, mut res: Response<()>| {
+            if let Some(ban) = ip.and_then(|ip| shared.handshake_limiter.handshake(ip)) {
+                shared.audit_log.banned(&ban);
+
+                *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+
+                return Err(Response::from_parts(
+                    res.into_parts().0,
+                    Some(format!("Too many connection attempts [{}]", connection_id)),
+                ));
+            }
+
+            if !shared.settings.runtime.load().origin_allowlist.allows(req) {
+                *res.status_mut() = StatusCode::FORBIDDEN;
+
+                return Err(Response::from_parts(
+                    res.into_parts().0,
+                    Some(format!("Origin not allowed [{}]", connection_id)),
+                ));
+            }
+
+            // an api key is checked against storage once the handshake is done, since this can't wait on it
+            let authenticated = match BotCredentials::from_headers(req.headers()) {
+                Ok(Some(credentials)) => {
+                    bot_credentials = Some(credentials);
+
+                    Ok(())
+                }
+                Ok(None) => shared
+                    .jwt_auth
+                    .veryify_req(req)
+                    .map(|payload| access_token_payload = payload)
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err.to_string()),
+            };
+
+            let authenticated = match (&access_token_payload, &revoked) {
+                (Some(_), Some(Ok(true))) => Err("Access token revoked".to_owned()),
+                (Some(_), Some(Err(_))) => {
+                    *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+
+                    return Err(Response::from_parts(
+                        res.into_parts().0,
+                        Some(format!("Unable to verify access token [{}]", connection_id)),
+                    ));
+                }
+                (Some(_), Some(Ok(false))) => {
+                    revocation_checked = true;
+
+                    authenticated
+                }
+                _ => authenticated,
+            };
+
+            match authenticated {
+                Ok(()) => {
+                    device_id = JWTAuth::requested_device_id(req);
+
+                    if let Some(negotiated_encoding) = Encoding::negotiate(req) {
+                        encoding = negotiated_encoding;
+
+                        res.headers_mut().insert(
+                            Encoding::subprotocol_header(),
+                            HeaderValue::from_static(encoding.subprotocol()),
+                        );
+                    }
+
+                    Ok(res)
+                }
+                Err(err) => {
+                    handshake_auth_failure(
+                        &shared.audit_log,
+                        &shared.handshake_limiter,
+                        ip,
+                        access_token_payload
+                            .as_ref()
+                            .map(|payload| payload.username.clone()),
+                        &connection_id,
+                        err.clone(),
+                    );
+
+                    *res.status_mut() = StatusCode::UNAUTHORIZED;
+
+                    Err(Response::from_parts(
+                        res.into_parts().0,
+                        Some(format!("{} [{}]", err, connection_id)),
+                    ))
+                }
+            }
+        },
+        Some(shared.websocket_config),
+    )
+    .await
+    {
+        Ok(mut websocket) => {
+            let is_bot = bot_credentials.is_some();
+
+            if let Some(bot_credentials) = bot_credentials {
+                match bot_credentials.verify(shared.db.as_ref()).await {
+                    Ok(payload) => access_token_payload = Some(payload),
+                    Err(BotAuthError::DatabaseError(err)) => {
+                        error!("Error checking api key: {}", err);
+
+                        let _ = websocket
+                            .close(Some(CloseFrame {
+                                code: CloseCode::Again,
+                                reason: format!("Unable to verify api key [{}]", connection_id)
+                                    .into(),
+                            }))
+                            .await;
+
+                        return;
+                    }
+                    Err(err) => {
+                        handshake_auth_failure(
+                            &shared.audit_log,
+                            &shared.handshake_limiter,
+                            ip,
+                            None,
+                            &connection_id,
+                            err.to_string(),
+                        );
+
+                        let _ = websocket
+                            .close(Some(CloseFrame {
+                                code: CloseCode::Policy,
+                                reason: format!("{} [{}]", err, connection_id).into(),
+                            }))
+                            .await;
+
+                        return;
+                    }
+                }
+            }
+
+            let access_token_payload = match access_token_payload {
+                Some(access_token_payload) => access_token_payload,
+                None => match auth::identify::identify(
+                    &mut websocket,
+                    &shared.jwt_auth,
+                    shared.settings.identify_deadline,
+                )
+                .await
+                {
+                    Ok((access_token_payload, identified_device_id)) => {
+                        device_id = device_id.or(identified_device_id);
+
+                        access_token_payload
+                    }
+                    Err(err) => {
+                        info!("Closing unidentified websocket connection: {}", err);
+
+                        if let IdentifyError::InvalidToken(err) = &err {
+                            handshake_auth_failure(
+                                &shared.audit_log,
+                                &shared.handshake_limiter,
+                                ip,
+                                None,
+                                &connection_id,
+                                err.to_string(),
+                            );
+                        }
+
+                        let _ = websocket
+                            .close(Some(CloseFrame {
+                                code: CloseCode::Policy,
+                                reason: format!("Valid access token required [{}]", connection_id)
+                                    .into(),
+                            }))
+                            .await;
+
+                        return;
+                    }
+                },
+            };
+
+            // the token's claim wins over whatever the client said
+            let device_id = access_token_payload.device_id.clone().or(device_id);
+
+            // the identify path, bot keys, and anything the request didn't give away before the handshake
+            let revocation = match revocation_checked {
+                true => Ok(false),
+                false => {
+                    auth::is_revoked(
+                        shared.db.as_ref(),
+                        &access_token_payload,
+                        device_id.as_deref(),
+                    )
+                    .await
+                }
+            };
+
+            match revocation {
+                Ok(false) => {}
+                Ok(true) => {
+                    handshake_auth_failure(
+                        &shared.audit_log,
+                        &shared.handshake_limiter,
+                        ip,
+                        Some(access_token_payload.username.clone()),
+                        &connection_id,
+                        "Access token revoked".to_owned(),
+                    );
+
+                    let _ = websocket
+                        .close(Some(CloseFrame {
+                            code: CloseCode::Policy,
+                            reason: format!("Access token revoked [{}]", connection_id).into(),
+                        }))
+                        .await;
+
+                    return;
+                }
+                Err(err) => {
+                    error!("Error checking access token revocation: {}", err);
+
+                    let _ = websocket
+                        .close(Some(CloseFrame {
+                            code: CloseCode::Again,
+                            reason: format!("Unable to verify access token [{}]", connection_id)
+                                .into(),
+                        }))
+                        .await;
+
+                    return;
+                }
+            }
+
+            let username = access_token_payload.username.clone();
+
+            let conn = connection(
+                &shared,
+                connection_id,
+                websocket,
+                encoding,
+                access_token_payload,
+                device_id,
+                is_bot,
+            );
+
+            if let Err(fatal_connection_error) = conn.handle().await {
+                error!(
+                    "Error during websocket connection for user with username {}: {}",
+                    username, fatal_connection_error
+                );
+            };
+        }
+        Err(err) => {
+            error!("Error during websocket handshake: {}", err);
+        }
+    }
+}
+
+// everything but the websocket and who it's for is the same for every connection
+fn connection(
+    shared: &Shared,
+    connection_id: String,
+    websocket: WebSocketStream<Transport>,
+    encoding: Encoding,
+    payload: AccessTokenPayload,
+    device_id: Option<String>,
+    is_bot: bool,
+) -> Connection {
+    let settings = &shared.settings;
+
+    let ip = websocket.get_ref().peer_addr();
+
+    Connection {
+        connection_id,
+        websocket,
+        encoding,
+        db: shared.db.clone(),
+        message_bus: shared.message_bus.clone(),
+        hasher: shared.hasher.clone(),
+        object_store: shared.object_store.clone(),
+        analytics: shared.analytics.clone(),
+        webhooks: shared.webhooks.clone(),
+        presence: shared.presence.clone(),
+        membership: shared.membership.clone(),
+        audit_log: shared.audit_log.clone(),
+        job_queue: shared.job_queue.clone(),
+        node_id: settings.node_id.clone(),
+        rate_limiter: shared.rate_limiter.clone(),
+        handshake_limiter: shared.handshake_limiter.clone(),
+        challenges: shared.challenges.clone(),
+        spam_detector: shared.spam_detector.clone(),
+        jwt_auth: shared.jwt_auth.clone(),
+        registry: shared.registry.clone(),
+        runtime: settings.runtime.clone(),
+        max_attachment_size: settings.max_attachment_size,
+        key_distribution_ttl: settings.key_distribution_ttl,
+        mention_ttl: settings.mention_ttl,
+        max_frame_size: settings.max_frame_size,
+        operation_concurrency: settings.operation_concurrency,
+        operation_queue_limit: settings.operation_queue_limit,
+        database_timeout: settings.database_timeout,
+        nats_timeout: settings.nats_timeout,
+        nats_outage_limit: settings.nats_outage_limit,
+        nats_publish_max_attempts: settings.nats_publish_max_attempts,
+        send_timeout: settings.send_timeout,
+        token_expires_in: payload.expires_in(),
+        permissions: Permissions::from_payload(&payload),
+        phone_number: payload.phone_number,
+        username: payload.username,
+        device_id,
+        ip,
+        is_bot,
+    }
+}
+
+// a connection for a caller that isn't a websocket client, like the grpc service, run over an in memory websocket.
+// the caller has already authenticated, and gets back the client's end
+pub(crate) async fn bridge(
+    shared: Arc<Shared>,
+    connection_id: String,
+    payload: AccessTokenPayload,
+    device_id: Option<String>,
+    is_bot: bool,
+) -> WebSocketStream<DuplexStream> {
+    let (client, server) = tokio::io::duplex(shared.settings.max_frame_size);
+
+    let websocket = WebSocketStream::from_raw_socket(
+        Transport::memory(server),
+        Role::Server,
+        Some(shared.websocket_config),
+    )
+    .await;
+
+    let username = payload.username.clone();
+
+    let conn = connection(
+        &shared,
+        connection_id.clone(),
+        websocket,
+        Encoding::Grpc,
+        payload,
+        device_id,
+        is_bot,
+    );
+
+    let span = info_span!("connection", connection_id = %connection_id);
+
+    tokio::task::spawn(
+        async move {
+            if let Err(fatal_connection_error) = conn.handle().await {
+                error!(
+                    "Error during bridged connection for user with username {}: {}",
+                    username, fatal_connection_error
+                );
+            }
+        }
+        .instrument(span),
+    );
+
+    WebSocketStream::from_raw_socket(client, Role::Client, None).await
+}
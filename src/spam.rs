@@ -0,0 +1,216 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+
+// heuristics on top of the token buckets for users that stay under the rate limit but are clearly spamming. each
+// time one trips the user gets a strike, and enough strikes escalate from throttling to a mute to being disconnected
+
+// users without a strike whose history has aged out carry no information, so they get dropped past this
+const PRUNE_THRESHOLD: usize = 10_000;
+
+const CHOOSEE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy)]
+pub struct SpamThresholds {
+    pub burst_window: Duration,
+    pub burst_limit: usize,      // messages within the burst window
+    pub duplicate_sample: usize, // how many recent messages the duplicate ratio is taken over
+    pub duplicate_ratio: f64,
+    pub distinct_choosees_per_hour: usize,
+    pub mute_after_strikes: u32,
+    pub close_after_strikes: u32,
+    pub mute_duration: Duration,
+    pub strike_decay: Duration, // strikes are forgotten after this long without a new one
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamReason {
+    Burst,
+    Duplicates,
+    Choosees,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamVerdict {
+    Allow,
+    Throttle(SpamReason),
+    Mute(Duration), // how long is left
+    Close(SpamReason),
+}
+
+#[derive(Default)]
+struct SpamState {
+    sent_at: VecDeque<Instant>,
+    content_hashes: VecDeque<u64>,
+    choosees: VecDeque<(Instant, String)>,
+    strikes: u32,
+    struck_at: Option<Instant>,
+    muted_until: Option<Instant>,
+}
+
+pub struct SpamDetector {
+    thresholds: SpamThresholds,
+    states: Mutex<HashMap<String, SpamState>>,
+}
+
+impl SpamDetector {
+    pub fn new(thresholds: SpamThresholds) -> Self {
+        Self {
+            thresholds,
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // called for every message a user tries to send or choose with, before it's stored
+    pub fn check(
+        &self,
+        username: &str,
+        content: &str,
+        choosee_username: Option<&str>,
+    ) -> SpamVerdict {
+        let now = Instant::now();
+
+        let mut states = self.states.lock().unwrap();
+
+        if states.len() > PRUNE_THRESHOLD {
+            states.retain(|_, state| {
+                state.strikes > 0
+                    || state
+                        .sent_at
+                        .back()
+                        .is_some_and(|sent_at| now.duration_since(*sent_at) < CHOOSEE_WINDOW)
+            });
+        }
+
+        let state = states.entry(username.to_owned()).or_default();
+
+        if let Some(muted_until) = state.muted_until {
+            if now < muted_until {
+                return SpamVerdict::Mute(muted_until - now);
+            }
+
+            state.muted_until = None;
+        }
+
+        if state
+            .struck_at
+            .is_some_and(|struck_at| now.duration_since(struck_at) > self.thresholds.strike_decay)
+        {
+            state.strikes = 0;
+            state.struck_at = None;
+        }
+
+        let Some(reason) = self.record(state, now, content, choosee_username) else {
+            return SpamVerdict::Allow;
+        };
+
+        state.strikes += 1;
+        state.struck_at = Some(now);
+
+        let verdict = if state.strikes >= self.thresholds.close_after_strikes {
+            state.strikes = 0;
+            state.struck_at = None;
+
+            SpamVerdict::Close(reason)
+        } else if state.strikes >= self.thresholds.mute_after_strikes {
+            state.muted_until = Some(now + self.thresholds.mute_duration);
+
+            SpamVerdict::Mute(self.thresholds.mute_duration)
+        } else {
+            SpamVerdict::Throttle(reason)
+        };
+
+        match verdict {
+            SpamVerdict::Throttle(_) => metrics::SPAM_THROTTLED.increment(),
+            SpamVerdict::Mute(_) => metrics::SPAM_MUTED.increment(),
+            SpamVerdict::Close(_) => metrics::SPAM_CLOSED.increment(),
+            SpamVerdict::Allow => {}
+        }
+
+        warn!(
+            "Spam detected for user with username {} ({:?}, strike {}): {:?}",
+            username, reason, state.strikes, verdict
+        );
+
+        verdict
+    }
+
+    // adds the message to the user's history and returns which heuristic it tripped, if any
+    fn record(
+        &self,
+        state: &mut SpamState,
+        now: Instant,
+        content: &str,
+        choosee_username: Option<&str>,
+    ) -> Option<SpamReason> {
+        while state
+            .sent_at
+            .front()
+            .is_some_and(|sent_at| now.duration_since(*sent_at) > self.thresholds.burst_window)
+        {
+            state.sent_at.pop_front();
+        }
+
+        state.sent_at.push_back(now);
+
+        let mut hasher = DefaultHasher::new();
+        content.trim().to_lowercase().hash(&mut hasher);
+        let content_hash = hasher.finish();
+
+        if state.content_hashes.len() >= self.thresholds.duplicate_sample {
+            state.content_hashes.pop_front();
+        }
+
+        state.content_hashes.push_back(content_hash);
+
+        if let Some(choosee_username) = choosee_username {
+            while state
+                .choosees
+                .front()
+                .is_some_and(|(chose_at, _)| now.duration_since(*chose_at) > CHOOSEE_WINDOW)
+            {
+                state.choosees.pop_front();
+            }
+
+            if !state
+                .choosees
+                .iter()
+                .any(|(_, username)| username == choosee_username)
+            {
+                state.choosees.push_back((now, choosee_username.to_owned()));
+            }
+        }
+
+        if state.sent_at.len() > self.thresholds.burst_limit {
+            return Some(SpamReason::Burst);
+        }
+
+        // only judged on a full sample, a couple of "ok"s in a row isn't spam
+        if state.content_hashes.len() >= self.thresholds.duplicate_sample {
+            let duplicates = state
+                .content_hashes
+                .iter()
+                .filter(|hash| **hash == content_hash)
+                .count()
+                - 1;
+
+            if duplicates as f64 / state.content_hashes.len() as f64
+                >= self.thresholds.duplicate_ratio
+            {
+                return Some(SpamReason::Duplicates);
+            }
+        }
+
+        if choosee_username.is_some()
+            && state.choosees.len() > self.thresholds.distinct_choosees_per_hour
+        {
+            return Some(SpamReason::Choosees);
+        }
+
+        None
+    }
+}
@@ -0,0 +1,2 @@
+// storage that isn't the database. the database lives in db
+pub mod object_store;
@@ -0,0 +1,171 @@
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use schemars::JsonSchema;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+// clients upload straight to the bucket with urls signed here, so files never pass through the gateway. signs aws
+// sigv4 query strings, which s3, gcs (with hmac interoperability keys), r2 and minio all accept. urls are path style,
+// {endpoint}/{bucket}/{key}, since that works everywhere
+
+// everything but unreserved characters, as sigv4 expects
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+pub struct ObjectStoreOptions {
+    pub endpoint: String, // like https://s3.us-east-1.amazonaws.com or https://storage.googleapis.com
+    pub region: String,   // gcs ignores it, but it's still part of the signature
+    pub bucket: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub upload_expiry: Duration,
+}
+
+pub struct ObjectStore {
+    scheme: String,
+    host: String,
+    region: String,
+    bucket: String,
+    access_key_id: String,
+    secret_access_key: String,
+    upload_expiry: Duration,
+}
+
+#[derive(Serialize, Clone, JsonSchema)]
+pub struct PresignedUpload {
+    pub object_key: String,
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ObjectStore {
+    pub fn new(options: ObjectStoreOptions) -> Self {
+        let (scheme, host) = options
+            .endpoint
+            .trim_end_matches('/')
+            .split_once("://")
+            .expect("Object store endpoint must include a scheme");
+
+        Self {
+            scheme: scheme.to_owned(),
+            host: host.to_owned(),
+            region: options.region,
+            bucket: options.bucket,
+            access_key_id: options.access_key_id,
+            secret_access_key: options.secret_access_key,
+            upload_expiry: options.upload_expiry,
+        }
+    }
+
+    // the upload has to be sent with exactly this content type, and content length when one is given, or the
+    // signature won't match
+    pub fn presign_put(
+        &self,
+        object_key: &str,
+        content_type: &str,
+        content_length: Option<u64>,
+    ) -> PresignedUpload {
+        let now = Utc::now();
+
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+
+        let path = format!(
+            "/{}/{}",
+            encode(&self.bucket),
+            object_key
+                .split('/')
+                .map(encode)
+                .collect::<Vec<_>>()
+                .join("/")
+        );
+
+        let mut headers = vec![
+            ("content-type", content_type.to_owned()),
+            ("host", self.host.clone()),
+        ];
+
+        if let Some(content_length) = content_length {
+            headers.insert(0, ("content-length", content_length.to_string()));
+        }
+
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+
+        // already in sorted order, which the signature needs
+        let query = [
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_owned()),
+            (
+                "X-Amz-Credential",
+                format!("{}/{}", self.access_key_id, scope),
+            ),
+            ("X-Amz-Date", timestamp.clone()),
+            ("X-Amz-Expires", self.upload_expiry.as_secs().to_string()),
+            ("X-Amz-SignedHeaders", signed_headers.clone()),
+        ]
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+        let canonical_headers = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect::<String>();
+
+        let canonical_request = format!(
+            "PUT\n{}\n{}\n{}\n{}\nUNSIGNED-PAYLOAD",
+            path, query, canonical_headers, signed_headers
+        );
+
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [date.as_str(), &self.region, "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.secret_access_key).into_bytes(),
+                |key, part| hmac(&key, part),
+            );
+
+        let signature = hex::encode(hmac(&signing_key, &string_to_sign));
+
+        PresignedUpload {
+            object_key: object_key.to_owned(),
+            url: format!(
+                "{}://{}{}?{}&X-Amz-Signature={}",
+                self.scheme, self.host, path, query, signature
+            ),
+            expires_at: now
+                + chrono::Duration::from_std(self.upload_expiry)
+                    .expect("Upload expiry out of range"),
+        }
+    }
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, UNRESERVED).to_string()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC should accept keys of any length");
+
+    mac.update(data.as_bytes());
+
+    mac.finalize().into_bytes().to_vec()
+}
@@ -0,0 +1,90 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::db::Storage;
+use crate::jobs::{Job, JobError, JobHandler};
+
+// how many days in a row both users in a conversation have sent something, with days in utc. a streak is extended as
+// messages are saved, by the first one that makes it a day both users sent something on. it grows by one if its last
+// day was yesterday and starts over at one otherwise
+//
+// a day one of them skips ends the streak, but nothing is sent then, so the rollover job writes it down after the
+// fact. until it has, reads treat a streak whose last day was before yesterday as over already
+
+pub const JOB_KIND: &str = "streak_rollover";
+
+// days start at midnight utc, and periodic jobs are lined up from the epoch, so this runs shortly after each one
+pub const ROLLOVER_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// lengths both users are told they reached
+const MILESTONES: [u32; 6] = [3, 7, 30, 100, 365, 1000];
+
+// streaks ended per storage call, the rollover keeps going until a call comes back short
+const ROLLOVER_BATCH: i32 = 500;
+
+pub fn is_milestone(length: u32) -> bool {
+    MILESTONES.contains(&length)
+}
+
+// what to show for a streak last extended on last_day, whether or not the rollover has gotten to it
+pub fn current_length(length: u32, last_day: Option<NaiveDate>, today: NaiveDate) -> u32 {
+    match last_day {
+        Some(last_day)
+            if today
+                .pred_opt()
+                .is_some_and(|yesterday| last_day >= yesterday) =>
+        {
+            length
+        }
+        _ => 0,
+    }
+}
+
+// the streak's new length when a message sent on day extends it, given the last day the other user sent something on
+pub fn extend(
+    length: u32,
+    last_day: Option<NaiveDate>,
+    other_messaged_on: Option<NaiveDate>,
+    day: NaiveDate,
+) -> Option<u32> {
+    if other_messaged_on != Some(day) || last_day.is_some_and(|last_day| last_day >= day) {
+        return None;
+    }
+
+    match last_day {
+        Some(last_day) if last_day.succ_opt() == Some(day) => Some(length + 1),
+        _ => Some(1),
+    }
+}
+
+pub struct StreakRollover {
+    pub db: Arc<dyn Storage>,
+}
+
+#[async_trait]
+impl JobHandler for StreakRollover {
+    async fn handle(&self, _job: &Job) -> Result<(), JobError> {
+        let yesterday = Utc::now()
+            .date_naive()
+            .pred_opt()
+            .expect("Yesterday should be a valid date");
+
+        let mut ended = 0;
+
+        loop {
+            let batch = self.db.end_streaks(yesterday, ROLLOVER_BATCH).await?;
+
+            ended += batch;
+
+            if batch < ROLLOVER_BATCH as usize {
+                break;
+            }
+        }
+
+        info!("Ended {} streaks last extended before {}", ended, yesterday);
+
+        Ok(())
+    }
+}
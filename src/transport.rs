@@ -0,0 +1,273 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+// what a websocket runs over. unix sockets are for a sidecar proxy on the same host, like envoy or nginx, so it can
+// skip loopback tcp. in memory ones carry connections bridged from the grpc service
+//
+// the head of the first request can be read ahead of time to tell websocket upgrades from plain http requests. it's
+// handed back out by the first reads after, so whatever handles the connection still sees the whole request
+//
+// behind a load balancer speaking the proxy protocol, the header it sends first says which client the connection is
+// for. it's read and dropped before anything else, so the rest never sees it
+
+// longer than any request head a client sends
+const MAX_HEAD_LENGTH: usize = 16 << 10;
+
+// what starts a version 2 header, which can't be mistaken for the start of an http request
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// the signature, the version and command, the address family and the length of the rest
+const PROXY_V2_HEADER_LENGTH: usize = 16;
+
+// version 1 headers are one line, no longer than this including the line ending
+const PROXY_V1_MAX_LENGTH: usize = 107;
+
+pub struct Transport {
+    stream: Stream,
+    head: Vec<u8>,
+    head_read: usize,          // how much of the head has been handed back out
+    peer_addr: Option<IpAddr>, // unknown for unix sockets without a proxy protocol header, and in memory ones
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Memory(DuplexStream),
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind_unix(path: &Path) -> io::Result<Self> {
+        // left behind if the last run didn't shut down cleanly, and binding fails while it's there
+        match tokio::fs::remove_file(path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        UnixListener::bind(path).map(Self::Unix)
+    }
+
+    pub async fn accept(&self) -> io::Result<Transport> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+
+                Ok(Transport::new(Stream::Tcp(stream), Some(addr.ip())))
+            }
+            Self::Unix(listener) => Ok(Transport::new(
+                Stream::Unix(listener.accept().await?.0),
+                None,
+            )),
+        }
+    }
+}
+
+impl Transport {
+    fn new(stream: Stream, peer_addr: Option<IpAddr>) -> Self {
+        Self {
+            stream,
+            head: Vec::new(),
+            head_read: 0,
+            peer_addr,
+        }
+    }
+
+    pub fn memory(stream: DuplexStream) -> Self {
+        Self::new(Stream::Memory(stream), None)
+    }
+
+    // the client's once a proxy protocol header has been read
+    pub fn peer_addr(&self) -> Option<IpAddr> {
+        self.peer_addr
+    }
+
+    // either version of the proxy protocol. only before anything else has been read, and only when every connection
+    // comes through a proxy that sends one, since otherwise clients could claim any address. the proxy's own
+    // connections, like health checks, keep the peer's address
+    pub async fn read_proxy_header(&mut self) -> io::Result<()> {
+        self.fill(PROXY_V2_SIGNATURE.len()).await?;
+
+        let (length, source) = if self.head.starts_with(PROXY_V2_SIGNATURE) {
+            self.fill(PROXY_V2_HEADER_LENGTH).await?;
+
+            let length = PROXY_V2_HEADER_LENGTH
+                + u16::from_be_bytes([self.head[14], self.head[15]]) as usize;
+
+            self.fill(length).await?;
+
+            if self.head[12] >> 4 != 2 {
+                return Err(invalid_proxy_header("unsupported version"));
+            }
+
+            let addresses = &self.head[PROXY_V2_HEADER_LENGTH..length];
+
+            let source = match (self.head[12] & 0x0f, self.head[13] >> 4) {
+                (0, _) => None, // local
+                (1, 1) if addresses.len() >= 12 => {
+                    Some(IpAddr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap()))
+                }
+                (1, 2) if addresses.len() >= 36 => Some(IpAddr::from(
+                    <[u8; 16]>::try_from(&addresses[..16]).unwrap(),
+                )),
+                (1, 0 | 3) => None, // unspecified or a unix socket
+                _ => {
+                    return Err(invalid_proxy_header(
+                        "unsupported command or address family",
+                    ))
+                }
+            };
+
+            (length, source)
+        } else if self.head.starts_with(b"PROXY ") {
+            let end = loop {
+                if let Some(end) = self.head.windows(2).position(|window| window == b"\r\n") {
+                    break end;
+                }
+
+                if self.head.len() >= PROXY_V1_MAX_LENGTH || self.read_more().await? == 0 {
+                    return Err(invalid_proxy_header("unterminated line"));
+                }
+            };
+
+            let line = std::str::from_utf8(&self.head[..end])
+                .map_err(|_| invalid_proxy_header("not utf-8"))?;
+
+            let mut fields = line.split(' ').skip(1);
+
+            let source = match fields.next() {
+                Some("TCP4" | "TCP6") => Some(
+                    fields
+                        .next()
+                        .and_then(|source| source.parse().ok())
+                        .ok_or_else(|| invalid_proxy_header("invalid source address"))?,
+                ),
+                Some("UNKNOWN") => None,
+                _ => return Err(invalid_proxy_header("unsupported protocol")),
+            };
+
+            (end + 2, source)
+        } else {
+            return Err(invalid_proxy_header("missing"));
+        };
+
+        self.head.drain(..length);
+
+        if let Some(source) = source {
+            self.peer_addr = Some(source);
+        }
+
+        Ok(())
+    }
+
+    // reads up to the blank line ending the request head, or as much as there was if the client stopped or sent
+    // something too long to be one. only before anything else has been read
+    pub async fn peek_head(&mut self) -> io::Result<&[u8]> {
+        while !self.head.windows(4).any(|window| window == b"\r\n\r\n")
+            && self.head.len() < MAX_HEAD_LENGTH
+        {
+            if self.read_more().await? == 0 {
+                break;
+            }
+        }
+
+        Ok(&self.head)
+    }
+
+    // until the head is at least this long
+    async fn fill(&mut self, length: usize) -> io::Result<()> {
+        while self.head.len() < length {
+            if self.read_more().await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn read_more(&mut self) -> io::Result<usize> {
+        let mut buf = [0; 1024];
+
+        let read = match &mut self.stream {
+            Stream::Tcp(stream) => stream.read(&mut buf).await?,
+            Stream::Unix(stream) => stream.read(&mut buf).await?,
+            Stream::Memory(stream) => stream.read(&mut buf).await?,
+        };
+
+        self.head.extend_from_slice(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+fn invalid_proxy_header(problem: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Proxy protocol header {}", problem),
+    )
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let transport = self.get_mut();
+
+        if transport.head_read < transport.head.len() {
+            let unread = &transport.head[transport.head_read..];
+            let len = unread.len().min(buf.remaining());
+
+            buf.put_slice(&unread[..len]);
+            transport.head_read += len;
+
+            return Poll::Ready(Ok(()));
+        }
+
+        match &mut transport.stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Memory(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
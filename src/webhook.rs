@@ -0,0 +1,205 @@
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::metrics;
+use crate::models::report::Report;
+use crate::retry_policy::RetryPolicy;
+
+// posts events to endpoints run by server side integrations. every endpoint gets every event type it's subscribed to,
+// delivered from whichever node the event happened on, so nothing has to run as a worker
+//
+// bodies are signed like X-Webhook-Signature: t=1700000000,v1=<hex hmac-sha256 of "1700000000.<body>">, so receivers
+// can check both who sent it and that it isn't an old one being replayed. X-Webhook-Id stays the same across retries
+// of one delivery for deduplicating
+//
+// users can't block each other through the gateway yet, so there's no event for it until they can
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEventType {
+    ConversationCreated,
+    ReportFiled,
+}
+
+impl FromStr for WebhookEventType {
+    type Err = String;
+
+    fn from_str(event_type: &str) -> Result<Self, Self::Err> {
+        match event_type.trim() {
+            "conversation.created" => Ok(Self::ConversationCreated),
+            "report.filed" => Ok(Self::ReportFiled),
+            event_type => Err(format!("Unknown webhook event type: {}", event_type)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum WebhookEvent {
+    #[serde(rename = "conversation.created", rename_all = "camelCase")]
+    ConversationCreated {
+        conversation_id: String,
+        chooser_username: String,
+        choosee_username: String,
+        created_at: DateTime<Utc>,
+    },
+    #[serde(rename = "report.filed")]
+    ReportFiled(Report),
+}
+
+impl WebhookEvent {
+    fn event_type(&self) -> WebhookEventType {
+        match self {
+            Self::ConversationCreated { .. } => WebhookEventType::ConversationCreated,
+            Self::ReportFiled(_) => WebhookEventType::ReportFiled,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+pub struct WebhookOptions {
+    pub urls: Vec<String>,
+    pub secret: String, // shared by every endpoint
+    pub event_types: Vec<WebhookEventType>,
+    pub timeout: Duration,         // per attempt
+    pub retry_policy: RetryPolicy, // for connection errors, 429s and 5xx responses
+}
+
+pub struct Webhooks {
+    client: reqwest::Client,
+    urls: Vec<String>,
+    secret: String,
+    event_types: Vec<WebhookEventType>,
+    retry_policy: RetryPolicy,
+}
+
+#[derive(Debug, Error)]
+enum WebhookError {
+    #[error("Error sending webhook: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Webhook endpoint responded with status {0}")]
+    Rejected(u16),
+}
+
+impl WebhookError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(_) => true,
+            Self::Rejected(status) => *status == 429 || *status >= 500,
+        }
+    }
+}
+
+impl Webhooks {
+    pub fn new(options: WebhookOptions) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(options.timeout)
+                .build()
+                .expect("Webhook client should build"),
+            urls: options.urls,
+            secret: options.secret,
+            event_types: options.event_types,
+            retry_policy: options.retry_policy,
+        }
+    }
+
+    // returns right away, delivery and its retries happen in the background
+    pub fn dispatch(self: &Arc<Self>, event: WebhookEvent) {
+        if !self.event_types.contains(&event.event_type()) {
+            return;
+        }
+
+        let id = Uuid::new_v4().to_string();
+
+        let body = serde_json::to_string(&Payload {
+            id: &id,
+            event: &event,
+        })
+        .expect("Webhook payload should serialize");
+
+        for url in 0..self.urls.len() {
+            let webhooks = self.clone();
+            let id = id.clone();
+            let body = body.clone();
+
+            tokio::task::spawn(async move {
+                webhooks.deliver(&webhooks.urls[url], &id, &body).await;
+            });
+        }
+    }
+
+    async fn deliver(&self, url: &str, id: &str, body: &str) {
+        let mut attempt = 0;
+
+        loop {
+            match self.deliver_once(url, id, body).await {
+                Ok(()) => {
+                    metrics::WEBHOOKS_DELIVERED.increment();
+
+                    return;
+                }
+                Err(err) if err.is_retryable() && attempt + 1 < self.retry_policy.max_attempts => {
+                    metrics::WEBHOOKS_RETRIED.increment();
+
+                    debug!("Retrying webhook {} to {}: {}", id, url, err);
+
+                    tokio::time::sleep(self.retry_policy.delay(attempt)).await;
+
+                    attempt += 1;
+                }
+                Err(err) => {
+                    metrics::WEBHOOKS_FAILED.increment();
+
+                    warn!("Failed to deliver webhook {} to {}: {}", id, url, err);
+
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn deliver_once(&self, url: &str, id: &str, body: &str) -> Result<(), WebhookError> {
+        let timestamp = Utc::now().timestamp();
+
+        let response = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .header("X-Webhook-Id", id)
+            .header(
+                "X-Webhook-Signature",
+                format!("t={},v1={}", timestamp, self.sign(timestamp, body)),
+            )
+            .body(body.to_owned())
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(WebhookError::Rejected(response.status().as_u16()))
+        }
+    }
+
+    fn sign(&self, timestamp: i64, body: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC should accept keys of any length");
+
+        mac.update(format!("{}.{}", timestamp, body).as_bytes());
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+}
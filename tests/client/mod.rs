@@ -0,0 +1,268 @@
+// a minimal client for the json protocol, written against the wire format rather than the server's types so it
+// doubles as a reference for sdk authors. everything a client has to do is here: authenticate on the upgrade, wait
+// for hello, send operations as {"op": ..., "d": ...} text frames and tell responses and events apart by their op
+
+#![allow(dead_code)] // not every test binary uses every part of it
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tungstenite::{client::IntoClientRequest, http::HeaderValue, protocol::CloseFrame, Message};
+
+use realtime::auth::AccessTokenPayload;
+
+// how long to wait for a frame that should arrive, well above what the in memory backends take
+const FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+// short enough to keep tests quick, long enough that anything the server was going to send has arrived
+const QUIET_PERIOD: Duration = Duration::from_millis(300);
+
+pub struct Client {
+    websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    pub hello: Value,
+}
+
+// one decoded text frame
+#[derive(Debug)]
+pub struct Frame {
+    pub op: String,
+    pub d: Value,
+}
+
+// signed the way the api signs them, which the server only needs the shared secret to check
+pub fn token(secret: &str, username: &str, roles: &[&str]) -> String {
+    scoped_token(secret, username, roles, &[])
+}
+
+// without scopes a token allows everything a user can do
+pub fn scoped_token(secret: &str, username: &str, roles: &[&str], scopes: &[&str]) -> String {
+    sign(
+        secret,
+        &AccessTokenPayload {
+            phone_number: 1,
+            username: username.to_owned(),
+            exp: now() + 3600,
+            iat: None,
+            roles: roles.iter().map(|role| (*role).to_owned()).collect(),
+            scopes: scopes.iter().map(|scope| (*scope).to_owned()).collect(),
+            device_id: None,
+            purpose: None,
+        },
+    )
+}
+
+// the short lived kind the api hands out to confirm one action, like deleting an account. issued_ago is for
+// making stale ones
+pub fn purpose_token(secret: &str, username: &str, purpose: &str, issued_ago: Duration) -> String {
+    sign(
+        secret,
+        &AccessTokenPayload {
+            phone_number: 1,
+            username: username.to_owned(),
+            exp: now() + 3600,
+            iat: Some(now() - issued_ago.as_secs()),
+            roles: Vec::new(),
+            scopes: Vec::new(),
+            device_id: None,
+            purpose: Some(purpose.to_owned()),
+        },
+    )
+}
+
+fn sign(secret: &str, payload: &AccessTokenPayload) -> String {
+    jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        payload,
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+impl Client {
+    // the token goes in the authorization header. browsers can't set that on an upgrade, so they pass ?token= instead
+    pub async fn connect(addr: SocketAddr, token: &str) -> Result<Self, tungstenite::Error> {
+        let (websocket, _) =
+            tokio_tungstenite::connect_async(Self::upgrade_request(addr, token)).await?;
+
+        Self::greeted(websocket).await
+    }
+
+    // the way a load balancer speaking the proxy protocol connects, with a header saying who the client is before
+    // anything the client sent
+    pub async fn connect_with_proxy_header(
+        addr: SocketAddr,
+        proxy_header: &[u8],
+        token: &str,
+    ) -> Result<Self, tungstenite::Error> {
+        let mut stream = TcpStream::connect(addr).await?;
+
+        stream.write_all(proxy_header).await?;
+
+        let (websocket, _) = tokio_tungstenite::client_async(
+            Self::upgrade_request(addr, token),
+            MaybeTlsStream::Plain(stream),
+        )
+        .await?;
+
+        Self::greeted(websocket).await
+    }
+
+    fn upgrade_request(addr: SocketAddr, token: &str) -> tungstenite::handshake::client::Request {
+        let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+
+        request.headers_mut().insert(
+            "Authorization",
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+
+        request
+    }
+
+    // without a token on the upgrade the first frame has to be an identify, before the server's deadline
+    pub async fn identify(addr: SocketAddr, token: &str) -> Result<Self, tungstenite::Error> {
+        let mut websocket = Self::unauthenticated(addr).await?;
+
+        websocket
+            .send(Message::Text(
+                json!({ "op": "identify", "d": { "token": token } }).to_string(),
+            ))
+            .await?;
+
+        Self::greeted(websocket).await
+    }
+
+    pub async fn unauthenticated(
+        addr: SocketAddr,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, tungstenite::Error> {
+        let (websocket, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await?;
+
+        Ok(websocket)
+    }
+
+    // hello is always the first frame once a connection is authenticated
+    async fn greeted(
+        websocket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> Result<Self, tungstenite::Error> {
+        let mut client = Self {
+            websocket,
+            hello: Value::Null,
+        };
+
+        client.hello = client.expect("hello").await;
+
+        Ok(client)
+    }
+
+    pub fn connection_id(&self) -> &str {
+        self.hello["connection_id"].as_str().unwrap()
+    }
+
+    pub async fn send(&mut self, op: &str, d: Value) {
+        let frame = if d.is_null() {
+            json!({ "op": op })
+        } else {
+            json!({ "op": op, "d": d })
+        };
+
+        self.websocket
+            .send(Message::Text(frame.to_string()))
+            .await
+            .unwrap();
+    }
+
+    // pings are answered by tungstenite on the next read, so they're never seen here
+    pub async fn next(&mut self) -> Option<Frame> {
+        loop {
+            let message = tokio::time::timeout(FRAME_TIMEOUT, self.websocket.next())
+                .await
+                .expect("Timed out waiting for a frame")?;
+
+            match message.ok()? {
+                Message::Text(text) => {
+                    let mut frame: Value = serde_json::from_str(&text).unwrap();
+
+                    return Some(Frame {
+                        op: frame["op"].as_str().unwrap().to_owned(),
+                        d: frame["d"].take(),
+                    });
+                }
+                Message::Close(_) => return None,
+                _ => {}
+            }
+        }
+    }
+
+    // the next frame has to be this one, anything else fails the test
+    pub async fn expect(&mut self, op: &str) -> Value {
+        match self.next().await {
+            Some(frame) if frame.op == op => frame.d,
+            Some(frame) => panic!("Expected {} but got {}: {}", op, frame.op, frame.d),
+            None => panic!("Expected {} but the connection closed", op),
+        }
+    }
+
+    // errors have a stable machine readable code, the message is for humans only
+    pub async fn expect_error(&mut self, code: &str) -> Value {
+        let error = self.expect("error").await;
+
+        assert_eq!(error["code"], code, "{}", error);
+
+        error
+    }
+
+    // for asserting nothing else was sent
+    pub async fn expect_silence(&mut self) {
+        if let Ok(Some(Ok(message))) =
+            tokio::time::timeout(QUIET_PERIOD, self.websocket.next()).await
+        {
+            panic!("Expected nothing but got {:?}", message);
+        }
+    }
+
+    // for the queries answered with a response of the same name
+    pub async fn request(&mut self, op: &str, d: Value) -> Value {
+        self.send(op, d).await;
+
+        self.expect(op).await
+    }
+
+    // the code says why the server closed the connection and how to reconnect, see the feature list in hello
+    pub async fn expect_close(&mut self) -> CloseFrame<'static> {
+        close_frame(&mut self.websocket)
+            .await
+            .expect("Closed without a close frame")
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.websocket.close(None).await;
+
+        while let Some(Ok(_)) = self.websocket.next().await {}
+    }
+}
+
+// what the server closed the connection with, skipping whatever it sent before that
+pub async fn close_frame(
+    websocket: &mut WebSocketStream<MaybeTlsStream<TcpStream>>,
+) -> Option<CloseFrame<'static>> {
+    loop {
+        match tokio::time::timeout(FRAME_TIMEOUT, websocket.next())
+            .await
+            .expect("Timed out waiting for the connection to close")
+        {
+            Some(Ok(Message::Close(close_frame))) => return close_frame,
+            Some(Ok(_)) => {}
+            Some(Err(_)) | None => return None,
+        }
+    }
+}
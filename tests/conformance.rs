@@ -0,0 +1,1237 @@
+// runs the server in process on the in memory backends and drives it as a real websocket client would. what's
+// asserted here is the protocol clients depend on, so a change that breaks one of these breaks deployed apps too
+
+mod client;
+
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tungstenite::protocol::frame::coding::CloseCode;
+
+use async_trait::async_trait;
+use client::{close_frame, purpose_token, scoped_token, token, Client};
+use realtime::{
+    auth::{JWTAuth, JWTValidationConfig},
+    challenge::{Challenge, ChallengeContext, ChallengeError, ChallengeKind, ChallengeVerifier},
+    db::{MemoryStorage, Storage},
+    handshake_limit::HandshakeLimits,
+    hash::{HashAlgorithm, HashEncoding, Hasher},
+    models::profile::Profile,
+    rate_limit::Budget,
+    runtime_config::RuntimeConfig,
+    Server, ServerError, Settings,
+};
+
+const SECRET: &str = "conformance";
+
+// low enough for a test to trip them in a few connections
+fn handshake_limits(handshake_burst: f64, auth_failure_limit: u32) -> Settings {
+    Settings {
+        handshake_limits: HandshakeLimits {
+            handshakes: Budget {
+                burst: handshake_burst,
+                per_second: 0.01,
+            },
+            auth_failure_limit,
+            auth_failure_window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(60),
+        },
+        ..Settings::default()
+    }
+}
+
+struct TestServer {
+    addr: SocketAddr,
+    db: Arc<MemoryStorage>, // for seeding what the api would have written
+    shutdown_tx: Option<oneshot::Sender<()>>, // shuts the server down when sent or dropped
+}
+
+impl TestServer {
+    async fn start() -> Self {
+        Self::start_with(Settings::default()).await
+    }
+
+    async fn start_with(settings: Settings) -> Self {
+        Self::launch(settings, None).await
+    }
+
+    async fn start_with_challenges(challenges: Arc<dyn ChallengeVerifier>) -> Self {
+        Self::launch(Settings::default(), Some(challenges)).await
+    }
+
+    async fn launch(settings: Settings, challenges: Option<Arc<dyn ChallengeVerifier>>) -> Self {
+        let db = Arc::new(MemoryStorage::default());
+        let (bound_tx, bound_rx) = oneshot::channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let server = Server::builder()
+            .with_storage(db.clone())
+            .with_auth(JWTAuth::new(
+                SECRET,
+                JWTValidationConfig {
+                    issuer: None,
+                    audience: None,
+                    leeway: Duration::from_secs(60),
+                    max_token_age: None,
+                },
+            ))
+            .with_hasher(Arc::new(Hasher::new(
+                SECRET.to_owned(),
+                HashAlgorithm::HmacSha256,
+                HashEncoding::Base64Url,
+                false,
+            )))
+            .with_challenges(challenges)
+            .with_settings(settings)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .on_bound(|addrs| {
+                let _ = bound_tx.send(addrs[0]);
+            })
+            .with_shutdown(async {
+                let _ = shutdown_rx.await;
+            });
+
+        tokio::spawn(server.run());
+
+        Self {
+            addr: bound_rx.await.expect("Server failed to start"),
+            db,
+            shutdown_tx: Some(shutdown_tx),
+        }
+    }
+
+    fn shut_down(&mut self) {
+        self.shutdown_tx.take();
+    }
+
+    async fn connect(&self, username: &str) -> Client {
+        Client::connect(self.addr, &token(SECRET, username, &[]))
+            .await
+            .unwrap()
+    }
+
+    async fn befriend(&self, a: &str, b: &str) {
+        self.db
+            .create_friendship(profile(a), profile(b), Vec::new())
+            .await
+            .unwrap();
+    }
+}
+
+fn profile(username: &str) -> Profile {
+    Profile {
+        username: username.to_owned(),
+        name: username.to_owned(),
+    }
+}
+
+// alice chooses bob and both sides see it. returns the conversation id
+async fn choose(alice: &mut Client, bob: &mut Client, content: &str) -> String {
+    alice
+        .send(
+            "choose",
+            json!({ "content": content, "choosee_username": "bob" }),
+        )
+        .await;
+
+    let sent = alice.expect("sent").await;
+    let conversation_id = sent["conversation_id"].as_str().unwrap().to_owned();
+
+    let chosen = bob.expect("chosen").await;
+
+    assert_eq!(chosen["conversation_id"], conversation_id);
+    assert_eq!(chosen["content"], content);
+    assert_eq!(chosen["sent_at"], sent["sent_at"]);
+    assert_eq!(chosen["silent"], false);
+
+    conversation_id
+}
+
+// presence is written once a connection is set up and cleared once the server notices it's gone, neither of which
+// a client is told about, so this polls
+async fn online_friends_become(client: &mut Client, usernames: Value) {
+    for _ in 0..40 {
+        let online = client.request("onlineFriends", Value::Null).await;
+
+        if online["usernames"] == usernames {
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    panic!("Online friends never became {}", usernames);
+}
+
+#[tokio::test]
+async fn rejects_an_invalid_token_on_upgrade() {
+    let server = TestServer::start().await;
+
+    let err = Client::connect(server.addr, &token("not the secret", "alice", &[]))
+        .await
+        .err()
+        .expect("Connected with a token signed by the wrong secret");
+
+    match err {
+        tungstenite::Error::Http(response) => assert_eq!(response.status(), 401),
+        err => panic!("Expected a 401 but got {}", err),
+    }
+}
+
+#[tokio::test]
+async fn rejects_an_invalid_token_on_identify() {
+    let server = TestServer::start().await;
+
+    let mut websocket = Client::unauthenticated(server.addr).await.unwrap();
+
+    futures_util::SinkExt::send(
+        &mut websocket,
+        tungstenite::Message::Text(
+            json!({ "op": "identify", "d": { "token": "garbage" } }).to_string(),
+        ),
+    )
+    .await
+    .unwrap();
+
+    let close_frame = close_frame(&mut websocket)
+        .await
+        .expect("Closed without a close frame");
+
+    assert_eq!(close_frame.code, CloseCode::Policy);
+}
+
+#[tokio::test]
+async fn says_hello_after_identifying() {
+    let server = TestServer::start().await;
+
+    let client = Client::identify(server.addr, &token(SECRET, "alice", &[]))
+        .await
+        .unwrap();
+
+    assert_eq!(client.hello["protocol_version"], 1);
+    assert!(!client.connection_id().is_empty());
+    assert!(client.hello["heartbeat_interval_ms"].as_u64().unwrap() > 0);
+    assert!(client.hello["features"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("ping")));
+}
+
+#[tokio::test]
+async fn answers_basic_queries() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+
+    alice.send("ping", json!({ "nonce": "n1" })).await;
+
+    let pong = alice.expect("pong").await;
+
+    assert_eq!(pong["nonce"], "n1");
+
+    let first = alice.request("time", Value::Null).await;
+    let second = alice.request("time", Value::Null).await;
+
+    assert!(second["sequence"].as_u64() > first["sequence"].as_u64());
+
+    let info = alice.request("connectionInfo", Value::Null).await;
+
+    assert_eq!(info["connection_id"], alice.connection_id());
+    assert_eq!(info["encoding"], "json");
+    assert_eq!(info["is_bot"], false);
+
+    let retention = alice.request("retentionPolicy", Value::Null).await;
+
+    assert!(retention["message_retention_seconds"].is_null());
+}
+
+#[tokio::test]
+async fn delivers_chooses_and_messages_both_ways() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "hi bob").await;
+
+    bob.send(
+        "send",
+        json!({ "content": "hi alice", "conversation_id": conversation_id }),
+    )
+    .await;
+
+    let sent = bob.expect("sent").await;
+
+    assert_eq!(sent["conversation_id"], conversation_id);
+
+    let message = alice.expect("message").await;
+
+    assert_eq!(message["conversation_id"], conversation_id);
+    assert_eq!(message["content"], "hi alice");
+    assert_eq!(message["sent_at"], sent["sent_at"]);
+
+    alice
+        .send(
+            "send",
+            json!({ "content": "how are you", "conversation_id": conversation_id }),
+        )
+        .await;
+
+    alice.expect("sent").await;
+
+    let message = bob.expect("message").await;
+
+    assert_eq!(message["content"], "how are you");
+
+    // nobody hears their own messages back on the connection they sent them from
+    alice.expect_silence().await;
+    bob.expect_silence().await;
+}
+
+#[tokio::test]
+async fn relays_key_material_and_keeps_it_for_whoever_was_offline() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "hi bob").await;
+
+    alice
+        .send(
+            "distributeKey",
+            json!({ "conversation_id": conversation_id, "key_material": "c2VuZGVyIGtleQ==" }),
+        )
+        .await;
+
+    let distributed = alice.expect("keyDistributed").await;
+
+    let key_distribution = bob.expect("keyDistribution").await;
+
+    assert_eq!(key_distribution["conversation_id"], conversation_id);
+    assert_eq!(key_distribution["key_material"], "c2VuZGVyIGtleQ==");
+    assert_eq!(key_distribution["sent_at"], distributed["sent_at"]);
+
+    // as if bob had missed the event
+    let stored = bob
+        .request(
+            "keyDistributions",
+            json!({ "conversation_id": conversation_id }),
+        )
+        .await;
+
+    assert_eq!(stored["key_distributions"].as_array().unwrap().len(), 1);
+    assert_eq!(
+        stored["key_distributions"][0]["key_material"],
+        "c2VuZGVyIGtleQ=="
+    );
+    assert_eq!(stored["key_distributions"][0]["from_chooser"], true);
+
+    // nobody is handed back their own
+    let own = alice
+        .request(
+            "keyDistributions",
+            json!({ "conversation_id": conversation_id }),
+        )
+        .await;
+
+    assert_eq!(own["key_distributions"], json!([]));
+}
+
+#[tokio::test]
+async fn serves_history_oldest_first() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "first").await;
+
+    bob.send(
+        "send",
+        json!({ "content": "second", "conversation_id": conversation_id }),
+    )
+    .await;
+
+    bob.expect("sent").await;
+    alice.expect("message").await;
+
+    let history = alice
+        .request(
+            "messages",
+            json!({
+                "conversation_id": conversation_id,
+                "take": 10,
+                "after_sent_at": "2000-01-01T00:00:00Z",
+            }),
+        )
+        .await;
+
+    assert_eq!(history["conversation_id"], conversation_id);
+
+    let messages = history["messages"].as_array().unwrap();
+
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["content"], "first");
+    assert_eq!(messages[0]["from_chooser"], true);
+    assert_eq!(messages[1]["content"], "second");
+    assert_eq!(messages[1]["from_chooser"], false);
+
+    let conversations = alice.request("conversations", json!({})).await;
+
+    assert_eq!(
+        conversations["conversations"][0]["conversation_id"],
+        conversation_id
+    );
+}
+
+#[tokio::test]
+async fn counts_messages_from_each_side() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "first").await;
+
+    for content in ["second", "third"] {
+        bob.send(
+            "send",
+            json!({ "content": content, "conversation_id": conversation_id }),
+        )
+        .await;
+
+        bob.expect("sent").await;
+        alice.expect("message").await;
+    }
+
+    let stats = alice
+        .request(
+            "conversationStats",
+            json!({ "conversation_id": conversation_id }),
+        )
+        .await;
+
+    assert_eq!(stats["conversation_id"], conversation_id);
+    assert_eq!(stats["stats"]["messages_from_chooser"], 1);
+    assert_eq!(stats["stats"]["messages_from_choosee"], 2);
+}
+
+#[tokio::test]
+async fn starts_a_streak_once_both_users_message_on_a_day() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "first").await;
+
+    let conversations = alice.request("conversations", json!({})).await;
+
+    assert_eq!(conversations["conversations"][0]["streak"], 0);
+
+    for content in ["second", "third"] {
+        bob.send(
+            "send",
+            json!({ "content": content, "conversation_id": conversation_id }),
+        )
+        .await;
+
+        bob.expect("sent").await;
+        alice.expect("message").await;
+    }
+
+    // only the first of bob's messages counts
+    let conversations = alice.request("conversations", json!({})).await;
+
+    assert_eq!(
+        conversations["conversations"][0]["conversation_id"],
+        conversation_id
+    );
+    assert_eq!(conversations["conversations"][0]["streak"], 1);
+}
+
+#[tokio::test]
+async fn delivers_mentions_past_a_muted_conversation() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "hi bob").await;
+
+    alice
+        .send(
+            "setNotificationPrefs",
+            json!({ "conversation_id": conversation_id, "prefs": { "muted": true } }),
+        )
+        .await;
+
+    alice.expect("notificationPrefsChanged").await;
+
+    bob.send(
+        "send",
+        json!({ "content": "look", "conversation_id": conversation_id, "mention": true }),
+    )
+    .await;
+
+    let sent = bob.expect("sent").await;
+
+    let message = alice.expect("message").await;
+
+    assert_eq!(message["silent"], true);
+    assert_eq!(message["mention"], true);
+
+    let mention = alice.expect("mention").await;
+
+    assert_eq!(mention["conversation_id"], conversation_id);
+    assert_eq!(mention["content"], "look");
+    assert_eq!(mention["sent_at"], sent["sent_at"]);
+    assert_eq!(mention["silent"], false);
+
+    let mentions = alice.request("mentions", json!({ "take": 10 })).await;
+
+    assert_eq!(mentions["mentions"].as_array().unwrap().len(), 1);
+    assert_eq!(mentions["mentions"][0]["conversation_id"], conversation_id);
+    assert_eq!(mentions["mentions"][0]["sent_at"], sent["sent_at"]);
+
+    // only whoever was mentioned has it
+    let mentions = bob.request("mentions", json!({ "take": 10 })).await;
+
+    assert_eq!(mentions["mentions"], json!([]));
+}
+
+#[tokio::test]
+async fn relays_and_keeps_stickers_and_gifs() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "hi bob").await;
+
+    let sticker = json!({ "type": "sticker", "pack_id": "cats", "sticker_id": "wave" });
+
+    bob.send(
+        "send",
+        json!({ "content": "", "conversation_id": conversation_id, "kind": sticker }),
+    )
+    .await;
+
+    bob.expect("sent").await;
+
+    let message = alice.expect("message").await;
+
+    assert_eq!(message["kind"], sticker);
+
+    // gifs from anywhere but the provider could track who opened them
+    bob.send(
+        "send",
+        json!({
+            "content": "",
+            "conversation_id": conversation_id,
+            "kind": { "type": "gif", "provider": "giphy", "id": "abc", "url": "https://example.com/abc.gif" },
+        }),
+    )
+    .await;
+
+    bob.expect_error("INVALID_REQUEST").await;
+
+    let gif = json!({
+        "type": "gif",
+        "provider": "giphy",
+        "id": "abc",
+        "url": "https://media.giphy.com/media/abc/giphy.gif",
+    });
+
+    bob.send(
+        "send",
+        json!({ "content": "[gif]", "conversation_id": conversation_id, "kind": gif }),
+    )
+    .await;
+
+    bob.expect("sent").await;
+
+    let message = alice.expect("message").await;
+
+    assert_eq!(message["kind"], gif);
+    assert_eq!(message["content"], "[gif]");
+
+    let history = alice
+        .request(
+            "messages",
+            json!({
+                "conversation_id": conversation_id,
+                "take": 10,
+                "after_sent_at": "2000-01-01T00:00:00Z",
+            }),
+        )
+        .await;
+
+    let messages = history["messages"].as_array().unwrap();
+
+    assert_eq!(messages.len(), 3);
+    assert!(messages[0].get("kind").is_none());
+    assert_eq!(messages[1]["kind"], sticker);
+    assert_eq!(messages[2]["kind"], gif);
+}
+
+#[tokio::test]
+async fn shares_poll_votes_with_both_participants() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "hi bob").await;
+
+    alice
+        .send(
+            "createPoll",
+            json!({
+                "conversation_id": conversation_id,
+                "question": "dinner?",
+                "options": ["pizza", "tacos", "ramen"],
+            }),
+        )
+        .await;
+
+    let created = alice.expect("pollUpdate").await;
+
+    assert_eq!(bob.expect("pollUpdate").await["poll"], created["poll"]);
+    assert_eq!(created["poll"]["created_by_chooser"], true);
+
+    let poll_id = created["poll"]["poll_id"].as_str().unwrap().to_owned();
+
+    bob.send(
+        "vote",
+        json!({ "conversation_id": conversation_id, "poll_id": poll_id, "option": 1 }),
+    )
+    .await;
+
+    let voted = alice.expect("pollUpdate").await;
+
+    assert_eq!(bob.expect("pollUpdate").await["poll"], voted["poll"]);
+    assert_eq!(voted["poll"]["choosee_vote"], 1);
+
+    let votes = voted["poll"]["options"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|option| option["votes"].as_u64().unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(votes, [0, 1, 0]);
+
+    bob.send(
+        "vote",
+        json!({ "conversation_id": conversation_id, "poll_id": poll_id, "option": 3 }),
+    )
+    .await;
+
+    bob.expect_error("INVALID_REQUEST").await;
+
+    let polls = alice
+        .request("polls", json!({ "conversation_id": conversation_id }))
+        .await;
+
+    assert_eq!(polls["polls"].as_array().unwrap().len(), 1);
+    assert_eq!(polls["polls"][0]["options"][1]["votes"], 1);
+}
+
+#[tokio::test]
+async fn rejects_malformed_conversation_ids() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+
+    alice
+        .send(
+            "send",
+            json!({ "content": "hello?", "conversation_id": "not an id" }),
+        )
+        .await;
+
+    let error = alice.expect_error("INVALID_REQUEST").await;
+
+    assert_eq!(error["retryable"], false);
+
+    // the connection is still usable afterwards
+    alice.send("ping", json!({ "nonce": "n2" })).await;
+    alice.expect("pong").await;
+}
+
+#[tokio::test]
+async fn lists_friends_and_which_are_online() {
+    let server = TestServer::start().await;
+
+    server.befriend("alice", "bob").await;
+    server.befriend("alice", "carol").await;
+
+    let mut alice = server.connect("alice").await;
+    let bob = server.connect("bob").await;
+
+    online_friends_become(&mut alice, json!(["bob"])).await;
+
+    // the same list of friends is served over plain http on the websocket listener
+    let friends: Value = reqwest::Client::new()
+        .get(format!("http://{}/friends", server.addr))
+        .bearer_auth(token(SECRET, "alice", &[]))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    let mut usernames = friends["friends"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|friend| friend["username"].as_str().unwrap())
+        .collect::<Vec<_>>();
+
+    usernames.sort_unstable();
+
+    assert_eq!(usernames, ["bob", "carol"]);
+
+    bob.close().await;
+
+    online_friends_become(&mut alice, json!([])).await;
+}
+
+#[tokio::test]
+async fn rejects_friends_over_http_without_a_token() {
+    let server = TestServer::start().await;
+
+    let response = reqwest::get(format!("http://{}/friends", server.addr))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test]
+async fn rejects_friends_over_http_without_the_read_scope() {
+    let server = TestServer::start().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/friends", server.addr))
+        .bearer_auth(scoped_token(SECRET, "alice", &[], &["write"]))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 403);
+}
+
+#[tokio::test]
+async fn announces_the_successor_of_a_conversation_chosen_from_another_connection() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let predecessor_conversation_id = choose(&mut alice, &mut bob, "hi").await;
+
+    alice.close().await;
+
+    let mut alice = server.connect("alice").await;
+
+    alice
+        .send(
+            "choose",
+            json!({ "content": "hi again", "choosee_username": "bob" }),
+        )
+        .await;
+
+    // the ack, the event and the announcement can come in any order
+    let mut alice_frames = [alice.next().await.unwrap(), alice.next().await.unwrap()];
+    let mut bob_frames = [bob.next().await.unwrap(), bob.next().await.unwrap()];
+
+    alice_frames.sort_by(|a, b| a.op.cmp(&b.op));
+    bob_frames.sort_by(|a, b| a.op.cmp(&b.op));
+
+    assert_eq!(alice_frames[1].op, "sent");
+    assert_eq!(bob_frames[0].op, "chosen");
+
+    let successor_conversation_id = &alice_frames[1].d["conversation_id"];
+
+    for rollover in [&alice_frames[0], &bob_frames[1]] {
+        assert_eq!(rollover.op, "conversationRollover");
+        assert_eq!(rollover.d["conversation_id"], predecessor_conversation_id);
+        assert_eq!(
+            &rollover.d["successor_conversation_id"],
+            successor_conversation_id
+        );
+    }
+}
+
+#[tokio::test]
+async fn reports_choosee_presence_to_the_chooser() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+    let mut bob = server.connect("bob").await;
+
+    let conversation_id = choose(&mut alice, &mut bob, "hi").await;
+
+    bob.send(
+        "registerPresenceChoosee",
+        json!({ "conversation_id": conversation_id, "leaving": false }),
+    )
+    .await;
+
+    let event = alice.expect("chooseePresence").await;
+
+    assert_eq!(event["conversation_id"], conversation_id);
+    assert_eq!(event["leaving"], false);
+
+    let presence = alice
+        .request(
+            "chooseePresence",
+            json!({ "conversation_id": conversation_id, "take": 10 }),
+        )
+        .await;
+
+    assert_eq!(presence["conversation_id"], conversation_id);
+    assert_eq!(
+        presence["events"],
+        json!([{ "leaving": false, "occurred_at": event["occurred_at"] }])
+    );
+
+    // only the choosee's presence is the chooser's business
+    alice
+        .send(
+            "registerPresenceChoosee",
+            json!({ "conversation_id": conversation_id, "leaving": true }),
+        )
+        .await;
+
+    assert!(alice.next().await.is_none());
+}
+
+#[tokio::test]
+async fn closes_connections_when_shutting_down() {
+    let mut server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+
+    assert!(alice.hello["features"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("closeReasons")));
+
+    server.shut_down();
+
+    let close_frame = alice.expect_close().await;
+
+    // reconnect right away, to another node
+    assert_eq!(u16::from(close_frame.code), 4005);
+    assert!(close_frame.reason.contains(alice.connection_id()));
+
+    // and nothing new is accepted meanwhile
+    assert!(Client::connect(server.addr, &token(SECRET, "bob", &[]))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn throttles_handshakes_per_address() {
+    let server = TestServer::start_with(handshake_limits(2.0, 10)).await;
+
+    server.connect("alice").await;
+    server.connect("alice").await;
+
+    match Client::connect(server.addr, &token(SECRET, "alice", &[])).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 429),
+        Err(err) => panic!("Expected a 429 but got {}", err),
+        Ok(_) => panic!("Connected over the handshake budget"),
+    }
+
+    // and banned since, so not even a response
+    match Client::connect(server.addr, &token(SECRET, "alice", &[])).await {
+        Err(tungstenite::Error::Http(response)) => {
+            panic!("Expected no response but got {}", response.status())
+        }
+        Err(_) => {}
+        Ok(_) => panic!("Connected while banned"),
+    }
+}
+
+#[tokio::test]
+async fn bans_an_address_after_too_many_failed_authentications() {
+    let server = TestServer::start_with(handshake_limits(20.0, 2)).await;
+
+    for _ in 0..3 {
+        assert!(
+            Client::connect(server.addr, &token("not the secret", "alice", &[]))
+                .await
+                .is_err()
+        );
+    }
+
+    // a valid token doesn't help once the address is banned
+    assert!(Client::connect(server.addr, &token(SECRET, "alice", &[]))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn counts_failed_http_authentications_toward_a_ban() {
+    let server = TestServer::start_with(handshake_limits(20.0, 2)).await;
+
+    for _ in 0..3 {
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/friends", server.addr))
+            .bearer_auth(token("not the secret", "alice", &[]))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 401);
+    }
+
+    assert!(Client::connect(server.addr, &token(SECRET, "alice", &[]))
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn lets_admins_ban_and_unban_addresses() {
+    let server = TestServer::start().await;
+    let mut admin = Client::connect(server.addr, &token(SECRET, "admin", &["admin"]))
+        .await
+        .unwrap();
+
+    admin.send("listBans", Value::Null).await;
+
+    assert_eq!(admin.expect("bans").await["bans"], json!([]));
+
+    admin
+        .send(
+            "ban",
+            json!({ "ip": "127.0.0.1", "seconds": 60, "reason": "testing" }),
+        )
+        .await;
+
+    let banned = admin.expect("banned").await;
+
+    assert_eq!(banned["ban"]["ip"], "127.0.0.1");
+    assert_eq!(banned["everywhere"], true);
+
+    admin.send("listBans", Value::Null).await;
+
+    let bans = admin.expect("bans").await;
+
+    assert_eq!(bans["bans"][0]["ip"], "127.0.0.1");
+    assert_eq!(bans["bans"][0]["reason"], "testing");
+
+    // connections already open stay open, new ones are dropped
+    assert!(Client::connect(server.addr, &token(SECRET, "alice", &[]))
+        .await
+        .is_err());
+
+    admin.send("unban", json!({ "ip": "127.0.0.1" })).await;
+
+    assert_eq!(admin.expect("unbanned").await["everywhere"], true);
+
+    admin.send("listBans", Value::Null).await;
+
+    assert_eq!(admin.expect("bans").await["bans"], json!([]));
+
+    server.connect("alice").await;
+}
+
+#[tokio::test]
+async fn acknowledges_admin_operations() {
+    let server = TestServer::start().await;
+    let mut admin = Client::connect(server.addr, &token(SECRET, "admin", &["admin"]))
+        .await
+        .unwrap();
+    let mut bob = server.connect("bob").await;
+
+    admin.send("kick", json!({ "username": "bob" })).await;
+
+    let kicked = admin.expect("kicked").await;
+
+    assert_eq!(kicked["username"], "bob");
+    assert_eq!(kicked["connections"], 1);
+    assert!(kicked.get("otherNodeId").is_none());
+
+    bob.expect_close().await;
+
+    admin
+        .send(
+            "announce",
+            json!({ "title": "Maintenance", "body": "Soon", "level": "info" }),
+        )
+        .await;
+
+    // the admin's own connection gets it too, in whichever order the ack and the bus deliver
+    let mut frames = [admin.next().await.unwrap(), admin.next().await.unwrap()];
+
+    frames.sort_by(|a, b| a.op.cmp(&b.op));
+
+    assert_eq!(frames[0].op, "announced");
+    assert_eq!(frames[0].d["everywhere"], true);
+    assert_eq!(frames[1].op, "announcement");
+
+    admin.send("replayFailedEvents", Value::Null).await;
+
+    assert_eq!(admin.expect("failedEventsReplayed").await["replayed"], 0);
+}
+
+#[tokio::test]
+async fn rejects_revoked_tokens_before_the_upgrade() {
+    let server = TestServer::start().await;
+    let mut admin = Client::connect(server.addr, &token(SECRET, "admin", &["admin"]))
+        .await
+        .unwrap();
+    let mut alice = server.connect("alice").await;
+
+    admin
+        .send("revokeTokens", json!({ "username": "alice" }))
+        .await;
+
+    assert_eq!(admin.expect("tokensRevoked").await["username"], "alice");
+    assert_eq!(alice.expect_close().await.code, CloseCode::Policy);
+
+    match Client::connect(server.addr, &token(SECRET, "alice", &[])).await {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 401),
+        Err(err) => panic!("Expected a 401 but got {}", err),
+        Ok(_) => panic!("Connected with a revoked token"),
+    }
+}
+
+#[tokio::test]
+async fn only_lets_admins_ban() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+
+    alice
+        .send(
+            "ban",
+            json!({ "ip": "127.0.0.1", "seconds": 60, "reason": "testing" }),
+        )
+        .await;
+
+    alice.expect_error("FORBIDDEN").await;
+
+    server.connect("bob").await;
+}
+
+#[tokio::test]
+async fn drops_roles_a_refreshed_token_no_longer_has() {
+    let server = TestServer::start().await;
+    let mut admin = Client::connect(server.addr, &token(SECRET, "admin", &["admin"]))
+        .await
+        .unwrap();
+
+    admin
+        .send(
+            "refreshToken",
+            json!({ "token": token(SECRET, "admin", &[]) }),
+        )
+        .await;
+
+    let refreshed = admin.expect("tokenRefreshed").await;
+
+    assert!(refreshed["token_expires_at"].is_string());
+
+    admin.send("listBans", Value::Null).await;
+
+    admin.expect_error("FORBIDDEN").await;
+}
+
+#[tokio::test]
+async fn deletes_an_account_only_with_a_fresh_confirmation_token() {
+    let server = TestServer::start().await;
+    let mut alice = server.connect("alice").await;
+
+    // and confirmation tokens aren't access tokens
+    assert!(Client::connect(
+        server.addr,
+        &purpose_token(SECRET, "alice", "delete_account", Duration::ZERO),
+    )
+    .await
+    .is_err());
+
+    alice
+        .send(
+            "deleteMyAccount",
+            json!({ "confirmation_token": token(SECRET, "alice", &[]) }),
+        )
+        .await;
+
+    alice.expect_error("FORBIDDEN").await;
+
+    alice
+        .send(
+            "deleteMyAccount",
+            json!({
+                "confirmation_token": purpose_token(
+                    SECRET,
+                    "alice",
+                    "delete_account",
+                    Duration::from_secs(3600),
+                ),
+            }),
+        )
+        .await;
+
+    alice.expect_error("FORBIDDEN").await;
+
+    alice
+        .send(
+            "deleteMyAccount",
+            json!({
+                "confirmation_token": purpose_token(SECRET, "alice", "delete_account", Duration::ZERO),
+            }),
+        )
+        .await;
+
+    alice.expect("accountDeletionScheduled").await;
+}
+
+// version 2 of the proxy protocol, for a tcp connection over ipv4
+fn proxy_v2_header(source: [u8; 4]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+
+    header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    header.extend_from_slice(&source);
+    header.extend_from_slice(&[127, 0, 0, 1, 0x13, 0x88, 0, 80]);
+
+    header
+}
+
+#[tokio::test]
+async fn limits_the_address_from_the_proxy_protocol_header() {
+    let server = TestServer::start_with(Settings {
+        proxy_protocol: true,
+        ..handshake_limits(1.0, 10)
+    })
+    .await;
+
+    let alice = token(SECRET, "alice", &[]);
+
+    Client::connect_with_proxy_header(
+        server.addr,
+        b"PROXY TCP4 203.0.113.7 127.0.0.1 5000 80\r\n",
+        &alice,
+    )
+    .await
+    .unwrap();
+
+    // the same client, over budget
+    match Client::connect_with_proxy_header(server.addr, &proxy_v2_header([203, 0, 113, 7]), &alice)
+        .await
+    {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 429),
+        Err(err) => panic!("Expected a 429 but got {}", err),
+        Ok(_) => panic!("Connected over the handshake budget"),
+    }
+
+    // another client through the same proxy
+    Client::connect_with_proxy_header(server.addr, &proxy_v2_header([203, 0, 113, 8]), &alice)
+        .await
+        .unwrap();
+
+    // nothing gets in without a header
+    assert!(Client::connect(server.addr, &alice).await.is_err());
+}
+
+// challenges everyone but carol, and takes "ok" as the solution
+struct TestChallenges;
+
+#[async_trait]
+impl ChallengeVerifier for TestChallenges {
+    async fn challenge(&self, context: &ChallengeContext) -> Option<Challenge> {
+        (context.username != "carol").then(|| Challenge {
+            kind: ChallengeKind::Captcha,
+            site_key: Some("site".to_owned()),
+            nonce: "nonce".to_owned(),
+        })
+    }
+
+    async fn verify(
+        &self,
+        _context: &ChallengeContext,
+        _challenge: &Challenge,
+        solution: &str,
+    ) -> Result<bool, ChallengeError> {
+        Ok(solution == "ok")
+    }
+}
+
+#[tokio::test]
+async fn holds_back_mutations_until_the_challenge_is_solved() {
+    let server = TestServer::start_with_challenges(Arc::new(TestChallenges)).await;
+    let mut alice = server.connect("alice").await;
+    let mut carol = server.connect("carol").await;
+
+    let challenge = alice.expect("challengeRequired").await;
+
+    assert_eq!(challenge["kind"], "captcha");
+    assert_eq!(challenge["site_key"], "site");
+
+    alice
+        .send(
+            "choose",
+            json!({ "content": "hi", "choosee_username": "carol" }),
+        )
+        .await;
+
+    alice.expect("challengeRequired").await;
+
+    // queries aren't held back
+    alice.send("ping", json!({ "nonce": "n1" })).await;
+    alice.expect("pong").await;
+
+    alice
+        .send("solveChallenge", json!({ "solution": "wrong" }))
+        .await;
+    alice.expect_error("INVALID_REQUEST").await;
+
+    alice
+        .send("solveChallenge", json!({ "solution": "ok" }))
+        .await;
+    alice.expect("challengeSolved").await;
+
+    alice
+        .send(
+            "choose",
+            json!({ "content": "hi", "choosee_username": "carol" }),
+        )
+        .await;
+
+    alice.expect("sent").await;
+
+    // carol was never challenged, so this is the first thing she's been sent
+    carol.expect("chosen").await;
+}
+
+#[tokio::test]
+async fn refuses_to_start_with_a_runtime_config_connections_would_panic_on() {
+    let settings = Settings::default();
+
+    settings.runtime.store(Arc::new(RuntimeConfig {
+        send_budget: Budget {
+            burst: 10.0,
+            per_second: 0.0,
+        },
+        ..RuntimeConfig::default()
+    }));
+
+    let result = Server::builder()
+        .with_auth(JWTAuth::new(
+            SECRET,
+            JWTValidationConfig {
+                issuer: None,
+                audience: None,
+                leeway: Duration::from_secs(60),
+                max_token_age: None,
+            },
+        ))
+        .with_hasher(Arc::new(Hasher::new(
+            SECRET.to_owned(),
+            HashAlgorithm::HmacSha256,
+            HashEncoding::Base64Url,
+            false,
+        )))
+        .with_settings(settings)
+        .bind("127.0.0.1:0".parse().unwrap())
+        .run()
+        .await;
+
+    assert!(matches!(result, Err(ServerError::InvalidRuntimeConfig(_))));
+}
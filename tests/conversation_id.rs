@@ -0,0 +1,192 @@
+// conversation ids are built from hashed usernames and parsed back from whatever clients send, so these check that
+// any username, under every hashing setup a deployment can be in, gives an id that survives the trip
+
+use proptest::prelude::*;
+
+use realtime::{
+    conversation_id::{ConversationId, ConversationRole},
+    hash::{HashAlgorithm, HashEncoding, Hasher},
+};
+
+// empty, ascii, anything unicode and long ones, since usernames aren't validated before they're hashed
+fn username() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just(String::new()),
+        "[a-z0-9_.]{1,20}",
+        any::<String>(),
+        ".{200,1000}",
+    ]
+}
+
+// every combination Init can build, including the rollout from md5 base64 hashes. generated as plain values since
+// the hasher isn't Debug, so it doesn't print its secret
+#[derive(Debug, Clone)]
+struct HasherOptions {
+    secret: String,
+    md5: bool,
+    base64: bool,
+    accept_legacy: bool,
+}
+
+impl HasherOptions {
+    fn hasher(&self) -> Hasher {
+        Hasher::new(
+            self.secret.clone(),
+            if self.md5 {
+                HashAlgorithm::Md5
+            } else {
+                HashAlgorithm::HmacSha256
+            },
+            if self.base64 {
+                HashEncoding::Base64
+            } else {
+                HashEncoding::Base64Url
+            },
+            self.accept_legacy,
+        )
+    }
+}
+
+fn hasher_options() -> impl Strategy<Value = HasherOptions> {
+    (any::<String>(), any::<bool>(), any::<bool>(), any::<bool>()).prop_map(
+        |(secret, md5, base64, accept_legacy)| HasherOptions {
+            secret,
+            md5,
+            base64,
+            accept_legacy,
+        },
+    )
+}
+
+proptest! {
+    #[test]
+    fn parses_back_to_the_same_id(
+        hasher_options in hasher_options(),
+        chooser in username(),
+        choosee in username(),
+    ) {
+        let hasher = hasher_options.hasher();
+
+        let conversation_id = ConversationId::new(&hasher, &chooser, &choosee);
+        let string = conversation_id.to_string();
+
+        let parsed = ConversationId::try_from(string.clone());
+
+        prop_assert!(parsed.is_ok(), "{} didn't parse", string);
+
+        let parsed = parsed.unwrap();
+
+        prop_assert!(parsed == conversation_id);
+        prop_assert_eq!(parsed.to_string(), string);
+        prop_assert_eq!(parsed.get_window(), conversation_id.get_window());
+    }
+
+    #[test]
+    fn resolves_roles_from_the_usernames(
+        hasher_options in hasher_options(),
+        chooser in username(),
+        choosee in username(),
+        other in username(),
+    ) {
+        let hasher = hasher_options.hasher();
+
+        prop_assume!(other != chooser && other != choosee);
+
+        let conversation_id =
+            ConversationId::try_from(ConversationId::new(&hasher, &chooser, &choosee).to_string())
+                .unwrap();
+
+        prop_assert!(matches!(
+            conversation_id.get_role_of_username(&hasher, &chooser),
+            ConversationRole::Chooser
+        ));
+
+        // someone choosing themselves is the chooser first
+        if chooser != choosee {
+            prop_assert!(matches!(
+                conversation_id.get_role_of_username(&hasher, &choosee),
+                ConversationRole::Choosee
+            ));
+        }
+
+        prop_assert!(matches!(
+            conversation_id.get_role_of_username(&hasher, &other),
+            ConversationRole::NotInConversation
+        ));
+    }
+
+    #[test]
+    fn hashes_into_the_id(hasher_options in hasher_options(), chooser in username(), choosee in username()) {
+        let hasher = hasher_options.hasher();
+
+        let conversation_id = ConversationId::new(&hasher, &chooser, &choosee);
+
+        prop_assert_eq!(conversation_id.get_chooser_hash(), hasher.hash(&chooser));
+        prop_assert_eq!(conversation_id.get_choosee_hash(), hasher.hash(&choosee));
+
+        for username in [&chooser, &choosee] {
+            let hash = hasher.hash(username);
+
+            // never has a dot, which separates the parts of the id, or anything that isn't allowed in a bus subject
+            prop_assert_eq!(hash.len(), 22);
+            prop_assert!(hash
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '-' | '_')));
+
+            prop_assert_eq!(hasher.hash(username), hash.clone());
+            prop_assert_eq!(&hasher.hashes(username)[0], &hash);
+        }
+    }
+
+    // the two hashes and a time segment run together, as ids were built before the dotted format
+    #[test]
+    fn keeps_legacy_ids_working(
+        secret in any::<String>(),
+        chooser in username(),
+        choosee in username(),
+        hour in 0..24u32,
+    ) {
+        let legacy_hasher = Hasher::new(secret.clone(), HashAlgorithm::Md5, HashEncoding::Base64, false);
+        let hasher = Hasher::new(secret, HashAlgorithm::HmacSha256, HashEncoding::Base64Url, true);
+
+        let string = format!(
+            "{}{}23115{}",
+            legacy_hasher.hash(&chooser),
+            legacy_hasher.hash(&choosee),
+            hour
+        );
+
+        let parsed = ConversationId::try_from(string.clone());
+
+        prop_assert!(parsed.is_ok(), "{} didn't parse", string);
+
+        let parsed = parsed.unwrap();
+
+        prop_assert_eq!(parsed.to_string(), string);
+        prop_assert!(!parsed.is_current());
+        prop_assert!(matches!(
+            parsed.get_role_of_username(&hasher, &chooser),
+            ConversationRole::Chooser
+        ));
+
+        if chooser != choosee {
+            prop_assert!(matches!(
+                parsed.get_role_of_username(&hasher, &choosee),
+                ConversationRole::Choosee
+            ));
+        }
+    }
+
+    #[test]
+    fn never_panics_on_arbitrary_ids(string in prop_oneof![
+        any::<String>(),
+        // four parts, so parsing gets past the split
+        "[^.]{0,30}\\.[^.]{0,30}\\.[0-9 ]{0,12}\\.[^.]{0,14}",
+        // no dots and long enough to be read as a legacy id
+        "[A-Za-z0-9+/]{44}[0-9 ]{0,10}",
+    ]) {
+        if let Ok(conversation_id) = ConversationId::try_from(string.clone()) {
+            prop_assert_eq!(conversation_id.to_string(), string);
+        }
+    }
+}
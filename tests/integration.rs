@@ -0,0 +1,239 @@
+// the same kind of end to end run as the conformance suite, but against real scylla and nats containers instead of the
+// in memory backends. needs docker, so it only runs with `cargo test --features integration`
+
+mod client;
+
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use testcontainers::{clients::Cli, core::WaitFor, GenericImage, RunnableImage};
+
+use client::{token, Client};
+use realtime::{
+    auth::{JWTAuth, JWTValidationConfig},
+    db::{
+        CoalescerOptions, Consistencies, ContentKeys, KeyspaceOptions, Replication, ScyllaStorage,
+        SessionOptions, Storage,
+    },
+    hash::{HashAlgorithm, HashEncoding, Hasher},
+    message_bus::{MessageBus, NatsBus},
+    retry_policy::RetryPolicy,
+    Server,
+};
+use scylla::statement::Consistency;
+
+const SECRET: &str = "integration";
+
+// scylla only listens for cql some time after logging that it's starting to, and the schema migrations want every
+// node to agree on the schema, so connecting is retried for a while
+const SCYLLA_ATTEMPTS: u32 = 30;
+
+fn scylla_image() -> RunnableImage<GenericImage> {
+    RunnableImage::from((
+        GenericImage::new("scylladb/scylla", "5.2")
+            .with_exposed_port(9042)
+            .with_wait_for(WaitFor::message_on_stderr(
+                "Starting listening for CQL clients",
+            )),
+        // one core and little memory is plenty for a test, and keeps it from taking over the machine
+        ["--smp", "1", "--memory", "512M", "--overprovisioned", "1"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+fn nats_image() -> GenericImage {
+    GenericImage::new("nats", "2.10")
+        .with_exposed_port(4222)
+        .with_wait_for(WaitFor::message_on_stderr("Server is ready"))
+}
+
+// runs the schema migrations along the way
+async fn scylla(port: u16) -> Arc<dyn Storage> {
+    let session = SessionOptions {
+        known_nodes: vec![format!("127.0.0.1:{}", port)],
+        username: "cassandra".to_owned(), // only sent if the cluster asks, which the image doesn't by default
+        password: "cassandra".to_owned(),
+        connections_per_shard: None,
+        connection_timeout: Duration::from_secs(5),
+        request_timeout: Some(Duration::from_secs(30)),
+        local_datacenter: None,
+        include_remote_nodes: true,
+        token_aware: true,
+        retry_policy: RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(20),
+            max_delay: Duration::from_millis(500),
+        },
+        retry_budget_ratio: 0.1,
+        retry_budget_capacity: 100.0,
+    };
+
+    let keyspace = KeyspaceOptions {
+        name: "integration".to_owned(),
+        replication: Replication::Simple {
+            replication_factor: 1,
+        },
+        create: true,
+    };
+
+    let consistencies = Consistencies {
+        writes: Consistency::LocalQuorum,
+        history_reads: Consistency::LocalOne,
+        reads: Consistency::LocalQuorum,
+    };
+
+    let coalescing = Some(CoalescerOptions {
+        flush_interval: Duration::from_millis(2),
+        max_batch_size: 32,
+    });
+
+    let mut attempt = 1;
+
+    loop {
+        // encrypted, so the messages that make it back to clients went through decryption too
+        let content_keys =
+            ContentKeys::parse("test".to_owned(), &format!("test:{}=", "A".repeat(43))).unwrap();
+
+        match ScyllaStorage::build(
+            &session,
+            &keyspace,
+            &consistencies,
+            coalescing,
+            None,
+            Some(content_keys),
+        )
+        .await
+        {
+            Ok(db) => return Arc::new(db),
+            Err(err) if attempt < SCYLLA_ATTEMPTS => {
+                eprintln!("Scylla not ready yet ({}), retrying", err);
+
+                attempt += 1;
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Err(err) => panic!("Failed to connect to scylla: {}", err),
+        }
+    }
+}
+
+async fn nats(port: u16) -> Arc<dyn MessageBus> {
+    let nc = nats::asynk::connect(format!("127.0.0.1:{}", port))
+        .await
+        .expect("Failed to connect to nats");
+
+    Arc::new(NatsBus::new(nc))
+}
+
+// one gateway node. several can share the same scylla and nats, like a real cluster
+async fn start_node(db: Arc<dyn Storage>, message_bus: Arc<dyn MessageBus>) -> SocketAddr {
+    let (bound_tx, bound_rx) = tokio::sync::oneshot::channel();
+
+    let server = Server::builder()
+        .with_storage(db)
+        .with_bus(message_bus)
+        .with_auth(JWTAuth::new(
+            SECRET,
+            JWTValidationConfig {
+                issuer: None,
+                audience: None,
+                leeway: Duration::from_secs(60),
+                max_token_age: None,
+            },
+        ))
+        .with_hasher(Arc::new(Hasher::new(
+            SECRET.to_owned(),
+            HashAlgorithm::HmacSha256,
+            HashEncoding::Base64Url,
+            false,
+        )))
+        .bind("127.0.0.1:0".parse().unwrap())
+        .on_bound(|addrs| {
+            let _ = bound_tx.send(addrs[0]);
+        });
+
+    tokio::spawn(server.run());
+
+    bound_rx.await.expect("Server failed to start")
+}
+
+// containers are started through the blocking docker cli, so the runtime is only built once they're up
+#[test]
+fn delivers_messages_between_nodes_through_scylla_and_nats() {
+    let docker = Cli::default();
+    let scylla_container = docker.run(scylla_image());
+    let nats_container = docker.run(nats_image());
+
+    let scylla_port = scylla_container.get_host_port_ipv4(9042);
+    let nats_port = nats_container.get_host_port_ipv4(4222);
+
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let db = scylla(scylla_port).await;
+
+        // alice and bob on different nodes, so delivery has to go through nats
+        let alice_addr = start_node(db.clone(), nats(nats_port).await).await;
+        let bob_addr = start_node(db, nats(nats_port).await).await;
+
+        let mut alice = Client::connect(alice_addr, &token(SECRET, "alice", &[]))
+            .await
+            .unwrap();
+        let mut bob = Client::connect(bob_addr, &token(SECRET, "bob", &[]))
+            .await
+            .unwrap();
+
+        // the subscription for a new connection is made after hello is sent, and nats doesn't hold on to messages
+        // nobody was subscribed for yet
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        alice
+            .send(
+                "choose",
+                json!({ "content": "hi bob", "choosee_username": "bob" }),
+            )
+            .await;
+
+        let sent = alice.expect("sent").await;
+        let conversation_id = sent["conversation_id"].as_str().unwrap().to_owned();
+
+        let chosen = bob.expect("chosen").await;
+
+        assert_eq!(chosen["conversation_id"], conversation_id);
+        assert_eq!(chosen["content"], "hi bob");
+
+        bob.send(
+            "send",
+            json!({ "content": "hi alice", "conversation_id": conversation_id }),
+        )
+        .await;
+
+        bob.expect("sent").await;
+
+        let message = alice.expect("message").await;
+
+        assert_eq!(message["content"], "hi alice");
+
+        // both messages made it to scylla, and either node can read them back
+        let history = bob
+            .request(
+                "messages",
+                json!({
+                    "conversation_id": conversation_id,
+                    "take": 10,
+                    "after_sent_at": "2000-01-01T00:00:00Z",
+                }),
+            )
+            .await;
+
+        let contents = history["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["content"].as_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(contents, ["hi bob", "hi alice"]);
+    });
+}
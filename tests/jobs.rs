@@ -0,0 +1,64 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+use std::time::Duration;
+
+use realtime::{
+    db::MemoryStorage,
+    jobs::{self, Job, JobError, JobHandler, MemoryJobQueue, Workers},
+    models::job_status::JobState,
+    retry_policy::RetryPolicy,
+};
+
+struct Panics;
+
+#[async_trait]
+impl JobHandler for Panics {
+    async fn handle(&self, _job: &Job) -> Result<(), JobError> {
+        panic!("Handler panicked");
+    }
+}
+
+#[tokio::test]
+async fn records_a_panicked_job_as_failed() {
+    let queue = Arc::new(MemoryJobQueue::default());
+    let db = Arc::new(MemoryStorage::default());
+
+    let retry_policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(1),
+    };
+
+    tokio::task::spawn(
+        Workers::new(queue.clone(), db.clone(), retry_policy)
+            .handle("panics", Arc::new(Panics))
+            .run(1),
+    );
+
+    let job = Job::new("panics", ());
+    let job_id = job.job_id.clone();
+
+    jobs::enqueue(queue.as_ref(), db.as_ref(), job)
+        .await
+        .unwrap();
+
+    let job_status = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match db.job_status(&job_id) {
+                Some(job_status)
+                    if job_status.state != JobState::Queued
+                        && job_status.state != JobState::Running =>
+                {
+                    return job_status
+                }
+                _ => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("Timed out waiting for the job to settle");
+
+    assert!(job_status.state == JobState::Failed);
+    assert_eq!(job_status.attempts, 1);
+    assert_eq!(job_status.last_error.as_deref(), Some("Job panicked"));
+}